@@ -0,0 +1,43 @@
+//! Example of Criterion benchmarks run under contention
+//!
+//! Run with `cargo bench --features criterion`.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use testbench::criterion::{bench_contention_sweep, bench_under_contention};
+
+/// Compare the cost of an atomic increment with and without contention
+fn fetch_add(c: &mut Criterion) {
+    let atom = Arc::new(AtomicUsize::new(0));
+    c.bench_function("fetch_add/uncontended", |b| {
+        b.iter(|| atom.fetch_add(1, Ordering::Relaxed))
+    });
+    let antagonist_atom = atom.clone();
+    bench_under_contention(
+        c,
+        "fetch_add/contended",
+        move || antagonist_atom.fetch_add(1, Ordering::Relaxed),
+        || atom.fetch_add(1, Ordering::Relaxed),
+    );
+}
+
+/// Study how the cost of an atomic increment scales with contention
+fn fetch_add_sweep(c: &mut Criterion) {
+    let atom = Arc::new(AtomicUsize::new(0));
+    bench_contention_sweep(
+        c,
+        "fetch_add_sweep",
+        &[0, 1, 2, 4],
+        |_| {
+            let atom = atom.clone();
+            move || atom.fetch_add(1, Ordering::Relaxed)
+        },
+        || atom.fetch_add(1, Ordering::Relaxed),
+    );
+}
+
+criterion_group!(benches, fetch_add, fetch_add_sweep);
+criterion_main!(benches);
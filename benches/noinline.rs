@@ -0,0 +1,59 @@
+//! Effect of `noinline::consume()` on a computation whose result is unused,
+//! of `noinline::observe()` on stores which are never read, and of
+//! `noinline::call_mut_with()` on an antagonist fed by a local counter
+//!
+//! Run with `cargo bench --features criterion --bench noinline`.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use testbench::noinline;
+
+/// Sum some integers, discarding the sum or consuming it, store some integers,
+/// with or without observing the stores, then call an antagonist with a
+/// counter, with or without an inlining barrier
+fn noinline(c: &mut Criterion) {
+    let data = (0..1000u64).collect::<Vec<_>>();
+    c.bench_function("noinline/unused_sum", |b| {
+        b.iter(|| {
+            let _sum = data.iter().sum::<u64>();
+        })
+    });
+    c.bench_function("noinline/consumed_sum", |b| {
+        b.iter(|| noinline::consume(data.iter().sum::<u64>()))
+    });
+
+    let mut target = 0u64;
+    c.bench_function("noinline/unobserved_stores", |b| {
+        b.iter(|| {
+            for i in 0..1000 {
+                target = i;
+            }
+        })
+    });
+    c.bench_function("noinline/observed_stores", |b| {
+        b.iter(|| {
+            for i in 0..1000 {
+                target = i;
+                noinline::observe(&target);
+            }
+        })
+    });
+
+    let mut total = 0u64;
+    let mut antagonist = |i: u64| {
+        total = total.wrapping_add(i * i);
+        total
+    };
+    c.bench_function("noinline/inlined_antagonist", |b| {
+        b.iter(|| (0..1000).map(&mut antagonist).last())
+    });
+    c.bench_function("noinline/call_mut_with_antagonist", |b| {
+        b.iter(|| {
+            (0..1000)
+                .map(|i| noinline::call_mut_with(&mut antagonist, i))
+                .last()
+        })
+    });
+}
+
+criterion_group!(benches, noinline);
+criterion_main!(benches);
@@ -0,0 +1,34 @@
+//! Effect of optimization barriers on a trivially eliminable loop
+//!
+//! Run with `cargo bench --features criterion --bench opaque`, and add the
+//! `asm-black-box` feature to benchmark the inline assembly barrier.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use testbench::opaque;
+
+/// Sum some integers with no barrier, the standard hint, or this crate's
+/// barrier on every integer, then discard the sum
+fn opaque(c: &mut Criterion) {
+    const COUNT: u64 = 1000;
+    c.bench_function("opaque/none", |b| {
+        b.iter(|| {
+            let _sum = (0..COUNT).sum::<u64>();
+        })
+    });
+    // Criterion requires a much newer Rust than this crate, so the standard
+    // hint can be used here even though it is not available to the crate
+    #[allow(clippy::incompatible_msrv)]
+    c.bench_function("opaque/hint", |b| {
+        b.iter(|| {
+            let _sum = (0..COUNT).map(std::hint::black_box).sum::<u64>();
+        })
+    });
+    c.bench_function("opaque/black_box", |b| {
+        b.iter(|| {
+            let _sum = (0..COUNT).map(opaque::black_box).sum::<u64>();
+        })
+    });
+}
+
+criterion_group!(benches, opaque);
+criterion_main!(benches);
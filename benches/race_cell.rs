@@ -0,0 +1,30 @@
+//! Cost of RaceCell reads and writes for several kinds of contents
+//!
+//! Run with `cargo bench --features criterion --bench race_cell`.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use testbench::race_cell::{AtomicData, Locked, RaceCell};
+
+/// Benchmark the basic operations of a RaceCell with some contents
+fn bench_contents<T: AtomicData>(c: &mut Criterion, name: &str, value: T) {
+    let cell = RaceCell::new(value.clone());
+    c.bench_function(&format!("race_cell/{}/set", name), |b| {
+        b.iter(|| cell.set(value.clone()))
+    });
+    c.bench_function(&format!("race_cell/{}/get", name), |b| {
+        b.iter(|| cell.get())
+    });
+    c.bench_function(&format!("race_cell/{}/is_consistent", name), |b| {
+        b.iter(|| cell.is_consistent())
+    });
+}
+
+/// Compare primitive, array and Mutex-protected contents
+fn race_cell(c: &mut Criterion) {
+    bench_contents(c, "usize", 42usize);
+    bench_contents(c, "u64x8", [42u64; 8]);
+    bench_contents(c, "locked_string", Locked("x".repeat(64)));
+}
+
+criterion_group!(benches, race_cell);
+criterion_main!(benches);
@@ -0,0 +1,36 @@
+//! Cost of running an empty concurrent test with fresh threads, as done by
+//! `concurrent_test_2()`, or with the persistent workers of a
+//! `ConcurrentRunner`
+//!
+//! Run with `cargo bench --features criterion --bench runner`.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use testbench::runner::ConcurrentRunner;
+
+/// Number of concurrent tests run per benchmark iteration
+const INVOCATIONS: usize = 10_000;
+
+/// Run many empty concurrent tests in both ways
+fn runner(c: &mut Criterion) {
+    let mut group = c.benchmark_group("runner");
+    group.sample_size(10);
+    group.bench_function("concurrent_test_2", |b| {
+        b.iter(|| {
+            for _ in 0..INVOCATIONS {
+                testbench::concurrent_test_2(|| {}, || {});
+            }
+        })
+    });
+    let mut runner = ConcurrentRunner::new();
+    group.bench_function("run_2", |b| {
+        b.iter(|| {
+            for _ in 0..INVOCATIONS {
+                runner.run_2(|| {}, || {});
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, runner);
+criterion_main!(benches);
@@ -0,0 +1,25 @@
+//! Build script, which flags sanitized builds for `testbench::scale_iters()`
+//! and `testbench::env::sanitizers()`
+
+use std::env;
+
+/// Sanitizers which `testbench::env::SanitizerSet` tells apart
+const KNOWN_SANITIZERS: &[&str] = &["address", "hwaddress", "leak", "memory", "thread"];
+
+fn main() {
+    // Cargo exposes the cfg(sanitize = "...") that -Zsanitizer sets, which
+    // cannot be queried from the crate itself without a nightly feature
+    if let Some(sanitizers) = env::var_os("CARGO_CFG_SANITIZE") {
+        println!("cargo:rustc-cfg=testbench_sanitize");
+        for sanitizer in sanitizers.to_string_lossy().split(',') {
+            let sanitizer = sanitizer.trim();
+            let sanitizer = if KNOWN_SANITIZERS.contains(&sanitizer) {
+                sanitizer
+            } else {
+                "other"
+            };
+            println!("cargo:rustc-cfg=testbench_sanitize=\"{}\"", sanitizer);
+        }
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -0,0 +1,414 @@
+//! Control over which CPUs threads are allowed to run on
+//!
+//! Contention behaves very differently depending on whether two threads share
+//! a physical core (hyperthreading), a cache, or merely a machine. To get
+//! reproducible results, it is therefore useful to pin threads to chosen CPUs.
+//!
+//! CPUs are identified by the index that the operating system gives them.
+//! Actual pinning support requires the `affinity` feature, and is currently
+//! available on Linux and Windows, where the CPU topology is respectively read
+//! from sysfs and queried using `GetLogicalProcessorInformation`. In other
+//! configurations, including macOS, which does not support thread pinning,
+//! the functions of this module report an `AffinityError::Unsupported` error.
+
+use std::{fmt, io};
+
+/// Pin the current thread to a single logical CPU
+pub fn pin_current_thread_to(cpu: usize) -> Result<(), AffinityError> {
+    backend::set_current_thread_cpus(&[cpu]).map(|_previous| ())
+}
+
+/// List the logical CPUs that this process is allowed to run on
+///
+/// Where this information is not available, this falls back to assuming that
+/// the CPUs are numbered from 0 to `std::thread::available_parallelism()`.
+///
+pub fn logical_cpus() -> Vec<usize> {
+    backend::logical_cpus().unwrap_or_else(|_| {
+        let num_cpus = std::thread::available_parallelism().map_or(1, usize::from);
+        (0..num_cpus).collect()
+    })
+}
+
+/// List the physical CPU cores that this process is allowed to run on
+///
+/// Each physical core may host multiple logical CPUs, a feature known as
+/// simultaneous multi-threading or hyperthreading. Logical CPUs on the same
+/// core compete for execution resources, whereas cores from different packages
+/// (also known as sockets) communicate over a slower interconnect.
+///
+/// Cores are sorted by package, then by core ID.
+///
+pub fn physical_cores() -> Result<Vec<PhysicalCore>, AffinityError> {
+    backend::physical_cores()
+}
+
+/// Physical CPU core, hosting one or more logical CPUs
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct PhysicalCore {
+    /// Identifier of the CPU package (socket) that this core belongs to
+    pub package: usize,
+
+    /// Identifier of this core within its package
+    pub core: usize,
+
+    /// Logical CPUs hosted by this core
+    pub logical_cpus: Vec<usize>,
+}
+
+/// Error while querying or changing thread affinity
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum AffinityError {
+    /// Affinity control is not supported in this configuration
+    Unsupported,
+
+    /// The requested logical CPU does not exist or is not available
+    InvalidCpu(usize),
+
+    /// The operating system did not allow us to perform this operation
+    PermissionDenied,
+
+    /// Other operating system error
+    Os(io::Error),
+}
+//
+impl fmt::Display for AffinityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unsupported => write!(f, "thread affinity control is not supported"),
+            Self::InvalidCpu(cpu) => write!(f, "logical CPU {} is not available", cpu),
+            Self::PermissionDenied => write!(f, "not allowed to change thread affinity"),
+            Self::Os(error) => write!(f, "thread affinity error: {}", error),
+        }
+    }
+}
+//
+impl std::error::Error for AffinityError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Os(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Check that a logical CPU can be pinned to, without actually pinning to it
+pub(crate) fn check_cpu(cpu: usize) -> Result<(), AffinityError> {
+    if !backend::SUPPORTED {
+        Err(AffinityError::Unsupported)
+    } else if logical_cpus().contains(&cpu) {
+        Ok(())
+    } else {
+        Err(AffinityError::InvalidCpu(cpu))
+    }
+}
+
+/// Pin the current thread to a logical CPU until the returned guard is dropped
+pub(crate) fn pin_current_thread_scoped(cpu: usize) -> Result<RestoreAffinity, AffinityError> {
+    backend::set_current_thread_cpus(&[cpu]).map(RestoreAffinity)
+}
+
+/// Restores the former affinity of the current thread when dropped
+#[derive(Debug)]
+pub(crate) struct RestoreAffinity(Vec<usize>);
+//
+impl Drop for RestoreAffinity {
+    fn drop(&mut self) {
+        // The former affinity was accepted by the OS before, so this should
+        // not fail, and there is nothing sensible to do if it does.
+        let _ = backend::set_current_thread_cpus(&self.0);
+    }
+}
+
+/// Linux implementation, based on sched_setaffinity and sysfs
+#[cfg(all(feature = "affinity", target_os = "linux"))]
+mod backend {
+    use super::{AffinityError, PhysicalCore};
+    use std::{collections::BTreeMap, fs, io, mem, mem::size_of};
+
+    pub(super) const SUPPORTED: bool = true;
+
+    /// Query the CPU set of a thread or process (0 means current thread)
+    pub(super) fn get_cpus(pid: libc::pid_t) -> Result<Vec<usize>, AffinityError> {
+        // Safe because cpu_set_t is a plain bitmask, and all-zeroes is valid
+        let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+        // Safe because the set pointer and size are consistent
+        let result =
+            unsafe { libc::sched_getaffinity(pid, size_of::<libc::cpu_set_t>(), &mut set) };
+        if result != 0 {
+            return Err(last_error(None));
+        }
+        Ok((0..libc::CPU_SETSIZE as usize)
+            // Safe because the CPU index is within the bounds of the set
+            .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) })
+            .collect())
+    }
+
+    pub(super) fn logical_cpus() -> Result<Vec<usize>, AffinityError> {
+        // Safe because getpid has no precondition
+        get_cpus(unsafe { libc::getpid() })
+    }
+
+    pub(super) fn set_current_thread_cpus(cpus: &[usize]) -> Result<Vec<usize>, AffinityError> {
+        // Safe because cpu_set_t is a plain bitmask, and all-zeroes is valid
+        let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+        for &cpu in cpus {
+            if cpu >= libc::CPU_SETSIZE as usize {
+                return Err(AffinityError::InvalidCpu(cpu));
+            }
+            // Safe because we checked that the CPU index is within bounds
+            unsafe { libc::CPU_SET(cpu, &mut set) };
+        }
+        let previous = get_cpus(0)?;
+        // Safe because the set pointer and size are consistent
+        let result = unsafe { libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &set) };
+        if result != 0 {
+            return Err(last_error(cpus.first().copied()));
+        }
+        Ok(previous)
+    }
+
+    pub(super) fn physical_cores() -> Result<Vec<PhysicalCore>, AffinityError> {
+        let read_id = |cpu: usize, name: &str| -> Result<usize, AffinityError> {
+            let path = format!("/sys/devices/system/cpu/cpu{}/topology/{}", cpu, name);
+            let contents = fs::read_to_string(path).map_err(AffinityError::Os)?;
+            contents
+                .trim()
+                .parse()
+                .map_err(|e| AffinityError::Os(io::Error::new(io::ErrorKind::InvalidData, e)))
+        };
+        let mut cores = BTreeMap::<(usize, usize), Vec<usize>>::new();
+        for cpu in logical_cpus()? {
+            let key = (
+                read_id(cpu, "physical_package_id")?,
+                read_id(cpu, "core_id")?,
+            );
+            cores.entry(key).or_default().push(cpu);
+        }
+        Ok(cores
+            .into_iter()
+            .map(|((package, core), logical_cpus)| PhysicalCore {
+                package,
+                core,
+                logical_cpus,
+            })
+            .collect())
+    }
+
+    /// Translate the last OS error into an AffinityError
+    fn last_error(cpu: Option<usize>) -> AffinityError {
+        let error = io::Error::last_os_error();
+        match (error.raw_os_error(), cpu) {
+            (Some(libc::EPERM), _) => AffinityError::PermissionDenied,
+            (Some(libc::EINVAL), Some(cpu)) => AffinityError::InvalidCpu(cpu),
+            _ => AffinityError::Os(error),
+        }
+    }
+}
+
+/// Windows implementation, based on thread and process affinity masks and
+/// GetLogicalProcessorInformation
+///
+/// Only the first processor group (64 logical CPUs) is currently supported.
+///
+#[cfg(all(feature = "affinity", windows))]
+mod backend {
+    use super::{AffinityError, PhysicalCore};
+    use std::{collections::BTreeMap, io, mem::size_of, ptr};
+    use windows_sys::Win32::{
+        Foundation::{ERROR_ACCESS_DENIED, ERROR_INVALID_PARAMETER},
+        System::{
+            SystemInformation::{
+                GetLogicalProcessorInformation, RelationProcessorCore, RelationProcessorPackage,
+                SYSTEM_LOGICAL_PROCESSOR_INFORMATION,
+            },
+            Threading::{
+                GetCurrentProcess, GetCurrentThread, GetProcessAffinityMask, SetThreadAffinityMask,
+            },
+        },
+    };
+
+    pub(super) const SUPPORTED: bool = true;
+
+    /// Number of logical CPUs in an affinity mask
+    const MASK_BITS: usize = usize::BITS as usize;
+
+    /// Convert an affinity mask to a list of logical CPUs
+    fn mask_to_cpus(mask: usize) -> Vec<usize> {
+        (0..MASK_BITS)
+            .filter(|&cpu| mask & (1 << cpu) != 0)
+            .collect()
+    }
+
+    pub(super) fn logical_cpus() -> Result<Vec<usize>, AffinityError> {
+        let mut process_mask = 0;
+        let mut system_mask = 0;
+        // Safe because the pseudo-handle and mask pointers are valid
+        let result = unsafe {
+            GetProcessAffinityMask(GetCurrentProcess(), &mut process_mask, &mut system_mask)
+        };
+        if result == 0 {
+            return Err(last_error(None));
+        }
+        Ok(mask_to_cpus(process_mask))
+    }
+
+    pub(super) fn set_current_thread_cpus(cpus: &[usize]) -> Result<Vec<usize>, AffinityError> {
+        let mut mask = 0usize;
+        for &cpu in cpus {
+            if cpu >= MASK_BITS {
+                return Err(AffinityError::InvalidCpu(cpu));
+            }
+            mask |= 1 << cpu;
+        }
+        // Safe because the current thread pseudo-handle is always valid
+        let previous = unsafe { SetThreadAffinityMask(GetCurrentThread(), mask) };
+        if previous == 0 {
+            return Err(last_error(cpus.first().copied()));
+        }
+        Ok(mask_to_cpus(previous))
+    }
+
+    /// Windows does not number cores, so they are numbered in the order in
+    /// which they are listed within their package
+    pub(super) fn physical_cores() -> Result<Vec<PhysicalCore>, AffinityError> {
+        // Query the size of the processor information, then the information
+        let entry_size = size_of::<SYSTEM_LOGICAL_PROCESSOR_INFORMATION>();
+        let mut length = 0u32;
+        // Safe because a null buffer of zero length only queries the size
+        unsafe { GetLogicalProcessorInformation(ptr::null_mut(), &mut length) };
+        let mut entries =
+            vec![SYSTEM_LOGICAL_PROCESSOR_INFORMATION::default(); length as usize / entry_size];
+        let mut length = (entries.len() * entry_size) as u32;
+        // Safe because the buffer has the specified length
+        let result = unsafe { GetLogicalProcessorInformation(entries.as_mut_ptr(), &mut length) };
+        if result == 0 {
+            return Err(last_error(None));
+        }
+        entries.truncate(length as usize / entry_size);
+
+        // Group the logical CPUs that we can run on by package and core
+        let allowed = logical_cpus()?;
+        let packages = entries
+            .iter()
+            .filter(|entry| entry.Relationship == RelationProcessorPackage)
+            .map(|entry| entry.ProcessorMask)
+            .collect::<Vec<_>>();
+        let mut cores_per_package = BTreeMap::<usize, usize>::new();
+        let mut cores = BTreeMap::<(usize, usize), Vec<usize>>::new();
+        for entry in entries
+            .iter()
+            .filter(|entry| entry.Relationship == RelationProcessorCore)
+        {
+            let package = packages
+                .iter()
+                .position(|&mask| mask & entry.ProcessorMask != 0)
+                .unwrap_or(0);
+            let next_core = cores_per_package.entry(package).or_default();
+            let core = *next_core;
+            *next_core += 1;
+            let logical_cpus = mask_to_cpus(entry.ProcessorMask)
+                .into_iter()
+                .filter(|cpu| allowed.contains(cpu))
+                .collect::<Vec<_>>();
+            if !logical_cpus.is_empty() {
+                cores.insert((package, core), logical_cpus);
+            }
+        }
+        Ok(cores
+            .into_iter()
+            .map(|((package, core), logical_cpus)| PhysicalCore {
+                package,
+                core,
+                logical_cpus,
+            })
+            .collect())
+    }
+
+    /// Translate the last OS error into an AffinityError
+    fn last_error(cpu: Option<usize>) -> AffinityError {
+        let error = io::Error::last_os_error();
+        match (error.raw_os_error().map(|code| code as u32), cpu) {
+            (Some(ERROR_ACCESS_DENIED), _) => AffinityError::PermissionDenied,
+            (Some(ERROR_INVALID_PARAMETER), Some(cpu)) => AffinityError::InvalidCpu(cpu),
+            _ => AffinityError::Os(error),
+        }
+    }
+}
+
+/// Fallback implementation for unsupported configurations
+#[cfg(not(all(feature = "affinity", any(target_os = "linux", windows))))]
+mod backend {
+    use super::{AffinityError, PhysicalCore};
+
+    pub(super) const SUPPORTED: bool = false;
+
+    pub(super) fn logical_cpus() -> Result<Vec<usize>, AffinityError> {
+        Err(AffinityError::Unsupported)
+    }
+
+    pub(super) fn set_current_thread_cpus(_cpus: &[usize]) -> Result<Vec<usize>, AffinityError> {
+        Err(AffinityError::Unsupported)
+    }
+
+    pub(super) fn physical_cores() -> Result<Vec<PhysicalCore>, AffinityError> {
+        Err(AffinityError::Unsupported)
+    }
+}
+
+/// Tests of thread affinity control
+#[cfg(test)]
+mod tests {
+    use super::AffinityError;
+
+    // There should always be at least one logical CPU
+    #[test]
+    fn logical_cpus() {
+        assert!(!super::logical_cpus().is_empty());
+    }
+
+    // Physical cores should cover all logical CPUs exactly once, if supported
+    #[test]
+    fn physical_cores() {
+        match super::physical_cores() {
+            Ok(cores) => {
+                let mut cpus = cores
+                    .into_iter()
+                    .flat_map(|core| core.logical_cpus)
+                    .collect::<Vec<_>>();
+                cpus.sort_unstable();
+                assert_eq!(cpus, super::logical_cpus());
+            }
+            Err(AffinityError::Unsupported) => {}
+            Err(other) => panic!("Unexpected error: {}", other),
+        }
+    }
+
+    // Pinning should work on valid CPUs and fail on invalid ones, if supported
+    #[test]
+    fn pinning() {
+        std::thread::spawn(|| {
+            let cpu = super::logical_cpus()[0];
+            match super::pin_current_thread_to(cpu) {
+                Ok(()) => {
+                    #[cfg(all(feature = "affinity", target_os = "linux"))]
+                    assert_eq!(super::backend::get_cpus(0).unwrap(), vec![cpu]);
+                    assert_eq!(
+                        super::backend::set_current_thread_cpus(&[cpu]).unwrap(),
+                        vec![cpu]
+                    );
+                    assert!(matches!(
+                        super::pin_current_thread_to(usize::MAX),
+                        Err(AffinityError::InvalidCpu(usize::MAX))
+                    ));
+                }
+                Err(AffinityError::Unsupported) => {}
+                Err(other) => panic!("Unexpected error: {}", other),
+            }
+        })
+        .join()
+        .unwrap();
+    }
+}
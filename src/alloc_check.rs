@@ -0,0 +1,376 @@
+//! Memory allocation accounting, to detect leaks and unwanted allocations
+//!
+//! To use this module, install `CountingAllocator` as the global allocator of
+//! a test or benchmark binary:
+//!
+//! ```
+//! use testbench::alloc_check::{self, CountingAllocator};
+//!
+//! #[global_allocator]
+//! static ALLOCATOR: CountingAllocator = CountingAllocator::new();
+//!
+//! # fn main() {
+//! let values = alloc_check::assert_no_leaks(|| vec![1, 2, 3].iter().sum::<i32>());
+//! assert_eq!(values, 6);
+//! # }
+//! ```
+//!
+//! Allocations are counted both process-wide and per thread. Process-wide
+//! counts are what leak detection needs, but they also account for the
+//! allocations of unrelated threads, such as other tests running in parallel
+//! or antagonist threads. Tests which use process-wide counts should therefore
+//! be run one at a time, e.g. with `--test-threads=1`.
+//!
+//! Counting costs a few uncontended atomic increments and thread-local
+//! accesses per allocation, which is small compared to the cost of allocation
+//! itself, but atomic increments will contend when many threads allocate.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+/// Global allocator wrapper which counts allocations and deallocations
+///
+/// By default, allocations are forwarded to the system allocator, but any
+/// other global allocator can be wrapped using `with_allocator()`.
+///
+#[derive(Debug, Default)]
+pub struct CountingAllocator<A = System> {
+    /// Allocator that actually performs allocations
+    inner: A,
+}
+//
+impl CountingAllocator {
+    /// Count allocations from the system allocator
+    pub const fn new() -> Self {
+        Self { inner: System }
+    }
+}
+//
+impl<A> CountingAllocator<A> {
+    /// Count allocations from another allocator
+    pub const fn with_allocator(inner: A) -> Self {
+        Self { inner }
+    }
+}
+//
+// Safe because allocations are forwarded to an allocator which correctly
+// implements GlobalAlloc, and counting does not allocate.
+unsafe impl<A: GlobalAlloc> GlobalAlloc for CountingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            record_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            record_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout);
+        record_dealloc(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = self.inner.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            record_dealloc(layout.size());
+            record_alloc(new_size);
+        }
+        new_ptr
+    }
+}
+
+/// Process-wide allocation counters
+static GLOBAL: AtomicCounters = AtomicCounters::new();
+
+/// Truth that a `CountingAllocator` has allocated memory
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// Allocation counters of the current thread
+    //
+    // This must not have a destructor, as registering it would allocate.
+    static THREAD: ThreadCounters = const { ThreadCounters::new() };
+}
+
+/// Record an allocation (reallocations count as a deallocation followed by
+/// an allocation)
+fn record_alloc(size: usize) {
+    if !INSTALLED.load(Ordering::Relaxed) {
+        INSTALLED.store(true, Ordering::Relaxed);
+    }
+    GLOBAL.allocations.fetch_add(1, Ordering::Relaxed);
+    GLOBAL
+        .allocated_bytes
+        .fetch_add(size as u64, Ordering::Relaxed);
+    // Thread-local storage may be unavailable during thread teardown
+    let _ = THREAD.try_with(|counters| {
+        counters.allocations.set(counters.allocations.get() + 1);
+        counters
+            .allocated_bytes
+            .set(counters.allocated_bytes.get() + size as u64);
+    });
+}
+
+/// Record a deallocation
+fn record_dealloc(size: usize) {
+    GLOBAL.deallocations.fetch_add(1, Ordering::Relaxed);
+    GLOBAL
+        .deallocated_bytes
+        .fetch_add(size as u64, Ordering::Relaxed);
+    let _ = THREAD.try_with(|counters| {
+        counters.deallocations.set(counters.deallocations.get() + 1);
+        counters
+            .deallocated_bytes
+            .set(counters.deallocated_bytes.get() + size as u64);
+    });
+}
+
+/// Process-wide allocation counters
+struct AtomicCounters {
+    /// Number of allocations
+    allocations: AtomicU64,
+
+    /// Number of allocated bytes
+    allocated_bytes: AtomicU64,
+
+    /// Number of deallocations
+    deallocations: AtomicU64,
+
+    /// Number of deallocated bytes
+    deallocated_bytes: AtomicU64,
+}
+//
+impl AtomicCounters {
+    /// Set up zeroed counters
+    const fn new() -> Self {
+        Self {
+            allocations: AtomicU64::new(0),
+            allocated_bytes: AtomicU64::new(0),
+            deallocations: AtomicU64::new(0),
+            deallocated_bytes: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Per-thread allocation counters
+struct ThreadCounters {
+    /// Number of allocations
+    allocations: Cell<u64>,
+
+    /// Number of allocated bytes
+    allocated_bytes: Cell<u64>,
+
+    /// Number of deallocations
+    deallocations: Cell<u64>,
+
+    /// Number of deallocated bytes
+    deallocated_bytes: Cell<u64>,
+}
+//
+impl ThreadCounters {
+    /// Set up zeroed counters
+    const fn new() -> Self {
+        Self {
+            allocations: Cell::new(0),
+            allocated_bytes: Cell::new(0),
+            deallocations: Cell::new(0),
+            deallocated_bytes: Cell::new(0),
+        }
+    }
+}
+
+/// Allocation statistics
+///
+/// These are either totals since the start of the process or thread, as
+/// returned by `snapshot()` and `thread_snapshot()`, or differences between
+/// two such totals, as returned by `since()`.
+///
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct AllocStats {
+    /// Number of allocations
+    pub allocations: u64,
+
+    /// Number of allocated bytes
+    pub allocated_bytes: u64,
+
+    /// Number of deallocations
+    pub deallocations: u64,
+
+    /// Number of deallocated bytes
+    pub deallocated_bytes: u64,
+}
+//
+impl AllocStats {
+    /// Number of allocations which were not deallocated
+    ///
+    /// This can be negative for differences between totals, if more memory
+    /// was deallocated than allocated in between.
+    ///
+    pub fn live_allocations(&self) -> i64 {
+        self.allocations.wrapping_sub(self.deallocations) as i64
+    }
+
+    /// Number of bytes which were not deallocated
+    ///
+    /// This can be negative for differences between totals, if more memory
+    /// was deallocated than allocated in between.
+    ///
+    pub fn live_bytes(&self) -> i64 {
+        self.allocated_bytes.wrapping_sub(self.deallocated_bytes) as i64
+    }
+
+    /// Difference between these totals and earlier ones
+    pub fn since(&self, earlier: &Self) -> Self {
+        Self {
+            allocations: self.allocations.wrapping_sub(earlier.allocations),
+            allocated_bytes: self.allocated_bytes.wrapping_sub(earlier.allocated_bytes),
+            deallocations: self.deallocations.wrapping_sub(earlier.deallocations),
+            deallocated_bytes: self
+                .deallocated_bytes
+                .wrapping_sub(earlier.deallocated_bytes),
+        }
+    }
+}
+//
+impl fmt::Display for AllocStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} allocation(s) of {} byte(s), {} deallocation(s) of {} byte(s)",
+            self.allocations, self.allocated_bytes, self.deallocations, self.deallocated_bytes
+        )
+    }
+}
+
+/// Truth that a `CountingAllocator` is in use
+///
+/// This is detected by the allocator recording an allocation. If no memory
+/// has been allocated yet, which can happen when the runtime does not allocate
+/// before `main()` (e.g. under Miri), a small probe allocation is performed.
+///
+pub fn is_installed() -> bool {
+    if !INSTALLED.load(Ordering::Relaxed) {
+        drop(crate::opaque::black_box(Box::new(0u8)));
+    }
+    INSTALLED.load(Ordering::Relaxed)
+}
+
+/// Process-wide allocation totals
+///
+/// These are all zero if no `CountingAllocator` is in use.
+///
+pub fn snapshot() -> AllocStats {
+    AllocStats {
+        allocations: GLOBAL.allocations.load(Ordering::Relaxed),
+        allocated_bytes: GLOBAL.allocated_bytes.load(Ordering::Relaxed),
+        deallocations: GLOBAL.deallocations.load(Ordering::Relaxed),
+        deallocated_bytes: GLOBAL.deallocated_bytes.load(Ordering::Relaxed),
+    }
+}
+
+/// Allocation totals of the current thread
+///
+/// These are all zero if no `CountingAllocator` is in use.
+///
+pub fn thread_snapshot() -> AllocStats {
+    THREAD.with(|counters| AllocStats {
+        allocations: counters.allocations.get(),
+        allocated_bytes: counters.allocated_bytes.get(),
+        deallocations: counters.deallocations.get(),
+        deallocated_bytes: counters.deallocated_bytes.get(),
+    })
+}
+
+/// Check that an operation deallocates all the memory that it allocates
+///
+/// This uses process-wide counts, so allocations from other threads can cause
+/// false positives and negatives. See the module-level documentation.
+///
+/// # Panics
+///
+/// This function panics if no `CountingAllocator` is in use, or if the
+/// operation leaked memory.
+///
+pub fn assert_no_leaks<R>(body: impl FnOnce() -> R) -> R {
+    assert_installed();
+    let start = snapshot();
+    let result = body();
+    let stats = snapshot().since(&start);
+    assert!(
+        stats.live_allocations() <= 0 && stats.live_bytes() <= 0,
+        "Operation leaked {} allocation(s) totaling {} byte(s) ({})",
+        stats.live_allocations(),
+        stats.live_bytes(),
+        stats
+    );
+    result
+}
+
+/// Check that an operation does not allocate memory, on any thread
+///
+/// This uses process-wide counts, so allocations from other threads can cause
+/// false positives. Use `assert_no_thread_alloc()` to only check allocations
+/// from the current thread.
+///
+/// # Panics
+///
+/// This function panics if no `CountingAllocator` is in use, or if memory
+/// was allocated during the operation.
+///
+pub fn assert_no_alloc<R>(body: impl FnOnce() -> R) -> R {
+    assert_installed();
+    let start = snapshot();
+    let result = body();
+    check_no_alloc(snapshot().since(&start), "the process");
+    result
+}
+
+/// Check that an operation does not allocate memory on the current thread
+///
+/// Unlike `assert_no_alloc()`, this ignores allocations from other threads,
+/// such as antagonist threads of a contention benchmark.
+///
+/// # Panics
+///
+/// This function panics if no `CountingAllocator` is in use, or if memory
+/// was allocated by the current thread during the operation.
+///
+pub fn assert_no_thread_alloc<R>(body: impl FnOnce() -> R) -> R {
+    assert_installed();
+    let start = thread_snapshot();
+    let result = body();
+    check_no_alloc(thread_snapshot().since(&start), "the current thread");
+    result
+}
+
+/// Panic if no `CountingAllocator` is in use
+fn assert_installed() {
+    assert!(
+        is_installed(),
+        "A CountingAllocator must be used as the #[global_allocator]"
+    );
+}
+
+/// Panic if some memory was allocated
+fn check_no_alloc(stats: AllocStats, scope: &str) {
+    assert!(
+        stats.allocations == 0,
+        "Operation performed {} allocation(s) totaling {} byte(s) in {}",
+        stats.allocations,
+        stats.allocated_bytes,
+        scope
+    );
+}
@@ -0,0 +1,438 @@
+//! Concurrent tests of futures-based code
+//!
+//! `concurrent_test_2()` and friends run closures on several threads. This
+//! module does the same for futures: each future is driven to completion by
+//! its own thread, so that futures really run in parallel rather than being
+//! interleaved by a single-threaded executor. Like for closures, threads are
+//! synchronized by a barrier before they start polling their future, which
+//! maximizes the chance that the futures interact.
+//!
+//! Futures are driven by `block_on()`, a minimal executor which parks the
+//! thread until the future is woken up. This does not depend on any async
+//! runtime. Futures which need to run within a runtime, for example because
+//! they use its timers or I/O, can be driven by a runtime-provided executor
+//! instead, using `concurrent_test_async_on()`.
+//!
+//! Async code can also be benchmarked under contention, with either the
+//! benchmark or the antagonist being a future, see
+//! `run_under_contention_async()` and `run_under_async_contention()`.
+//!
+//! ```
+//! use std::sync::atomic::{AtomicUsize, Ordering};
+//! use testbench::async_test;
+//!
+//! let counter = AtomicUsize::new(0);
+//! let (a, b) = async_test::concurrent_test_async_2(
+//!     async { counter.fetch_add(1, Ordering::Relaxed) },
+//!     async { counter.fetch_add(1, Ordering::Relaxed) },
+//! );
+//! assert_eq!(a + b, 1);
+//! assert_eq!(counter.load(Ordering::Relaxed), 2);
+//! ```
+
+use crate::{trace, util::panic_message};
+use std::{
+    any::Any,
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::{Arc, Barrier},
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, Thread},
+};
+
+/// Future with erased type, as accepted by `concurrent_test_async_on()`
+pub type BoxFuture<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+/// Drive a future to completion on the current thread
+///
+/// The thread is parked whenever the future is pending, and unparked when the
+/// future's waker is invoked. This is enough for futures which only depend on
+/// other threads, such as channels and synchronization primitives, but not
+/// for futures which need an async runtime.
+///
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+/// Waker which unparks a thread
+#[derive(Debug)]
+struct ThreadWaker(Thread);
+//
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Test that running two futures concurrently works
+///
+/// Each future is driven to completion by `block_on()` on its own thread, and
+/// their outputs are returned.
+///
+/// # Panics
+///
+/// If a future panics, this function panics with a message that tells which
+/// future panicked, counting from 0, once both threads are done.
+///
+pub fn concurrent_test_async_2<F1, F2>(f1: F1, f2: F2) -> (F1::Output, F2::Output)
+where
+    F1: Future + Send,
+    F1::Output: Send,
+    F2: Future + Send,
+    F2::Output: Send,
+{
+    let barrier = Barrier::new(2);
+    let parent = trace::Parent::current();
+    let (r1, r2) = thread::scope(|s| {
+        let handle = s.spawn(|| participant(&parent, 0, &barrier, || block_on(f1)));
+        let r2 = participant(&parent, 1, &barrier, || block_on(f2));
+        let r1 = handle.join().expect("Panics should have been caught");
+        (r1, r2)
+    });
+    (check_participant(0, r1), check_participant(1, r2))
+}
+
+/// Test that running N futures of the same type concurrently works
+///
+/// This is a variant of `concurrent_test_async_2()` for any number of
+/// futures. Futures of different types can be boxed into a `BoxFuture`.
+///
+/// # Panics
+///
+/// If a future panics, this function panics with a message that tells which
+/// future panicked, once all threads are done.
+///
+pub fn concurrent_test_async_n<F>(futures: Vec<F>) -> Vec<F::Output>
+where
+    F: Future + Send,
+    F::Output: Send,
+{
+    run_participants(
+        futures
+            .into_iter()
+            .map(|future| move || block_on(future))
+            .collect(),
+    )
+}
+
+/// Test that running futures concurrently works, using a custom executor
+///
+/// Each future is passed to `executor` on its own thread, which must drive it
+/// to completion before returning. This is meant for futures which must run
+/// within an async runtime, e.g. `|future| runtime.block_on(future)`.
+///
+/// # Panics
+///
+/// If a future or the executor panics, this function panics with a message
+/// that tells which future was being run, once all threads are done.
+///
+pub fn concurrent_test_async_on<'a>(
+    executor: impl Fn(BoxFuture<'a>) + Sync,
+    futures: Vec<BoxFuture<'a>>,
+) {
+    let executor = &executor;
+    run_participants(
+        futures
+            .into_iter()
+            .map(|future| move || executor(future))
+            .collect(),
+    );
+}
+
+/// Benchmark a future while an antagonist is running in another thread
+///
+/// This is a variant of `run_under_contention()` where the benchmark is a
+/// future, which is driven to completion by `block_on()` on the calling
+/// thread. The antagonist runs in a loop on its own thread until then.
+///
+/// # Panics
+///
+/// This function will propagate panics from the antagonist and benchmark.
+/// The antagonist thread is stopped even if the benchmark panics.
+///
+pub fn run_under_contention_async<AntagonistResult, Benchmark: Future>(
+    antagonist: impl FnMut() -> AntagonistResult + Send,
+    benchmark: Benchmark,
+) -> Benchmark::Output {
+    run_under_contention_async_on(block_on, antagonist, benchmark)
+}
+
+/// Like `run_under_contention_async()`, but with a custom executor
+///
+/// The benchmark future is passed to `executor`, which must drive it to
+/// completion on the calling thread and return its output. This is meant for
+/// futures which must run within an async runtime, e.g.
+/// `|future| runtime.block_on(future)`.
+///
+/// # Panics
+///
+/// This function will propagate panics from the antagonist, executor and
+/// benchmark. The antagonist thread is stopped even if the benchmark panics.
+///
+pub fn run_under_contention_async_on<AntagonistResult, Benchmark: Future>(
+    executor: impl FnOnce(Benchmark) -> Benchmark::Output,
+    antagonist: impl FnMut() -> AntagonistResult + Send,
+    benchmark: Benchmark,
+) -> Benchmark::Output {
+    crate::run_under_contention(antagonist, || executor(benchmark))
+}
+
+/// Run a benchmark while an async antagonist is running in another thread
+///
+/// This is a variant of `run_under_contention()` where each antagonist
+/// iteration creates a future with `antagonist`, then drives it to completion
+/// with `block_on()` on the antagonist thread. Antagonists which need an
+/// async runtime can instead be run by `run_under_contention()` with an
+/// antagonist closure that calls the runtime's executor.
+///
+/// The benchmark may itself drive futures using `block_on()`.
+///
+/// # Panics
+///
+/// This function will propagate panics from the antagonist and benchmark.
+/// The antagonist thread is stopped even if the benchmark panics.
+///
+pub fn run_under_async_contention<Antagonist: Future, BenchmarkResult>(
+    mut antagonist: impl FnMut() -> Antagonist + Send,
+    benchmark: impl FnOnce() -> BenchmarkResult,
+) -> BenchmarkResult {
+    crate::run_under_contention(move || block_on(antagonist()), benchmark)
+}
+
+/// Run closures on their own threads, starting them together
+fn run_participants<T: Send>(tasks: Vec<impl FnOnce() -> T + Send>) -> Vec<T> {
+    let barrier = Barrier::new(tasks.len());
+    let parent = trace::Parent::current();
+    let results = thread::scope(|s| {
+        let mut tasks = tasks;
+        let last = tasks.pop();
+        let handles = tasks
+            .into_iter()
+            .enumerate()
+            .map(|(idx, task)| {
+                let (barrier, parent) = (&barrier, &parent);
+                s.spawn(move || participant(parent, idx, barrier, task))
+            })
+            .collect::<Vec<_>>();
+        let last_idx = handles.len();
+        let last = last.map(|task| participant(&parent, last_idx, &barrier, task));
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("Panics should have been caught"))
+            .chain(last)
+            .collect::<Vec<_>>()
+    });
+    results
+        .into_iter()
+        .enumerate()
+        .map(|(idx, result)| check_participant(idx, result))
+        .collect()
+}
+
+/// Wait for other participants, then run a task, catching panics so that
+/// the other participants can still be waited for
+fn participant<T>(
+    parent: &trace::Parent,
+    idx: usize,
+    barrier: &Barrier,
+    task: impl FnOnce() -> T,
+) -> Result<T, Box<dyn Any + Send + 'static>> {
+    let _span = trace::participant(parent, idx);
+    barrier.wait();
+    trace::barrier_released();
+    panic::catch_unwind(AssertUnwindSafe(task))
+}
+
+/// Propagate the panic of a participant, if any
+fn check_participant<T>(idx: usize, result: Result<T, Box<dyn Any + Send + 'static>>) -> T {
+    result.unwrap_or_else(|payload| panic!("Future {} panicked: {}", idx, panic_message(&*payload)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    };
+
+    /// Future which completes once `count` futures have reached it
+    ///
+    /// Pending futures are woken up by the last one to arrive, so this can
+    /// only complete if all futures are polled concurrently. Running them one
+    /// after the other would hang.
+    ///
+    async fn handshake(arrived: &AtomicUsize, wakers: &Mutex<Vec<Waker>>, count: usize) {
+        arrived.fetch_add(1, Ordering::AcqRel);
+        Handshake {
+            arrived,
+            wakers,
+            count,
+        }
+        .await
+    }
+    //
+    struct Handshake<'a> {
+        arrived: &'a AtomicUsize,
+        wakers: &'a Mutex<Vec<Waker>>,
+        count: usize,
+    }
+    //
+    impl Future for Handshake<'_> {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            // The last future to arrive increments the counter before locking
+            // the wakers, so it sees the wakers of all other futures
+            let mut wakers = self.wakers.lock().unwrap();
+            if self.arrived.load(Ordering::Acquire) == self.count {
+                wakers.drain(..).for_each(Waker::wake);
+                Poll::Ready(())
+            } else {
+                wakers.push(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    #[test]
+    fn two_futures() {
+        let arrived = AtomicUsize::new(0);
+        let wakers = Mutex::new(Vec::new());
+        let outputs = concurrent_test_async_2(
+            async {
+                handshake(&arrived, &wakers, 2).await;
+                1
+            },
+            async {
+                handshake(&arrived, &wakers, 2).await;
+                "two"
+            },
+        );
+        assert_eq!(outputs, (1, "two"));
+    }
+
+    #[test]
+    fn n_futures() {
+        const FUTURES: usize = 4;
+        let arrived = AtomicUsize::new(0);
+        let wakers = Mutex::new(Vec::new());
+        let futures = (0..FUTURES)
+            .map(|idx| {
+                let (arrived, wakers) = (&arrived, &wakers);
+                async move {
+                    handshake(arrived, wakers, FUTURES).await;
+                    idx
+                }
+            })
+            .collect();
+        assert_eq!(
+            concurrent_test_async_n(futures),
+            (0..FUTURES).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn panic_propagation() {
+        let payload = panic::catch_unwind(|| {
+            concurrent_test_async_2(async {}, async { panic!("Oops") });
+        })
+        .unwrap_err();
+        assert_eq!(
+            payload.downcast_ref::<String>().map(String::as_str),
+            Some("Future 1 panicked: Oops")
+        );
+    }
+
+    /// An async lock should work while a thread hammers it synchronously
+    #[test]
+    fn async_benchmark() {
+        let lock = tokio::sync::Mutex::new(0u64);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let result = run_under_contention_async_on(
+            |future| runtime.block_on(future),
+            || *lock.blocking_lock() += 1,
+            async {
+                for _ in 0..1000 {
+                    *lock.lock().await += 1;
+                    tokio::task::yield_now().await;
+                }
+                *lock.lock().await
+            },
+        );
+        assert!(result >= 1000);
+
+        let result = run_under_contention_async(|| *lock.blocking_lock() += 1, async {
+            *lock.lock().await += 1;
+            *lock.lock().await
+        });
+        assert!(result > 1000);
+    }
+
+    /// A synchronous benchmark should work while an async antagonist runs
+    #[test]
+    fn async_antagonist() {
+        let lock = tokio::sync::Mutex::new(0u64);
+        let iterations = AtomicUsize::new(0);
+        let result = run_under_async_contention(
+            || async {
+                *lock.lock().await += 1;
+                iterations.fetch_add(1, Ordering::Relaxed);
+            },
+            || {
+                for _ in 0..1000 {
+                    *block_on(lock.lock()) += 1;
+                }
+                *block_on(lock.lock())
+            },
+        );
+        assert_eq!(
+            *lock.blocking_lock(),
+            1000 + iterations.load(Ordering::Relaxed) as u64
+        );
+        assert!(result >= 1000);
+    }
+
+    /// Antagonists should stop when an async benchmark panics
+    #[test]
+    fn async_benchmark_panic() {
+        let payload = panic::catch_unwind(|| {
+            run_under_contention_async(std::hint::spin_loop, async { panic!("Oops") })
+        })
+        .unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"Oops"));
+    }
+
+    #[test]
+    fn custom_executor() {
+        let arrived = AtomicUsize::new(0);
+        let wakers = Mutex::new(Vec::new());
+        let executed = AtomicUsize::new(0);
+        let futures = (0..3)
+            .map(|_| -> BoxFuture<'_> { Box::pin(handshake(&arrived, &wakers, 3)) })
+            .collect();
+        concurrent_test_async_on(
+            |future| {
+                executed.fetch_add(1, Ordering::Relaxed);
+                block_on(future)
+            },
+            futures,
+        );
+        assert_eq!(executed.load(Ordering::Relaxed), 3);
+    }
+}
@@ -0,0 +1,348 @@
+//! Detection of store reordering across two memory locations
+//!
+//! RaceCell detects reads which overlap with a write to a single logical
+//! value. Another classic failure of thread synchronization protocols is store
+//! reordering across two memory locations, as in the message passing litmus
+//! test: a writer stores some data, then sets a flag, and a reader which sees
+//! the flag set nevertheless observes stale data.
+//!
+//! An `OrderingCanary` turns this failure into an observable event. Whether
+//! it actually occurs, however, depends on the hardware. x86 CPUs never
+//! reorder stores with other stores or loads with other loads, so on these
+//! CPUs, ordering violations are only caused by compiler optimizations and
+//! will rarely if ever be observed, even with relaxed memory orderings. CPUs
+//! with weaker memory models, such as ARM and POWER, are much more likely to
+//! expose them. To check that violations are detected on any hardware, use
+//! `publish_reordered()`, which stores the flag before the data on purpose.
+
+use crate::race_cell::{AtomicData, AtomicLoadStore, WriteDelay};
+#[cfg(feature = "portable-atomic")]
+use portable_atomic::AtomicU64;
+#[cfg(not(feature = "portable-atomic"))]
+use std::sync::atomic::AtomicU64;
+use std::{
+    fmt::{self, Debug},
+    sync::atomic::Ordering,
+};
+
+/// Message passing litmus test, which detects store reordering
+///
+/// `publish()` stores some data, tagged with a sequence number, then stores
+/// this sequence number in a flag. `observe()` loads the flag, then the data,
+/// and reports an ordering violation if the flag is more recent than the data.
+///
+/// By default, all memory accesses use relaxed ordering, so ordering
+/// violations are allowed. Use `publish_ordering()` and `observe_ordering()`
+/// to set the ordering of flag accesses, and thus check that release/acquire
+/// ordering prevents violations. Sequence numbers are allocated by `publish()`
+/// without synchronization, so there should be only one publisher thread.
+///
+pub struct OrderingCanary<T: AtomicData> {
+    /// Published data, tagged with the sequence number of its publication
+    data: <(T, u64) as AtomicData>::AtomicWrapper,
+
+    /// Sequence number of the last publication, 0 if none happened yet
+    flag: AtomicU64,
+
+    /// Memory ordering of flag stores
+    publish_ordering: Ordering,
+
+    /// Memory ordering of flag loads
+    observe_ordering: Ordering,
+}
+//
+impl<T: AtomicData> OrderingCanary<T> {
+    /// Create an OrderingCanary, with some initial data which is not
+    /// considered to be published
+    pub fn new(initial: T) -> Self {
+        Self {
+            data: AtomicLoadStore::new((initial, 0)),
+            flag: AtomicU64::new(0),
+            publish_ordering: Ordering::Relaxed,
+            observe_ordering: Ordering::Relaxed,
+        }
+    }
+
+    /// Set the memory ordering of flag stores, `Relaxed` by default
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the ordering is not valid for stores, i.e. if
+    /// it is `Acquire` or `AcqRel`.
+    ///
+    pub fn publish_ordering(mut self, ordering: Ordering) -> Self {
+        assert!(
+            !matches!(ordering, Ordering::Acquire | Ordering::AcqRel),
+            "Invalid memory ordering for a store: {:?}",
+            ordering
+        );
+        self.publish_ordering = ordering;
+        self
+    }
+
+    /// Set the memory ordering of flag loads, `Relaxed` by default
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the ordering is not valid for loads, i.e. if
+    /// it is `Release` or `AcqRel`.
+    ///
+    pub fn observe_ordering(mut self, ordering: Ordering) -> Self {
+        assert!(
+            !matches!(ordering, Ordering::Release | Ordering::AcqRel),
+            "Invalid memory ordering for a load: {:?}",
+            ordering
+        );
+        self.observe_ordering = ordering;
+        self
+    }
+
+    /// Publish some data, by storing it then storing the flag
+    ///
+    /// Returns the sequence number of this publication, starting at 1.
+    ///
+    pub fn publish(&self, data: T) -> u64 {
+        let sequence = self.next_sequence();
+        self.data.relaxed_store((data, sequence));
+        self.flag.store(sequence, self.publish_ordering);
+        sequence
+    }
+
+    /// Publish some data in the wrong order, by storing the flag, waiting,
+    /// then storing the data
+    ///
+    /// This emulates store reordering, so that one can check that readers
+    /// detect ordering violations even on hardware that does not reorder
+    /// stores. Returns the sequence number of this publication.
+    ///
+    pub fn publish_reordered(&self, data: T, delay: WriteDelay) -> u64 {
+        let sequence = self.next_sequence();
+        self.flag.store(sequence, self.publish_ordering);
+        delay.wait();
+        self.data.relaxed_store((data, sequence));
+        sequence
+    }
+
+    /// Observe the flag, then the data
+    pub fn observe(&self) -> CanaryObservation<T> {
+        let flag_seen = self.flag.load(self.observe_ordering);
+        let data_seen = self.data.relaxed_load();
+        classify(flag_seen, data_seen)
+    }
+
+    /// Sequence number of the next publication
+    fn next_sequence(&self) -> u64 {
+        self.flag.load(Ordering::Relaxed) + 1
+    }
+}
+//
+impl<T: AtomicData> Debug for OrderingCanary<T>
+where
+    <(T, u64) as AtomicData>::AtomicWrapper: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OrderingCanary")
+            .field("data", &self.data)
+            .field("flag", &self.flag)
+            .field("publish_ordering", &self.publish_ordering)
+            .field("observe_ordering", &self.observe_ordering)
+            .finish()
+    }
+}
+//
+impl<T: AtomicData + Default> Default for OrderingCanary<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+/// What a reader observed when checking an OrderingCanary
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum CanaryObservation<T> {
+    /// The flag was not set, so no data was published yet
+    NotPublished,
+
+    /// The flag was set, and the data was at least as recent as the flag
+    Published(T),
+
+    /// The flag was set, but the data was older than the flag
+    OrderingViolation {
+        /// Sequence number observed in the flag
+        flag_seen: u64,
+
+        /// Data observed after the flag, with the sequence number of the
+        /// publication which stored it
+        data_seen: (T, u64),
+    },
+}
+
+/// Classify the flag and tagged data observed by a reader
+fn classify<T>(flag_seen: u64, data_seen: (T, u64)) -> CanaryObservation<T> {
+    if flag_seen == 0 {
+        CanaryObservation::NotPublished
+    } else if data_seen.1 >= flag_seen {
+        CanaryObservation::Published(data_seen.0)
+    } else {
+        CanaryObservation::OrderingViolation {
+            flag_seen,
+            data_seen,
+        }
+    }
+}
+
+/// Tests of ordering canaries
+#[cfg(test)]
+mod tests {
+    use super::{classify, CanaryObservation, OrderingCanary};
+    use crate::race_cell::WriteDelay;
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
+    };
+
+    // Observations should be classified by comparing sequence numbers
+    #[test]
+    fn classification() {
+        assert_eq!(classify(0, ('a', 0)), CanaryObservation::NotPublished);
+        assert_eq!(classify(0, ('b', 1)), CanaryObservation::NotPublished);
+        assert_eq!(classify(1, ('b', 1)), CanaryObservation::Published('b'));
+        assert_eq!(classify(1, ('c', 2)), CanaryObservation::Published('c'));
+        assert_eq!(
+            classify(2, ('b', 1)),
+            CanaryObservation::OrderingViolation {
+                flag_seen: 2,
+                data_seen: ('b', 1)
+            }
+        );
+    }
+
+    // Sequential publications should be observed in order
+    #[test]
+    fn sequential() {
+        let canary = OrderingCanary::new(0u32)
+            .publish_ordering(Ordering::Release)
+            .observe_ordering(Ordering::Acquire);
+        assert_eq!(canary.observe(), CanaryObservation::NotPublished);
+        assert_eq!(canary.publish(42), 1);
+        assert_eq!(canary.observe(), CanaryObservation::Published(42));
+        assert_eq!(canary.publish(24), 2);
+        assert_eq!(canary.observe(), CanaryObservation::Published(24));
+    }
+
+    // Invalid orderings should be rejected upfront
+    #[test]
+    #[should_panic(expected = "Invalid memory ordering for a store")]
+    fn invalid_publish_ordering() {
+        let _ = OrderingCanary::new(0u8).publish_ordering(Ordering::Acquire);
+    }
+    //
+    #[test]
+    #[should_panic(expected = "Invalid memory ordering for a load")]
+    fn invalid_observe_ordering() {
+        let _ = OrderingCanary::new(0u8).observe_ordering(Ordering::Release);
+    }
+
+    // Emulated store reordering should be detected
+    #[test]
+    fn reordered() {
+        let canary = OrderingCanary::new(0u32);
+        let published = AtomicBool::new(false);
+        crate::concurrent_test_2(
+            || {
+                canary.publish_reordered(42, WriteDelay::Sleep(Duration::from_millis(100)));
+                published.store(true, Ordering::Release);
+            },
+            || {
+                let observation = loop {
+                    match canary.observe() {
+                        CanaryObservation::NotPublished => continue,
+                        other => break other,
+                    }
+                };
+                assert!(!published.load(Ordering::Acquire));
+                assert_eq!(
+                    observation,
+                    CanaryObservation::OrderingViolation {
+                        flag_seen: 1,
+                        data_seen: (0, 0)
+                    }
+                );
+            },
+        );
+    }
+
+    /// Relaxed publications may be observed out of order. This will rarely
+    /// happen on x86, which does not reorder stores, so this test only reports
+    /// how many violations were observed. Run it on a CPU with a weaker memory
+    /// model, such as an AArch64 CPU, to actually observe some.
+    ///
+    /// To maximize the odds of race conditions, this kind of test should be run
+    /// in single-threaded mode.
+    ///
+    #[test]
+    #[ignore]
+    fn relaxed_race() {
+        // Amount of publications to carry out
+        let publications_count = crate::scale_iters(10_000_000) as u64;
+
+        // Ordering canary that will be used
+        let canary = OrderingCanary::new(0);
+
+        // Count the ordering violations
+        crate::concurrent_test_2(
+            || {
+                for i in 1..=publications_count {
+                    canary.publish(i);
+                }
+            },
+            || {
+                let mut violations = 0usize;
+                let mut last_data = 0;
+                while last_data != publications_count {
+                    match canary.observe() {
+                        CanaryObservation::NotPublished => {}
+                        CanaryObservation::Published(data) => last_data = data,
+                        CanaryObservation::OrderingViolation { .. } => violations += 1,
+                    }
+                }
+                print!("{} ordering violations observed: ", violations);
+            },
+        );
+    }
+
+    /// Release/acquire publications should never be observed out of order.
+    ///
+    /// To maximize the odds of race conditions, this kind of test should be run
+    /// in single-threaded mode.
+    ///
+    #[test]
+    #[ignore]
+    fn release_acquire_race() {
+        // Amount of publications to carry out
+        let publications_count = crate::scale_iters(10_000_000) as u64;
+
+        // Ordering canary that will be used
+        let canary = OrderingCanary::new(0)
+            .publish_ordering(Ordering::Release)
+            .observe_ordering(Ordering::Acquire);
+
+        // Make sure that no ordering violation is observed
+        crate::concurrent_test_2(
+            || {
+                for i in 1..=publications_count {
+                    canary.publish(i);
+                }
+            },
+            || {
+                let mut last_data = 0;
+                while last_data != publications_count {
+                    match canary.observe() {
+                        CanaryObservation::NotPublished => {}
+                        CanaryObservation::Published(data) => last_data = data,
+                        violation => panic!("Unexpected {:?}", violation),
+                    }
+                }
+            },
+        );
+    }
+}
@@ -0,0 +1,139 @@
+//! Stock antagonists for common contention scenarios
+//!
+//! Writing a good antagonist takes some care: it must interfere with the
+//! benchmark in the intended way, at a high rate, without being optimized out
+//! by the compiler. This module provides ready-made antagonists for some
+//! common scenarios, to be used with `run_under_contention()` or the
+//! `contention` module.
+
+use crate::{probe, util::CachePadded};
+use std::{
+    mem::size_of,
+    sync::atomic::{self, AtomicU64, Ordering},
+};
+
+/// Antagonist that keeps a cache line busy with stores and memory fences
+///
+/// Each iteration stores a new value to the target, performs a sequentially
+/// consistent fence, which drains the store buffer, and reads the value back.
+/// On x86, the cache line is then flushed from the cache hierarchy. This keeps
+/// the target cache line bouncing between the antagonist and any other thread
+/// that accesses it, at a high rate. It is designed for testing code such as
+/// seqlocks, whose failure mode is loads being reordered before buffered
+/// stores.
+///
+/// The target is provided by the caller, so that it can be placed right next
+/// to the data under test. The value that was read back is returned, so that
+/// the compiler cannot optimize out the antagonist.
+///
+pub fn store_buffer_stressor(target: &CachePadded<AtomicU64>) -> impl FnMut() -> u64 + Send + '_ {
+    let mut value = 0u64;
+    move || {
+        value = value.wrapping_add(1);
+        target.store(value, Ordering::Relaxed);
+        atomic::fence(Ordering::SeqCst);
+        let read_back = target.load(Ordering::Relaxed);
+        flush_cache_line(target);
+        read_back
+    }
+}
+
+/// Antagonist that consumes memory bandwidth by streaming through a buffer
+///
+/// Each iteration reads through a whole buffer of `buffer_size` bytes, rounded
+/// down to a multiple of 8, which should be much larger than the last-level
+/// cache in order to hog memory bandwidth, see `probe::DEFAULT_BUFFER_SIZE`.
+/// This uses the same kernel as `probe::memory_bandwidth()`, which tells how
+/// close a given number of these antagonists gets to saturating the memory
+/// bandwidth of the machine.
+///
+/// The buffer is allocated and filled during the first iteration, so that
+/// its pages are placed on the NUMA node of the antagonist thread. Since
+/// iterations take milliseconds with large buffers, antagonists may take as
+/// long to stop once the benchmark is done.
+///
+/// # Panics
+///
+/// This function panics if `buffer_size` is smaller than 8.
+///
+pub fn memory_bandwidth_hog(buffer_size: usize) -> impl FnMut() -> u64 + Send {
+    assert!(
+        buffer_size >= size_of::<u64>(),
+        "The buffer must hold at least one u64"
+    );
+    let mut buffer = None;
+    move || {
+        let buffer =
+            buffer.get_or_insert_with(|| probe::filled_buffer(buffer_size / size_of::<u64>()));
+        probe::read_kernel(buffer)
+    }
+}
+
+/// Flush the cache line containing some data, where supported
+///
+/// Miri does not support cache flushes, so this does nothing under Miri.
+///
+#[inline]
+fn flush_cache_line<T>(data: &T) {
+    #[cfg(all(target_arch = "x86_64", target_feature = "sse2", not(miri)))]
+    {
+        let ptr: *const T = data;
+        // Safe because the pointer targets valid memory, and SSE2 is available
+        unsafe { std::arch::x86_64::_mm_clflush(ptr.cast()) };
+    }
+    #[cfg(all(target_arch = "x86", target_feature = "sse2", not(miri)))]
+    {
+        let ptr: *const T = data;
+        // Safe because the pointer targets valid memory, and SSE2 is available
+        unsafe { std::arch::x86::_mm_clflush(ptr.cast()) };
+    }
+    #[cfg(any(
+        not(all(
+            any(target_arch = "x86", target_arch = "x86_64"),
+            target_feature = "sse2"
+        )),
+        miri
+    ))]
+    {
+        let _ = data;
+    }
+}
+
+/// Tests of stock antagonists
+#[cfg(test)]
+mod tests {
+    use super::{memory_bandwidth_hog, store_buffer_stressor};
+    use crate::{contention::Contention, util::CachePadded, Intensity};
+    use std::{sync::atomic::AtomicU64, time::Duration};
+
+    // The store buffer stressor should run at a high rate
+    #[test]
+    fn store_buffer_stressor_rate() {
+        // Run longer at higher intensity, as scale_iters() expects more iterations
+        let duration = Duration::from_millis(Intensity::current().pick(10, 100, 1000));
+        let target = CachePadded::new(AtomicU64::new(0));
+        let (_, stats) = Contention::new().require_contention(true).run(
+            |_| store_buffer_stressor(&target),
+            || std::thread::sleep(duration),
+        );
+        assert!(stats.antagonists.overlapped());
+        assert!(stats.antagonists.overlapping_iterations[0] > crate::scale_iters(1_000) as u64);
+        assert_eq!(
+            target.into_inner().into_inner(),
+            stats.antagonists.iterations[0]
+        );
+    }
+
+    // The memory bandwidth hog should stream through its whole buffer
+    #[test]
+    fn memory_bandwidth_hog_sum() {
+        let (_, stats) = Contention::new().run(
+            |_| {
+                let mut hog = memory_bandwidth_hog(1000 * 8 + 7);
+                move || assert_eq!(hog(), 999 * 1000 / 2)
+            },
+            || std::thread::sleep(Duration::from_millis(10)),
+        );
+        assert!(stats.antagonists.iterations[0] > 0);
+    }
+}
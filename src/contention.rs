@@ -0,0 +1,1621 @@
+//! Configurable contention harness
+//!
+//! The `run_under_contention()` function at the root of this crate covers the
+//! common case of benchmarking an operation while a single antagonist thread
+//! is hammering on the same data. This module provides finer control over
+//! this process, starting with the amount of antagonist threads, and reports
+//! statistics about what the antagonists did while the benchmark was running.
+
+mod spawner;
+mod sweep;
+
+pub use self::{
+    spawner::{Spawner, ThreadSpawner},
+    sweep::{contention_sweep, Sweep, SweepPoint},
+};
+
+#[cfg(all(feature = "perf", target_os = "linux"))]
+use crate::perf::{PerfCounter, PerfCounters, PerfError};
+use crate::{
+    affinity::{self, AffinityError},
+    cpu_time, env,
+    latency::{self, LatencyStats},
+    noinline,
+    sched::{self, RestoreScheduling, RtPriority, SchedError},
+    timing, trace, warmup,
+};
+#[cfg(feature = "serde")]
+use serde::Serialize;
+#[cfg(shuttle)]
+use shuttle::{
+    hint,
+    sync::{
+        atomic::AtomicU8,
+        mpsc::{self, Receiver},
+        Barrier,
+    },
+    thread::{self, ScopedJoinHandle},
+};
+use std::{
+    fmt, panic,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
+#[cfg(not(shuttle))]
+use std::{
+    hint,
+    sync::{
+        atomic::AtomicU8,
+        mpsc::{self, Receiver},
+        Barrier,
+    },
+    thread::{self, ScopedJoinHandle},
+};
+
+/// Contention harness configuration
+///
+/// This builder lets you tune how antagonist threads are set up before running
+/// a benchmark under contention. Its default configuration is equivalent to
+/// what `run_under_contention()` does, namely running a single antagonist.
+///
+#[derive(Clone, Debug)]
+pub struct Contention {
+    /// Number of antagonist threads
+    antagonists: AntagonistCount,
+
+    /// Number of threads used by the benchmark
+    benchmark_threads: usize,
+
+    /// Maximal number of threads when oversubscribing
+    max_threads: usize,
+
+    /// CPU to which the benchmark thread should be pinned, if any
+    pin_benchmark: Option<usize>,
+
+    /// CPUs to which antagonist threads should be pinned, if any
+    pin_antagonists: Option<Vec<usize>>,
+
+    /// What antagonists do between two iterations
+    backoff: Backoff,
+
+    /// Truth that antagonists must have overlapped with the benchmark
+    require_contention: bool,
+
+    /// Duration for which all CPUs are warmed up before starting, if any
+    cpu_warmup: Option<Duration>,
+
+    /// Realtime priority of the benchmark thread, if any
+    realtime_benchmark: Option<RtPriority>,
+
+    /// Performance counters to be measured on the benchmark thread
+    #[cfg(all(feature = "perf", target_os = "linux"))]
+    perf_counters: Vec<PerfCounter>,
+}
+//
+impl Contention {
+    /// Start with the default configuration (one antagonist thread)
+    pub fn new() -> Self {
+        Self {
+            antagonists: AntagonistCount::Fixed(1),
+            benchmark_threads: 1,
+            max_threads: 1024,
+            pin_benchmark: None,
+            pin_antagonists: None,
+            backoff: Backoff::None,
+            require_contention: false,
+            cpu_warmup: None,
+            realtime_benchmark: None,
+            #[cfg(all(feature = "perf", target_os = "linux"))]
+            perf_counters: Vec::new(),
+        }
+    }
+
+    /// Set the number of antagonist threads
+    ///
+    /// Zero is accepted, and will result in the benchmark being run without
+    /// any contention, which is useful as a baseline.
+    ///
+    pub fn antagonists(mut self, count: usize) -> Self {
+        self.antagonists = AntagonistCount::Fixed(count);
+        self
+    }
+
+    /// Run more threads than the system can run in parallel
+    ///
+    /// Some bugs only appear when threads are preempted at an inopportune
+    /// time, which requires having more runnable threads than CPUs. With this
+    /// option, enough antagonists are spawned that the total number of threads
+    /// is `factor` times `testbench::parallelism()`, rounded up.
+    ///
+    /// The number of threads used by the benchmark is taken into account, see
+    /// `benchmark_threads()`, and the total thread count is capped to the
+    /// limit set by `max_threads()`. This replaces the antagonist count set by
+    /// `antagonists()`, and vice versa.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `factor` is negative or not finite.
+    ///
+    pub fn oversubscribe(mut self, factor: f32) -> Self {
+        assert!(
+            factor.is_finite() && factor >= 0.0,
+            "Oversubscription factor must be finite and positive"
+        );
+        self.antagonists = AntagonistCount::Oversubscribe(factor);
+        self
+    }
+
+    /// Tell how many threads the benchmark uses (default: 1)
+    ///
+    /// This is only used to compute the number of antagonists in
+    /// `oversubscribe()` mode. For example, a benchmark that runs a
+    /// `concurrent_test_2()` uses two threads.
+    ///
+    pub fn benchmark_threads(mut self, count: usize) -> Self {
+        self.benchmark_threads = count;
+        self
+    }
+
+    /// Cap the total number of threads in `oversubscribe()` mode
+    ///
+    /// This protects against absurd oversubscription factors. The default
+    /// limit is 1024 threads, including benchmark threads.
+    ///
+    pub fn max_threads(mut self, count: usize) -> Self {
+        self.max_threads = count;
+        self
+    }
+
+    /// Pin the benchmark thread to a certain logical CPU
+    ///
+    /// The benchmark runs on the thread that called `run()`, whose former
+    /// CPU affinity is restored once the benchmark is done. See the `affinity`
+    /// module for more information about CPU identifiers.
+    ///
+    pub fn pin_benchmark_to(mut self, cpu: usize) -> Self {
+        self.pin_benchmark = Some(cpu);
+        self
+    }
+
+    /// Pin each antagonist thread to a certain logical CPU
+    ///
+    /// The i-th antagonist thread is pinned to the i-th CPU of the list, which
+    /// must therefore have as many entries as there are antagonists.
+    ///
+    pub fn pin_antagonists_to(mut self, cpus: &[usize]) -> Self {
+        self.pin_antagonists = Some(cpus.to_owned());
+        self
+    }
+
+    /// Make antagonists back off between two iterations
+    ///
+    /// By default, antagonists run at full throttle, which can be so
+    /// aggressive that the benchmark never gets hold of the shared data and
+    /// ends up measuring starvation rather than contention. Backing off
+    /// between antagonist iterations lets you tune this.
+    ///
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Panic if some antagonist did not run concurrently with the benchmark
+    ///
+    /// Very short benchmarks can be over before antagonist threads even got
+    /// scheduled, in which case they are not measured under contention. The
+    /// `overlapping_iterations` antagonist statistic tells when this happens,
+    /// and this option turns it into a panic.
+    ///
+    pub fn require_contention(mut self, require: bool) -> Self {
+        self.require_contention = require;
+        self
+    }
+
+    /// Warm up all CPUs before starting the antagonists
+    ///
+    /// This calls `warmup::spin_all_cores()` with the specified duration, so
+    /// that the CPUs run at full clock frequency once the benchmark starts.
+    /// See the `warmup` module for more information.
+    ///
+    pub fn cpu_warmup(mut self, duration: Duration) -> Self {
+        self.cpu_warmup = Some(duration);
+        self
+    }
+
+    /// Run the benchmark thread with realtime scheduling
+    ///
+    /// This protects the benchmark thread from being preempted by antagonist
+    /// threads, which run with ordinary scheduling. The benchmark thread is
+    /// switched to realtime scheduling before antagonists are started, and
+    /// back once they are stopped. See the `sched` module for more
+    /// information, including the privileges that this requires.
+    ///
+    /// As there is no benchmark thread in `start()` mode, this is ignored
+    /// there.
+    ///
+    pub fn realtime_benchmark(mut self, priority: RtPriority) -> Self {
+        self.realtime_benchmark = Some(priority);
+        self
+    }
+
+    /// Measure performance counters on the benchmark thread
+    ///
+    /// Counters are started right before the benchmark and read right after
+    /// it, and their values are reported in the `ContentionStats`. See the
+    /// `perf` module for more information.
+    ///
+    #[cfg(all(feature = "perf", target_os = "linux"))]
+    pub fn perf_counters(mut self, counters: &[PerfCounter]) -> Self {
+        self.perf_counters = counters.to_owned();
+        self
+    }
+
+    /// Run a benchmark while antagonists are running in other threads
+    ///
+    /// The antagonist factory is called once per antagonist thread, with the
+    /// index of that thread as a parameter, before any thread is spawned. Each
+    /// resulting antagonist is then run in a loop in its own thread until the
+    /// benchmark is done.
+    ///
+    /// The same precautions as in `run_under_contention()` apply concerning
+    /// the need to prevent antagonists from being optimized out.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the configuration is invalid, use
+    /// `try_run()` if you want to handle this as an error.
+    ///
+    /// This function will propagate panics from the antagonist factory, the
+    /// antagonists and the benchmark. Antagonist threads are stopped even if
+    /// the benchmark panics.
+    ///
+    pub fn run<Antagonist, AntagonistResult, BenchmarkResult>(
+        &self,
+        antagonist_factory: impl FnMut(usize) -> Antagonist,
+        benchmark: impl FnOnce() -> BenchmarkResult,
+    ) -> (BenchmarkResult, ContentionStats)
+    where
+        Antagonist: FnMut() -> AntagonistResult + Send,
+    {
+        self.try_run(antagonist_factory, benchmark)
+            .unwrap_or_else(|e| panic!("Failed to set up contention: {}", e))
+    }
+
+    /// Like `run()`, but report configuration errors instead of panicking
+    ///
+    /// The configuration is checked before any antagonist thread is started.
+    ///
+    pub fn try_run<Antagonist, AntagonistResult, BenchmarkResult>(
+        &self,
+        antagonist_factory: impl FnMut(usize) -> Antagonist,
+        benchmark: impl FnOnce() -> BenchmarkResult,
+    ) -> Result<(BenchmarkResult, ContentionStats), ContentionError>
+    where
+        Antagonist: FnMut() -> AntagonistResult + Send,
+    {
+        self.validate()?;
+        #[cfg(all(feature = "perf", target_os = "linux"))]
+        let perf_counters = PerfCounters::open(&self.perf_counters)?;
+        self.warm_up();
+        let _restore_scheduling = self.set_realtime()?;
+
+        // Build all antagonists before spawning any thread, so that a panic in
+        // the factory cannot leave threads waiting on the start barrier.
+        let antagonists = (0..self.antagonist_count())
+            .map(antagonist_factory)
+            .collect::<Vec<_>>();
+
+        let start_barrier = Barrier::new(antagonists.len() + 1);
+        let phase = Phase::new();
+        let parent = trace::Parent::current();
+        thread::scope(|s| {
+            let handles = antagonists
+                .into_iter()
+                .enumerate()
+                .map(|(idx, mut antagonist)| {
+                    let pin = self.antagonist_pin(idx);
+                    let backoff = self.backoff;
+                    let (start_barrier, phase, parent) = (&start_barrier, &phase, &parent);
+                    s.spawn(move || {
+                        antagonist_loop(&mut antagonist, pin, backoff, start_barrier, phase, parent)
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            let (result, mut stats, end) = {
+                let _stop = StopOnDrop(&phase);
+                self.time_benchmark(
+                    || {
+                        start_barrier.wait();
+                    },
+                    &phase,
+                    benchmark,
+                    #[cfg(all(feature = "perf", target_os = "linux"))]
+                    &perf_counters,
+                )
+            };
+
+            let reports = handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap_or_else(|p| panic::resume_unwind(p)))
+                .collect();
+            stats.antagonists = AntagonistStats::from_reports(reports, self.backoff, end);
+            if self.require_contention {
+                stats.antagonists.assert_overlap();
+            }
+            Ok((result, stats))
+        })
+    }
+
+    /// Run a benchmark while antagonists are running on a custom spawner
+    ///
+    /// This is a variant of `run()` where antagonists are run as tasks of a
+    /// `Spawner`, such as an existing thread pool, instead of dedicated
+    /// threads. Since these tasks are not bound to a scope, antagonists must
+    /// be `'static`, as in `start()`.
+    ///
+    /// All antagonist tasks are guaranteed to have stopped and dropped their
+    /// antagonist by the time this function returns or unwinds.
+    ///
+    /// # Panics
+    ///
+    /// This function panics under the same conditions as `run()`.
+    ///
+    pub fn run_on<Antagonist, AntagonistResult, BenchmarkResult>(
+        &self,
+        spawner: &impl Spawner,
+        antagonist_factory: impl FnMut(usize) -> Antagonist,
+        benchmark: impl FnOnce() -> BenchmarkResult,
+    ) -> (BenchmarkResult, ContentionStats)
+    where
+        Antagonist: FnMut() -> AntagonistResult + Send + 'static,
+    {
+        self.try_run_on(spawner, antagonist_factory, benchmark)
+            .unwrap_or_else(|e| panic!("Failed to set up contention: {}", e))
+    }
+
+    /// Like `run_on()`, but report configuration errors instead of panicking
+    pub fn try_run_on<Antagonist, AntagonistResult, BenchmarkResult>(
+        &self,
+        spawner: &impl Spawner,
+        antagonist_factory: impl FnMut(usize) -> Antagonist,
+        benchmark: impl FnOnce() -> BenchmarkResult,
+    ) -> Result<(BenchmarkResult, ContentionStats), ContentionError>
+    where
+        Antagonist: FnMut() -> AntagonistResult + Send + 'static,
+    {
+        #[cfg(all(feature = "perf", target_os = "linux"))]
+        let perf_counters = PerfCounters::open(&self.perf_counters)?;
+        let _restore_scheduling = self.set_realtime()?;
+        let guard = self.spawn_antagonists(spawner, antagonist_factory, false)?;
+        let (result, mut stats, end) = self.time_benchmark(
+            || (),
+            &guard.phase,
+            benchmark,
+            #[cfg(all(feature = "perf", target_os = "linux"))]
+            &perf_counters,
+        );
+        stats.antagonists = guard.finish(end);
+        Ok((result, stats))
+    }
+
+    /// Measure the latency of each run of an operation under contention
+    ///
+    /// This is a variant of `run()` where the benchmark is an operation that
+    /// is run a certain number of times, and the duration of each run is
+    /// recorded, so that the latency distribution can be analyzed. The timer
+    /// overhead is calibrated before the benchmark, so the calibration is not
+    /// affected by the antagonists.
+    ///
+    /// # Panics
+    ///
+    /// This function panics under the same conditions as `run()`.
+    ///
+    pub fn run_latency<Antagonist, AntagonistResult>(
+        &self,
+        antagonist_factory: impl FnMut(usize) -> Antagonist,
+        iterations: usize,
+        operation: impl FnMut(),
+    ) -> (LatencyStats, ContentionStats)
+    where
+        Antagonist: FnMut() -> AntagonistResult + Send,
+    {
+        // Calibrate the timer overhead now, outside of the measured region
+        let _ = timing::timer_overhead();
+        self.run(antagonist_factory, || {
+            latency::record(iterations, operation)
+        })
+    }
+
+    /// Variant of `run_latency()` that uses the hardware cycle counter
+    ///
+    /// Runs of the operation are timed with `timing::cycles_start()` and
+    /// `timing::cycles_end()`, which is more precise than `Instant` for very
+    /// short operations. The counter is calibrated before the benchmark, so
+    /// the calibration is not affected by the antagonists.
+    ///
+    /// # Panics
+    ///
+    /// This function panics under the same conditions as `run()`.
+    ///
+    #[cfg(all(
+        feature = "cycles",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    pub fn run_latency_cycles<Antagonist, AntagonistResult>(
+        &self,
+        antagonist_factory: impl FnMut(usize) -> Antagonist,
+        iterations: usize,
+        operation: impl FnMut(),
+    ) -> (LatencyStats, ContentionStats)
+    where
+        Antagonist: FnMut() -> AntagonistResult + Send,
+    {
+        // Calibrate the cycle counter now, outside of the measured region
+        let _ = timing::cycles_to_duration(timing::cycles_overhead());
+        self.run(antagonist_factory, || {
+            latency::record_cycles(iterations, operation)
+        })
+    }
+
+    /// Start antagonist threads, which will run until the guard is dropped
+    ///
+    /// Sometimes, the benchmark is not a single function call, but a process
+    /// driven by some external tool such as a benchmarking framework. In this
+    /// case, it is more convenient to keep antagonists running until some
+    /// later point in time. This is what this method allows.
+    ///
+    /// Since the antagonists are not bound to a scope, they must be `'static`,
+    /// which means that data shared with the benchmark must be put in an
+    /// `Arc` or leaked. Once this function returns, all antagonist threads are
+    /// guaranteed to be running.
+    ///
+    /// As there is no benchmark thread in this mode, `pin_benchmark_to()` is
+    /// ignored here. You can pin your benchmark thread by yourself using the
+    /// `affinity` module.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the configuration is invalid, use
+    /// `try_start()` if you want to handle this as an error.
+    ///
+    /// This function will propagate panics from the antagonist factory.
+    ///
+    pub fn start<Antagonist, AntagonistResult>(
+        &self,
+        antagonist_factory: impl FnMut(usize) -> Antagonist,
+    ) -> ContentionGuard
+    where
+        Antagonist: FnMut() -> AntagonistResult + Send + 'static,
+    {
+        self.start_on(&ThreadSpawner, antagonist_factory)
+    }
+
+    /// Like `start()`, but report configuration errors instead of panicking
+    ///
+    /// The configuration is checked before any antagonist thread is started.
+    ///
+    pub fn try_start<Antagonist, AntagonistResult>(
+        &self,
+        antagonist_factory: impl FnMut(usize) -> Antagonist,
+    ) -> Result<ContentionGuard, ContentionError>
+    where
+        Antagonist: FnMut() -> AntagonistResult + Send + 'static,
+    {
+        self.try_start_on(&ThreadSpawner, antagonist_factory)
+    }
+
+    /// Like `start()`, but run antagonists as tasks of a custom spawner
+    ///
+    /// See `Spawner` for the requirements that the spawner must fulfill.
+    /// Stopping or dropping the resulting guard waits for all antagonist tasks
+    /// to have stopped and dropped their antagonist.
+    ///
+    /// # Panics
+    ///
+    /// This function panics under the same conditions as `start()`.
+    ///
+    pub fn start_on<Antagonist, AntagonistResult>(
+        &self,
+        spawner: &impl Spawner,
+        antagonist_factory: impl FnMut(usize) -> Antagonist,
+    ) -> ContentionGuard
+    where
+        Antagonist: FnMut() -> AntagonistResult + Send + 'static,
+    {
+        self.try_start_on(spawner, antagonist_factory)
+            .unwrap_or_else(|e| panic!("Failed to set up contention: {}", e))
+    }
+
+    /// Like `start_on()`, but report configuration errors instead of panicking
+    pub fn try_start_on<Antagonist, AntagonistResult>(
+        &self,
+        spawner: &impl Spawner,
+        antagonist_factory: impl FnMut(usize) -> Antagonist,
+    ) -> Result<ContentionGuard, ContentionError>
+    where
+        Antagonist: FnMut() -> AntagonistResult + Send + 'static,
+    {
+        self.spawn_antagonists(spawner, antagonist_factory, true)
+    }
+
+    /// Spawn antagonists and wait for them to be running
+    ///
+    /// If `measuring` is false, antagonists only start counting overlapping
+    /// iterations once the benchmark is started via `time_benchmark()`.
+    ///
+    fn spawn_antagonists<Antagonist, AntagonistResult>(
+        &self,
+        spawner: &impl Spawner,
+        antagonist_factory: impl FnMut(usize) -> Antagonist,
+        measuring: bool,
+    ) -> Result<ContentionGuard, ContentionError>
+    where
+        Antagonist: FnMut() -> AntagonistResult + Send + 'static,
+    {
+        self.validate()?;
+        self.warm_up();
+        let antagonists = (0..self.antagonist_count())
+            .map(antagonist_factory)
+            .collect::<Vec<_>>();
+        let antagonist_count = antagonists.len();
+
+        let start_barrier = Arc::new(Barrier::new(antagonist_count + 1));
+        let phase = Arc::new(Phase::new());
+        if measuring {
+            phase.start_measuring();
+        }
+        let (sender, results) = mpsc::channel();
+        let parent = trace::Parent::current();
+        for (idx, mut antagonist) in antagonists.into_iter().enumerate() {
+            let pin = self.antagonist_pin(idx);
+            let backoff = self.backoff;
+            let start_barrier = start_barrier.clone();
+            let phase = phase.clone();
+            let parent = parent.clone();
+            let sender = sender.clone();
+            spawner.spawn(Box::new(move || {
+                // Report panics through the channel, as some spawners do not
+                // handle them, and drop the antagonist before reporting.
+                let result = noinline::try_call_once(|| {
+                    antagonist_loop(
+                        &mut antagonist,
+                        pin,
+                        backoff,
+                        &start_barrier,
+                        &phase,
+                        &parent,
+                    )
+                });
+                drop(antagonist);
+                let _ = sender.send((idx, result));
+            }));
+        }
+        start_barrier.wait();
+        Ok(ContentionGuard {
+            phase,
+            antagonists: antagonist_count,
+            backoff: self.backoff,
+            require_contention: self.require_contention,
+            results,
+        })
+    }
+
+    /// Pin the benchmark thread if requested, wait for antagonists to be
+    /// ready, then run and measure the benchmark
+    ///
+    /// Antagonists are told to stop once the benchmark is done. Antagonist
+    /// statistics are left empty, for the caller to fill in, and the time at
+    /// which the benchmark ended is returned for this purpose.
+    ///
+    fn time_benchmark<BenchmarkResult>(
+        &self,
+        wait_for_antagonists: impl FnOnce(),
+        phase: &Phase,
+        benchmark: impl FnOnce() -> BenchmarkResult,
+        #[cfg(all(feature = "perf", target_os = "linux"))] perf_counters: &PerfCounters,
+    ) -> (BenchmarkResult, ContentionStats, Instant) {
+        // Wait for antagonists even if pinning failed, so that they are not
+        // left waiting on the start barrier.
+        let restore_affinity = self.pin_benchmark.map(affinity::pin_current_thread_scoped);
+        wait_for_antagonists();
+        let _restore_affinity = restore_affinity
+            .transpose()
+            .unwrap_or_else(|e| panic!("Failed to pin benchmark thread: {}", e));
+        let cpu_start = cpu_time::thread_cpu_time();
+        #[cfg(all(feature = "perf", target_os = "linux"))]
+        perf_counters.start();
+        let (result, start, end) = {
+            let _span = trace::benchmark();
+            let start = timing::now();
+            phase.start_measuring();
+            let result = benchmark();
+            (result, start, timing::now())
+        };
+        phase.stop();
+        #[cfg(all(feature = "perf", target_os = "linux"))]
+        perf_counters.stop();
+        let benchmark_cpu_time = cpu_time::thread_cpu_time()
+            .zip(cpu_start)
+            .map(|(end, start)| end.saturating_sub(start));
+        let stats = ContentionStats {
+            benchmark_time: end - start,
+            benchmark_cpu_time,
+            #[cfg(all(feature = "perf", target_os = "linux"))]
+            perf_counts: perf_counters.read(),
+            antagonists: AntagonistStats::default(),
+            unreliable_timing: !env::timing_reliable(),
+        };
+        (result, stats, end)
+    }
+
+    /// Check that the configuration is valid
+    fn validate(&self) -> Result<(), ContentionError> {
+        if let Some(pins) = &self.pin_antagonists {
+            if pins.len() != self.antagonist_count() {
+                return Err(ContentionError::PinListSize {
+                    antagonists: self.antagonist_count(),
+                    pins: pins.len(),
+                });
+            }
+        }
+        for &cpu in self
+            .pin_benchmark
+            .iter()
+            .chain(self.pin_antagonists.iter().flatten())
+        {
+            affinity::check_cpu(cpu)?;
+        }
+        Ok(())
+    }
+
+    /// Warm up the CPUs, if requested
+    fn warm_up(&self) {
+        if let Some(duration) = self.cpu_warmup {
+            warmup::spin_all_cores(duration);
+        }
+    }
+
+    /// Switch the benchmark thread to realtime scheduling, if requested
+    fn set_realtime(&self) -> Result<Option<RestoreScheduling>, SchedError> {
+        self.realtime_benchmark
+            .map(sched::set_realtime_scoped)
+            .transpose()
+    }
+
+    /// Number of antagonist threads to be spawned
+    fn antagonist_count(&self) -> usize {
+        match self.antagonists {
+            AntagonistCount::Fixed(count) => count,
+            AntagonistCount::Oversubscribe(factor) => {
+                let threads = (crate::parallelism().get() as f32 * factor).ceil() as usize;
+                threads
+                    .min(self.max_threads)
+                    .saturating_sub(self.benchmark_threads)
+            }
+        }
+    }
+
+    /// CPU to which the antagonist with a certain index should be pinned
+    fn antagonist_pin(&self, idx: usize) -> Option<usize> {
+        self.pin_antagonists.as_ref().map(|pins| pins[idx])
+    }
+}
+//
+impl Default for Contention {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Number of antagonist threads, possibly defined by oversubscription
+#[derive(Clone, Copy, Debug)]
+enum AntagonistCount {
+    /// Fixed number of antagonists
+    Fixed(usize),
+
+    /// Oversubscription factor
+    Oversubscribe(f32),
+}
+
+/// Error while setting up contention
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ContentionError {
+    /// The number of antagonist CPU pins does not match the antagonist count
+    PinListSize {
+        /// Number of antagonist threads
+        antagonists: usize,
+
+        /// Number of CPUs that antagonists should be pinned to
+        pins: usize,
+    },
+
+    /// Thread pinning is not possible
+    Affinity(AffinityError),
+
+    /// The benchmark thread cannot use realtime scheduling
+    Sched(SchedError),
+
+    /// Performance counters could not be opened
+    #[cfg(all(feature = "perf", target_os = "linux"))]
+    Perf(PerfError),
+}
+//
+impl fmt::Display for ContentionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PinListSize { antagonists, pins } => write!(
+                f,
+                "{} antagonist CPU pins were specified for {} antagonists",
+                pins, antagonists
+            ),
+            Self::Affinity(error) => write!(f, "{}", error),
+            Self::Sched(error) => write!(f, "{}", error),
+            #[cfg(all(feature = "perf", target_os = "linux"))]
+            Self::Perf(error) => write!(f, "{}", error),
+        }
+    }
+}
+//
+impl std::error::Error for ContentionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Affinity(error) => Some(error),
+            Self::Sched(error) => Some(error),
+            #[cfg(all(feature = "perf", target_os = "linux"))]
+            Self::Perf(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+//
+impl From<AffinityError> for ContentionError {
+    fn from(error: AffinityError) -> Self {
+        Self::Affinity(error)
+    }
+}
+//
+impl From<SchedError> for ContentionError {
+    fn from(error: SchedError) -> Self {
+        Self::Sched(error)
+    }
+}
+//
+#[cfg(all(feature = "perf", target_os = "linux"))]
+impl From<PerfError> for ContentionError {
+    fn from(error: PerfError) -> Self {
+        Self::Perf(error)
+    }
+}
+
+/// Antagonist threads that keep running until this guard is dropped
+///
+/// This is produced by `Contention::start()`. Use the `stop()` method if you
+/// are interested in antagonist statistics, otherwise dropping the guard will
+/// stop the antagonist threads just as well.
+///
+#[derive(Debug)]
+#[must_use = "antagonists are stopped as soon as the guard is dropped"]
+pub struct ContentionGuard {
+    /// Progress of the run, which tells antagonist threads when to stop
+    phase: Arc<Phase>,
+
+    /// Number of antagonists which have not reported their results yet
+    antagonists: usize,
+
+    /// Backoff used by the antagonists
+    backoff: Backoff,
+
+    /// Truth that antagonists must have overlapped with the benchmark
+    require_contention: bool,
+
+    /// Results of antagonists, tagged by antagonist index
+    results: Receiver<(usize, thread::Result<AntagonistReport>)>,
+}
+//
+impl ContentionGuard {
+    /// Stop the antagonist threads, wait for them, and report what they did
+    ///
+    /// In this mode, the benchmark is considered to run from the moment where
+    /// antagonists were started to the moment where this function is called.
+    ///
+    /// # Panics
+    ///
+    /// This function will propagate panics from the antagonists.
+    ///
+    pub fn stop(self) -> AntagonistStats {
+        self.finish(timing::now())
+    }
+
+    /// Implementation of `stop()`, given the time at which the benchmark ended
+    fn finish(mut self, benchmark_end: Instant) -> AntagonistStats {
+        let reports = self
+            .stop_impl()
+            .into_iter()
+            .map(|result| {
+                result
+                    .expect("An antagonist task was dropped without being run")
+                    .unwrap_or_else(|p| panic::resume_unwind(p))
+            })
+            .collect();
+        let stats = AntagonistStats::from_reports(reports, self.backoff, benchmark_end);
+        if self.require_contention {
+            stats.assert_overlap();
+        }
+        stats
+    }
+
+    /// Stop the antagonists and wait for all of them to report their result
+    ///
+    /// Results are ordered by antagonist index. Antagonists whose task was
+    /// dropped without being run have no result.
+    ///
+    fn stop_impl(&mut self) -> Vec<Option<thread::Result<AntagonistReport>>> {
+        self.phase.stop();
+        let mut results = (0..self.antagonists).map(|_| None).collect::<Vec<_>>();
+        for _ in 0..self.antagonists {
+            // This only fails once all senders are gone, i.e. once every task
+            // has either reported its result or been dropped.
+            match self.results.recv() {
+                Ok((idx, result)) => results[idx] = Some(result),
+                Err(_) => break,
+            }
+        }
+        self.antagonists = 0;
+        results
+    }
+}
+//
+impl Drop for ContentionGuard {
+    fn drop(&mut self) {
+        let results = self.stop_impl();
+        // Don't turn an ongoing panic into an abort by panicking again
+        if !thread::panicking() {
+            for result in results.into_iter().flatten() {
+                if let Err(payload) = result {
+                    panic::resume_unwind(payload);
+                }
+            }
+        }
+    }
+}
+
+/// Statistics about a run of the contention harness
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct ContentionStats {
+    /// Wall-clock time taken by the benchmark
+    pub benchmark_time: Duration,
+
+    /// CPU time consumed by the benchmark thread, if available
+    ///
+    /// This requires the `cpu-time` feature, and support from the operating
+    /// system. It tells apart a benchmark thread that was descheduled, which
+    /// consumes less CPU time than it takes wall-clock time, from one that was
+    /// merely slowed down by contention.
+    ///
+    pub benchmark_cpu_time: Option<Duration>,
+
+    /// Values of the performance counters measured on the benchmark thread
+    #[cfg(all(feature = "perf", target_os = "linux"))]
+    pub perf_counts: Vec<(PerfCounter, u64)>,
+
+    /// What the antagonists did while the benchmark was running
+    pub antagonists: AntagonistStats,
+
+    /// Truth that the benchmark ran in an environment where wall-clock
+    /// measurements are not meaningful, such as Miri or valgrind
+    ///
+    /// See `env::timing_reliable()` for details.
+    ///
+    pub unreliable_timing: bool,
+}
+//
+impl ContentionStats {
+    /// Ratio of benchmark wall-clock time to benchmark CPU time, if available
+    ///
+    /// This is close to 1 when the benchmark thread was running all the time,
+    /// and larger when it was descheduled or blocked.
+    ///
+    pub fn wall_to_cpu_ratio(&self) -> Option<f64> {
+        self.benchmark_cpu_time
+            .map(|cpu_time| self.benchmark_time.as_secs_f64() / cpu_time.as_secs_f64())
+    }
+}
+
+/// What antagonists do between two iterations
+#[allow(variant_size_differences)] // Small enough to be passed around by value
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[non_exhaustive]
+pub enum Backoff {
+    /// Start the next iteration immediately
+    #[default]
+    None,
+
+    /// Execute a spin loop hint a certain number of times
+    Spin(u32),
+
+    /// Yield to the operating system scheduler
+    Yield,
+
+    /// Sleep for a certain amount of time
+    Sleep(Duration),
+}
+//
+impl Backoff {
+    /// Back off once
+    fn wait(self) {
+        match self {
+            Self::None => {}
+            Self::Spin(count) => {
+                for _ in 0..count {
+                    hint::spin_loop();
+                }
+            }
+            Self::Yield => thread::yield_now(),
+            Self::Sleep(duration) => thread::sleep(duration),
+        }
+    }
+}
+
+/// Aggregate statistics about the antagonist threads
+///
+/// These statistics are collected by joining the antagonist threads, or by
+/// receiving a message from the antagonist tasks when a `Spawner` is used,
+/// which synchronizes with their completion. Therefore, by the time they are
+/// reported, all the side effects of the antagonists are visible to the
+/// caller of the contention harness.
+///
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[non_exhaustive]
+pub struct AntagonistStats {
+    /// Number of antagonist iterations performed by each antagonist thread
+    pub iterations: Vec<u64>,
+
+    /// Number of antagonist iterations which overlapped with the benchmark,
+    /// for each antagonist thread
+    ///
+    /// This counts iterations which started after the benchmark started and
+    /// ended before it ended. Antagonists detect the end of the benchmark with
+    /// some delay, so this count can be slightly overestimated, but it is only
+    /// nonzero if at least one iteration provably ended before the benchmark.
+    ///
+    pub overlapping_iterations: Vec<u64>,
+
+    /// What antagonists did between two iterations
+    pub backoff: Backoff,
+
+    /// Time between the end of the benchmark and the moment where the last
+    /// antagonist thread noticed that it should stop
+    ///
+    /// This includes the duration of the antagonist iteration that was in
+    /// progress, and can be surprisingly long when antagonist threads are
+    /// descheduled, as happens on oversubscribed machines.
+    ///
+    pub stop_latency: Duration,
+
+    /// Number of threads that the system could run in parallel
+    ///
+    /// This is the value of `testbench::parallelism()`, which also sizes the
+    /// antagonist thread pool in `oversubscribe()` mode.
+    ///
+    /// Comparing this with the number of antagonist and benchmark threads
+    /// tells whether the system was oversubscribed.
+    ///
+    pub available_parallelism: usize,
+}
+//
+impl AntagonistStats {
+    /// Truth that every antagonist thread ran concurrently with the benchmark
+    ///
+    /// This is vacuously true when there are no antagonist threads.
+    ///
+    pub fn overlapped(&self) -> bool {
+        self.overlapping_iterations.iter().all(|&iters| iters > 0)
+    }
+
+    /// Number of antagonist threads which were running
+    pub fn threads(&self) -> usize {
+        self.iterations.len()
+    }
+
+    /// Total number of antagonist iterations across all threads
+    pub fn total_iterations(&self) -> u64 {
+        self.iterations.iter().sum()
+    }
+
+    /// Build statistics from antagonist reports and the end of the benchmark
+    fn from_reports(
+        reports: Vec<AntagonistReport>,
+        backoff: Backoff,
+        benchmark_end: Instant,
+    ) -> Self {
+        let iterations = reports.iter().map(|report| report.iterations).collect();
+        let overlapping_iterations = reports
+            .iter()
+            .map(|report| match report.first_overlap_end {
+                Some(end) if end < benchmark_end => report.overlapping_iterations,
+                _ => 0,
+            })
+            .collect();
+        let stop_latency = reports
+            .iter()
+            .map(|report| report.stopped_at.saturating_duration_since(benchmark_end))
+            .max()
+            .unwrap_or(Duration::ZERO);
+        Self {
+            iterations,
+            overlapping_iterations,
+            backoff,
+            stop_latency,
+            available_parallelism: crate::parallelism().get(),
+        }
+    }
+
+    /// Panic if some antagonist thread did not overlap with the benchmark
+    fn assert_overlap(&self) {
+        for (idx, &iters) in self.overlapping_iterations.iter().enumerate() {
+            assert!(
+                iters > 0,
+                "Antagonist {} did not run concurrently with the benchmark",
+                idx
+            );
+        }
+    }
+}
+
+/// What an antagonist thread did while it was running
+#[derive(Debug)]
+struct AntagonistReport {
+    /// Number of iterations
+    iterations: u64,
+
+    /// Number of iterations that antagonists saw overlapping with the benchmark
+    overlapping_iterations: u64,
+
+    /// Time at which the first overlapping iteration was seen to have ended
+    first_overlap_end: Option<Instant>,
+
+    /// Time at which the antagonist noticed that it should stop
+    stopped_at: Instant,
+}
+
+/// Run an antagonist in a loop until told to stop, report what it did
+fn antagonist_loop<AntagonistResult>(
+    antagonist: &mut impl FnMut() -> AntagonistResult,
+    pin: Option<usize>,
+    backoff: Backoff,
+    start_barrier: &Barrier,
+    phase: &Phase,
+    parent: &trace::Parent,
+) -> AntagonistReport {
+    let _span = trace::antagonist(parent);
+    // Antagonists may run on threads that are not ours, whose affinity must
+    // be restored afterwards. Wait for the benchmark even if pinning failed,
+    // so that it is not left waiting on the barrier.
+    let restore_affinity = pin.map(affinity::pin_current_thread_scoped);
+    start_barrier.wait();
+    let _restore_affinity = restore_affinity
+        .transpose()
+        .unwrap_or_else(|e| panic!("Failed to pin antagonist thread: {}", e));
+
+    // An iteration overlapped with the benchmark if the benchmark was running
+    // both before and after it. The clock is only read once, to check that
+    // the first such iteration really ended before the benchmark did.
+    let (mut iterations, mut overlapping_iterations) = (0, 0);
+    let mut first_overlap_end = None;
+    let mut was_measuring = false;
+    loop {
+        let measuring = match phase.load() {
+            Phase::STARTING => false,
+            Phase::MEASURING => true,
+            _ => break,
+        };
+        if was_measuring && measuring {
+            overlapping_iterations += 1;
+            if first_overlap_end.is_none() {
+                first_overlap_end = Some(timing::now());
+            }
+        }
+        was_measuring = measuring;
+        antagonist();
+        iterations += 1;
+        backoff.wait();
+    }
+    AntagonistReport {
+        iterations,
+        overlapping_iterations,
+        first_overlap_end,
+        stopped_at: timing::now(),
+    }
+}
+
+/// Implementation of `run_under_contention_const()`
+pub(crate) fn run_fixed<const N: usize, Antagonist, AntagonistResult, BenchmarkResult>(
+    antagonists: [Antagonist; N],
+    benchmark: impl FnOnce() -> BenchmarkResult,
+) -> BenchmarkResult
+where
+    Antagonist: FnMut() -> AntagonistResult + Send,
+{
+    let start_barrier = Barrier::new(N + 1);
+    let phase = Phase::new();
+    let parent = trace::Parent::current();
+    thread::scope(|s| {
+        let handles = antagonists.map(|mut antagonist| {
+            let (start_barrier, phase, parent) = (&start_barrier, &phase, &parent);
+            s.spawn(move || {
+                antagonist_loop(
+                    &mut antagonist,
+                    None,
+                    Backoff::None,
+                    start_barrier,
+                    phase,
+                    parent,
+                );
+            })
+        });
+
+        let result = {
+            let _stop = StopOnDrop(&phase);
+            start_barrier.wait();
+            let _span = trace::benchmark();
+            phase.start_measuring();
+            benchmark()
+        };
+
+        for outcome in handles.map(ScopedJoinHandle::join) {
+            if let Err(payload) = outcome {
+                panic::resume_unwind(payload);
+            }
+        }
+        result
+    })
+}
+
+/// Progress of a contention run, as seen by the antagonists
+///
+/// Antagonists poll this once per iteration, which is how they know when to
+/// stop, and whether they are overlapping with the benchmark.
+///
+#[derive(Debug)]
+struct Phase(AtomicU8);
+//
+impl Phase {
+    /// Antagonists are running, but the benchmark has not started yet
+    const STARTING: u8 = 0;
+
+    /// The benchmark is running
+    const MEASURING: u8 = 1;
+
+    /// Antagonists should stop
+    const STOPPED: u8 = 2;
+
+    /// Start in the STARTING phase
+    fn new() -> Self {
+        Self(AtomicU8::new(Self::STARTING))
+    }
+
+    /// Tell antagonists that the benchmark has started
+    fn start_measuring(&self) {
+        self.0.store(Self::MEASURING, Ordering::Relaxed);
+    }
+
+    /// Tell antagonists to stop
+    fn stop(&self) {
+        if trace::ENABLED {
+            // Only report the first stop, as there can be several per run
+            if self.0.swap(Self::STOPPED, Ordering::Relaxed) != Self::STOPPED {
+                trace::stop_flag_set();
+            }
+        } else {
+            self.0.store(Self::STOPPED, Ordering::Relaxed);
+        }
+    }
+
+    /// Check the current phase
+    fn load(&self) -> u8 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Stops antagonists when dropped, including when the benchmark unwinds, so
+/// that antagonist threads never outlive the benchmark.
+struct StopOnDrop<'phase>(&'phase Phase);
+//
+impl Drop for StopOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.stop();
+    }
+}
+
+/// Tests of the contention harness
+#[cfg(test)]
+mod tests {
+    use super::{Backoff, Contention, ContentionError, Spawner, ThreadSpawner};
+    use crate::{
+        affinity::{self, AffinityError},
+        sched::{RtPriority, SchedError},
+    };
+    use std::{
+        panic::{self, AssertUnwindSafe},
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    // Every antagonist should be run, and its activity should be reported
+    #[test]
+    fn multiple_antagonists() {
+        let atom = AtomicUsize::new(0);
+        let (result, stats) = Contention::new().antagonists(3).run(
+            |_| || atom.fetch_add(1, Ordering::Relaxed),
+            || {
+                std::thread::sleep(Duration::from_millis(100));
+                42
+            },
+        );
+        assert_eq!(result, 42);
+        assert_eq!(stats.antagonists.threads(), 3);
+        assert!(stats.antagonists.iterations.iter().all(|&iters| iters > 0));
+        assert_eq!(
+            stats.antagonists.total_iterations(),
+            atom.load(Ordering::Relaxed) as u64
+        );
+        assert!(stats.benchmark_time >= Duration::from_millis(100));
+    }
+
+    // Zero antagonists is a valid uncontended baseline
+    #[test]
+    fn no_antagonist() {
+        let (result, stats) = Contention::new().antagonists(0).run(|_| || (), || 24);
+        assert_eq!(result, 24);
+        assert_eq!(stats.antagonists.threads(), 0);
+    }
+
+    // CPU warmup should happen before the antagonist factory is called
+    //
+    // Miri's clock does not reflect real time, so this cannot be checked there.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn cpu_warmup() {
+        let _lock = crate::warmup::tests::lock();
+        let start = Instant::now();
+        let mut factory_called = None;
+        Contention::new().cpu_warmup(Duration::from_millis(50)).run(
+            |_| {
+                factory_called.get_or_insert_with(|| start.elapsed());
+                || ()
+            },
+            || (),
+        );
+        assert!(factory_called.unwrap() >= Duration::from_millis(50));
+    }
+
+    // The benchmark should run with realtime scheduling if this is allowed
+    #[test]
+    fn realtime_benchmark() {
+        let result = Contention::new()
+            .realtime_benchmark(RtPriority::MIN)
+            .try_run(|_| || (), || 42);
+        match result {
+            Ok((result, stats)) => {
+                assert_eq!(result, 42);
+                assert_eq!(stats.antagonists.threads(), 1);
+            }
+            Err(ContentionError::Sched(SchedError::PermissionDenied | SchedError::Unsupported)) => {
+            }
+            Err(error) => panic!("Unexpected error: {}", error),
+        }
+    }
+
+    // Antagonists started via the guard API should run until stopped
+    #[test]
+    fn guard() {
+        let atom = Arc::new(AtomicUsize::new(0));
+        let guard = Contention::new().antagonists(2).start(|_| {
+            let atom = atom.clone();
+            move || atom.fetch_add(1, Ordering::Relaxed)
+        });
+        std::thread::sleep(Duration::from_millis(100));
+        let stats = guard.stop();
+        assert_eq!(stats.threads(), 2);
+        assert!(stats.iterations.iter().all(|&iters| iters > 0));
+        assert_eq!(
+            stats.total_iterations(),
+            atom.load(Ordering::Relaxed) as u64
+        );
+    }
+
+    // Spawner that counts how many tasks it was given
+    #[derive(Default)]
+    struct CountingSpawner(AtomicUsize);
+    //
+    impl Spawner for CountingSpawner {
+        fn spawn(&self, task: Box<dyn FnOnce() + Send>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            ThreadSpawner.spawn(task)
+        }
+    }
+
+    // Antagonists can be run by a custom spawner, and must have been dropped
+    // by the time the harness returns
+    #[test]
+    fn spawner() {
+        let spawner = CountingSpawner::default();
+        let alive = Arc::new(());
+        let (result, stats) = Contention::new().antagonists(2).run_on(
+            &spawner,
+            |_| {
+                let alive = alive.clone();
+                move || Arc::strong_count(&alive)
+            },
+            || {
+                std::thread::sleep(Duration::from_millis(100));
+                42
+            },
+        );
+        assert_eq!(result, 42);
+        assert_eq!(spawner.0.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.antagonists.threads(), 2);
+        assert!(stats.antagonists.iterations.iter().all(|&iters| iters > 0));
+        assert_eq!(Arc::strong_count(&alive), 1);
+    }
+
+    // Antagonist panics should be propagated by spawned tasks
+    #[test]
+    fn spawned_antagonist_panic() {
+        let started = Arc::new(AtomicBool::new(false));
+        let guard = Contention::new().start_on(&ThreadSpawner, |_| {
+            let started = started.clone();
+            move || {
+                started.store(true, Ordering::Relaxed);
+                panic!("Expected")
+            }
+        });
+        while !started.load(Ordering::Relaxed) {
+            std::thread::yield_now();
+        }
+        let result = panic::catch_unwind(AssertUnwindSafe(|| guard.stop()));
+        assert!(result.is_err());
+    }
+
+    // Rayon thread pools can run antagonists
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn rayon_pool() {
+        let pool = ::rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .build()
+            .unwrap();
+        let (_, stats) = Contention::new().antagonists(2).run_on(
+            &pool,
+            |_| || (),
+            || std::thread::sleep(Duration::from_millis(10)),
+        );
+        assert_eq!(stats.antagonists.threads(), 2);
+        pool.install(|| assert_eq!(::rayon::current_num_threads(), 2));
+    }
+
+    // Antagonists should tell whether they overlapped with the benchmark
+    #[test]
+    fn overlap() {
+        let (_, stats) = Contention::new()
+            .antagonists(2)
+            .require_contention(true)
+            .run(|_| || (), || std::thread::sleep(Duration::from_millis(100)));
+        assert!(stats.antagonists.overlapped());
+        let overlapping = &stats.antagonists.overlapping_iterations;
+        assert!(overlapping.iter().all(|&iters| iters > 0));
+        for (overlapping, total) in overlapping.iter().zip(&stats.antagonists.iterations) {
+            assert!(overlapping <= total);
+        }
+
+        let slow_antagonist = |_| || std::thread::sleep(Duration::from_millis(50));
+        let (_, stats) = Contention::new().run(slow_antagonist, || ());
+        assert!(!stats.antagonists.overlapped());
+        assert_eq!(stats.antagonists.overlapping_iterations, vec![0]);
+        let result = panic::catch_unwind(|| {
+            Contention::new()
+                .require_contention(true)
+                .run(slow_antagonist, || ())
+        });
+        assert!(result.is_err());
+    }
+
+    // Side effects of antagonists must be visible once the harness returns
+    #[test]
+    fn antagonist_effects() {
+        let mut log = Vec::new();
+        let mut log_ref = Some(&mut log);
+        let (_, stats) = Contention::new().run(
+            |_| {
+                let log = log_ref.take().unwrap();
+                move || log.push(())
+            },
+            || std::thread::sleep(Duration::from_millis(10)),
+        );
+        assert_eq!(log.len() as u64, stats.antagonists.iterations[0]);
+    }
+
+    // The time taken by antagonists to notice that they should stop should be
+    // measured, and include the iteration that was in progress
+    #[test]
+    fn stop_latency() {
+        let start = Instant::now();
+        let (_, stats) = Contention::new()
+            .backoff(Backoff::Sleep(Duration::from_millis(20)))
+            .run(|_| || (), || std::thread::sleep(Duration::from_millis(10)));
+        let stop_latency = stats.antagonists.stop_latency;
+        assert!(stop_latency > Duration::ZERO);
+        assert!(stop_latency <= start.elapsed() - stats.benchmark_time);
+    }
+
+    // Oversubscription should spawn more threads than there are CPUs
+    #[test]
+    fn oversubscribe() {
+        let (_, stats) = Contention::new()
+            .oversubscribe(2.0)
+            .run(|_| || (), || std::thread::sleep(Duration::from_millis(10)));
+        let parallelism = stats.antagonists.available_parallelism;
+        assert!(parallelism >= 1);
+        assert_eq!(stats.antagonists.threads(), 2 * parallelism - 1);
+
+        let (_, stats) = Contention::new()
+            .oversubscribe(100.0)
+            .benchmark_threads(2)
+            .max_threads(4)
+            .run(|_| || (), || ());
+        assert_eq!(stats.antagonists.threads(), 2);
+    }
+
+    // Yielding between iterations should slow antagonists down a lot
+    //
+    // Miri does not model the cost of yielding, so this cannot be checked
+    // there.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn backoff() {
+        let run = |backoff| {
+            Contention::new()
+                .backoff(backoff)
+                .run(|_| || (), || std::thread::sleep(Duration::from_millis(100)))
+                .1
+                .antagonists
+        };
+        let full_throttle = run(Backoff::None);
+        let yielding = run(Backoff::Yield);
+        assert_eq!(full_throttle.backoff, Backoff::None);
+        assert_eq!(yielding.backoff, Backoff::Yield);
+        assert!(yielding.total_iterations() < full_throttle.total_iterations() / 10);
+    }
+
+    // Latency measurements should produce one sample per iteration
+    #[test]
+    fn latency() {
+        let (latency, stats) = Contention::new().run_latency(
+            |_| || (),
+            100,
+            || std::thread::sleep(Duration::from_micros(100)),
+        );
+        assert_eq!(latency.len(), 100);
+        assert!(latency.raw_samples()[0] >= Duration::from_micros(100));
+        assert!(stats.benchmark_time >= Duration::from_millis(10));
+    }
+
+    // Latency can be measured with the cycle counter
+    #[test]
+    #[cfg(all(
+        feature = "cycles",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    fn latency_cycles() {
+        let (latency, _stats) = Contention::new().run_latency_cycles(
+            |_| || (),
+            100,
+            || std::thread::sleep(Duration::from_micros(100)),
+        );
+        assert_eq!(latency.len(), 100);
+        assert!(latency.raw_samples()[0] >= Duration::from_micros(90));
+        assert!(latency.timer_overhead() < Duration::from_micros(100));
+    }
+
+    // CPU time should tell sleeping benchmarks apart from running ones
+    #[test]
+    #[cfg(all(feature = "cpu-time", target_os = "linux"))]
+    fn cpu_time() {
+        let baseline = Contention::new().antagonists(0);
+        let benchmark_time = Duration::from_millis(100);
+        let (_, sleeping) = baseline.run(|_| || (), || std::thread::sleep(benchmark_time));
+        let sleeping_cpu_time = sleeping.benchmark_cpu_time.unwrap();
+        assert!(sleeping_cpu_time < benchmark_time / 10);
+        assert!(sleeping.wall_to_cpu_ratio().unwrap() > 10.0);
+
+        let (_, spinning) = baseline.run(
+            |_| || (),
+            || {
+                let start = Instant::now();
+                while start.elapsed() < benchmark_time {
+                    std::hint::spin_loop();
+                }
+            },
+        );
+        let spinning_cpu_time = spinning.benchmark_cpu_time.unwrap();
+        assert!(spinning_cpu_time > benchmark_time / 2);
+        assert!(spinning.wall_to_cpu_ratio().unwrap() < 2.0);
+    }
+
+    // Performance counters should be measured, if they can be opened
+    #[test]
+    #[cfg(all(feature = "perf", target_os = "linux"))]
+    fn perf_counters() {
+        use crate::perf::PerfCounter;
+        let result = Contention::new()
+            .perf_counters(&[PerfCounter::ContextSwitches])
+            .try_run(|_| || (), || std::thread::sleep(Duration::from_millis(10)));
+        match result {
+            Ok((_, stats)) => {
+                assert_eq!(stats.perf_counts.len(), 1);
+                assert_eq!(stats.perf_counts[0].0, PerfCounter::ContextSwitches);
+                assert!(stats.perf_counts[0].1 > 0);
+            }
+            Err(ContentionError::Perf(error)) => println!("Skipping test: {}", error),
+            Err(other) => panic!("Unexpected error: {}", other),
+        }
+    }
+
+    // Invalid pinning configurations should be reported before starting
+    #[test]
+    fn invalid_pinning() {
+        let result = Contention::new()
+            .antagonists(2)
+            .pin_antagonists_to(&[0])
+            .try_run(|_| || (), || ());
+        assert!(matches!(
+            result,
+            Err(ContentionError::PinListSize {
+                antagonists: 2,
+                pins: 1
+            })
+        ));
+
+        let result = Contention::new()
+            .pin_benchmark_to(usize::MAX)
+            .try_run(|_| || (), || ());
+        assert!(matches!(
+            result,
+            Err(ContentionError::Affinity(
+                AffinityError::InvalidCpu(usize::MAX) | AffinityError::Unsupported
+            ))
+        ));
+    }
+
+    // Pinned threads should run on the requested CPU, if supported
+    #[test]
+    fn pinning() {
+        let cpu = affinity::logical_cpus()[0];
+        let result = Contention::new()
+            .pin_antagonists_to(&[cpu])
+            .pin_benchmark_to(cpu)
+            .try_run(|_| || (), || 42);
+        match result {
+            Ok((result, _stats)) => assert_eq!(result, 42),
+            Err(ContentionError::Affinity(AffinityError::Unsupported)) => {}
+            Err(other) => panic!("Unexpected error: {}", other),
+        }
+    }
+
+    // A panicking benchmark must not leave antagonists running forever
+    #[test]
+    fn benchmark_panic() {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            Contention::new().run(|_| || (), || panic!("expected panic"))
+        }));
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,49 @@
+//! Execution of antagonists on user-provided threads
+
+/// Something that can run antagonist tasks concurrently
+///
+/// By default, the contention harness spawns one OS thread per antagonist.
+/// This trait lets you run antagonists on threads that you manage yourself
+/// instead, such as those of an existing thread pool.
+///
+/// Antagonist tasks block until all of them have started, and keep running
+/// until the harness tells them to stop. A spawner must therefore run every
+/// task that it is given, and have enough threads available to run all of
+/// them at the same time, otherwise the harness will wait forever. If the
+/// harness is called from one of the spawner's threads, that thread is not
+/// available for running antagonists.
+///
+pub trait Spawner {
+    /// Run a task asynchronously, on some thread other than the current one
+    fn spawn(&self, task: Box<dyn FnOnce() + Send>);
+}
+//
+impl<S: Spawner + ?Sized> Spawner for &S {
+    fn spawn(&self, task: Box<dyn FnOnce() + Send>) {
+        (**self).spawn(task)
+    }
+}
+
+/// Spawner that runs each task in a new OS thread
+///
+/// This is what the contention harness uses by default.
+///
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ThreadSpawner;
+//
+impl Spawner for ThreadSpawner {
+    fn spawn(&self, task: Box<dyn FnOnce() + Send>) {
+        #[cfg(shuttle)]
+        shuttle::thread::spawn(task);
+        #[cfg(not(shuttle))]
+        std::thread::spawn(task);
+    }
+}
+
+/// Rayon thread pools can run antagonists as long-lived tasks
+#[cfg(feature = "rayon")]
+impl Spawner for ::rayon::ThreadPool {
+    fn spawn(&self, task: Box<dyn FnOnce() + Send>) {
+        ::rayon::ThreadPool::spawn(self, task)
+    }
+}
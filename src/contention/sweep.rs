@@ -0,0 +1,232 @@
+//! Sweeps of a benchmark across several antagonist thread counts
+
+use super::{AntagonistStats, Contention};
+use crate::{warmup, Intensity};
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use std::time::Duration;
+
+/// Run a benchmark under an increasing number of antagonist threads
+///
+/// This is a shorthand for `Sweep::new(counts).run(...)`, which runs the
+/// benchmark once per antagonist count. See `Sweep` for more options.
+///
+pub fn contention_sweep<Antagonist, AntagonistResult>(
+    counts: &[usize],
+    antagonist_factory: impl FnMut(usize) -> Antagonist,
+    benchmark: impl FnMut() -> Duration,
+) -> Vec<SweepPoint>
+where
+    Antagonist: FnMut() -> AntagonistResult + Send,
+{
+    Sweep::new(counts).run(antagonist_factory, benchmark)
+}
+
+/// Contention sweep configuration
+///
+/// A contention sweep runs the same benchmark under a varying number of
+/// antagonist threads, which is useful for characterizing how the performance
+/// of a concurrent data structure degrades as contention increases.
+///
+/// Antagonist threads are fully torn down between two measurements, so that
+/// one measurement cannot leak interference into the next one.
+///
+#[derive(Clone, Debug)]
+pub struct Sweep {
+    /// Antagonist thread counts to be measured, in order
+    counts: Vec<usize>,
+
+    /// Number of times each antagonist thread count is measured
+    repetitions: usize,
+
+    /// Truth that repetitions should be interleaved
+    interleaved: bool,
+
+    /// Duration for which all CPUs are warmed up before the sweep, if any
+    cpu_warmup: Option<Duration>,
+}
+//
+impl Sweep {
+    /// Prepare to measure each of the specified antagonist thread counts
+    ///
+    /// Each count is measured once, or 5 times at `Soak` `Intensity`.
+    ///
+    pub fn new(counts: &[usize]) -> Self {
+        Self {
+            counts: counts.to_owned(),
+            repetitions: Intensity::current().pick(1, 1, 5),
+            interleaved: false,
+            cpu_warmup: None,
+        }
+    }
+
+    /// Measure each antagonist thread count multiple times
+    pub fn repetitions(mut self, repetitions: usize) -> Self {
+        self.repetitions = repetitions;
+        self
+    }
+
+    /// Interleave repetitions of the measurements
+    ///
+    /// By default, all repetitions of a given antagonist count are measured
+    /// back to back. In interleaved mode, the whole sweep is instead performed
+    /// once, then a second time, and so on. This spreads slow drifts of system
+    /// performance, such as those caused by CPU heating up, across all points.
+    ///
+    pub fn interleaved(mut self, interleaved: bool) -> Self {
+        self.interleaved = interleaved;
+        self
+    }
+
+    /// Warm up all CPUs before the first measurement
+    ///
+    /// This calls `warmup::spin_all_cores()` with the specified duration once,
+    /// before the sweep starts, so that the first measurements are not taken
+    /// on CPUs running at a lower clock frequency than the later ones. See
+    /// the `warmup` module for more information.
+    ///
+    pub fn cpu_warmup(mut self, duration: Duration) -> Self {
+        self.cpu_warmup = Some(duration);
+        self
+    }
+
+    /// Run the sweep
+    ///
+    /// The antagonist factory is called with the index of each antagonist
+    /// thread, as in `Contention::run()`. The benchmark is expected to perform
+    /// its own timing and return the measured duration.
+    ///
+    /// Measurements are returned in the order in which they were performed.
+    ///
+    /// # Panics
+    ///
+    /// This function will propagate panics from the antagonist factory, the
+    /// antagonists and the benchmark.
+    ///
+    pub fn run<Antagonist, AntagonistResult>(
+        &self,
+        mut antagonist_factory: impl FnMut(usize) -> Antagonist,
+        mut benchmark: impl FnMut() -> Duration,
+    ) -> Vec<SweepPoint>
+    where
+        Antagonist: FnMut() -> AntagonistResult + Send,
+    {
+        if let Some(duration) = self.cpu_warmup {
+            warmup::spin_all_cores(duration);
+        }
+        let mut points = Vec::with_capacity(self.counts.len() * self.repetitions);
+        let mut measure = |antagonists: usize, repetition: usize| {
+            let (measurement, stats) = Contention::new()
+                .antagonists(antagonists)
+                .run(&mut antagonist_factory, &mut benchmark);
+            points.push(SweepPoint {
+                antagonists,
+                repetition,
+                measurement,
+                antagonist_stats: stats.antagonists,
+            });
+        };
+        if self.interleaved {
+            for repetition in 0..self.repetitions {
+                for &count in &self.counts {
+                    measure(count, repetition);
+                }
+            }
+        } else {
+            for &count in &self.counts {
+                for repetition in 0..self.repetitions {
+                    measure(count, repetition);
+                }
+            }
+        }
+        points
+    }
+}
+
+/// Measurement from a contention sweep
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[non_exhaustive]
+pub struct SweepPoint {
+    /// Number of antagonist threads that were running
+    pub antagonists: usize,
+
+    /// Repetition index of this measurement, for this antagonist count
+    pub repetition: usize,
+
+    /// Duration measured by the benchmark
+    pub measurement: Duration,
+
+    /// What the antagonists did while the benchmark was running
+    pub antagonist_stats: AntagonistStats,
+}
+
+/// Tests of contention sweeps
+#[cfg(test)]
+mod tests {
+    use super::{contention_sweep, Sweep};
+    use std::time::{Duration, Instant};
+
+    // A basic sweep should measure each antagonist count in order, as many
+    // times as the test intensity requires
+    #[test]
+    fn basic_sweep() {
+        let repetitions = Sweep::new(&[]).repetitions;
+        let points = contention_sweep(
+            &[0, 1, 2],
+            |_| || std::hint::spin_loop(),
+            || {
+                let start = Instant::now();
+                std::thread::sleep(Duration::from_millis(10));
+                start.elapsed()
+            },
+        );
+        assert_eq!(points.len(), 3 * repetitions);
+        for (idx, point) in points.iter().enumerate() {
+            let count = idx / repetitions;
+            assert_eq!(point.antagonists, count);
+            assert_eq!(point.repetition, idx % repetitions);
+            assert_eq!(point.antagonist_stats.threads(), count);
+            assert!(point.measurement >= Duration::from_millis(10));
+        }
+    }
+
+    // Repetitions should be performed back to back or interleaved on demand
+    #[test]
+    fn repetitions() {
+        let order = |interleaved| {
+            Sweep::new(&[1, 2])
+                .repetitions(2)
+                .interleaved(interleaved)
+                .run(|_| || (), || Duration::ZERO)
+                .into_iter()
+                .map(|point| (point.antagonists, point.repetition))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(order(false), vec![(1, 0), (1, 1), (2, 0), (2, 1)]);
+        assert_eq!(order(true), vec![(1, 0), (2, 0), (1, 1), (2, 1)]);
+    }
+
+    // CPU warmup should happen once, before the first measurement
+    //
+    // Miri's clock does not reflect real time, so this cannot be checked there.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn cpu_warmup() {
+        let _lock = crate::warmup::tests::lock();
+        let start = Instant::now();
+        let mut first_measurement = None;
+        Sweep::new(&[0, 0])
+            .repetitions(1)
+            .cpu_warmup(Duration::from_millis(50))
+            .run(
+                |_| || (),
+                || {
+                    first_measurement.get_or_insert_with(|| start.elapsed());
+                    Duration::ZERO
+                },
+            );
+        assert!(first_measurement.unwrap() >= Duration::from_millis(50));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
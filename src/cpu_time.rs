@@ -0,0 +1,74 @@
+//! Measurement of the CPU time consumed by the current thread
+//!
+//! This requires the `cpu-time` feature, and is currently supported on Unix
+//! systems that provide a thread CPU-time clock, and on Windows.
+
+use std::time::Duration;
+
+/// CPU time consumed by the current thread so far, if available
+pub(crate) fn thread_cpu_time() -> Option<Duration> {
+    backend::thread_cpu_time()
+}
+
+/// Unix implementation, based on clock_gettime
+#[cfg(all(feature = "cpu-time", unix))]
+mod backend {
+    use std::{mem, time::Duration};
+
+    pub(super) fn thread_cpu_time() -> Option<Duration> {
+        // Safe because timespec is plain old data, and all-zeroes is valid
+        let mut time: libc::timespec = unsafe { mem::zeroed() };
+        // Safe because the timespec pointer is valid
+        let result = unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut time) };
+        if result != 0 {
+            return None;
+        }
+        Some(Duration::new(time.tv_sec as u64, time.tv_nsec as u32))
+    }
+}
+
+/// Windows implementation, based on GetThreadTimes
+#[cfg(all(feature = "cpu-time", windows))]
+mod backend {
+    use std::time::Duration;
+    use windows_sys::Win32::{
+        Foundation::FILETIME,
+        System::Threading::{GetCurrentThread, GetThreadTimes},
+    };
+
+    pub(super) fn thread_cpu_time() -> Option<Duration> {
+        let zero = FILETIME {
+            dwLowDateTime: 0,
+            dwHighDateTime: 0,
+        };
+        let (mut creation, mut exit, mut kernel, mut user) = (zero, zero, zero, zero);
+        // Safe because the current thread pseudo-handle and pointers are valid
+        let result = unsafe {
+            GetThreadTimes(
+                GetCurrentThread(),
+                &mut creation,
+                &mut exit,
+                &mut kernel,
+                &mut user,
+            )
+        };
+        if result == 0 {
+            return None;
+        }
+        // FILETIMEs are expressed in units of 100ns
+        let to_ticks =
+            |time: FILETIME| (u64::from(time.dwHighDateTime) << 32) | u64::from(time.dwLowDateTime);
+        let ticks = to_ticks(kernel) + to_ticks(user);
+        Some(Duration::from_nanos(ticks * 100))
+    }
+}
+
+/// Fallback implementation for unsupported configurations
+#[cfg(not(all(feature = "cpu-time", any(unix, windows))))]
+mod backend {
+    use std::time::Duration;
+
+    pub(super) fn thread_cpu_time() -> Option<Duration> {
+        None
+    }
+}
@@ -0,0 +1,70 @@
+//! Integration with the Criterion benchmarking framework
+//!
+//! Gluing `run_under_contention()` into a Criterion benchmark by hand is a bad
+//! idea, because the antagonists then get started and stopped for every
+//! Criterion sample, which adds overhead to the timing loop and skews results.
+//!
+//! The helpers from this module instead start antagonists once, let Criterion
+//! sample the benchmark routine as usual (including warmup), and only stop the
+//! antagonists once Criterion is done.
+
+use crate::contention::Contention;
+use ::criterion::{measurement::WallTime, BenchmarkGroup, BenchmarkId, Criterion};
+
+/// Benchmark a routine with Criterion while an antagonist is running
+///
+/// Since the antagonist keeps running while Criterion does its own thing, it
+/// must be `'static`. Data which is shared with the routine should be put in
+/// an `Arc` or leaked.
+///
+/// # Panics
+///
+/// This function will propagate panics from the antagonist and the routine.
+///
+pub fn bench_under_contention<AntagonistResult, RoutineResult>(
+    c: &mut Criterion,
+    id: &str,
+    antagonist: impl FnMut() -> AntagonistResult + Send + 'static,
+    mut routine: impl FnMut() -> RoutineResult,
+) {
+    let mut antagonist = Some(antagonist);
+    let guard = Contention::new().start(|_| {
+        antagonist
+            .take()
+            .expect("There should be only one antagonist")
+    });
+    c.bench_function(id, |b| b.iter(&mut routine));
+    guard.stop();
+}
+
+/// Benchmark a routine with Criterion under various numbers of antagonists
+///
+/// This produces a Criterion benchmark group called `group_name`, within which
+/// one benchmark is run for each antagonist thread count in `counts`, using the
+/// count as a benchmark parameter. The antagonist factory is called with the
+/// index of each antagonist thread, as in `Contention::run()`.
+///
+/// # Panics
+///
+/// This function will propagate panics from the antagonist factory, the
+/// antagonists and the routine.
+///
+pub fn bench_contention_sweep<Antagonist, AntagonistResult, RoutineResult>(
+    c: &mut Criterion,
+    group_name: &str,
+    counts: &[usize],
+    mut antagonist_factory: impl FnMut(usize) -> Antagonist,
+    mut routine: impl FnMut() -> RoutineResult,
+) where
+    Antagonist: FnMut() -> AntagonistResult + Send + 'static,
+{
+    let mut group: BenchmarkGroup<'_, WallTime> = c.benchmark_group(group_name);
+    for &count in counts {
+        let guard = Contention::new()
+            .antagonists(count)
+            .start(&mut antagonist_factory);
+        group.bench_function(BenchmarkId::from_parameter(count), |b| b.iter(&mut routine));
+        guard.stop();
+    }
+    group.finish();
+}
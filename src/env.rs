@@ -0,0 +1,295 @@
+//! Detection of instrumented execution environments
+//!
+//! Some tests must behave differently when the code is not running natively,
+//! but under an interpreter like Miri, a dynamic instrumentation framework like
+//! valgrind, with a sanitizer, or with a debugger attached. For example,
+//! timing-based assertions are meaningless in these environments, and stress
+//! tests may need to be scaled down or skipped. This module tells these
+//! environments apart.
+//!
+//! ```
+//! if !testbench::env::timing_reliable() {
+//!     println!("Skipping timing-based test in an instrumented environment");
+//!     return;
+//! }
+//! ```
+
+#[cfg(all(target_os = "linux", not(miri)))]
+use std::{env, ffi::OsString};
+
+/// Truth that the code is interpreted by Miri
+pub fn is_miri() -> bool {
+    cfg!(miri)
+}
+
+/// Truth that the code runs under valgrind
+///
+/// On x86_64 and aarch64, this uses valgrind's `RUNNING_ON_VALGRIND` client
+/// request, which is a no-op instruction sequence when running natively. On
+/// other Linux targets, this checks whether valgrind's preloaded libraries are
+/// in `LD_PRELOAD`. On other targets, this is always false.
+///
+pub fn is_valgrind() -> bool {
+    valgrind::running_on_valgrind()
+}
+
+/// Set of sanitizers that are enabled
+///
+/// Sanitizers are primarily detected at build time, based on the sanitizers
+/// which cargo reports to have been enabled with `-Zsanitizer`. On Linux,
+/// sanitizer runtimes which were injected at run time via `LD_PRELOAD` are
+/// also detected, as is the case when sanitized C libraries are used.
+///
+#[cfg_attr(any(not(target_os = "linux"), miri), allow(unused_mut))]
+pub fn sanitizers() -> SanitizerSet {
+    let mut set = SanitizerSet {
+        address: cfg!(testbench_sanitize = "address"),
+        hwaddress: cfg!(testbench_sanitize = "hwaddress"),
+        leak: cfg!(testbench_sanitize = "leak"),
+        memory: cfg!(testbench_sanitize = "memory"),
+        thread: cfg!(testbench_sanitize = "thread"),
+        other: cfg!(testbench_sanitize = "other"),
+    };
+    #[cfg(all(target_os = "linux", not(miri)))]
+    {
+        let preloaded = |runtime: &str| preloaded(env::var_os("LD_PRELOAD"), runtime);
+        set.address |= preloaded("libasan");
+        set.hwaddress |= preloaded("libhwasan");
+        set.leak |= preloaded("liblsan");
+        set.thread |= preloaded("libtsan");
+    }
+    set
+}
+
+/// Truth that a debugger or other tracer (e.g. strace) is attached
+///
+/// This is currently only detected on Linux, via `/proc/self/status`.
+///
+pub fn is_debugged() -> bool {
+    #[cfg(all(target_os = "linux", not(miri)))]
+    {
+        std::fs::read_to_string("/proc/self/status")
+            .map(|status| tracer_attached(&status))
+            .unwrap_or(false)
+    }
+    #[cfg(any(not(target_os = "linux"), miri))]
+    {
+        false
+    }
+}
+
+/// Truth that wall-clock measurements are meaningful in this environment
+///
+/// This is false under Miri and valgrind, when a sanitizer is enabled, and
+/// when a debugger is attached, as all of these slow down the code by
+/// different amounts depending on what it does, so timings do not reflect
+/// those of native execution.
+///
+/// Debug builds are not considered unreliable, as their timings are still
+/// representative of the relative cost of unoptimized operations.
+///
+pub fn timing_reliable() -> bool {
+    !(is_miri() || is_valgrind() || !sanitizers().is_empty() || is_debugged())
+}
+
+/// Set of sanitizers, as returned by `sanitizers()`
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct SanitizerSet {
+    /// AddressSanitizer
+    pub address: bool,
+
+    /// Hardware-assisted AddressSanitizer
+    pub hwaddress: bool,
+
+    /// LeakSanitizer
+    pub leak: bool,
+
+    /// MemorySanitizer
+    pub memory: bool,
+
+    /// ThreadSanitizer
+    pub thread: bool,
+
+    /// Another sanitizer, such as control flow integrity checking
+    pub other: bool,
+}
+//
+impl SanitizerSet {
+    /// Truth that no sanitizer is enabled
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Truth that a library whose file name starts with `runtime` is preloaded
+///
+/// `LD_PRELOAD` is a list of paths separated by spaces or colons.
+///
+#[cfg(all(target_os = "linux", not(miri)))]
+fn preloaded(ld_preload: Option<OsString>, runtime: &str) -> bool {
+    let ld_preload = match ld_preload {
+        Some(ld_preload) => ld_preload,
+        None => return false,
+    };
+    ld_preload
+        .to_string_lossy()
+        .split(&[' ', ':'][..])
+        .filter_map(|path| path.rsplit('/').next())
+        .any(|file_name| file_name.starts_with(runtime))
+}
+
+/// Truth that a `/proc/self/status` file reports an attached tracer
+#[cfg(all(target_os = "linux", not(miri)))]
+fn tracer_attached(status: &str) -> bool {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("TracerPid:"))
+        .and_then(|pid| pid.trim().parse::<u32>().ok())
+        .map_or(false, |pid| pid != 0)
+}
+
+/// x86_64 valgrind detection
+#[cfg(all(target_arch = "x86_64", not(miri)))]
+mod valgrind {
+    use std::arch::asm;
+
+    /// Code of the `RUNNING_ON_VALGRIND` client request
+    const RUNNING_ON_VALGRIND: usize = 0x1001;
+
+    pub(super) fn running_on_valgrind() -> bool {
+        let request = [RUNNING_ON_VALGRIND, 0, 0, 0, 0, 0];
+        let result: usize;
+        // Safe because the rotations of rdi add up to 128 bits, and swapping
+        // rbx with itself does nothing, so this sequence has no effect unless
+        // valgrind recognizes it and writes its answer into rdx
+        unsafe {
+            asm!(
+                "rol rdi, 3",
+                "rol rdi, 13",
+                "rol rdi, 61",
+                "rol rdi, 51",
+                "xchg rbx, rbx",
+                in("rax") request.as_ptr(),
+                inout("rdx") 0usize => result,
+                out("rdi") _,
+                options(nostack),
+            );
+        }
+        result != 0
+    }
+}
+
+/// aarch64 valgrind detection
+#[cfg(all(target_arch = "aarch64", not(miri)))]
+mod valgrind {
+    use std::arch::asm;
+
+    /// Code of the `RUNNING_ON_VALGRIND` client request
+    const RUNNING_ON_VALGRIND: usize = 0x1001;
+
+    pub(super) fn running_on_valgrind() -> bool {
+        let request = [RUNNING_ON_VALGRIND, 0, 0, 0, 0, 0];
+        let result: usize;
+        // Safe because the rotations of x12 add up to 128 bits, and or-ing x10
+        // with itself does nothing, so this sequence has no effect unless
+        // valgrind recognizes it and writes its answer into x3
+        unsafe {
+            asm!(
+                "ror x12, x12, #3",
+                "ror x12, x12, #13",
+                "ror x12, x12, #51",
+                "ror x12, x12, #61",
+                "orr x10, x10, x10",
+                in("x4") request.as_ptr(),
+                inout("x3") 0usize => result,
+                out("x12") _,
+                options(nostack),
+            );
+        }
+        result != 0
+    }
+}
+
+/// Heuristic valgrind detection on other Linux targets
+#[cfg(all(
+    target_os = "linux",
+    not(any(target_arch = "x86_64", target_arch = "aarch64", miri))
+))]
+mod valgrind {
+    pub(super) fn running_on_valgrind() -> bool {
+        super::preloaded(std::env::var_os("LD_PRELOAD"), "vgpreload")
+    }
+}
+
+/// Valgrind is not detected on other targets, nor under Miri
+#[cfg(any(
+    miri,
+    not(any(target_os = "linux", target_arch = "x86_64", target_arch = "aarch64"))
+))]
+mod valgrind {
+    pub(super) fn running_on_valgrind() -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Native test runs should not be flagged as instrumented. Sanitized runs
+    // are excluded, as are runs under a debugger.
+    #[test]
+    #[cfg(not(any(miri, testbench_sanitize)))]
+    fn native() {
+        assert!(!is_miri());
+        assert!(!is_valgrind());
+        assert!(sanitizers().is_empty());
+        if !is_debugged() {
+            assert!(timing_reliable());
+        }
+    }
+
+    // Miri should be detected, and make timings unreliable
+    #[test]
+    #[cfg(miri)]
+    fn miri() {
+        assert!(is_miri());
+        assert!(!is_valgrind());
+        assert!(!is_debugged());
+        assert!(!timing_reliable());
+    }
+
+    // Sanitized builds should be detected, and make timings unreliable
+    #[test]
+    #[cfg(testbench_sanitize)]
+    fn sanitized() {
+        assert!(!sanitizers().is_empty());
+        assert!(!timing_reliable());
+    }
+
+    // Sanitizer runtimes should be recognized in LD_PRELOAD
+    #[test]
+    #[cfg(all(target_os = "linux", not(miri)))]
+    fn ld_preload() {
+        let check = |ld_preload: &str, runtime| preloaded(Some(ld_preload.into()), runtime);
+        assert!(check("/usr/lib/libasan.so.8", "libasan"));
+        assert!(check("libfoo.so:/lib/libtsan.so.2 libbar.so", "libtsan"));
+        assert!(check(
+            "/usr/libexec/valgrind/vgpreload_core.so",
+            "vgpreload"
+        ));
+        assert!(!check("", "libasan"));
+        assert!(!check("/opt/libasan/libfoo.so", "libasan"));
+        assert!(!preloaded(None, "libasan"));
+    }
+
+    // Attached tracers should be recognized in /proc/self/status
+    #[test]
+    #[cfg(all(target_os = "linux", not(miri)))]
+    fn tracer_pid() {
+        assert!(!tracer_attached("Name:\ttest\nTracerPid:\t0\nUid:\t0\n"));
+        assert!(tracer_attached("Name:\ttest\nTracerPid:\t1234\nUid:\t0\n"));
+        assert!(!tracer_attached("Name:\ttest\n"));
+    }
+}
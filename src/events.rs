@@ -0,0 +1,287 @@
+//! Low-overhead recording of timestamped events from many threads
+//!
+//! Logging what concurrent threads do is a good way to understand failures,
+//! but logging through shared data structures synchronizes threads and hides
+//! the interleavings that one wanted to observe. An `EventLog` avoids this by
+//! giving each thread its own preallocated buffer, which it can append to
+//! without synchronizing with other threads. Buffers are merged into a single
+//! timeline once all threads are done.
+//!
+//! ```
+//! use testbench::events::EventLog;
+//!
+//! let mut log = EventLog::new(1000);
+//! let (mut writer1, mut writer2) = (log.register(), log.register());
+//! testbench::concurrent_test_2(
+//!     move || writer1.record("hello"),
+//!     move || writer2.record("world"),
+//! );
+//! let events = log.drain_merged();
+//! assert_eq!(events.len(), 2);
+//! ```
+
+use crate::timing;
+use std::{
+    mem,
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// Log of events recorded by multiple threads
+///
+/// Each thread records events through its own `EventWriter`, obtained with
+/// `register()`. Once all writers are dropped, the events can be collected
+/// using `drain_merged()`. Since writers borrow the log, this is enforced by
+/// the borrow checker.
+///
+#[derive(Debug)]
+pub struct EventLog<T> {
+    /// Time origin of event timestamps
+    start: Instant,
+
+    /// Maximal number of events per writer
+    capacity: usize,
+
+    /// Buffers of writers, which are filled in when writers are dropped
+    buffers: Mutex<Vec<ThreadBuffer<T>>>,
+}
+//
+impl<T> EventLog<T> {
+    /// Set up an event log where each writer can record `capacity` events
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            start: timing::now(),
+            capacity,
+            buffers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Maximal number of events that each writer can record
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Register a new writer, with its own preallocated buffer
+    ///
+    /// Writers are numbered in registration order, starting from 0. This
+    /// number is used to tell which writer recorded which event.
+    ///
+    pub fn register(&self) -> EventWriter<'_, T> {
+        let mut buffers = self.lock();
+        buffers.push(ThreadBuffer::default());
+        EventWriter {
+            log: self,
+            thread: buffers.len() - 1,
+            buffer: ThreadBuffer {
+                events: Vec::with_capacity(self.capacity),
+                overflow: 0,
+            },
+        }
+    }
+
+    /// Number of events which were dropped because a writer's buffer was full,
+    /// among writers which are done recording
+    pub fn overflow(&self) -> u64 {
+        self.lock().iter().map(|buffer| buffer.overflow).sum()
+    }
+
+    /// Collect the events from all writers into a single timeline
+    ///
+    /// Events are sorted by timestamp. Events with identical timestamps are
+    /// sorted by writer, then in recording order. The buffers and overflow
+    /// counters of writers are reset.
+    ///
+    pub fn drain_merged(&mut self) -> Vec<Event<T>> {
+        let buffers = self
+            .buffers
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut events = buffers
+            .iter_mut()
+            .enumerate()
+            .flat_map(|(thread, buffer)| {
+                buffer.overflow = 0;
+                buffer
+                    .events
+                    .drain(..)
+                    .map(move |(timestamp, value)| Event {
+                        thread,
+                        timestamp,
+                        value,
+                    })
+            })
+            .collect::<Vec<_>>();
+        // Stable sort, so that the order of events with identical timestamps
+        // is that in which they were collected
+        events.sort_by_key(|event| event.timestamp);
+        events
+    }
+
+    /// Access the buffers, ignoring poisoning as they are always consistent
+    fn lock(&self) -> MutexGuard<'_, Vec<ThreadBuffer<T>>> {
+        self.buffers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Handle which a thread uses to record events into an `EventLog`
+///
+/// Recorded events are only transferred to the log when the writer is
+/// dropped.
+///
+#[derive(Debug)]
+pub struct EventWriter<'log, T> {
+    /// Log that events are recorded into
+    log: &'log EventLog<T>,
+
+    /// Index of this writer
+    thread: usize,
+
+    /// Events recorded so far
+    buffer: ThreadBuffer<T>,
+}
+//
+impl<T> EventWriter<'_, T> {
+    /// Index of this writer, in registration order
+    pub fn thread(&self) -> usize {
+        self.thread
+    }
+
+    /// Record an event, timestamped with the current time
+    ///
+    /// This does not synchronize with other threads or allocate memory, so
+    /// its overhead is dominated by the cost of reading the clock. If the
+    /// buffer is full, the event is dropped and counted as an overflow.
+    ///
+    #[inline]
+    pub fn record(&mut self, value: T) {
+        if self.buffer.events.len() < self.log.capacity {
+            let timestamp = timing::now().saturating_duration_since(self.log.start);
+            self.buffer.events.push((timestamp, value));
+        } else {
+            self.buffer.overflow += 1;
+        }
+    }
+
+    /// Number of events which were dropped because the buffer was full
+    pub fn overflow(&self) -> u64 {
+        self.buffer.overflow
+    }
+}
+//
+impl<T> Drop for EventWriter<'_, T> {
+    fn drop(&mut self) {
+        self.log.lock()[self.thread] = mem::take(&mut self.buffer);
+    }
+}
+
+/// Events recorded by one writer
+#[derive(Debug)]
+struct ThreadBuffer<T> {
+    /// Recorded events and their timestamps
+    events: Vec<(Duration, T)>,
+
+    /// Number of events which were dropped because the buffer was full
+    overflow: u64,
+}
+//
+impl<T> Default for ThreadBuffer<T> {
+    fn default() -> Self {
+        Self {
+            events: Vec::new(),
+            overflow: 0,
+        }
+    }
+}
+
+/// Event from an `EventLog`
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct Event<T> {
+    /// Index of the writer which recorded the event
+    pub thread: usize,
+
+    /// Time at which the event was recorded, relative to the log's creation
+    pub timestamp: Duration,
+
+    /// Recorded value
+    pub value: T,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn auto_traits() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<EventLog<u32>>();
+        assert_send_sync::<EventWriter<'_, u32>>();
+    }
+
+    #[test]
+    fn overflow() {
+        let mut log = EventLog::new(10);
+        {
+            let mut writer = log.register();
+            for i in 0..15 {
+                writer.record(i);
+            }
+            assert_eq!(writer.overflow(), 5);
+        }
+        assert_eq!(log.overflow(), 5);
+        let values = log
+            .drain_merged()
+            .into_iter()
+            .map(|event| event.value)
+            .collect::<Vec<_>>();
+        assert_eq!(values, (0..10).collect::<Vec<_>>());
+        assert_eq!(log.overflow(), 0);
+        assert!(log.drain_merged().is_empty());
+    }
+
+    #[test]
+    fn unused_writer() {
+        let mut log = EventLog::<()>::new(10);
+        let writer = log.register();
+        assert_eq!(writer.thread(), 0);
+        drop(writer);
+        assert!(log.drain_merged().is_empty());
+    }
+
+    /// Record events from many threads, checking that none is lost
+    ///
+    /// Events are tagged with the writer index and a sequence number, so that
+    /// it can be checked that each writer's events are merged in order.
+    ///
+    #[test]
+    fn concurrent_recording() {
+        const THREADS: usize = 8;
+        let events_count = crate::scale_iters(10_000);
+        let mut log = EventLog::new(events_count);
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                let mut writer = log.register();
+                s.spawn(move || {
+                    for seq in 0..events_count {
+                        writer.record((writer.thread(), seq));
+                    }
+                });
+            }
+        });
+        assert_eq!(log.overflow(), 0);
+        let events = log.drain_merged();
+        assert_eq!(events.len(), THREADS * events_count);
+        let mut next_seq = [0; THREADS];
+        for (event, next) in events.iter().zip(&events[1..]) {
+            assert!(event.timestamp <= next.timestamp);
+        }
+        for event in events {
+            let (thread, seq) = event.value;
+            assert_eq!(event.thread, thread);
+            assert_eq!(seq, next_seq[thread]);
+            next_seq[thread] += 1;
+        }
+        assert_eq!(next_seq, [events_count; THREADS]);
+    }
+}
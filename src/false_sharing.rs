@@ -0,0 +1,272 @@
+//! Measurement of the cost of false sharing
+//!
+//! When two threads modify different variables which happen to share a cache
+//! line, the cache line keeps bouncing between their CPU cores, as if they
+//! were modifying the same variable. This module quantifies this effect on the
+//! host machine, by having two threads increment counters that either share a
+//! cache line (`AdjacentPair`) or live in different cache lines
+//! (`PaddedPair`), and comparing how long an increment takes in each case.
+//!
+//! ```
+//! let report = testbench::false_sharing::measure(10_000);
+//! println!("{}", report);
+//! assert!(report.ratio.is_finite());
+//! ```
+
+use crate::{
+    affinity,
+    contention::Contention,
+    timing,
+    util::{CachePadded, CACHE_LINE_SIZE},
+};
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Pair of values which share a cache line
+///
+/// The pair is aligned to 128 bytes, so both values are guaranteed to share a
+/// cache line as long as the pair is no larger than a cache line.
+///
+#[derive(Debug, Default)]
+#[repr(C, align(128))]
+pub struct AdjacentPair<T> {
+    /// First value, used by the benchmark thread
+    pub first: T,
+
+    /// Second value, used by the antagonist thread
+    pub second: T,
+}
+//
+impl<T> AdjacentPair<T> {
+    /// Put two values next to each other
+    pub const fn new(first: T, second: T) -> Self {
+        Self { first, second }
+    }
+}
+
+/// Pair of values which live in different cache lines
+#[derive(Debug, Default)]
+pub struct PaddedPair<T> {
+    /// First value, used by the benchmark thread
+    pub first: CachePadded<T>,
+
+    /// Second value, used by the antagonist thread
+    pub second: CachePadded<T>,
+}
+//
+impl<T> PaddedPair<T> {
+    /// Put two values in different cache lines
+    pub const fn new(first: T, second: T) -> Self {
+        Self {
+            first: CachePadded::new(first),
+            second: CachePadded::new(second),
+        }
+    }
+}
+
+/// Measure the cost of false sharing between two atomic counters
+///
+/// This is a shorthand for `measure_with()` where the operation is a relaxed
+/// `fetch_add` on an `AtomicU64`.
+///
+pub fn measure(iterations: u64) -> FalseSharingReport {
+    measure_with::<AtomicU64>(iterations, |counter| {
+        counter.fetch_add(1, Ordering::Relaxed);
+    })
+}
+
+/// Measure the cost of false sharing for an operation on a custom type
+///
+/// For each layout, a default-constructed pair of values is built. The
+/// benchmark then runs `operation` on the first value `iterations` times,
+/// while an antagonist from the `contention` module runs it in a loop on the
+/// second value.
+///
+/// Where thread pinning is possible, the benchmark and antagonist threads are
+/// pinned to the same two CPUs for both layouts, which are picked on
+/// different physical cores if possible. Otherwise, threads are not pinned,
+/// and the results are less reproducible.
+///
+/// # Panics
+///
+/// This function panics if `iterations` is zero, and propagates panics from
+/// `operation`.
+///
+pub fn measure_with<T: Default + Sync>(
+    iterations: u64,
+    operation: impl Fn(&T) + Sync,
+) -> FalseSharingReport {
+    assert!(iterations > 0, "At least one iteration is needed");
+    let cpus = pick_cpus();
+    let mut contention = Contention::new().antagonists(1);
+    if let Some((benchmark_cpu, antagonist_cpu)) = cpus {
+        contention = contention
+            .pin_benchmark_to(benchmark_cpu)
+            .pin_antagonists_to(&[antagonist_cpu]);
+    }
+    let operation = &operation;
+    let time = |first: &T, second: &T| {
+        let (duration, _stats) = contention.run(
+            |_| move || operation(second),
+            || {
+                let start = timing::now();
+                for _ in 0..iterations {
+                    operation(first);
+                }
+                start.elapsed()
+            },
+        );
+        duration
+    };
+
+    let adjacent_pair = AdjacentPair::<T>::default();
+    let adjacent = time(&adjacent_pair.first, &adjacent_pair.second);
+    let padded_pair = PaddedPair::<T>::default();
+    let padded = time(&padded_pair.first, &padded_pair.second);
+
+    let per_iteration =
+        |total: Duration| Duration::from_secs_f64(total.as_secs_f64() / iterations as f64);
+    FalseSharingReport {
+        iterations,
+        adjacent: per_iteration(adjacent),
+        padded: per_iteration(padded),
+        ratio: adjacent.as_secs_f64() / padded.as_secs_f64(),
+        cache_line_size: cache_line_size(),
+        padding: CACHE_LINE_SIZE,
+        cpus,
+    }
+}
+
+/// Results of a false sharing measurement
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct FalseSharingReport {
+    /// Number of operations that were timed for each layout
+    pub iterations: u64,
+
+    /// Average duration of an operation when the values share a cache line
+    pub adjacent: Duration,
+
+    /// Average duration of an operation when the values are padded
+    pub padded: Duration,
+
+    /// Slowdown caused by false sharing
+    ///
+    /// This is the ratio of the total duration of the operations with
+    /// adjacent values to that with padded values, so it is more precise than
+    /// the ratio of the average durations, which are rounded to nanoseconds.
+    ///
+    pub ratio: f64,
+
+    /// Size of a cache line, as reported by the operating system, if known
+    ///
+    /// This is currently only detected on Linux.
+    ///
+    pub cache_line_size: Option<usize>,
+
+    /// Alignment of padded values, in bytes
+    ///
+    /// This is `util::CACHE_LINE_SIZE`, which can be larger than the actual
+    /// cache line size, because some CPUs prefetch cache lines by pairs, so
+    /// that neighboring lines still interfere.
+    ///
+    pub padding: usize,
+
+    /// CPUs to which the benchmark and antagonist threads were pinned, if any
+    pub cpus: Option<(usize, usize)>,
+}
+//
+impl fmt::Display for FalseSharingReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "False sharing slows operations down by {:.2}x ({:?} vs {:?} per operation, ",
+            self.ratio, self.adjacent, self.padded
+        )?;
+        if let Some(cache_line_size) = self.cache_line_size {
+            write!(f, "{}-byte cache lines, ", cache_line_size)?;
+        }
+        write!(f, "{}-byte padding", self.padding)?;
+        if let Some((benchmark_cpu, antagonist_cpu)) = self.cpus {
+            write!(f, ", CPUs {} and {}", benchmark_cpu, antagonist_cpu)?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Pick two CPUs for the benchmark and antagonist threads, if pinning works
+///
+/// CPUs from different physical cores are preferred, since hyperthreads share
+/// their L1 cache and thus do not suffer from false sharing as much.
+///
+fn pick_cpus() -> Option<(usize, usize)> {
+    let cpus = match affinity::physical_cores() {
+        Ok(cores) if cores.len() >= 2 => cores
+            .iter()
+            .filter_map(|core| core.logical_cpus.first().copied())
+            .collect(),
+        _ => affinity::logical_cpus(),
+    };
+    match cpus[..] {
+        [first, second, ..]
+            if affinity::check_cpu(first).is_ok() && affinity::check_cpu(second).is_ok() =>
+        {
+            Some((first, second))
+        }
+        _ => None,
+    }
+}
+
+/// Size of a cache line, as reported by the operating system
+///
+/// This is not queried under Miri, whose isolation forbids file access.
+///
+#[cfg(all(target_os = "linux", not(miri)))]
+fn cache_line_size() -> Option<usize> {
+    std::fs::read_to_string("/sys/devices/system/cpu/cpu0/cache/index0/coherency_line_size")
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Size of a cache line, as reported by the operating system
+#[cfg(any(not(target_os = "linux"), miri))]
+fn cache_line_size() -> Option<usize> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layouts() {
+        let adjacent = AdjacentPair::new(AtomicU64::new(0), AtomicU64::new(0));
+        let first: *const AtomicU64 = &adjacent.first;
+        let second: *const AtomicU64 = &adjacent.second;
+        assert_eq!(first as usize / 64, second as usize / 64);
+
+        let padded = PaddedPair::new(AtomicU64::new(0), AtomicU64::new(0));
+        let first: *const AtomicU64 = &*padded.first;
+        let second: *const AtomicU64 = &*padded.second;
+        assert!(second as usize - first as usize >= CACHE_LINE_SIZE);
+    }
+
+    #[test]
+    fn plausible_report() {
+        let iterations = crate::scale_iters(100_000) as u64;
+        let report = measure(iterations);
+        assert_eq!(report.iterations, iterations);
+        assert!(report.adjacent > Duration::ZERO);
+        assert!(report.padded > Duration::ZERO);
+        assert!(report.ratio.is_finite() && report.ratio > 0.0);
+        assert_eq!(report.padding, CACHE_LINE_SIZE);
+        if let Some(cache_line_size) = report.cache_line_size {
+            assert!(cache_line_size.is_power_of_two());
+        }
+    }
+}
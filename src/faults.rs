@@ -0,0 +1,453 @@
+//! Seeded fault injection, to make rare thread interleavings more likely
+//!
+//! Some concurrency bugs only manifest when a thread gets delayed at a very
+//! specific point of its execution. Randomly perturbing thread scheduling at
+//! points of interest makes such interleavings more likely. A `FaultPlan`
+//! does so in a reproducible way: its decisions are derived from a seed, and
+//! it records the faults that it injected so that they can be reported when a
+//! test fails.
+//!
+//! ```
+//! use std::time::Duration;
+//! use testbench::faults::FaultPlan;
+//!
+//! let plan = FaultPlan::new(42)
+//!     .delay_probability(0.01)
+//!     .max_delay(Duration::from_micros(50));
+//! testbench::concurrent_test_2(
+//!     || {
+//!         plan.point("writer: before store");
+//!         // ...
+//!     },
+//!     || {
+//!         plan.point("reader: before load");
+//!         // ...
+//!     },
+//! );
+//! println!("{}", plan.trace());
+//! ```
+//!
+//! The fault injected at a given point only depends on the seed, the label of
+//! the point, and how many points were reached before it. Single-threaded
+//! code therefore gets the same faults for a given seed. In multi-threaded
+//! code, the order in which threads reach injection points can vary, so a
+//! failing seed only replays exactly if the threads reach the same points in
+//! the same order, which the injected faults tend to encourage.
+
+use crate::rng::TestRng;
+use std::{
+    fmt, hint,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Seeded policy for injecting faults, and record of the injected faults
+///
+/// At each injection point, marked by calling `point()`, the plan randomly
+/// decides to either delay the calling thread, make it yield to the OS
+/// scheduler, make it spin for a while, or do nothing. The probability of
+/// each fault is configured using the builder methods. Probabilities are
+/// considered in this order, so if they sum above 1, the last ones are
+/// effectively reduced.
+///
+#[derive(Debug)]
+pub struct FaultPlan {
+    /// Seed from which all decisions are derived
+    seed: u64,
+
+    /// Probability of delaying the calling thread
+    delay_probability: f64,
+
+    /// Maximal delay
+    max_delay: Duration,
+
+    /// Probability of yielding to the OS scheduler
+    yield_probability: f64,
+
+    /// Probability of spinning
+    spin_probability: f64,
+
+    /// Maximal number of spin iterations
+    max_spins: u32,
+
+    /// Number of injection points which were reached so far
+    points: AtomicU64,
+
+    /// Faults which were injected so far
+    events: Mutex<Vec<FaultEvent>>,
+}
+//
+impl FaultPlan {
+    /// Set up a fault injection plan with a certain seed
+    ///
+    /// Initially, no fault is injected. Use the builder methods to configure
+    /// fault probabilities.
+    ///
+    /// A random seed can be drawn with `rng::TestRng::from_entropy()`.
+    ///
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            delay_probability: 0.0,
+            max_delay: Duration::from_micros(10),
+            yield_probability: 0.0,
+            spin_probability: 0.0,
+            max_spins: 1000,
+            points: AtomicU64::new(0),
+            events: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Set the probability of delaying the calling thread (default: 0)
+    ///
+    /// Delays are uniformly distributed between zero and `max_delay()`. Short
+    /// delays are implemented by busy-waiting, and longer ones by sleeping,
+    /// which may take longer than requested.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `probability` is not between 0 and 1.
+    ///
+    pub fn delay_probability(mut self, probability: f64) -> Self {
+        self.delay_probability = check_probability(probability);
+        self
+    }
+
+    /// Set the maximal delay (default: 10µs)
+    pub fn max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Set the probability of yielding to the OS scheduler (default: 0)
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `probability` is not between 0 and 1.
+    ///
+    pub fn yield_probability(mut self, probability: f64) -> Self {
+        self.yield_probability = check_probability(probability);
+        self
+    }
+
+    /// Set the probability of spinning for a while (default: 0)
+    ///
+    /// The number of spin iterations is uniformly distributed between 1 and
+    /// `max_spins()`. Unlike delays and yields, spinning keeps the thread
+    /// running, which perturbs timings without involving the OS scheduler.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `probability` is not between 0 and 1.
+    ///
+    pub fn spin_probability(mut self, probability: f64) -> Self {
+        self.spin_probability = check_probability(probability);
+        self
+    }
+
+    /// Set the maximal number of spin iterations (default: 1000)
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `max_spins` is zero.
+    ///
+    pub fn max_spins(mut self, max_spins: u32) -> Self {
+        assert!(max_spins > 0, "Spinning requires at least one iteration");
+        self.max_spins = max_spins;
+        self
+    }
+
+    /// Seed from which all decisions are derived
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Injection point, where a fault may be injected
+    ///
+    /// The label identifies the injection point in the trace. It also affects
+    /// which fault is injected, so that different points get different
+    /// faults. The injected fault is returned.
+    ///
+    pub fn point(&self, label: &str) -> Fault {
+        let index = self.points.fetch_add(1, Ordering::Relaxed);
+        let fault = self.decide(label, index);
+        if fault != Fault::None {
+            self.events
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(FaultEvent {
+                    index,
+                    label: label.to_owned(),
+                    fault,
+                });
+        }
+        fault.inject();
+        fault
+    }
+
+    /// Record of the faults that were injected so far
+    ///
+    /// Events are sorted by injection point index.
+    ///
+    pub fn trace(&self) -> FaultTrace {
+        let mut events = self
+            .events
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        events.sort_by_key(|event| event.index);
+        FaultTrace {
+            seed: self.seed,
+            points: self.points.load(Ordering::Relaxed),
+            events,
+        }
+    }
+
+    /// Decide which fault should be injected at some point
+    fn decide(&self, label: &str, index: u64) -> Fault {
+        let key = TestRng::from_seed(self.seed ^ fnv1a(label)).next_u64();
+        let mut rng = TestRng::from_seed(key ^ TestRng::from_seed(index).next_u64());
+        let choice = rng.next_f64();
+        let mut threshold = self.delay_probability;
+        if choice < threshold {
+            let max_nanos = self.max_delay.as_nanos().min(u64::MAX.into()) as u64;
+            let nanos = rng.next_bounded(max_nanos.saturating_add(1));
+            return Fault::Delay(Duration::from_nanos(nanos));
+        }
+        threshold += self.yield_probability;
+        if choice < threshold {
+            return Fault::Yield;
+        }
+        threshold += self.spin_probability;
+        if choice < threshold {
+            return Fault::Spin(rng.next_bounded(u64::from(self.max_spins)) as u32 + 1);
+        }
+        Fault::None
+    }
+}
+
+/// Check that a probability is between 0 and 1
+fn check_probability(probability: f64) -> f64 {
+    assert!(
+        (0.0..=1.0).contains(&probability),
+        "Probabilities must be between 0 and 1"
+    );
+    probability
+}
+
+/// Fault injected at an injection point
+#[allow(variant_size_differences)] // Small enough to be passed around by value
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    /// No fault
+    None,
+
+    /// Sleep for some duration
+    Delay(Duration),
+
+    /// Yield to the OS scheduler
+    Yield,
+
+    /// Spin for some number of iterations
+    Spin(u32),
+}
+//
+impl Fault {
+    /// Inject this fault into the current thread
+    fn inject(self) {
+        match self {
+            Self::None => {}
+            Self::Delay(duration) => {
+                // Sleeping has coarse granularity, so short delays spin
+                if duration < Duration::from_micros(100) {
+                    let start = Instant::now();
+                    while start.elapsed() < duration {
+                        hint::spin_loop();
+                    }
+                } else {
+                    thread::sleep(duration);
+                }
+            }
+            Self::Yield => thread::yield_now(),
+            Self::Spin(iterations) => {
+                for _ in 0..iterations {
+                    hint::spin_loop();
+                }
+            }
+        }
+    }
+}
+//
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => write!(f, "no fault"),
+            Self::Delay(duration) => write!(f, "delay by {:?}", duration),
+            Self::Yield => write!(f, "yield"),
+            Self::Spin(iterations) => write!(f, "spin {} times", iterations),
+        }
+    }
+}
+
+/// Fault which was injected at some point
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct FaultEvent {
+    /// Index of the injection point, in the order where points were reached
+    pub index: u64,
+
+    /// Label of the injection point
+    pub label: String,
+
+    /// Fault which was injected
+    pub fault: Fault,
+}
+//
+impl fmt::Display for FaultEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{} {}: {}", self.index, self.label, self.fault)
+    }
+}
+
+/// Record of the faults injected by a `FaultPlan`
+///
+/// This is meant to be printed when a test fails, so that the failure can be
+/// understood and replayed with the same seed.
+///
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct FaultTrace {
+    /// Seed of the fault injection plan
+    pub seed: u64,
+
+    /// Number of injection points which were reached
+    pub points: u64,
+
+    /// Faults which were injected, sorted by injection point index
+    pub events: Vec<FaultEvent>,
+}
+//
+impl fmt::Display for FaultTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Fault plan with seed {} injected {} fault(s) at {} point(s)",
+            self.seed,
+            self.events.len(),
+            self.points
+        )?;
+        for event in &self.events {
+            write!(f, "\n- {}", event)?;
+        }
+        Ok(())
+    }
+}
+
+/// FNV-1a hash, which unlike std's hashers is guaranteed to be stable
+fn fnv1a(label: &str) -> u64 {
+    label.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auto_traits() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<FaultPlan>();
+    }
+
+    /// Run a single-threaded sequence of injection points
+    fn run_points(plan: &FaultPlan) -> FaultTrace {
+        for i in 0..200 {
+            plan.point(if i % 3 == 0 { "push" } else { "pop" });
+        }
+        plan.trace()
+    }
+
+    /// Set up a plan which injects all kinds of (cheap) faults
+    fn plan(seed: u64) -> FaultPlan {
+        FaultPlan::new(seed)
+            .delay_probability(0.1)
+            .max_delay(Duration::from_nanos(100))
+            .yield_probability(0.1)
+            .spin_probability(0.1)
+            .max_spins(10)
+    }
+
+    #[test]
+    fn determinism() {
+        let trace = run_points(&plan(42));
+        assert_eq!(trace, run_points(&plan(42)));
+        assert_eq!(trace.seed, 42);
+        assert_eq!(trace.points, 200);
+        // About 30% of points should have injected faults, of all kinds
+        assert!((30..=90).contains(&trace.events.len()));
+        for expected in [Fault::Yield, Fault::Delay(Duration::ZERO), Fault::Spin(1)] {
+            assert!(trace
+                .events
+                .iter()
+                .any(|event| std::mem::discriminant(&event.fault)
+                    == std::mem::discriminant(&expected)));
+        }
+        for (event, next) in trace.events.iter().zip(&trace.events[1..]) {
+            assert!(event.index < next.index);
+        }
+        assert!(trace.to_string().starts_with(&format!(
+            "Fault plan with seed 42 injected {} fault(s) at 200 point(s)\n- #",
+            trace.events.len()
+        )));
+    }
+
+    #[test]
+    fn seed_dependence() {
+        let trace = run_points(&plan(42));
+        assert_ne!(trace.events, run_points(&plan(43)).events);
+        assert_ne!(trace.events, run_points(&plan(0)).events);
+    }
+
+    #[test]
+    fn no_fault() {
+        let plan = FaultPlan::new(42);
+        assert_eq!(plan.point("nothing"), Fault::None);
+        let trace = run_points(&plan);
+        assert_eq!(trace.points, 201);
+        assert!(trace.events.is_empty());
+    }
+
+    #[test]
+    fn certain_fault() {
+        let plan = FaultPlan::new(42).yield_probability(1.0);
+        assert!(run_points(&plan)
+            .events
+            .iter()
+            .all(|event| event.fault == Fault::Yield));
+    }
+
+    #[test]
+    fn concurrent_points() {
+        let plan = plan(42);
+        crate::concurrent_test_2(
+            || {
+                for _ in 0..100 {
+                    plan.point("left");
+                }
+            },
+            || {
+                for _ in 0..100 {
+                    plan.point("right");
+                }
+            },
+        );
+        let trace = plan.trace();
+        assert_eq!(trace.points, 200);
+        assert!(!trace.events.is_empty());
+    }
+}
@@ -0,0 +1,296 @@
+//! Latency distributions of repeated operations
+//!
+//! Mean throughput hides what contention often affects the most, namely the
+//! tail of the latency distribution. This module provides tools for recording
+//! the duration of individual operations and analyzing their distribution.
+
+use crate::{
+    contention::Contention,
+    timing::{self, timer_overhead},
+    util::CachePadded,
+};
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+/// Latency distribution of a repeated operation
+///
+/// This holds latency samples, sorted in increasing order, from which the
+/// estimated overhead of the timer used to measure them has been subtracted.
+/// Samples which are shorter than the timer overhead are clamped to zero.
+/// Percentiles are computed using the nearest-rank method.
+///
+/// For auditing purposes, the raw samples and the timer overhead that was
+/// subtracted from them are also available. Operations that are not much
+/// longer than the timer overhead are better measured in batches, using
+/// `timing::measure_batch()`.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct LatencyStats {
+    /// Latency samples minus the timer overhead, sorted in increasing order
+    samples: Vec<Duration>,
+
+    /// Raw latency samples, sorted in increasing order
+    raw_samples: Vec<Duration>,
+
+    /// Estimated overhead of the timer used to measure the samples
+    timer_overhead: Duration,
+}
+//
+impl LatencyStats {
+    /// Build latency statistics from raw samples and timer overhead estimate
+    pub fn new(mut raw_samples: Vec<Duration>, timer_overhead: Duration) -> Self {
+        raw_samples.sort_unstable();
+        // Subtracting the same value from all samples preserves their order
+        let samples = raw_samples
+            .iter()
+            .map(|sample| sample.saturating_sub(timer_overhead))
+            .collect();
+        Self {
+            samples,
+            raw_samples,
+            timer_overhead,
+        }
+    }
+
+    /// Latency samples minus the timer overhead, sorted in increasing order
+    pub fn samples(&self) -> &[Duration] {
+        &self.samples[..]
+    }
+
+    /// Raw latency samples, including the timer overhead, sorted in
+    /// increasing order
+    pub fn raw_samples(&self) -> &[Duration] {
+        &self.raw_samples[..]
+    }
+
+    /// Estimated timer overhead, which was subtracted from the samples
+    pub fn timer_overhead(&self) -> Duration {
+        self.timer_overhead
+    }
+
+    /// Number of latency samples
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Truth that there are no latency samples
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Minimal latency
+    ///
+    /// # Panics
+    ///
+    /// All latency accessors panic if there are no latency samples.
+    ///
+    pub fn min(&self) -> Duration {
+        self.quantile(0.0)
+    }
+
+    /// Median latency
+    pub fn median(&self) -> Duration {
+        self.quantile(0.5)
+    }
+
+    /// 99th percentile of latency
+    pub fn p99(&self) -> Duration {
+        self.quantile(0.99)
+    }
+
+    /// 99.9th percentile of latency
+    pub fn p999(&self) -> Duration {
+        self.quantile(0.999)
+    }
+
+    /// Maximal latency
+    pub fn max(&self) -> Duration {
+        self.quantile(1.0)
+    }
+
+    /// Latency quantile, for a quantile between 0.0 and 1.0
+    ///
+    /// This uses the nearest-rank method: the result is the smallest sample
+    /// such that a fraction `q` of the samples is smaller or equal to it.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if there are no samples or `q` is out of range.
+    ///
+    pub fn quantile(&self, q: f64) -> Duration {
+        assert!(!self.samples.is_empty(), "No latency sample available");
+        assert!((0.0..=1.0).contains(&q), "Quantile must be in [0, 1]");
+        let rank = (q * self.samples.len() as f64).ceil() as usize;
+        self.samples[rank.saturating_sub(1)]
+    }
+}
+
+/// Measure the latency of compare-and-swap on a contended memory location
+///
+/// An `AtomicUsize` is put on its own cache line, and a number of antagonist
+/// threads keep incrementing it with compare-and-swap loops. Meanwhile, the
+/// benchmark thread performs the given number of increments using the same
+/// technique, and the latency of each of its compare-and-swap attempts is
+/// recorded, separating successful attempts from failed ones.
+///
+/// This is a quick way to characterize the cost of contended atomic
+/// operations on a given machine, and an example of how the building blocks of
+/// this crate can be combined.
+///
+pub fn measure_cas_latency(contending_threads: usize, iterations: usize) -> CasLatency {
+    let target = CachePadded::new(AtomicUsize::new(0));
+    let antagonist_factory = |_| {
+        || {
+            let current = target.load(Ordering::Relaxed);
+            target
+                .compare_exchange(
+                    current,
+                    current.wrapping_add(1),
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+        }
+    };
+    let benchmark = || {
+        let timer_overhead = timer_overhead();
+        let mut successful = Vec::with_capacity(iterations);
+        let mut failed = Vec::with_capacity(iterations);
+        for _ in 0..iterations {
+            loop {
+                let current = target.load(Ordering::Relaxed);
+                let start = timing::now();
+                let result = target.compare_exchange(
+                    current,
+                    current.wrapping_add(1),
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                );
+                let elapsed = start.elapsed();
+                if result.is_ok() {
+                    successful.push(elapsed);
+                    break;
+                } else {
+                    failed.push(elapsed);
+                }
+            }
+        }
+        CasLatency {
+            successful: LatencyStats::new(successful, timer_overhead),
+            failed: LatencyStats::new(failed, timer_overhead),
+        }
+    };
+    Contention::new()
+        .antagonists(contending_threads)
+        .run(antagonist_factory, benchmark)
+        .0
+}
+
+/// Latency distributions of compare-and-swap attempts
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct CasLatency {
+    /// Latency of successful attempts, one per iteration
+    pub successful: LatencyStats,
+
+    /// Latency of failed attempts, which may be empty
+    pub failed: LatencyStats,
+}
+
+/// Record the latency of each of a number of runs of an operation
+///
+/// The sample buffer is allocated upfront, so that recording samples does not
+/// allocate memory while the operation is being measured.
+///
+pub(crate) fn record(iterations: usize, mut operation: impl FnMut()) -> LatencyStats {
+    let timer_overhead = timer_overhead();
+    let mut samples = Vec::with_capacity(iterations);
+    for _ in 0..iterations {
+        let start = timing::now();
+        operation();
+        samples.push(start.elapsed());
+    }
+    LatencyStats::new(samples, timer_overhead)
+}
+
+/// Variant of `record()` that uses the hardware cycle counter
+///
+/// The counter is calibrated before the measurements, and counter differences
+/// are only converted to durations once all runs are done.
+///
+#[cfg(all(
+    feature = "cycles",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub(crate) fn record_cycles(iterations: usize, mut operation: impl FnMut()) -> LatencyStats {
+    let overhead = timing::cycles_to_duration(timing::cycles_overhead());
+    let mut cycles = Vec::with_capacity(iterations);
+    for _ in 0..iterations {
+        let start = timing::cycles_start();
+        operation();
+        cycles.push(timing::cycles_end().wrapping_sub(start));
+    }
+    let samples = cycles.into_iter().map(timing::cycles_to_duration).collect();
+    LatencyStats::new(samples, overhead)
+}
+
+/// Tests of latency statistics
+#[cfg(test)]
+mod tests {
+    use super::{measure_cas_latency, LatencyStats};
+    use std::time::Duration;
+
+    // Percentiles should follow the nearest-rank method
+    #[test]
+    fn percentiles() {
+        let samples = (1..=1000).rev().map(Duration::from_nanos).collect();
+        let stats = LatencyStats::new(samples, Duration::ZERO);
+        assert_eq!(stats.len(), 1000);
+        assert_eq!(stats.min(), Duration::from_nanos(1));
+        assert_eq!(stats.median(), Duration::from_nanos(500));
+        assert_eq!(stats.p99(), Duration::from_nanos(990));
+        assert_eq!(stats.p999(), Duration::from_nanos(999));
+        assert_eq!(stats.max(), Duration::from_nanos(1000));
+    }
+
+    // The timer overhead should be subtracted, saturating at zero, and the raw
+    // samples should remain available
+    #[test]
+    fn overhead_subtraction() {
+        let samples = vec![Duration::from_nanos(30), Duration::from_nanos(5)];
+        let stats = LatencyStats::new(samples, Duration::from_nanos(10));
+        assert_eq!(stats.samples(), &[Duration::ZERO, Duration::from_nanos(20)]);
+        assert_eq!(
+            stats.raw_samples(),
+            &[Duration::from_nanos(5), Duration::from_nanos(30)]
+        );
+        assert_eq!(stats.timer_overhead(), Duration::from_nanos(10));
+        assert_eq!(stats.min(), Duration::ZERO);
+    }
+
+    // Recording should produce one sample per iteration
+    #[test]
+    fn record() {
+        let stats = super::record(10, || std::thread::sleep(Duration::from_millis(1)));
+        assert_eq!(stats.len(), 10);
+        assert!(stats.raw_samples()[0] >= Duration::from_millis(1));
+        assert!(stats.timer_overhead() < Duration::from_millis(1));
+        for (&sample, &raw_sample) in stats.samples().iter().zip(stats.raw_samples()) {
+            assert_eq!(sample, raw_sample - stats.timer_overhead());
+        }
+    }
+
+    // Contended CAS latency should be measured once per iteration
+    #[test]
+    fn cas_latency() {
+        for threads in 0..=2 {
+            let latency = measure_cas_latency(threads, 1000);
+            assert_eq!(latency.successful.len(), 1000);
+            if threads == 0 {
+                assert!(latency.failed.is_empty());
+            }
+        }
+    }
+}
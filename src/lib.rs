@@ -11,6 +11,26 @@
 //!
 //! For examples of this crate at work, look at its "tests" and "benchs"
 //! submodules, which showcase expected usage.
+//!
+//! # Tracing
+//!
+//! With the `tracing` feature, the test and benchmark harnesses of this crate
+//! report what they are doing through the `tracing` crate, so that their time
+//! does not show up as anonymous in profiles of instrumented programs:
+//!
+//! - A `participant` span covers each closure or future of a concurrent test,
+//!   with the `index` of the participant and the name of its `thread`. The
+//!   release of the start barrier is reported as an event within it.
+//! - Runs under contention have an `antagonist` span per antagonist loop and a
+//!   `benchmark` span around the benchmark, and report when the antagonists
+//!   are told to stop.
+//! - Watchdogs report when their deadline and their grace period expire.
+//! - `RaceCell::get_logged()` reports the races that it detects.
+//!
+//! Spans of threads spawned by the harnesses are nested into the span that was
+//! current when the harness was called. Without this feature, the crate does
+//! not depend on `tracing`, and the instrumentation compiles down to nothing.
+//! Note that `tracing` requires a newer Rust compiler than this crate.
 
 #![warn(
     anonymous_parameters,
@@ -28,13 +48,70 @@
     variant_size_differences
 )]
 
+pub mod affinity;
+pub mod alloc_check;
+#[cfg(feature = "async")]
+pub mod async_test;
+pub mod canary;
+pub mod contenders;
+pub mod contention;
+mod cpu_time;
+#[cfg(feature = "criterion")]
+pub mod criterion;
+pub mod env;
+pub mod events;
+pub mod false_sharing;
+pub mod faults;
+pub mod latency;
+pub mod linearizability;
+pub mod litmus;
+#[cfg(loom)]
+pub mod loom;
 pub mod noinline;
+pub mod opaque;
+mod parallelism;
+#[cfg(all(feature = "perf", target_os = "linux"))]
+pub mod perf;
+pub mod probe;
+#[cfg(feature = "proptest")]
+pub mod proptest;
 pub mod race_cell;
+pub mod report;
+pub mod rng;
+pub mod runner;
+mod scaling;
+pub mod sched;
+#[cfg(shuttle)]
+pub mod shuttle;
+pub mod stats;
+pub mod stress;
+pub mod subprocess;
+pub mod sync;
+pub mod throughput;
+pub mod timing;
+pub mod topology;
+mod trace;
+pub mod util;
+pub mod warmup;
+pub mod watchdog;
+
+// Lets derive macros refer to this crate as `::testbench` in its own tests
+#[cfg(all(test, feature = "derive"))]
+extern crate self as testbench;
 
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Barrier,
+#[cfg(all(loom, shuttle))]
+compile_error!("The loom and shuttle backends cannot be enabled at the same time");
+
+use crate::stats::SampleStats;
+pub use crate::{
+    parallelism::parallelism,
+    scaling::{scale_iters, Intensity, IterScale},
 };
+#[cfg(shuttle)]
+use ::shuttle::{sync::Barrier, thread};
+use std::time::Duration;
+#[cfg(not(shuttle))]
+use std::{sync::Barrier, thread};
 
 /// Test that running two operations concurrently works
 ///
@@ -54,12 +131,13 @@ use std::sync::{
 ///
 pub fn concurrent_test_2(f1: impl FnOnce() + Send, f2: impl FnOnce() + Send) {
     let barrier = Barrier::new(2);
-    std::thread::scope(|s| {
+    let parent = trace::Parent::current();
+    thread::scope(|s| {
         s.spawn(|| {
-            barrier.wait();
+            let _span = start_participant(&parent, 0, &barrier);
             noinline::call_once(f1);
         });
-        barrier.wait();
+        let _span = start_participant(&parent, 1, &barrier);
         noinline::call_once(f2);
     })
 }
@@ -80,20 +158,29 @@ pub fn concurrent_test_3(
     f3: impl FnOnce() + Send,
 ) {
     let barrier = Barrier::new(3);
-    std::thread::scope(|s| {
+    let parent = trace::Parent::current();
+    thread::scope(|s| {
         s.spawn(|| {
-            barrier.wait();
+            let _span = start_participant(&parent, 0, &barrier);
             noinline::call_once(f1);
         });
         s.spawn(|| {
-            barrier.wait();
+            let _span = start_participant(&parent, 1, &barrier);
             noinline::call_once(f2);
         });
-        barrier.wait();
+        let _span = start_participant(&parent, 2, &barrier);
         noinline::call_once(f3);
     })
 }
 
+/// Enter the span of a concurrent test participant, then wait for the others
+fn start_participant(parent: &trace::Parent, index: usize, barrier: &Barrier) -> trace::Entered {
+    let span = trace::participant(parent, index);
+    barrier.wait();
+    trace::barrier_released();
+    span
+}
+
 /// Perform some operation while another is running in a loop in another thread
 ///
 /// For multithreaded code, benchmarking the performance of isolated operations
@@ -110,46 +197,171 @@ pub fn concurrent_test_3(
 ///
 /// - You can hide the fact that the code is run in a loop by preventing the
 ///   compiler from inlining it there, see this crate's `noinline::call_mut()`.
+///   If every iteration needs a different input, such as a counter, pass it
+///   through the inlining barrier with `noinline::call_mut_with()`.
 /// - You can obscure the fact that inputs are always the same and outputs are
-///   are not used by using `core::hint::black_box()` on nightly Rust, or its
-///   emulation by the Criterion benchmarking crate.
+///   are not used by using this crate's `opaque::black_box()`.
+/// - You can keep outputs from being optimized out by passing them to this
+///   crate's `noinline::consume()`.
 /// - You can generate inputs that the compiler cannot guess using a random
 ///   number generator, and use your outputs by sending them through some sort
 ///   of reduction function (sum, min, max...) and checking the result.
 ///
+/// The benchmark is only run once, so it may consume the data that it works
+/// on. For more control over the antagonist threads, and to get statistics
+/// about what they did, see the `contention` module.
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::atomic::{AtomicU64, Ordering};
+/// use testbench::opaque::black_box;
+///
+/// // Measure a sum while another thread hammers a shared counter
+/// let counter = AtomicU64::new(0);
+/// let sum = testbench::run_under_contention(
+///     || counter.fetch_add(black_box(1), Ordering::Relaxed),
+///     || (0..1000u64).map(black_box).sum::<u64>(),
+/// );
+/// assert_eq!(sum, 499_500);
+/// ```
+///
+/// # Panics
+///
+/// This function will propagate panics from the antagonist and benchmark.
+/// The antagonist thread is stopped even if the benchmark panics.
+///
 pub fn run_under_contention<AntagonistResult, BenchmarkResult>(
-    mut antagonist: impl FnMut() -> AntagonistResult + Send,
-    mut benchmark: impl FnMut() -> BenchmarkResult,
+    antagonist: impl FnMut() -> AntagonistResult + Send,
+    benchmark: impl FnOnce() -> BenchmarkResult,
 ) -> BenchmarkResult {
-    let start_barrier = Barrier::new(2);
-    let continue_flag = AtomicBool::new(true);
-    std::thread::scope(|s| {
-        s.spawn(|| {
-            start_barrier.wait();
-            while continue_flag.load(Ordering::Relaxed) {
-                antagonist();
-            }
-        });
-        start_barrier.wait();
-        let result = benchmark();
-        continue_flag.store(false, Ordering::Relaxed);
-        result
+    let mut antagonist = Some(antagonist);
+    let (result, _stats) = contention::Contention::new().run(
+        |_| {
+            antagonist
+                .take()
+                .expect("There should be only one antagonist")
+        },
+        benchmark,
+    );
+    result
+}
+
+/// Variant of `run_under_contention()` that reduces the antagonist's outputs
+///
+/// The outputs of every antagonist iteration are folded into an accumulator,
+/// starting from `init`, and the final accumulator is returned along with the
+/// benchmark's result once the antagonist has stopped. This keeps the compiler
+/// from optimizing out the antagonist's work, and can also collect useful
+/// information such as the maximal value that the antagonist observed.
+///
+/// The fold is carried out on the antagonist thread, so it does not add any
+/// cross-thread communication to the antagonist loop. The final accumulator
+/// is only read after the antagonist thread has been joined, which makes all
+/// of its side effects visible to the caller.
+///
+/// # Panics
+///
+/// This function will propagate panics from the antagonist, the fold and the
+/// benchmark.
+///
+pub fn run_under_contention_fold<AntagonistResult, Accumulator, BenchmarkResult>(
+    mut antagonist: impl FnMut() -> AntagonistResult + Send,
+    init: Accumulator,
+    mut fold: impl FnMut(Accumulator, AntagonistResult) -> Accumulator + Send,
+    benchmark: impl FnOnce() -> BenchmarkResult,
+) -> (BenchmarkResult, Accumulator)
+where
+    Accumulator: Send,
+{
+    let mut accumulator = Some(init);
+    let state = &mut accumulator;
+    let result = run_under_contention(
+        move || {
+            let current = state.take().expect("Accumulator should be present");
+            *state = Some(fold(current, antagonist()));
+        },
+        benchmark,
+    );
+    let accumulator = accumulator.expect("Accumulator should be present");
+    (result, accumulator)
+}
+
+/// Variant of `run_under_contention()` that measures a benchmark repeatedly
+///
+/// The benchmark is run `samples` times, while the antagonist runs
+/// continuously in the background, and each run returns its own duration. This
+/// allows measuring it in whichever way is most appropriate, for example with
+/// `timing::measure()`, which subtracts the timer overhead, or with
+/// `timing::measure_batch()` for operations that are not much longer than the
+/// timer overhead. The resulting samples are then summarized.
+///
+/// # Panics
+///
+/// This function will propagate panics from the antagonist and benchmark.
+///
+pub fn run_under_contention_sampled<AntagonistResult>(
+    antagonist: impl FnMut() -> AntagonistResult + Send,
+    mut benchmark: impl FnMut() -> Duration,
+    samples: usize,
+) -> SampleStats {
+    run_under_contention(antagonist, || {
+        SampleStats::from_durations((0..samples).map(|_| benchmark()).collect())
     })
 }
 
+/// Variant of `run_under_contention()` for a fixed number of antagonists
+///
+/// Each antagonist of the array is run in a loop in its own thread while the
+/// benchmark is running. As the number of antagonists is known at compile
+/// time, this does not need any heap allocation besides that of the threads
+/// themselves, which keeps the overhead of the harness minimal and constant.
+///
+/// Antagonists of different types can be used by putting them in an enum, or
+/// by using trait objects.
+///
+/// # Panics
+///
+/// This function will propagate panics from the antagonists and benchmark.
+/// Antagonist threads are stopped even if the benchmark panics.
+///
+pub fn run_under_contention_const<const N: usize, Antagonist, AntagonistResult, BenchmarkResult>(
+    antagonists: [Antagonist; N],
+    benchmark: impl FnOnce() -> BenchmarkResult,
+) -> BenchmarkResult
+where
+    Antagonist: FnMut() -> AntagonistResult + Send,
+{
+    contention::run_fixed(antagonists, benchmark)
+}
+
 /// Examples of concurrent testing code
 #[cfg(test)]
 mod tests {
+    use crate::{throughput::calibrate_iterations, Intensity};
     use std::{
-        sync::atomic::{AtomicUsize, Ordering},
+        panic,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc,
+        },
         time::Duration,
     };
 
+    // Target duration of each thread's work in concurrent tests
+    const TARGET_DURATION: Duration = Duration::from_millis(500);
+
     // Check the behaviour of concurrent atomic swaps and fetch-adds
     #[test]
     fn swap_and_fetch_add() {
         // Amount of atomic operations to check
-        const ATOMIC_OPS_COUNT: usize = 100_000_000;
+        let probe_atom = AtomicUsize::new(0);
+        let atomic_ops_count = calibrate_iterations(
+            || {
+                probe_atom.fetch_add(1, Ordering::Relaxed);
+            },
+            TARGET_DURATION,
+        );
 
         // Create a shared atomic variable
         let atom = AtomicUsize::new(0);
@@ -159,7 +371,7 @@ mod tests {
         super::concurrent_test_2(
             || {
                 // One thread continuously increments the atomic variable...
-                for _ in 1..=ATOMIC_OPS_COUNT {
+                for _ in 1..=atomic_ops_count {
                     let former_atom = atom.fetch_add(1, Ordering::Relaxed);
                     assert!((former_atom == 0) || (former_atom == last_value));
                     last_value = former_atom + 1;
@@ -167,9 +379,9 @@ mod tests {
             },
             || {
                 // ...as another continuously resets it to zero
-                for _ in 1..=ATOMIC_OPS_COUNT {
+                for _ in 1..=atomic_ops_count {
                     let former_atom = atom.swap(0, Ordering::Relaxed);
-                    assert!(former_atom <= ATOMIC_OPS_COUNT);
+                    assert!(former_atom <= atomic_ops_count);
                 }
             },
         );
@@ -179,7 +391,13 @@ mod tests {
     #[test]
     fn fetch_and_or_xor() {
         // Amount of atomic operations to check
-        const ATOMIC_OPS_COUNT: usize = 30_000_000;
+        let probe_atom = AtomicUsize::new(0);
+        let atomic_ops_count = calibrate_iterations(
+            || {
+                probe_atom.fetch_xor(0b1111, Ordering::Relaxed);
+            },
+            TARGET_DURATION,
+        );
 
         // Create a shared atomic variable. Even though this is an atomic Usize,
         // we will only use the 16 low-order bits for maximal portability.
@@ -197,7 +415,7 @@ mod tests {
         super::concurrent_test_3(
             || {
                 // One thread runs fetch-ands in a loop...
-                for _ in 1..=ATOMIC_OPS_COUNT {
+                for _ in 1..=atomic_ops_count {
                     let old_val = atom.fetch_and(AND_MASK, Ordering::Relaxed);
                     assert_eq!(old_val & 0b1111_1111_1111_1111, old_val);
                     assert!((old_val & XOR_MASK == XOR_MASK) || (old_val & XOR_MASK == 0));
@@ -206,7 +424,7 @@ mod tests {
             },
             || {
                 // ...another runs fetch-ors in a loop...
-                for _ in 1..=ATOMIC_OPS_COUNT {
+                for _ in 1..=atomic_ops_count {
                     let old_val = atom.fetch_or(OR_MASK, Ordering::Relaxed);
                     assert_eq!(old_val & 0b1111_1111_1111_1111, old_val);
                     assert!((old_val & XOR_MASK == XOR_MASK) || (old_val & XOR_MASK == 0));
@@ -215,7 +433,7 @@ mod tests {
             },
             || {
                 // ...and the last one runs fetch-xors in a loop...
-                for _ in 1..=ATOMIC_OPS_COUNT {
+                for _ in 1..=atomic_ops_count {
                     let old_val = atom.fetch_xor(XOR_MASK, Ordering::Relaxed);
                     assert_eq!(old_val & 0b1111_1111_1111_1111, old_val);
                     assert!((old_val & XOR_MASK == XOR_MASK) || (old_val & XOR_MASK == 0));
@@ -228,11 +446,85 @@ mod tests {
     // Show how adversarial code is actually run in concurrent "benchmarking"
     #[test]
     fn antagonist_showcase() {
+        // Run longer at higher intensity, as scale_iters() expects more iterations
+        let duration = Duration::from_millis(Intensity::current().pick(10, 100, 1000));
         let atom = AtomicUsize::new(0);
         super::run_under_contention(
             || atom.fetch_add(1, Ordering::Relaxed),
-            || std::thread::sleep(Duration::from_millis(100)),
+            || std::thread::sleep(duration),
+        );
+        assert!(atom.load(Ordering::Relaxed) > crate::scale_iters(100_000));
+    }
+
+    // Antagonist outputs can be folded into an accumulator
+    #[test]
+    fn antagonist_fold() {
+        let atom = AtomicUsize::new(0);
+        let (result, (iterations, max)) = super::run_under_contention_fold(
+            || atom.fetch_add(1, Ordering::Relaxed),
+            (0, 0),
+            |(iterations, max), value| (iterations + 1, max.max(value)),
+            || {
+                std::thread::sleep(Duration::from_millis(10));
+                42
+            },
+        );
+        assert_eq!(result, 42);
+        assert!(iterations > 0);
+        assert_eq!(iterations, atom.load(Ordering::Relaxed));
+        assert_eq!(max, iterations - 1);
+    }
+
+    // A fixed set of antagonists of different kinds can be used
+    #[test]
+    fn const_antagonists() {
+        let atom = AtomicUsize::new(0);
+        let increment = || atom.fetch_add(1, Ordering::Relaxed);
+        let reset = || atom.swap(0, Ordering::Relaxed);
+        let read = || atom.load(Ordering::Relaxed);
+        let antagonists: [&(dyn Fn() -> usize + Sync); 3] = [&increment, &reset, &read];
+        let result = super::run_under_contention_const::<3, _, _, _>(antagonists, || {
+            std::thread::sleep(Duration::from_millis(10));
+            42
+        });
+        assert_eq!(result, 42);
+    }
+
+    // A benchmark can be sampled repeatedly under contention
+    #[test]
+    fn sampled_benchmark() {
+        let atom = AtomicUsize::new(0);
+        let stats = super::run_under_contention_sampled(
+            || atom.fetch_add(1, Ordering::Relaxed),
+            || crate::timing::measure(|| std::thread::sleep(Duration::from_millis(1))),
+            10,
         );
-        assert!(atom.load(Ordering::Relaxed) > 100000);
+        assert_eq!(stats.len(), 10);
+        assert!(stats.min() >= Duration::from_micros(900));
+        assert!(atom.load(Ordering::Relaxed) > 0);
+    }
+
+    // The benchmark may consume the data that it works on
+    #[test]
+    fn consuming_benchmark() {
+        let data = vec![1, 2, 3];
+        let (sender, receiver) = mpsc::channel();
+        let sum = super::run_under_contention(
+            || (),
+            move || {
+                sender.send(data.len()).unwrap();
+                data.into_iter().sum::<i32>()
+            },
+        );
+        assert_eq!(sum, 6);
+        assert_eq!(receiver.recv().unwrap(), 3);
+    }
+
+    // A panicking benchmark must not leave the antagonist running forever
+    #[test]
+    fn benchmark_panic() {
+        let result =
+            panic::catch_unwind(|| super::run_under_contention(|| (), || panic!("expected panic")));
+        assert!(result.is_err());
     }
 }
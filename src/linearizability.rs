@@ -0,0 +1,665 @@
+//! Linearizability checking of recorded operation histories
+//!
+//! A concurrent object is linearizable if every operation appears to take
+//! effect atomically at some point between its invocation and its response.
+//! This can be checked by recording which operations each thread invoked,
+//! when, and with which result, using a `HistoryRecorder`, and searching for
+//! an order of the operations which agrees with a sequential model of the
+//! object, described by a `SequentialSpec`, using `check()`.
+//!
+//! ```
+//! use std::sync::atomic::{AtomicU64, Ordering};
+//! use testbench::linearizability::{self, HistoryRecorder, SequentialSpec};
+//!
+//! #[derive(Clone, Debug)]
+//! enum RegisterOp {
+//!     Write(u64),
+//!     Read,
+//! }
+//!
+//! #[derive(Clone, Default, Eq, Hash, PartialEq)]
+//! struct Register(u64);
+//! //
+//! impl SequentialSpec for Register {
+//!     type Op = RegisterOp;
+//!     type Result = Option<u64>;
+//!     fn apply(&mut self, op: &RegisterOp) -> Option<u64> {
+//!         match *op {
+//!             RegisterOp::Write(value) => {
+//!                 self.0 = value;
+//!                 None
+//!             }
+//!             RegisterOp::Read => Some(self.0),
+//!         }
+//!     }
+//! }
+//!
+//! let register = AtomicU64::new(0);
+//! let recorder = HistoryRecorder::new();
+//! testbench::concurrent_test_2(
+//!     || {
+//!         let write = recorder.invoke(RegisterOp::Write(42));
+//!         register.store(42, Ordering::Relaxed);
+//!         write.respond(None);
+//!     },
+//!     || {
+//!         let read = recorder.invoke(RegisterOp::Read);
+//!         read.respond(Some(register.load(Ordering::Relaxed)));
+//!     },
+//! );
+//! let history = recorder.into_history();
+//! linearizability::check::<Register>(&history).unwrap();
+//! ```
+//!
+//! The checker implements the Wing & Gong search, with the state caching
+//! optimization of Lowe. Its worst-case complexity is exponential, but it
+//! handles histories of a few thousand events on small objects.
+
+use std::{
+    collections::HashSet,
+    fmt::{self, Debug},
+    hash::Hash,
+    mem,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+/// Sequential specification of a concurrent object
+///
+/// This is a model of the object which is only used by one thread at a time.
+/// It must be cheap to clone and hash, as the checker caches the states that
+/// it has already explored.
+///
+pub trait SequentialSpec: Clone + Eq + Hash {
+    /// Operation that can be applied to the object
+    type Op: Clone + Debug;
+
+    /// Result of an operation
+    type Result: Clone + Debug + PartialEq;
+
+    /// Apply an operation to the object, and return its result
+    fn apply(&mut self, op: &Self::Op) -> Self::Result;
+}
+
+/// Recorder of concurrent operation histories
+///
+/// Threads call `invoke()` right before they perform an operation, and
+/// `respond()` on the result right after. Events are timestamped using a
+/// logical clock, which reflects the order in which they were recorded.
+///
+#[derive(Debug)]
+pub struct HistoryRecorder<Op, Res> {
+    /// History recorded so far
+    history: Mutex<History<Op, Res>>,
+}
+//
+impl<Op, Res> HistoryRecorder<Op, Res> {
+    /// Set up a recorder with an empty history
+    pub fn new() -> Self {
+        Self {
+            history: Mutex::new(History {
+                operations: Vec::new(),
+                clock: 0,
+            }),
+        }
+    }
+
+    /// Record that an operation is being invoked
+    ///
+    /// The operation remains pending, i.e. it may or may not have taken effect,
+    /// until `respond()` is called on the result.
+    ///
+    pub fn invoke(&self, op: Op) -> Invocation<'_, Op, Res> {
+        let mut history = self.lock();
+        let invoked = history.tick();
+        history.operations.push(Operation {
+            op,
+            invoked,
+            response: None,
+        });
+        Invocation {
+            recorder: self,
+            index: history.operations.len() - 1,
+        }
+    }
+
+    /// Extract the recorded history
+    pub fn into_history(self) -> History<Op, Res> {
+        self.history
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Access the recorded history, ignoring poisoning since it is always
+    /// left in a consistent state
+    fn lock(&self) -> MutexGuard<'_, History<Op, Res>> {
+        self.history.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+//
+impl<Op, Res> Default for HistoryRecorder<Op, Res> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Operation which was invoked, and is waiting for its response
+#[derive(Debug)]
+#[must_use = "Operations are pending until their result is recorded"]
+pub struct Invocation<'recorder, Op, Res> {
+    /// Recorder that the operation belongs to
+    recorder: &'recorder HistoryRecorder<Op, Res>,
+
+    /// Index of the operation in the history
+    index: usize,
+}
+//
+impl<Op, Res> Invocation<'_, Op, Res> {
+    /// Record the result of the operation
+    pub fn respond(self, result: Res) {
+        let mut history = self.recorder.lock();
+        let responded = history.tick();
+        history.operations[self.index].response = Some((result, responded));
+    }
+}
+
+/// History of concurrent operations
+#[derive(Clone, Debug)]
+pub struct History<Op, Res> {
+    /// Operations, in order of invocation
+    operations: Vec<Operation<Op, Res>>,
+
+    /// Logical clock used to timestamp events
+    clock: u64,
+}
+//
+impl<Op, Res> History<Op, Res> {
+    /// Operations, in order of invocation
+    pub fn operations(&self) -> &[Operation<Op, Res>] {
+        &self.operations[..]
+    }
+
+    /// Read the logical clock, then advance it
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock - 1
+    }
+}
+//
+impl<Op: Debug, Res: Debug> fmt::Display for History<Op, Res> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, operation) in self.operations.iter().enumerate() {
+            if idx > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "- #{}: {}", idx, operation)?;
+        }
+        Ok(())
+    }
+}
+
+/// Operation from a concurrent history
+#[derive(Clone, Debug)]
+pub struct Operation<Op, Res> {
+    /// Operation which was invoked
+    op: Op,
+
+    /// Timestamp of the invocation
+    invoked: u64,
+
+    /// Result of the operation and timestamp of the response, if any
+    response: Option<(Res, u64)>,
+}
+//
+impl<Op, Res> Operation<Op, Res> {
+    /// Operation which was invoked
+    pub fn op(&self) -> &Op {
+        &self.op
+    }
+
+    /// Timestamp of the invocation
+    pub fn invoked(&self) -> u64 {
+        self.invoked
+    }
+
+    /// Result of the operation, or `None` if it is still pending
+    pub fn result(&self) -> Option<&Res> {
+        self.response.as_ref().map(|(result, _)| result)
+    }
+
+    /// Timestamp of the response, or `None` if the operation is still pending
+    pub fn responded(&self) -> Option<u64> {
+        self.response.as_ref().map(|&(_, responded)| responded)
+    }
+}
+//
+impl<Op: Debug, Res: Debug> fmt::Display for Operation<Op, Res> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.response {
+            Some((result, responded)) => write!(
+                f,
+                "{:?} -> {:?} (invoked at {}, responded at {})",
+                self.op, result, self.invoked, responded
+            ),
+            None => write!(f, "{:?} (invoked at {}, pending)", self.op, self.invoked),
+        }
+    }
+}
+
+/// Order in which the operations of a history appear to take effect
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Witness {
+    /// Indices of operations in the history
+    order: Vec<usize>,
+}
+//
+impl Witness {
+    /// Indices of operations in the history, in the order where they appear
+    /// to take effect
+    ///
+    /// Pending operations are only included if they must have taken effect
+    /// for the history to be linearizable.
+    ///
+    pub fn order(&self) -> &[usize] {
+        &self.order[..]
+    }
+}
+
+/// Evidence that a history is not linearizable
+#[derive(Clone, Debug)]
+pub struct Violation<Op, Res> {
+    /// Shortest prefix of the history which is not linearizable
+    prefix: History<Op, Res>,
+}
+//
+impl<Op, Res> Violation<Op, Res> {
+    /// Shortest prefix of the history which is not linearizable
+    ///
+    /// This is the history of all events up to the one that made the history
+    /// non-linearizable. Operations which responded after that event are
+    /// pending in this prefix.
+    ///
+    pub fn prefix(&self) -> &History<Op, Res> {
+        &self.prefix
+    }
+}
+//
+impl<Op: Debug, Res: Debug> fmt::Display for Violation<Op, Res> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "history is not linearizable, shortest non-linearizable prefix:"
+        )?;
+        write!(f, "{}", self.prefix)
+    }
+}
+//
+impl<Op: Debug, Res: Debug> std::error::Error for Violation<Op, Res> {}
+
+/// Check that a history is linearizable, starting from the default state
+pub fn check<S: SequentialSpec + Default>(
+    history: &History<S::Op, S::Result>,
+) -> Result<Witness, Violation<S::Op, S::Result>> {
+    check_from(S::default(), history)
+}
+
+/// Check that a history is linearizable, starting from a certain state
+pub fn check_from<S: SequentialSpec>(
+    initial: S,
+    history: &History<S::Op, S::Result>,
+) -> Result<Witness, Violation<S::Op, S::Result>> {
+    let events = Events::new(history);
+    if let Some(order) = search(&initial, history, &events, events.len()) {
+        return Ok(Witness { order });
+    }
+
+    // Linearizability is prefix-closed, so the shortest non-linearizable
+    // prefix can be found by bisection.
+    let (mut linearizable, mut non_linearizable) = (0, events.len());
+    while non_linearizable - linearizable > 1 {
+        let middle = linearizable + (non_linearizable - linearizable) / 2;
+        if search(&initial, history, &events, middle).is_some() {
+            linearizable = middle;
+        } else {
+            non_linearizable = middle;
+        }
+    }
+    Err(Violation {
+        prefix: events.prefix(history, non_linearizable),
+    })
+}
+
+/// Invocation or response event
+#[derive(Clone, Copy, Debug)]
+struct Event {
+    /// Index of the operation in the history
+    operation: usize,
+
+    /// Truth that this is a response event
+    is_response: bool,
+}
+
+/// Events of a history, sorted by timestamp
+struct Events(Vec<Event>);
+//
+impl Events {
+    /// Sort the events of a history
+    fn new<Op, Res>(history: &History<Op, Res>) -> Self {
+        let mut events = history
+            .operations
+            .iter()
+            .enumerate()
+            .flat_map(|(operation, op)| {
+                let invocation = (op.invoked, operation, false);
+                let response = op.responded().map(|time| (time, operation, true));
+                std::iter::once(invocation).chain(response)
+            })
+            .collect::<Vec<_>>();
+        events.sort_unstable_by_key(|&(time, _, _)| time);
+        Self(
+            events
+                .into_iter()
+                .map(|(_, operation, is_response)| Event {
+                    operation,
+                    is_response,
+                })
+                .collect(),
+        )
+    }
+
+    /// Number of events
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Extract the history made of the first `len` events
+    fn prefix<Op: Clone, Res: Clone>(
+        &self,
+        history: &History<Op, Res>,
+        len: usize,
+    ) -> History<Op, Res> {
+        let mut prefix = History {
+            operations: Vec::new(),
+            clock: 0,
+        };
+        let mut indices = vec![usize::MAX; history.operations.len()];
+        for event in &self.0[..len] {
+            let operation = &history.operations[event.operation];
+            if event.is_response {
+                prefix.operations[indices[event.operation]].response = operation.response.clone();
+            } else {
+                indices[event.operation] = prefix.operations.len();
+                prefix.operations.push(Operation {
+                    response: None,
+                    ..operation.clone()
+                });
+            }
+        }
+        prefix.clock = history.clock;
+        prefix
+    }
+}
+
+/// Marker for the end of the event list
+const NIL: usize = usize::MAX;
+
+/// Search for a linearization of the history made of the first `len` events
+fn search<S: SequentialSpec>(
+    initial: &S,
+    history: &History<S::Op, S::Result>,
+    events: &Events,
+    len: usize,
+) -> Option<Vec<usize>> {
+    let events = &events.0[..len];
+
+    // Doubly linked list of events which remain to be linearized, where the
+    // node at index `len` is the list head
+    let head = len;
+    let mut next = (0..=len)
+        .map(|pos| if pos + 1 < len { pos + 1 } else { NIL })
+        .collect::<Vec<_>>();
+    next[head] = if len > 0 { 0 } else { NIL };
+    let mut prev = (0..=len)
+        .map(|pos| if pos == 0 { head } else { pos - 1 })
+        .collect::<Vec<_>>();
+    prev[head] = NIL;
+
+    // Position of the invocation and response of each operation
+    let mut invocations = vec![NIL; history.operations.len()];
+    let mut responses = vec![NIL; history.operations.len()];
+    for (pos, event) in events.iter().enumerate() {
+        if event.is_response {
+            responses[event.operation] = pos;
+        } else {
+            invocations[event.operation] = pos;
+        }
+    }
+    let unlink = |next: &mut [usize], prev: &mut [usize], pos: usize| {
+        let (before, after) = (prev[pos], next[pos]);
+        next[before] = after;
+        if after != NIL {
+            prev[after] = before;
+        }
+    };
+    let relink = |next: &mut [usize], prev: &mut [usize], pos: usize| {
+        let (before, after) = (prev[pos], next[pos]);
+        next[before] = pos;
+        if after != NIL {
+            prev[after] = pos;
+        }
+    };
+
+    // Search state
+    let mut state = initial.clone();
+    let mut linearized = vec![0u64; (history.operations.len() + 63) / 64];
+    let mut remaining = events.iter().filter(|event| event.is_response).count();
+    let mut stack: Vec<(usize, S)> = Vec::new();
+    let mut cache = HashSet::new();
+    let mut entry = next[head];
+    loop {
+        if remaining == 0 {
+            return Some(stack.into_iter().map(|(operation, _)| operation).collect());
+        }
+        if entry != NIL && !events[entry].is_response {
+            let operation = events[entry].operation;
+            let response = responses[operation];
+            let mut new_state = state.clone();
+            let result = new_state.apply(&history.operations[operation].op);
+            let consistent =
+                response == NIL || history.operations[operation].result() == Some(&result);
+            if consistent {
+                linearized[operation / 64] |= 1 << (operation % 64);
+                if cache.insert((linearized.clone(), new_state.clone())) {
+                    stack.push((operation, mem::replace(&mut state, new_state)));
+                    unlink(&mut next, &mut prev, entry);
+                    if response != NIL {
+                        unlink(&mut next, &mut prev, response);
+                        remaining -= 1;
+                    }
+                    entry = next[head];
+                    continue;
+                }
+                linearized[operation / 64] &= !(1 << (operation % 64));
+            }
+            entry = next[entry];
+        } else {
+            // Reached a response, or the end of the list, without finding an
+            // operation to linearize: backtrack
+            let (operation, old_state) = stack.pop()?;
+            state = old_state;
+            linearized[operation / 64] &= !(1 << (operation % 64));
+            let response = responses[operation];
+            if response != NIL {
+                relink(&mut next, &mut prev, response);
+                remaining += 1;
+            }
+            let invocation = invocations[operation];
+            relink(&mut next, &mut prev, invocation);
+            entry = next[invocation];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    #[derive(Clone, Debug, PartialEq)]
+    enum RegisterOp {
+        Write(u64),
+        Read,
+    }
+
+    /// Sequential model of a register
+    #[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+    struct Register(u64);
+    //
+    impl SequentialSpec for Register {
+        type Op = RegisterOp;
+        type Result = Option<u64>;
+        fn apply(&mut self, op: &RegisterOp) -> Option<u64> {
+            match *op {
+                RegisterOp::Write(value) => {
+                    self.0 = value;
+                    None
+                }
+                RegisterOp::Read => Some(self.0),
+            }
+        }
+    }
+
+    /// Record a sequence of operations from a single thread
+    fn run_ops(
+        recorder: &HistoryRecorder<RegisterOp, Option<u64>>,
+        register: &AtomicU64,
+        ops: &[RegisterOp],
+        lose_writes: bool,
+    ) {
+        for (idx, op) in ops.iter().enumerate() {
+            let invocation = recorder.invoke(op.clone());
+            match *op {
+                RegisterOp::Write(value) => {
+                    // The buggy register loses every other write
+                    if !lose_writes || idx % 2 == 0 {
+                        register.store(value, Ordering::SeqCst);
+                    }
+                    invocation.respond(None);
+                }
+                RegisterOp::Read => invocation.respond(Some(register.load(Ordering::SeqCst))),
+            }
+        }
+    }
+
+    #[test]
+    fn empty_history() {
+        let history = HistoryRecorder::<RegisterOp, Option<u64>>::new().into_history();
+        assert!(check::<Register>(&history).unwrap().order().is_empty());
+    }
+
+    #[test]
+    fn overlapping_operations() {
+        // A read that overlaps with a write may return the old or new value,
+        // and must be linearized accordingly
+        for value in [0, 1] {
+            let recorder = HistoryRecorder::new();
+            let write = recorder.invoke(RegisterOp::Write(1));
+            let read = recorder.invoke(RegisterOp::Read);
+            read.respond(Some(value));
+            write.respond(None);
+            let history = recorder.into_history();
+            let expected: &[usize] = if value == 0 { &[1, 0] } else { &[0, 1] };
+            assert_eq!(check::<Register>(&history).unwrap().order(), expected);
+        }
+    }
+
+    #[test]
+    fn pending_operations() {
+        // A pending write may or may not have taken effect
+        for (value, expected) in [(0, vec![1]), (1, vec![0, 1])] {
+            let recorder = HistoryRecorder::new();
+            let _pending = recorder.invoke(RegisterOp::Write(1));
+            recorder.invoke(RegisterOp::Read).respond(Some(value));
+            let history = recorder.into_history();
+            assert_eq!(history.operations()[0].result(), None);
+            assert_eq!(check::<Register>(&history).unwrap().order(), expected);
+        }
+    }
+
+    #[test]
+    fn minimal_prefix() {
+        // A read that misses a previous write is not linearizable, and
+        // nothing after it should be reported
+        let recorder = HistoryRecorder::new();
+        let register = AtomicU64::new(0);
+        run_ops(&recorder, &register, &[RegisterOp::Write(1)], false);
+        let other_write = recorder.invoke(RegisterOp::Write(2));
+        recorder.invoke(RegisterOp::Read).respond(Some(0));
+        other_write.respond(None);
+        run_ops(
+            &recorder,
+            &register,
+            &[RegisterOp::Read, RegisterOp::Write(3)],
+            false,
+        );
+        let history = recorder.into_history();
+        let violation = check::<Register>(&history).unwrap_err();
+        let prefix = violation.prefix().operations();
+        assert_eq!(prefix.len(), 3);
+        assert_eq!(prefix[0].result(), Some(&None));
+        assert_eq!(prefix[1].op(), &RegisterOp::Write(2));
+        assert_eq!(prefix[1].result(), None);
+        assert_eq!(prefix[2].result(), Some(&Some(0)));
+        assert_eq!(
+            violation.to_string(),
+            "history is not linearizable, shortest non-linearizable prefix:\n\
+             - #0: Write(1) -> None (invoked at 0, responded at 1)\n\
+             - #1: Write(2) (invoked at 2, pending)\n\
+             - #2: Read -> Some(0) (invoked at 3, responded at 4)"
+        );
+    }
+
+    /// Operations carried out by each thread of the concurrent tests
+    fn thread_ops(thread: u64, count: usize) -> Vec<RegisterOp> {
+        (0..count as u64)
+            .map(|idx| {
+                if idx % 3 == 2 {
+                    RegisterOp::Read
+                } else {
+                    RegisterOp::Write(thread * 1_000_000 + idx)
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn correct_register() {
+        let recorder = HistoryRecorder::new();
+        let register = AtomicU64::new(0);
+        let count = crate::scale_iters(5_000);
+        let (ops1, ops2) = (thread_ops(1, count), thread_ops(2, count));
+        crate::concurrent_test_2(
+            || run_ops(&recorder, &register, &ops1, false),
+            || run_ops(&recorder, &register, &ops2, false),
+        );
+        let history = recorder.into_history();
+        assert_eq!(history.operations().len(), 2 * count);
+        let witness = check::<Register>(&history).unwrap();
+        assert_eq!(witness.order().len(), 2 * count);
+    }
+
+    #[test]
+    fn lossy_register() {
+        let recorder = HistoryRecorder::new();
+        let register = AtomicU64::new(0);
+        // Enough operations are needed for lost writes to be noticed
+        let count = crate::scale_iters(5_000).max(30);
+        let (ops1, ops2) = (thread_ops(1, count), thread_ops(2, count));
+        crate::concurrent_test_2(
+            || run_ops(&recorder, &register, &ops1, true),
+            || run_ops(&recorder, &register, &ops2, false),
+        );
+        let history = recorder.into_history();
+        let violation = check::<Register>(&history).unwrap_err();
+        assert!(check::<Register>(violation.prefix()).is_err());
+    }
+}
@@ -0,0 +1,363 @@
+//! Memory-ordering litmus tests
+//!
+//! Litmus tests are tiny concurrent programs whose possible outcomes tell
+//! which reorderings of memory operations are allowed by a memory model. This
+//! module runs the classic ones on the host hardware many times, and counts
+//! how often each outcome is observed. Each test is parameterized by the
+//! memory orderings of its atomic operations, so that one can check which
+//! outcomes these orderings rule out, and which ones actually show up.
+//!
+//! ```
+//! use std::sync::atomic::Ordering;
+//!
+//! let outcomes = testbench::litmus::store_buffering(Ordering::SeqCst, Ordering::SeqCst, 1000);
+//! assert_eq!(outcomes.values().sum::<u64>(), 1000);
+//! assert!(!outcomes.contains_key(&(0, 0)));
+//! ```
+//!
+//! # Observable outcomes
+//!
+//! An outcome that the Rust memory model allows for some orderings may never
+//! be observed in practice, either because the hardware does not reorder the
+//! operations at hand or because the race window is too narrow. Here is what
+//! can be expected from common hardware when the outcome is allowed:
+//!
+//! - Store buffering: `(0, 0)` is readily observed on x86 and AArch64 alike,
+//!   since both have store buffers, unless all operations are `SeqCst`.
+//! - Message passing: `(1, 0)` cannot be observed on x86, whose total store
+//!   order keeps the stores in order, unless the compiler reorders them. It
+//!   can be observed on AArch64 and POWER with `Relaxed` operations.
+//! - Load buffering: `(1, 1)` is very rarely observed, even on hardware that
+//!   allows it like AArch64, and never on x86.
+//! - IRIW: `(1, 0, 1, 0)` can only be observed on hardware that is not
+//!   multi-copy atomic, like POWER, not on x86 or AArch64.
+//!
+//! The tests of this module therefore only check that forbidden outcomes do
+//! not show up.
+//!
+//! # Harness
+//!
+//! Threads are spawned once per test, and run the iterations in lockstep,
+//! synchronizing through a `SpinBarrier` before and after each iteration. The
+//! calling thread participates in the test, and resets the shared state
+//! between two iterations, which makes millions of iterations affordable.
+//! Since threads busy-wait at the barrier, this works best when there are at
+//! least as many CPU cores as test threads.
+
+use crate::sync::SpinBarrier;
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicU32, Ordering},
+    thread,
+};
+
+/// Store buffering: can both threads miss each other's store?
+///
+/// One thread stores 1 into `x`, then loads `y`, while another thread stores 1
+/// into `y`, then loads `x`. Outcomes are the values that the first and second
+/// thread loaded. `(0, 0)` is forbidden if all operations are `SeqCst`.
+///
+/// # Panics
+///
+/// This function panics if `store` is not a valid store ordering or `load` is
+/// not a valid load ordering.
+///
+pub fn store_buffering(
+    store: Ordering,
+    load: Ordering,
+    iterations: u64,
+) -> BTreeMap<(u32, u32), u64> {
+    check_orderings(store, load);
+    run(
+        iterations,
+        &[
+            &|s| {
+                s.x.store(1, store);
+                s.registers[0].store(s.y.load(load), Ordering::Relaxed);
+            },
+            &|s| {
+                s.y.store(1, store);
+                s.registers[1].store(s.x.load(load), Ordering::Relaxed);
+            },
+        ],
+        |s| (s.register(0), s.register(1)),
+    )
+}
+
+/// Message passing: can a thread see a flag without the data it guards?
+///
+/// One thread stores 1 into `data` with `Relaxed` ordering, then stores 1 into
+/// `flag`, while another thread loads `flag`, then loads `data` with `Relaxed`
+/// ordering. Outcomes are the loaded values of `flag` and `data`. `(1, 0)` is
+/// forbidden if `store` is at least `Release` and `load` at least `Acquire`.
+///
+/// # Panics
+///
+/// This function panics if `store` is not a valid store ordering or `load` is
+/// not a valid load ordering.
+///
+pub fn message_passing(
+    store: Ordering,
+    load: Ordering,
+    iterations: u64,
+) -> BTreeMap<(u32, u32), u64> {
+    check_orderings(store, load);
+    run(
+        iterations,
+        &[
+            &|s| {
+                s.x.store(1, Ordering::Relaxed);
+                s.y.store(1, store);
+            },
+            &|s| {
+                s.registers[0].store(s.y.load(load), Ordering::Relaxed);
+                s.registers[1].store(s.x.load(Ordering::Relaxed), Ordering::Relaxed);
+            },
+        ],
+        |s| (s.register(0), s.register(1)),
+    )
+}
+
+/// Load buffering: can both threads see a store that comes after their load?
+///
+/// One thread loads `x`, then stores 1 into `y`, while another thread loads
+/// `y`, then stores 1 into `x`. Outcomes are the values that the first and
+/// second thread loaded. `(1, 1)` is forbidden if `load` is at least `Acquire`
+/// or `store` is at least `Release`.
+///
+/// # Panics
+///
+/// This function panics if `store` is not a valid store ordering or `load` is
+/// not a valid load ordering.
+///
+pub fn load_buffering(
+    store: Ordering,
+    load: Ordering,
+    iterations: u64,
+) -> BTreeMap<(u32, u32), u64> {
+    check_orderings(store, load);
+    run(
+        iterations,
+        &[
+            &|s| {
+                s.registers[0].store(s.x.load(load), Ordering::Relaxed);
+                s.y.store(1, store);
+            },
+            &|s| {
+                s.registers[1].store(s.y.load(load), Ordering::Relaxed);
+                s.x.store(1, store);
+            },
+        ],
+        |s| (s.register(0), s.register(1)),
+    )
+}
+
+/// Independent reads of independent writes: can two threads disagree on the
+/// order of two stores?
+///
+/// Two threads respectively store 1 into `x` and `y`. Two other threads load
+/// both variables, one of them loading `x` first and the other loading `y`
+/// first. Outcomes are the values loaded by the first reader (`x`, then `y`),
+/// followed by those loaded by the second reader (`y`, then `x`).
+/// `(1, 0, 1, 0)` is forbidden if all operations are `SeqCst`.
+///
+/// # Panics
+///
+/// This function panics if `store` is not a valid store ordering or `load` is
+/// not a valid load ordering.
+///
+pub fn iriw(
+    store: Ordering,
+    load: Ordering,
+    iterations: u64,
+) -> BTreeMap<(u32, u32, u32, u32), u64> {
+    check_orderings(store, load);
+    run(
+        iterations,
+        &[
+            &|s| s.x.store(1, store),
+            &|s| s.y.store(1, store),
+            &|s| {
+                s.registers[0].store(s.x.load(load), Ordering::Relaxed);
+                s.registers[1].store(s.y.load(load), Ordering::Relaxed);
+            },
+            &|s| {
+                s.registers[2].store(s.y.load(load), Ordering::Relaxed);
+                s.registers[3].store(s.x.load(load), Ordering::Relaxed);
+            },
+        ],
+        |s| (s.register(0), s.register(1), s.register(2), s.register(3)),
+    )
+}
+
+/// Make sure that orderings are valid before spawning threads, as a panic in
+/// one test thread would leave the others stuck at the barrier
+fn check_orderings(store: Ordering, load: Ordering) {
+    assert!(
+        !matches!(store, Ordering::Acquire | Ordering::AcqRel),
+        "{:?} is not a valid store ordering",
+        store
+    );
+    assert!(
+        !matches!(load, Ordering::Release | Ordering::AcqRel),
+        "{:?} is not a valid load ordering",
+        load
+    );
+}
+
+/// Shared state of a litmus test
+#[derive(Debug, Default)]
+struct State {
+    /// First shared variable
+    x: AtomicU32,
+
+    /// Second shared variable
+    y: AtomicU32,
+
+    /// Values loaded by the test threads
+    registers: [AtomicU32; 4],
+}
+//
+impl State {
+    /// Value of a register, to be read once the test threads are done
+    fn register(&self, idx: usize) -> u32 {
+        self.registers[idx].load(Ordering::Relaxed)
+    }
+
+    /// Reset the state before the next iteration
+    fn reset(&self) {
+        self.x.store(0, Ordering::Relaxed);
+        self.y.store(0, Ordering::Relaxed);
+        for register in &self.registers {
+            register.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Run a litmus test and count the outcomes
+///
+/// The first thread body runs on the calling thread, which observes the
+/// outcome and resets the state while the other threads wait for the next
+/// iteration. The barrier orders these operations with those of the other
+/// threads, so they do not need stronger than `Relaxed` ordering.
+///
+fn run<Outcome: Ord>(
+    iterations: u64,
+    threads: &[&(dyn Fn(&State) + Sync)],
+    observe: impl Fn(&State) -> Outcome,
+) -> BTreeMap<Outcome, u64> {
+    let state = State::default();
+    let barrier = SpinBarrier::new(threads.len());
+    let mut outcomes = BTreeMap::new();
+    thread::scope(|s| {
+        for &body in &threads[1..] {
+            let (state, barrier) = (&state, &barrier);
+            s.spawn(move || {
+                for _ in 0..iterations {
+                    barrier.wait();
+                    body(state);
+                    barrier.wait();
+                }
+            });
+        }
+        for _ in 0..iterations {
+            barrier.wait();
+            threads[0](&state);
+            barrier.wait();
+            *outcomes.entry(observe(&state)).or_insert(0) += 1;
+            state.reset();
+        }
+    });
+    outcomes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fmt::Debug;
+
+    /// Number of iterations of each litmus test
+    fn iterations() -> u64 {
+        crate::scale_iters(20_000) as u64
+    }
+
+    /// Check that all iterations produced an outcome made of 0s and 1s
+    fn check_outcomes<Outcome: Debug>(
+        outcomes: &BTreeMap<Outcome, u64>,
+        values: impl Fn(&Outcome) -> Vec<u32>,
+    ) {
+        assert_eq!(outcomes.values().sum::<u64>(), iterations());
+        for outcome in outcomes.keys() {
+            assert!(
+                values(outcome).iter().all(|&value| value <= 1),
+                "{:?}",
+                outcome
+            );
+        }
+    }
+
+    #[test]
+    fn store_buffering() {
+        for &(store, load) in &[
+            (Ordering::Relaxed, Ordering::Relaxed),
+            (Ordering::Release, Ordering::Acquire),
+            (Ordering::SeqCst, Ordering::SeqCst),
+        ] {
+            let outcomes = super::store_buffering(store, load, iterations());
+            check_outcomes(&outcomes, |&(a, b)| vec![a, b]);
+            if store == Ordering::SeqCst && load == Ordering::SeqCst {
+                assert!(!outcomes.contains_key(&(0, 0)), "{:?}", outcomes);
+            }
+        }
+    }
+
+    #[test]
+    fn message_passing() {
+        for &(store, load) in &[
+            (Ordering::Relaxed, Ordering::Relaxed),
+            (Ordering::Release, Ordering::Acquire),
+            (Ordering::SeqCst, Ordering::SeqCst),
+        ] {
+            let outcomes = super::message_passing(store, load, iterations());
+            check_outcomes(&outcomes, |&(a, b)| vec![a, b]);
+            if store != Ordering::Relaxed && load != Ordering::Relaxed {
+                assert!(!outcomes.contains_key(&(1, 0)), "{:?}", outcomes);
+            }
+        }
+    }
+
+    #[test]
+    fn load_buffering() {
+        for &(store, load) in &[
+            (Ordering::Relaxed, Ordering::Relaxed),
+            (Ordering::Release, Ordering::Relaxed),
+            (Ordering::Relaxed, Ordering::Acquire),
+        ] {
+            let outcomes = super::load_buffering(store, load, iterations());
+            check_outcomes(&outcomes, |&(a, b)| vec![a, b]);
+            if store != Ordering::Relaxed || load != Ordering::Relaxed {
+                assert!(!outcomes.contains_key(&(1, 1)), "{:?}", outcomes);
+            }
+        }
+    }
+
+    #[test]
+    fn iriw() {
+        for &(store, load) in &[
+            (Ordering::Relaxed, Ordering::Relaxed),
+            (Ordering::SeqCst, Ordering::SeqCst),
+        ] {
+            let outcomes = super::iriw(store, load, iterations());
+            check_outcomes(&outcomes, |&(a, b, c, d)| vec![a, b, c, d]);
+            if store == Ordering::SeqCst && load == Ordering::SeqCst {
+                assert!(!outcomes.contains_key(&(1, 0, 1, 0)), "{:?}", outcomes);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Acquire is not a valid store ordering")]
+    fn invalid_ordering() {
+        super::store_buffering(Ordering::Acquire, Ordering::Acquire, iterations());
+    }
+}
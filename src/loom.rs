@@ -0,0 +1,59 @@
+//! Concurrent test helpers for the loom model checker
+//!
+//! When this crate is built with `RUSTFLAGS="--cfg loom"`, the types of the
+//! `race_cell` module use loom's atomics, so they can be used inside of a
+//! `loom::model()`. This module provides versions of `concurrent_test_2()`
+//! and `concurrent_test_3()` which spawn loom threads, so that loom explores
+//! the possible interleavings of the provided operations.
+//!
+//! Loom has no scoped threads, so the operations must be `'static`. Share
+//! data between them using `loom::sync::Arc`. Since types which use loom's
+//! atomics may only be used inside of a loom model, run your loom tests
+//! separately from other tests, e.g. using a dedicated integration test:
+//!
+//! ```text
+//! RUSTFLAGS="--cfg loom" cargo test --release --test loom
+//! ```
+
+use loom::thread::{self, JoinHandle};
+
+/// Test that running two operations concurrently works, under loom
+///
+/// This must be called inside of a `loom::model()`.
+///
+/// # Panics
+///
+/// This function will propagate panics from the inner functors.
+///
+pub fn concurrent_test_2(f1: impl FnOnce() + Send + 'static, f2: impl FnOnce()) {
+    let thread1 = thread::spawn(f1);
+    f2();
+    join(thread1);
+}
+
+/// Test that running three operations concurrently works, under loom
+///
+/// This must be called inside of a `loom::model()`.
+///
+/// # Panics
+///
+/// This function will propagate panics from the inner functors.
+///
+pub fn concurrent_test_3(
+    f1: impl FnOnce() + Send + 'static,
+    f2: impl FnOnce() + Send + 'static,
+    f3: impl FnOnce(),
+) {
+    let thread1 = thread::spawn(f1);
+    let thread2 = thread::spawn(f2);
+    f3();
+    join(thread1);
+    join(thread2);
+}
+
+/// Wait for a loom thread, propagating its panics
+fn join(thread: JoinHandle<()>) {
+    if let Err(payload) = thread.join() {
+        std::panic::resume_unwind(payload);
+    }
+}
@@ -4,33 +4,459 @@
 //! benchmarking and multi-threaded validation as it leads some testing and
 //! benchmarking constructs to be optimized out. This module can be used to
 //! avoid this outcome without altering the function being called itself.
+//!
+//! Conversely, computations whose result is not used may be optimized out
+//! entirely, and so may stores to memory that is never read. This module
+//! provides two ways to prevent this:
+//!
+//! - `consume()` takes ownership of a value, and keeps the computation that
+//!   produced it alive. Use it on the outputs of a benchmark or antagonist
+//!   closure.
+//! - `observe()` takes a reference to a memory location, and makes the
+//!   compiler believe that the current contents of this location are read.
+//!   Use it after stores that would otherwise be dead, e.g. when an
+//!   antagonist closure repeatedly writes to some memory location. It does
+//!   not actually read the memory location, so it can be used on data which
+//!   other threads are concurrently writing to, such as atomics.
+//!
+//! `core::hint::black_box()`, which is stable since Rust 1.66, has the effect
+//! of both, and additionally hides its output value from the optimizer. But it
+//! is only documented to be a best-effort hint, whereas `consume()` relies on
+//! a volatile read of the value, which the compiler must preserve, and
+//! `observe()` hands the address of the memory location to
+//! `opaque::black_box()`.
+//!
+//! Panics from the callables are propagated, unless the `try_` variants of
+//! the barriers are used, which catch them and return their payload.
+//!
+//! The inlining barriers for callables are `#[track_caller]`. This passes the
+//! location of their call site as an implicit argument, and does not affect
+//! inlining: the barriers remain `#[inline(never)]` function calls. But the
+//! barriers never panic by themselves, and the implicit location is not passed
+//! on when they invoke the callable through the `Fn` traits. So the attribute
+//! does not change what panics and `Location::caller()` report: panics raised
+//! by a closure, and `#[track_caller]` functions that it calls, point to the
+//! code of the closure, never to this module. A `#[track_caller]` function
+//! which is passed directly to a barrier, such as `std::panic::panic_any`,
+//! will report a location inside of the standard library. Wrap it into a
+//! closure in order to report the location of the closure instead.
+//!
+//! # Guarantees
+//!
+//! By default, the inlining barriers for callables and futures are
+//! `#[inline(never)]` functions which call the callable directly. rustc and
+//! LLVM honor `#[inline(never)]` even with link-time optimization, so each use
+//! of a barrier remains a real function call, and costs no more than that.
+//! But this does not keep the optimizer from analyzing the callable across the
+//! call, e.g. to propagate constant arguments into it or to notice that its
+//! result is not used.
+//!
+//! With the `opaque-calls` feature, the barriers additionally call the
+//! callable through a function pointer which goes through
+//! `opaque::black_box()`, which keeps the optimizer from knowing which
+//! function is called, even with link-time optimization. The guarantee is then
+//! as strong as that of `opaque::black_box()`, which is strongest with the
+//! `asm-black-box` feature. But every use of a barrier then pays for an
+//! indirect call, in the antagonist loops that the barriers are meant for, so
+//! this feature should only be enabled when direct calls are not enough.
+//!
+//! `consume()` relies on a volatile read, which the compiler must preserve
+//! whether this function is inlined or not. `observe()` is `#[inline(never)]`
+//! and lets the address of the memory location escape through
+//! `opaque::black_box()`, so callers must assume that the contents of this
+//! location are read. This is as strong as `opaque::black_box()`.
+//!
+//! Only the `try_` variants of the barriers and `block_on_noinline()` need the
+//! standard library. Everything else in this module is implemented in a
+//! submodule which has neither the standard library prelude nor the `std`
+//! crate in scope, so that the compiler checks that it only uses `core`. The
+//! rest of the crate still needs the standard library, though.
+//!
+//! # Async code
+//!
+//! For async code, `NoInline` wraps a future into another future whose `poll()`
+//! method is not inlined, and `block_on_noinline()` runs such a future to
+//! completion on the current thread, without any async runtime.
+
+mod portable;
+
+pub use self::portable::{
+    call, call_mut, call_mut_with, call_once, call_once_with, call_with, consume, observe, NoInline,
+};
+
+use self::portable::opaque_call;
+use std::{
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, Thread},
+};
 
-/// Inlining barrier for FnOnce
+/// Inlining barrier for FnOnce, which catches panics from the inner callable
 ///
-/// # Panics
+/// The result of the inner callable is returned if it did not panic, and the
+/// panic payload is returned otherwise, as with `std::panic::catch_unwind()`.
+///
+/// Unlike `catch_unwind()`, this function does not require the callable to be
+/// `UnwindSafe`, which most closures that capture state by mutable reference
+/// are not. The caller is responsible for not observing any state that a
+/// panic left inconsistent, e.g. by propagating the payload after cleaning up
+/// or by discarding the state that the callable was working on.
 ///
-/// This function will propagate panics from the inner callable.
 #[inline(never)]
-pub fn call_once(callable: impl FnOnce()) {
-    callable()
+#[track_caller]
+pub fn try_call_once<R>(callable: impl FnOnce() -> R) -> thread::Result<R> {
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        opaque_call(callable, (), |callable, ()| callable())
+    }))
 }
 
-/// Inlining barrier for FnMut
+/// Inlining barrier for FnMut, which catches panics from the inner callable
 ///
-/// # Panics
+/// See `try_call_once()` for more information about panic handling.
 ///
-/// This function will propagate panics from the inner callable.
 #[inline(never)]
-pub fn call_mut(callable: &mut impl FnMut()) {
-    callable()
+#[track_caller]
+pub fn try_call_mut<R>(callable: &mut impl FnMut() -> R) -> thread::Result<R> {
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        opaque_call(callable, (), |callable, ()| callable())
+    }))
 }
 
-/// Inlining barrier for Fn
+/// Inlining barrier for Fn, which catches panics from the inner callable
 ///
-/// # Panics
+/// See `try_call_once()` for more information about panic handling.
 ///
-/// This function will propagate panics from the inner callable.
 #[inline(never)]
-pub fn call(callable: &impl Fn()) {
-    callable()
+#[track_caller]
+pub fn try_call<R>(callable: &impl Fn() -> R) -> thread::Result<R> {
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        opaque_call(callable, (), |callable, ()| callable())
+    }))
+}
+
+/// Run a future to completion on the current thread, through an inlining
+/// barrier
+///
+/// This is a minimal executor, which parks the current thread while the
+/// future is pending. It is meant for simple benchmarks of futures which do
+/// not need an async runtime.
+///
+/// # Panics
+///
+/// This function will propagate panics from the future.
+///
+pub fn block_on_noinline<F: Future>(future: F) -> F::Output {
+    let mut future = NoInline::new(future);
+    // Safe because the future is shadowed, so it cannot be moved anymore
+    let mut future = unsafe { Pin::new_unchecked(&mut future) };
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::park();
+    }
+}
+
+/// Waker which unparks a thread
+struct ThreadWaker(Thread);
+//
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.unpark()
+    }
+}
+
+/// Tests of inlining barriers
+#[cfg(test)]
+mod tests {
+    use super::NoInline;
+    use std::{
+        future::Future,
+        panic::{self, Location, UnwindSafe},
+        pin::Pin,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex, PoisonError,
+        },
+        task::{Context, Poll, Wake, Waker},
+        thread,
+    };
+
+    // Results of the inner callables should be returned
+    #[test]
+    fn return_values() {
+        let owned = String::from("hello");
+        assert_eq!(super::call_once(move || owned), "hello");
+
+        let mut counter = 0;
+        let mut increment = || {
+            counter += 1;
+            counter
+        };
+        assert_eq!(super::call_mut(&mut increment), 1);
+        assert_eq!(super::call_mut(&mut increment), 2);
+
+        let double = |x: u32| x * 2;
+        assert_eq!(super::call(&|| double(21)), 42);
+    }
+
+    // Unit-returning callables should work as before
+    #[test]
+    fn unit_return() {
+        let mut called = false;
+        super::call_once(|| called = true);
+        assert!(called);
+    }
+
+    // Arguments should be passed through the barriers
+    #[test]
+    fn arguments() {
+        let owned = String::from("hello");
+        assert_eq!(super::call_once_with(|s: String| s.len(), owned), 5);
+
+        let mut total = 0;
+        let mut accumulate = |x: u32| {
+            total += x;
+            total
+        };
+        assert_eq!(super::call_mut_with(&mut accumulate, 2), 2);
+        assert_eq!(super::call_mut_with(&mut accumulate, 3), 5);
+
+        assert_eq!(super::call_with(&|x: u32| x * 2, 21), 42);
+    }
+
+    // Argument-passing barriers are meant to be used in benchmark loops, where
+    // the argument changes on every iteration
+    #[test]
+    fn contended_benchmark() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        const ITERATIONS: u64 = 1000;
+        let shared = AtomicU64::new(0);
+        let mut antagonist_input = 0;
+        let mut antagonist = |x: u64| shared.store(x, Ordering::Relaxed);
+        let square = |x: u64| x * x;
+        let sum = crate::run_under_contention(
+            || {
+                antagonist_input += 1;
+                super::call_mut_with(&mut antagonist, antagonist_input)
+            },
+            || {
+                (0..ITERATIONS)
+                    .map(|i| super::call_with(&square, i))
+                    .sum::<u64>()
+            },
+        );
+        assert_eq!(sum, (0..ITERATIONS).map(|i| i * i).sum::<u64>());
+        assert_eq!(shared.load(Ordering::Relaxed), antagonist_input);
+    }
+
+    // Consumed values should be dropped exactly once
+    #[test]
+    fn consume() {
+        use std::rc::Rc;
+        let shared = Rc::new(42);
+        super::consume(shared.clone());
+        assert_eq!(Rc::strong_count(&shared), 1);
+        super::consume(());
+        super::consume([0u8; 4096]);
+    }
+
+    // Observed values should be left untouched
+    #[test]
+    fn observe() {
+        let value = (1u8, 2u64);
+        super::observe(&value);
+        assert_eq!(value, (1, 2));
+        super::observe(&());
+        super::observe(&[0u8; 4096]);
+        super::observe(&String::from("hello"));
+    }
+
+    // Observing an atomic which another thread is storing to should be fine
+    #[test]
+    fn observe_concurrent_stores() {
+        use std::sync::atomic::AtomicU64;
+        let iterations = crate::scale_iters(100_000) as u64;
+        let target = AtomicU64::new(0);
+        crate::concurrent_test_2(
+            || {
+                for i in 1..=iterations {
+                    target.store(i, Ordering::Relaxed);
+                }
+            },
+            || {
+                for _ in 0..iterations {
+                    super::observe(&target);
+                }
+            },
+        );
+        assert_eq!(target.load(Ordering::Relaxed), iterations);
+    }
+
+    /// Future which is pending on its first poll, waking its waker and
+    /// recording it, then ready with some value
+    struct PendingOnce {
+        waker: Option<Waker>,
+        value: u32,
+    }
+    //
+    impl Future for PendingOnce {
+        type Output = u32;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u32> {
+            if self.waker.is_some() {
+                Poll::Ready(self.value)
+            } else {
+                self.waker = Some(cx.waker().clone());
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Waker which counts how many times it was woken up
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+    //
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.wake_by_ref()
+        }
+
+        fn wake_by_ref(self: &Arc<Self>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // NoInline should forward polls, results and wakers to the inner future
+    #[test]
+    fn no_inline_future() {
+        let counter = Arc::new(CountingWaker::default());
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut future = NoInline::new(PendingOnce {
+            waker: None,
+            value: 42,
+        });
+
+        assert_eq!(Pin::new(&mut future).poll(&mut cx), Poll::Pending);
+        assert_eq!(counter.0.load(Ordering::Relaxed), 1);
+        let inner = future.into_inner();
+        inner.waker.as_ref().unwrap().wake_by_ref();
+        assert_eq!(counter.0.load(Ordering::Relaxed), 2);
+
+        let mut future = NoInline::new(inner);
+        assert_eq!(Pin::new(&mut future).poll(&mut cx), Poll::Ready(42));
+    }
+
+    // block_on_noinline should run futures to completion
+    #[test]
+    fn block_on() {
+        assert_eq!(super::block_on_noinline(async { 24 }), 24);
+        let future = PendingOnce {
+            waker: None,
+            value: 42,
+        };
+        assert_eq!(super::block_on_noinline(future), 42);
+    }
+
+    /// Lock which must be held by tests that replace the panic hook
+    static PANIC_HOOK: Mutex<()> = Mutex::new(());
+
+    /// Run a function, recording the file and line of panics that it raises
+    ///
+    /// Panics from other threads are forwarded to the previous panic hook.
+    ///
+    fn record_panic_location<R>(
+        f: impl FnOnce() -> R + UnwindSafe,
+    ) -> (thread::Result<R>, Option<(String, u32)>) {
+        let _lock = PANIC_HOOK.lock().unwrap_or_else(PoisonError::into_inner);
+        let test_thread = thread::current().id();
+        let location = Arc::new(Mutex::new(None));
+        let previous_hook = Arc::new(panic::take_hook());
+        panic::set_hook({
+            let location = location.clone();
+            let previous_hook = previous_hook.clone();
+            Box::new(move |info| {
+                if thread::current().id() == test_thread {
+                    *location.lock().unwrap() =
+                        info.location().map(|l| (l.file().to_owned(), l.line()));
+                } else {
+                    previous_hook(info)
+                }
+            })
+        });
+        let result = panic::catch_unwind(f);
+        // Other threads may still be running the hook, so the previous hook
+        // is reinstalled behind a forwarding hook instead of being unwrapped
+        drop(panic::take_hook());
+        panic::set_hook(Box::new(move |info| previous_hook(info)));
+        let location = location.lock().unwrap().take();
+        (result, location)
+    }
+
+    // Panics and caller locations should point to user code, not to the
+    // inlining barriers
+    #[test]
+    fn caller_location() {
+        #[track_caller]
+        fn caller() -> &'static Location<'static> {
+            Location::caller()
+        }
+        assert_eq!(super::call_once(|| caller()).file(), file!());
+        assert_eq!(super::call_mut(&mut || caller()).file(), file!());
+        assert_eq!(super::call(&|| caller()).file(), file!());
+        assert_eq!(super::call_with(&|()| caller(), ()).file(), file!());
+
+        let (result, location) =
+            record_panic_location(|| super::call_once(|| panic::panic_any(42u32)));
+        let line = line!() - 1;
+        assert_eq!(result.unwrap_err().downcast_ref::<u32>(), Some(&42));
+        assert_eq!(location, Some((file!().to_owned(), line)));
+    }
+
+    // Unwind-catching barriers should return results or panic payloads
+    #[test]
+    fn try_call() {
+        assert_eq!(super::try_call_once(|| 42).unwrap(), 42);
+        let payload = super::try_call_once(|| panic::panic_any(24u32)).unwrap_err();
+        assert_eq!(payload.downcast_ref::<u32>(), Some(&24));
+
+        let mut calls = 0;
+        let mut fail_second = || {
+            calls += 1;
+            assert!(calls < 2, "second call");
+            calls
+        };
+        assert_eq!(super::try_call_mut(&mut fail_second).unwrap(), 1);
+        let payload = super::try_call_mut(&mut fail_second).unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"second call"));
+        assert_eq!(calls, 2);
+
+        assert_eq!(super::try_call(&|| "ok").unwrap(), "ok");
+        let payload = super::try_call(&|| -> u8 { panic!("{}", 42) }).unwrap_err();
+        assert_eq!(payload.downcast_ref::<String>().unwrap(), "42");
+    }
+
+    // Inlining barriers should be usable in recursive code
+    #[test]
+    fn recursion() {
+        fn factorial(n: u64) -> u64 {
+            if n == 0 {
+                1
+            } else {
+                n * super::call(&|| factorial(n - 1))
+            }
+        }
+        assert_eq!(factorial(10), 3_628_800);
+    }
 }
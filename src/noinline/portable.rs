@@ -0,0 +1,190 @@
+//! Inlining barriers which only need `core`
+//!
+//! This module does not have the standard library prelude, nor the `std`
+//! crate, in scope. Code which needs the standard library goes into the parent
+//! module instead, so that these barriers remain usable without it.
+
+#![no_implicit_prelude]
+
+use crate::opaque;
+use ::core::{
+    default::Default,
+    fmt::Debug,
+    future::Future,
+    mem::{self, ManuallyDrop},
+    ops::{Fn, FnMut, FnOnce},
+    pin::Pin,
+    ptr,
+    task::{Context, Poll},
+};
+
+/// Inlining barrier for FnOnce, returning the result of the inner callable
+///
+/// # Panics
+///
+/// This function will propagate panics from the inner callable.
+#[inline(never)]
+#[track_caller]
+pub fn call_once<R>(callable: impl FnOnce() -> R) -> R {
+    opaque_call(callable, (), |callable, ()| callable())
+}
+
+/// Inlining barrier for FnMut, returning the result of the inner callable
+///
+/// # Panics
+///
+/// This function will propagate panics from the inner callable.
+#[inline(never)]
+#[track_caller]
+pub fn call_mut<R>(callable: &mut impl FnMut() -> R) -> R {
+    opaque_call(callable, (), |callable, ()| callable())
+}
+
+/// Inlining barrier for Fn, returning the result of the inner callable
+///
+/// # Panics
+///
+/// This function will propagate panics from the inner callable.
+#[inline(never)]
+#[track_caller]
+pub fn call<R>(callable: &impl Fn() -> R) -> R {
+    opaque_call(callable, (), |callable, ()| callable())
+}
+
+/// Inlining barrier for FnOnce, passing an argument through the barrier
+///
+/// This avoids constructing a fresh closure capturing the argument on every
+/// call, which would let the optimizer see where the argument comes from.
+///
+/// # Panics
+///
+/// This function will propagate panics from the inner callable.
+#[inline(never)]
+#[track_caller]
+pub fn call_once_with<A, R>(callable: impl FnOnce(A) -> R, arg: A) -> R {
+    opaque_call(callable, arg, |callable, arg| callable(arg))
+}
+
+/// Inlining barrier for FnMut, passing an argument through the barrier
+///
+/// This is the recommended way to call antagonists which need a different
+/// input on every iteration, such as a counter or a random number, since the
+/// optimizer cannot see that the input is e.g. an induction variable and turn
+/// the loop of calls into a closed-form computation.
+///
+/// The argument is passed by value, which is the point: the callable cannot
+/// tell where it came from. But large arguments are then copied on every call.
+/// If this copy is too expensive, pass a reference instead, keeping in mind
+/// that the optimizer may then see through loads from the referenced data
+/// that do not change from one call to the next.
+///
+/// # Panics
+///
+/// This function will propagate panics from the inner callable.
+#[inline(never)]
+#[track_caller]
+pub fn call_mut_with<A, R>(callable: &mut impl FnMut(A) -> R, arg: A) -> R {
+    opaque_call(callable, arg, |callable, arg| callable(arg))
+}
+
+/// Inlining barrier for Fn, passing an argument through the barrier
+///
+/// # Panics
+///
+/// This function will propagate panics from the inner callable.
+#[inline(never)]
+#[track_caller]
+pub fn call_with<A, R>(callable: &impl Fn(A) -> R, arg: A) -> R {
+    opaque_call(callable, arg, |callable, arg| callable(arg))
+}
+
+/// Call a function through a pointer that the optimizer cannot see through
+///
+/// Since the optimizer does not know which function is called, it can neither
+/// inline it, even with link-time optimization, nor specialize it for its
+/// arguments.
+///
+#[cfg(feature = "opaque-calls")]
+#[inline(always)]
+pub(super) fn opaque_call<F, A, R>(callable: F, arg: A, invoke: fn(F, A) -> R) -> R {
+    opaque::black_box(invoke)(callable, arg)
+}
+
+/// Call a function directly
+///
+/// Without the `opaque-calls` feature, the inlining barriers rely on being
+/// `#[inline(never)]` alone, and this is inlined into a direct call.
+///
+#[cfg(not(feature = "opaque-calls"))]
+#[inline(always)]
+pub(super) fn opaque_call<F, A, R>(callable: F, arg: A, invoke: fn(F, A) -> R) -> R {
+    invoke(callable, arg)
+}
+
+/// Output sink, which keeps the computation of a value from being optimized
+/// out
+///
+/// The value is read using a volatile read, which the compiler must assume to
+/// have side effects, so it must actually compute the value. This is a
+/// stable Rust emulation of `std::hint::black_box()`, which is cheap enough
+/// to be called on every iteration of a benchmark.
+///
+#[inline(never)]
+pub fn consume<T>(value: T) {
+    let value = ManuallyDrop::new(value);
+    // Safe because the value is valid, and only the copy is dropped
+    mem::drop(unsafe { ptr::read_volatile(&*value) });
+}
+
+/// Read barrier, which makes the compiler believe that some unknown code read
+/// the current contents of a memory location
+///
+/// Stores to this memory location that precede this call thus cannot be
+/// optimized out. Only the address of the memory location goes through
+/// `opaque::black_box()`, and its contents are never actually read. So the
+/// cost of this function does not depend on the size of the value, and it can
+/// be used on memory that other threads are concurrently writing to, such as
+/// an atomic variable or a `RaceCell`.
+///
+#[inline(never)]
+pub fn observe<T>(value: &T) {
+    let value: *const T = value;
+    opaque::black_box(value);
+}
+
+/// Inlining barrier for futures
+///
+/// This future forwards to the inner future, but its `poll()` method is never
+/// inlined into its caller, which is usually the executor loop of an async
+/// benchmark.
+///
+#[derive(Debug, Default)]
+pub struct NoInline<F>(F);
+//
+impl<F: Future> NoInline<F> {
+    /// Wrap a future into an inlining barrier
+    pub fn new(future: F) -> Self {
+        Self(future)
+    }
+
+    /// Extract the inner future
+    pub fn into_inner(self) -> F {
+        self.0
+    }
+}
+//
+impl<F: Future> Future for NoInline<F> {
+    type Output = F::Output;
+
+    /// # Panics
+    ///
+    /// This function will propagate panics from the inner future.
+    #[inline(never)]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        // Safe because the inner future is pinned whenever NoInline is: it is
+        // never moved out of a pinned NoInline, which has no Drop impl and is
+        // only Unpin if the inner future is.
+        let inner = unsafe { self.map_unchecked_mut(|this| &mut this.0) };
+        opaque_call(inner, cx, |inner, cx| inner.poll(cx))
+    }
+}
@@ -0,0 +1,100 @@
+//! Optimization barriers for values
+//!
+//! `black_box()` returns its input unchanged, but the compiler cannot tell
+//! what the output is, nor that the input was not used. In benchmarks, it
+//! keeps constant inputs from being constant-folded, and unused outputs from
+//! being optimized out.
+//!
+//! `core::hint::black_box()` does the same, but it requires Rust 1.66, and
+//! is only documented to be a best-effort hint. So by default, this module's
+//! `black_box()` is emulated using a volatile read of the value, which the
+//! compiler must preserve, as the Criterion benchmarking crate used to do
+//! before the hint was stabilized.
+//!
+//! With the `asm-black-box` feature, on x86, ARM and RISC-V CPUs, it is
+//! instead implemented as an empty inline assembly block, which the compiler
+//! must assume to read and modify the value through its address. This is
+//! stronger than the volatile read, as the compiler cannot assume that the
+//! output is equal to the input, and it does not require copying the value.
+
+/// Return the input, in a way that the compiler cannot see through
+#[inline]
+pub fn black_box<T>(value: T) -> T {
+    backend::black_box(value)
+}
+
+/// Inline assembly implementation of black_box
+#[cfg(all(
+    feature = "asm-black-box",
+    any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64",
+        target_arch = "riscv32",
+        target_arch = "riscv64"
+    )
+))]
+mod backend {
+    use core::{arch::asm, ptr};
+
+    #[inline]
+    pub(super) fn black_box<T>(mut value: T) -> T {
+        // Safe because the assembly block is empty. It only receives the
+        // address of the value, which is assumed to be read and written since
+        // the assembly block is not marked as `nomem` or `readonly`.
+        unsafe {
+            asm!(
+                "/* {0} */",
+                in(reg) ptr::addr_of_mut!(value),
+                options(nostack, preserves_flags)
+            )
+        };
+        value
+    }
+}
+
+/// Volatile read implementation of black_box
+#[cfg(not(all(
+    feature = "asm-black-box",
+    any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64",
+        target_arch = "riscv32",
+        target_arch = "riscv64"
+    )
+)))]
+mod backend {
+    use core::{mem::ManuallyDrop, ptr};
+
+    #[inline]
+    pub(super) fn black_box<T>(value: T) -> T {
+        let value = ManuallyDrop::new(value);
+        // Safe because the value is valid, and only the copy is dropped
+        unsafe { ptr::read_volatile(&*value) }
+    }
+}
+
+/// Tests of optimization barriers
+#[cfg(test)]
+mod tests {
+    use super::black_box;
+    use std::rc::Rc;
+
+    // Values should go through the barrier unchanged, and be dropped once
+    #[test]
+    fn identity() {
+        assert_eq!(black_box(42u8), 42);
+        assert_eq!(black_box([1u64, 2, 3]), [1, 2, 3]);
+        assert_eq!(black_box(String::from("hello")), "hello");
+        black_box(());
+
+        let shared = Rc::new(0);
+        let copy = black_box(shared.clone());
+        assert_eq!(Rc::strong_count(&shared), 2);
+        drop(copy);
+        assert_eq!(Rc::strong_count(&shared), 1);
+    }
+}
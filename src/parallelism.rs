@@ -0,0 +1,155 @@
+//! Detection of the number of threads that can usefully run in parallel
+
+use std::{
+    env,
+    ffi::OsStr,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Once,
+    },
+};
+
+/// Name of the environment variable that overrides `parallelism()`
+const THREADS_VAR: &str = "TESTBENCH_THREADS";
+
+/// Number of threads that concurrent tests and benchmarks should expect to
+/// run in parallel
+///
+/// This starts from `std::thread::available_parallelism()`, which is 1 if
+/// unknown. On Linux, it is further capped by the CPU quota of the process'
+/// cgroup, where one is set and can be read from `/sys/fs/cgroup`, because
+/// older Rust versions do not account for it. This matters in CI containers,
+/// which often report all the CPUs of the host but only let the process use a
+/// few of them.
+///
+/// Setting the `TESTBENCH_THREADS` environment variable to a positive integer
+/// overrides this detection, which is useful to reproduce a failure observed
+/// on another machine. Values which are zero or not integers are ignored.
+///
+/// The result is computed on first use and cached afterwards, so changing the
+/// environment variable after that has no effect.
+///
+pub fn parallelism() -> NonZeroUsize {
+    static DETECTION: Once = Once::new();
+    static PARALLELISM: AtomicUsize = AtomicUsize::new(1);
+    DETECTION.call_once(|| {
+        let parallelism = detect(env::var_os(THREADS_VAR).as_deref());
+        PARALLELISM.store(parallelism.get(), Ordering::Relaxed);
+    });
+    NonZeroUsize::new(PARALLELISM.load(Ordering::Relaxed))
+        .expect("Parallelism is initialized to a nonzero value")
+}
+
+/// Detect parallelism, given the value of the override environment variable
+fn detect(env_override: Option<&OsStr>) -> NonZeroUsize {
+    if let Some(threads) = env_override.and_then(parse_override) {
+        return threads;
+    }
+    let available = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    let parallelism = match cgroup_quota() {
+        Some(quota) => available.min(quota.get()),
+        None => available,
+    };
+    NonZeroUsize::new(parallelism.max(1)).expect("Parallelism was clamped to be nonzero")
+}
+
+/// Parse the value of the override environment variable, if valid
+fn parse_override(value: &OsStr) -> Option<NonZeroUsize> {
+    value
+        .to_str()?
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .and_then(NonZeroUsize::new)
+}
+
+/// Number of CPUs allowed by the cgroup CPU quota, if any
+///
+/// This is not queried under Miri, whose isolation forbids file access.
+///
+#[cfg(all(target_os = "linux", not(miri)))]
+fn cgroup_quota() -> Option<NonZeroUsize> {
+    use std::fs;
+    if let Ok(cpu_max) = fs::read_to_string("/sys/fs/cgroup/cpu.max") {
+        return parse_cgroup_v2(&cpu_max);
+    }
+    ["/sys/fs/cgroup/cpu", "/sys/fs/cgroup/cpu,cpuacct"]
+        .iter()
+        .find_map(|dir| {
+            let quota = fs::read_to_string(format!("{dir}/cpu.cfs_quota_us")).ok()?;
+            let period = fs::read_to_string(format!("{dir}/cpu.cfs_period_us")).ok()?;
+            parse_cgroup_v1(&quota, &period)
+        })
+}
+//
+#[cfg(any(not(target_os = "linux"), miri))]
+fn cgroup_quota() -> Option<NonZeroUsize> {
+    None
+}
+
+/// Parse the contents of a cgroup v2 `cpu.max` file, e.g. "200000 100000"
+#[cfg_attr(any(not(target_os = "linux"), miri), allow(dead_code))]
+fn parse_cgroup_v2(cpu_max: &str) -> Option<NonZeroUsize> {
+    let mut fields = cpu_max.split_whitespace();
+    let quota = fields.next()?;
+    let period = fields.next().unwrap_or("100000");
+    parse_cgroup_v1(quota, period)
+}
+
+/// Parse the contents of cgroup v1 `cpu.cfs_quota_us` and `cpu.cfs_period_us`
+/// files, where a quota of "-1" (v1) or "max" (v2) means unlimited
+///
+/// Fractional quotas are rounded up, as a process which is allowed 1.5 CPUs
+/// can still have 2 threads running in parallel.
+///
+#[cfg_attr(any(not(target_os = "linux"), miri), allow(dead_code))]
+fn parse_cgroup_v1(quota: &str, period: &str) -> Option<NonZeroUsize> {
+    let quota = quota.trim().parse::<u64>().ok()?;
+    let period = period.trim().parse::<u64>().ok().filter(|&p| p > 0)?;
+    let cpus = quota.saturating_add(period - 1) / period;
+    NonZeroUsize::new((cpus.min(usize::MAX as u64) as usize).max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn threads(count: usize) -> NonZeroUsize {
+        NonZeroUsize::new(count).unwrap()
+    }
+
+    #[test]
+    fn env_override() {
+        assert_eq!(detect(Some(OsStr::new("3"))), threads(3));
+        assert_eq!(detect(Some(OsStr::new(" 42\n"))), threads(42));
+        assert_eq!(detect(Some(OsStr::new("1"))), threads(1));
+    }
+
+    #[test]
+    fn invalid_env_override() {
+        let detected = detect(None);
+        for value in ["", "0", "-2", "1.5", "many", "4 threads"] {
+            assert_eq!(detect(Some(OsStr::new(value))), detected, "{value:?}");
+        }
+    }
+
+    #[test]
+    fn cached_parallelism() {
+        let parallelism = parallelism();
+        assert_eq!(parallelism, detect(env::var_os(THREADS_VAR).as_deref()));
+        assert_eq!(parallelism, super::parallelism());
+    }
+
+    #[test]
+    fn cgroup_parsing() {
+        assert_eq!(parse_cgroup_v2("max 100000\n"), None);
+        assert_eq!(parse_cgroup_v2("200000 100000\n"), Some(threads(2)));
+        assert_eq!(parse_cgroup_v2("150000 100000\n"), Some(threads(2)));
+        assert_eq!(parse_cgroup_v2("1000 100000\n"), Some(threads(1)));
+        assert_eq!(parse_cgroup_v2(""), None);
+        assert_eq!(parse_cgroup_v1("-1\n", "100000\n"), None);
+        assert_eq!(parse_cgroup_v1("400000\n", "100000\n"), Some(threads(4)));
+        assert_eq!(parse_cgroup_v1("400000\n", "0\n"), None);
+    }
+}
@@ -0,0 +1,259 @@
+//! Hardware and software performance counters (Linux only)
+//!
+//! Wall-clock time tells how much a benchmark was slowed down by contention,
+//! but not why. Performance counters such as CPU cycles, cache misses and
+//! context switches help answering this question.
+//!
+//! This module is a thin layer over the Linux `perf_event_open` system call.
+//! Counters only cover the thread that opened them, and hardware counters only
+//! measure activity in user mode, so that they can be used by unprivileged
+//! processes on systems where `/proc/sys/kernel/perf_event_paranoid` is 2 or
+//! less.
+
+use std::{
+    fmt, io,
+    mem::size_of,
+    os::raw::{c_int, c_ulong},
+};
+
+/// Performance counter that can be measured
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum PerfCounter {
+    /// CPU cycles
+    Cycles,
+
+    /// Retired instructions
+    Instructions,
+
+    /// Cache misses, which usually means last-level cache misses
+    CacheMisses,
+
+    /// Context switches
+    ContextSwitches,
+}
+//
+impl PerfCounter {
+    /// Event type and configuration, as understood by perf_event_open
+    fn type_and_config(self) -> (u32, u64) {
+        const PERF_TYPE_HARDWARE: u32 = 0;
+        const PERF_TYPE_SOFTWARE: u32 = 1;
+        match self {
+            Self::Cycles => (PERF_TYPE_HARDWARE, 0),
+            Self::Instructions => (PERF_TYPE_HARDWARE, 1),
+            Self::CacheMisses => (PERF_TYPE_HARDWARE, 3),
+            Self::ContextSwitches => (PERF_TYPE_SOFTWARE, 3),
+        }
+    }
+}
+//
+impl fmt::Display for PerfCounter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Cycles => "cycles",
+            Self::Instructions => "instructions",
+            Self::CacheMisses => "cache misses",
+            Self::ContextSwitches => "context switches",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Set of performance counters monitoring the thread that created it
+///
+/// Counters are created in a disabled state. Use `start()` to reset and
+/// enable them, `stop()` to disable them, and `read()` to query their values.
+///
+#[derive(Debug)]
+pub struct PerfCounters {
+    /// Performance counters and associated file descriptors
+    counters: Vec<(PerfCounter, c_int)>,
+}
+//
+impl PerfCounters {
+    /// Open performance counters for the current thread
+    ///
+    /// This will fail if the process is not allowed to open performance
+    /// counters, or if the hardware does not support some of them.
+    ///
+    pub fn open(counters: &[PerfCounter]) -> Result<Self, PerfError> {
+        let mut result = Self {
+            counters: Vec::with_capacity(counters.len()),
+        };
+        for &counter in counters {
+            let fd = open_counter(counter).map_err(|error| PerfError { counter, error })?;
+            result.counters.push((counter, fd));
+        }
+        Ok(result)
+    }
+
+    /// Reset the counters to zero and start counting
+    pub fn start(&self) {
+        for &(_, fd) in &self.counters {
+            // Safe because fd is a valid perf event file descriptor
+            unsafe {
+                libc::ioctl(fd, PERF_EVENT_IOC_RESET, 0);
+                libc::ioctl(fd, PERF_EVENT_IOC_ENABLE, 0);
+            }
+        }
+    }
+
+    /// Stop counting
+    pub fn stop(&self) {
+        for &(_, fd) in &self.counters {
+            // Safe because fd is a valid perf event file descriptor
+            unsafe { libc::ioctl(fd, PERF_EVENT_IOC_DISABLE, 0) };
+        }
+    }
+
+    /// Read the current counter values
+    ///
+    /// Counters whose value cannot be read are reported as zero.
+    ///
+    pub fn read(&self) -> Vec<(PerfCounter, u64)> {
+        self.counters
+            .iter()
+            .map(|&(counter, fd)| {
+                let mut value = 0u64;
+                let buffer: *mut u64 = &mut value;
+                // Safe because fd is valid and value is a valid 8-byte buffer
+                let bytes = unsafe { libc::read(fd, buffer.cast(), size_of::<u64>()) };
+                if bytes != size_of::<u64>() as isize {
+                    value = 0;
+                }
+                (counter, value)
+            })
+            .collect()
+    }
+}
+//
+impl Drop for PerfCounters {
+    fn drop(&mut self) {
+        for &(_, fd) in &self.counters {
+            // Safe because we own the file descriptor
+            unsafe { libc::close(fd) };
+        }
+    }
+}
+
+/// Failure to open a performance counter
+#[derive(Debug)]
+pub struct PerfError {
+    /// Performance counter that could not be opened
+    pub counter: PerfCounter,
+
+    /// Underlying operating system error
+    pub error: io::Error,
+}
+//
+impl fmt::Display for PerfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to open {} counter: {}", self.counter, self.error)
+    }
+}
+//
+impl std::error::Error for PerfError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+// ioctl requests for perf event file descriptors
+const PERF_EVENT_IOC_ENABLE: libc::Ioctl = 0x2400;
+const PERF_EVENT_IOC_DISABLE: libc::Ioctl = 0x2401;
+const PERF_EVENT_IOC_RESET: libc::Ioctl = 0x2403;
+
+/// First published version of the perf_event_attr struct (PERF_ATTR_SIZE_VER0)
+///
+/// The kernel accepts this older layout, which is all we need.
+///
+#[repr(C)]
+struct PerfEventAttr {
+    type_: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+}
+
+/// Open a performance counter for the current thread, in a disabled state
+fn open_counter(counter: PerfCounter) -> io::Result<c_int> {
+    const DISABLED: u64 = 1 << 0;
+    const EXCLUDE_KERNEL: u64 = 1 << 5;
+    const EXCLUDE_HV: u64 = 1 << 6;
+    const PERF_FLAG_FD_CLOEXEC: c_ulong = 1 << 3;
+
+    let (type_, config) = counter.type_and_config();
+    // Context switches happen in kernel mode, so they are only counted if
+    // kernel activity is not excluded, which is allowed for software events.
+    let flags = if counter == PerfCounter::ContextSwitches {
+        DISABLED
+    } else {
+        DISABLED | EXCLUDE_KERNEL | EXCLUDE_HV
+    };
+    let attr = PerfEventAttr {
+        type_,
+        size: size_of::<PerfEventAttr>() as u32,
+        config,
+        sample_period: 0,
+        sample_type: 0,
+        read_format: 0,
+        flags,
+        wakeup_events: 0,
+        bp_type: 0,
+        config1: 0,
+    };
+    let attr: *const PerfEventAttr = &attr;
+    let (this_thread, any_cpu, no_group): (libc::pid_t, c_int, c_int) = (0, -1, -1);
+    // Safe because attr points to a valid perf_event_attr
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_perf_event_open,
+            attr,
+            this_thread,
+            any_cpu,
+            no_group,
+            PERF_FLAG_FD_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(fd as c_int)
+    }
+}
+
+/// Tests of performance counters
+#[cfg(test)]
+mod tests {
+    use super::{PerfCounter, PerfCounters};
+
+    // Counters should count something, if they can be opened at all
+    #[test]
+    fn smoke_test() {
+        let counters = match PerfCounters::open(&[PerfCounter::Instructions]) {
+            Ok(counters) => counters,
+            Err(error) => {
+                println!("Skipping test: {}", error);
+                return;
+            }
+        };
+        counters.start();
+        let mut sum = 0u64;
+        for i in 0..100_000u64 {
+            // Safe because this is a read from a valid local variable
+            sum = sum.wrapping_add(unsafe { std::ptr::read_volatile(&i) });
+        }
+        counters.stop();
+        assert_ne!(sum, 0);
+        let values = counters.read();
+        assert_eq!(values.len(), 1);
+        assert_eq!(values[0].0, PerfCounter::Instructions);
+        assert!(values[0].1 > 0);
+    }
+}
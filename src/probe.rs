@@ -0,0 +1,196 @@
+//! Probes of the hardware capabilities that contention benchmarks depend on
+//!
+//! Results obtained under a resource-hogging antagonist are easier to
+//! interpret when one knows how much of the resource the machine has. This
+//! module measures such capabilities, currently the memory bandwidth, using
+//! the same kernels as the stock antagonists of the `contenders` module.
+//!
+//! ```
+//! use std::time::Duration;
+//! use testbench::probe;
+//!
+//! let report = probe::memory_bandwidth(1 << 20, Duration::from_millis(10), 1);
+//! println!("Memory bandwidth: {:.1} GB/s", report.aggregate);
+//! assert!(report.aggregate > 0.0);
+//! ```
+
+use crate::{noinline, opaque, timing};
+use std::{mem::size_of, sync::Barrier, thread, time::Duration};
+
+/// Default size of the buffer streamed by each thread, in bytes
+///
+/// This is larger than the last-level cache of most machines, so that the
+/// buffer is streamed from main memory.
+///
+pub const DEFAULT_BUFFER_SIZE: usize = 64 << 20;
+
+/// Measure how fast some threads can stream data from memory
+///
+/// Each of the `threads` threads allocates its own buffer of `buffer_size`
+/// bytes, rounded down to a multiple of 8, and fills it so that its pages are
+/// placed on the NUMA node of the thread that uses them. Once all threads are
+/// ready, each thread reads through its whole buffer repeatedly, until
+/// `duration` has elapsed.
+///
+/// The buffer must be much larger than the last-level cache for this to
+/// measure the memory bandwidth, see `DEFAULT_BUFFER_SIZE`.
+///
+/// # Panics
+///
+/// This function panics if `buffer_size` is smaller than 8 or `threads` is
+/// zero.
+///
+pub fn memory_bandwidth(buffer_size: usize, duration: Duration, threads: usize) -> BandwidthReport {
+    assert!(
+        buffer_size >= size_of::<u64>(),
+        "The buffer must hold at least one u64"
+    );
+    assert!(threads > 0, "At least one thread is needed");
+    let barrier = Barrier::new(threads);
+    let per_thread = thread::scope(|s| {
+        let workers = (0..threads)
+            .map(|_| {
+                s.spawn(|| {
+                    let buffer = filled_buffer(buffer_size / size_of::<u64>());
+                    barrier.wait();
+                    let start = timing::now();
+                    let mut passes = 0u64;
+                    let elapsed = loop {
+                        noinline::consume(read_kernel(&buffer));
+                        passes += 1;
+                        let elapsed = start.elapsed();
+                        if elapsed >= duration {
+                            break elapsed;
+                        }
+                    };
+                    let bytes = passes * (buffer.len() * size_of::<u64>()) as u64;
+                    bytes as f64 / elapsed.as_secs_f64() / 1e9
+                })
+            })
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("Bandwidth probes should not panic"))
+            .collect::<Vec<_>>()
+    });
+    BandwidthReport {
+        threads,
+        buffer_size,
+        aggregate: per_thread.iter().sum(),
+        per_thread,
+    }
+}
+
+/// Measure the memory bandwidth with 1 to `max_threads` threads
+///
+/// This calls `memory_bandwidth()` for each thread count, and returns the
+/// reports in order of increasing thread count. It tells how many threads it
+/// takes to saturate the memory bandwidth, and thus how close a given number
+/// of bandwidth-hogging antagonists gets to saturation.
+///
+/// # Panics
+///
+/// This function panics under the same conditions as `memory_bandwidth()`.
+///
+pub fn memory_bandwidth_sweep(
+    buffer_size: usize,
+    duration: Duration,
+    max_threads: usize,
+) -> Vec<BandwidthReport> {
+    assert!(max_threads > 0, "At least one thread is needed");
+    (1..=max_threads)
+        .map(|threads| memory_bandwidth(buffer_size, duration, threads))
+        .collect()
+}
+
+/// Results of a memory bandwidth measurement
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct BandwidthReport {
+    /// Number of threads that streamed data concurrently
+    pub threads: usize,
+
+    /// Size of the buffer streamed by each thread, in bytes
+    pub buffer_size: usize,
+
+    /// Bandwidth achieved by each thread, in GB/s
+    pub per_thread: Vec<f64>,
+
+    /// Total bandwidth achieved by all threads, in GB/s
+    pub aggregate: f64,
+}
+
+/// Allocate a buffer of `len` u64s and write to all of it
+///
+/// Writing every element ensures that the pages of the buffer are actually
+/// allocated by the calling thread, rather than mapped to the shared zero page
+/// as freshly allocated memory usually is until it is written to.
+///
+pub(crate) fn filled_buffer(len: usize) -> Vec<u64> {
+    (0..len as u64).collect()
+}
+
+/// Read through a whole buffer, returning a value that depends on all of it
+///
+/// The buffer goes through `opaque::black_box()`, so the compiler cannot
+/// assume anything about its contents, nor skip reading it.
+///
+#[inline(never)]
+pub(crate) fn read_kernel(buffer: &[u64]) -> u64 {
+    opaque::black_box(buffer)
+        .iter()
+        .fold(0, |sum, &value| sum.wrapping_add(value))
+}
+
+/// Tests of hardware probes
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Buffer size used by the tests, which is kept small to keep them fast
+    fn buffer_size() -> usize {
+        crate::scale_iters(8 << 20)
+    }
+
+    // The kernel should read the whole buffer
+    #[test]
+    fn kernel() {
+        let buffer = filled_buffer(1000);
+        assert_eq!(read_kernel(&buffer), 999 * 1000 / 2);
+    }
+
+    // Bandwidth measurements should yield plausible positive numbers
+    #[test]
+    fn plausible_bandwidth() {
+        let report = memory_bandwidth(buffer_size(), Duration::from_millis(20), 2);
+        assert_eq!(report.threads, 2);
+        assert_eq!(report.per_thread.len(), 2);
+        for &bandwidth in &report.per_thread {
+            assert!(bandwidth.is_finite() && bandwidth > 0.0);
+        }
+        let sum = report.per_thread.iter().sum::<f64>();
+        assert!((report.aggregate - sum).abs() <= 1e-9 * sum);
+    }
+
+    // More threads should not make the aggregate bandwidth collapse
+    //
+    // Miri's clock does not reflect real time, so this cannot be checked there.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn sweep_sanity() {
+        let reports = memory_bandwidth_sweep(buffer_size(), Duration::from_millis(50), 2);
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[1].threads, 2);
+        assert!(
+            reports[1].aggregate > reports[0].aggregate / 3.0,
+            "{:?}",
+            reports
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one u64")]
+    fn empty_buffer() {
+        memory_bandwidth(4, Duration::ZERO, 1);
+    }
+}
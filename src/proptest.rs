@@ -0,0 +1,250 @@
+//! Integration with the proptest property-based testing framework
+//!
+//! Property-based testing generates many inputs for a test, and when one of
+//! them makes the test fail, shrinks it into a simpler input which still
+//! fails. `concurrent_prop()` applies this to concurrent tests: it generates
+//! one input per thread, and runs the test body several times per input,
+//! since a racy test which failed once may well pass on the next run.
+//!
+//! ```should_panic
+//! use std::{
+//!     sync::atomic::{AtomicU32, Ordering},
+//!     thread,
+//! };
+//!
+//! /// Counter whose increments are not atomic, and can thus be lost
+//! struct BrokenCounter(AtomicU32);
+//! //
+//! impl BrokenCounter {
+//!     fn increment(&self) {
+//!         let value = self.0.load(Ordering::Relaxed);
+//!         thread::yield_now();
+//!         self.0.store(value + 1, Ordering::Relaxed);
+//!     }
+//! }
+//!
+//! // Should fail, with an input that shrinks to a few increments per thread
+//! testbench::proptest::concurrent_prop((0..100u32, 0..100u32), |&a, &b| {
+//!     let counter = BrokenCounter(AtomicU32::new(0));
+//!     testbench::concurrent_test_2(
+//!         || (0..a).for_each(|_| counter.increment()),
+//!         || (0..b).for_each(|_| counter.increment()),
+//!     );
+//!     assert_eq!(counter.0.load(Ordering::Relaxed), a + b);
+//! });
+//! ```
+//!
+//! # Shrinking and nondeterminism
+//!
+//! Proptest assumes that tests are deterministic: when a simpler input passes,
+//! it concludes that this input does not trigger the failure, and tries other
+//! simplifications. With concurrent tests, a simpler input may just have been
+//! lucky, so shrinking stops earlier than it could have. Running each input
+//! several times, as configured by `ConcurrentProp::repetitions()`, makes this
+//! less likely, at the expense of test duration. Conversely, the minimal
+//! input that is reported may not fail on every run, so reproducing a failure
+//! may require running it several times.
+//!
+//! Failure messages mention which repetition failed. Inputs are generated from
+//! proptest's RNG, which can be seeded (e.g. with the `PROPTEST_RNG_SEED`
+//! environment variable) in order to replay a failing run's inputs.
+
+use crate::{util::panic_message, Intensity};
+use ::proptest::{
+    strategy::Strategy,
+    test_runner::{Config, TestCaseError, TestError, TestRunner},
+};
+use std::{
+    fmt::Debug,
+    panic::{self, AssertUnwindSafe},
+};
+
+/// Run a concurrent test on inputs generated by a proptest strategy
+///
+/// The strategy generates one input for each of two threads, and the body
+/// typically runs `concurrent_test_2()` with these inputs. This is a shorthand
+/// for `ConcurrentProp::new().run(strategy, body)`.
+///
+/// # Panics
+///
+/// This function panics if the body panics for some input, reporting the
+/// shrunk input.
+///
+pub fn concurrent_prop<A: Debug, B: Debug>(
+    strategy: impl Strategy<Value = (A, B)>,
+    body: impl Fn(&A, &B),
+) {
+    ConcurrentProp::new().run(strategy, body)
+}
+
+/// Configuration of a concurrent property-based test
+#[derive(Clone, Debug)]
+pub struct ConcurrentProp {
+    /// Proptest configuration
+    config: Config,
+
+    /// Number of times the body is run for each input
+    repetitions: usize,
+}
+//
+impl ConcurrentProp {
+    /// Default number of times the body is run for each input
+    ///
+    /// This is the default at `Normal` `Intensity`. It is divided by 4 at
+    /// `Quick` intensity and multiplied by 4 at `Soak` intensity.
+    ///
+    pub const DEFAULT_REPETITIONS: usize = 32;
+
+    /// Start from proptest's default configuration
+    ///
+    /// Failure persistence is disabled, as the source file of the test is not
+    /// known. It can be enabled again with `config()`.
+    ///
+    pub fn new() -> Self {
+        Self {
+            config: Config {
+                failure_persistence: None,
+                ..Config::default()
+            },
+            repetitions: Intensity::current().pick(
+                Self::DEFAULT_REPETITIONS / 4,
+                Self::DEFAULT_REPETITIONS,
+                Self::DEFAULT_REPETITIONS * 4,
+            ),
+        }
+    }
+
+    /// Use a custom proptest configuration
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Set the number of inputs that are generated
+    pub fn cases(mut self, cases: u32) -> Self {
+        self.config.cases = cases;
+        self
+    }
+
+    /// Set how many times the body is run for each input (default: 32)
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `repetitions` is zero.
+    ///
+    pub fn repetitions(mut self, repetitions: usize) -> Self {
+        assert!(repetitions > 0, "The body must be run at least once");
+        self.repetitions = repetitions;
+        self
+    }
+
+    /// Run a concurrent test on inputs generated by a proptest strategy
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the body panics for some input, reporting the
+    /// shrunk input.
+    ///
+    pub fn run<A: Debug, B: Debug>(
+        &self,
+        strategy: impl Strategy<Value = (A, B)>,
+        body: impl Fn(&A, &B),
+    ) {
+        let mut runner = TestRunner::new(self.config.clone());
+        let result = runner.run(&strategy, |(a, b)| {
+            for repetition in 0..self.repetitions {
+                if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| body(&a, &b))) {
+                    return Err(TestCaseError::fail(format!(
+                        "failed on repetition {}/{}: {}",
+                        repetition + 1,
+                        self.repetitions,
+                        panic_message(&*payload)
+                    )));
+                }
+            }
+            Ok(())
+        });
+        match result {
+            Ok(()) => {}
+            Err(TestError::Fail(reason, (a, b))) => panic!(
+                "Concurrent property {}\nminimal failing input: {:?}",
+                reason,
+                (a, b)
+            ),
+            Err(TestError::Abort(reason)) => {
+                panic!("Concurrent property test aborted: {}", reason)
+            }
+        }
+    }
+}
+//
+impl Default for ConcurrentProp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+        thread,
+    };
+
+    #[test]
+    fn passing_property() {
+        let runs = AtomicUsize::new(0);
+        ConcurrentProp::new()
+            .cases(10)
+            .repetitions(3)
+            .run((0..10u32, 0..10u32), |&a, &b| {
+                runs.fetch_add(1, Ordering::Relaxed);
+                let counter = AtomicU32::new(0);
+                crate::concurrent_test_2(
+                    || {
+                        counter.fetch_add(a, Ordering::Relaxed);
+                    },
+                    || {
+                        counter.fetch_add(b, Ordering::Relaxed);
+                    },
+                );
+                assert_eq!(counter.load(Ordering::Relaxed), a + b);
+            });
+        assert_eq!(runs.load(Ordering::Relaxed), 30);
+    }
+
+    #[test]
+    fn broken_counter() {
+        let payload = panic::catch_unwind(|| {
+            concurrent_prop((0..100u32, 0..100u32), |&a, &b| {
+                let counter = AtomicU32::new(0);
+                let increment = || {
+                    let value = counter.load(Ordering::Relaxed);
+                    thread::yield_now();
+                    counter.store(value + 1, Ordering::Relaxed);
+                };
+                crate::concurrent_test_2(
+                    || (0..a).for_each(|_| increment()),
+                    || (0..b).for_each(|_| increment()),
+                );
+                assert_eq!(counter.load(Ordering::Relaxed), a + b, "Lost increments");
+            })
+        })
+        .unwrap_err();
+        let message = payload.downcast_ref::<String>().unwrap();
+        assert!(message.contains("failed on repetition "), "{}", message);
+        assert!(message.contains("Lost increments"), "{}", message);
+
+        // Both threads must increment for updates to be lost, and shrinking
+        // should get rid of most increments
+        let input = message.rsplit("minimal failing input: ").next().unwrap();
+        let (a, b) = input
+            .trim_matches(|c| c == '(' || c == ')')
+            .split_once(", ")
+            .unwrap();
+        let (a, b) = (a.parse::<u32>().unwrap(), b.parse::<u32>().unwrap());
+        assert!(a > 0 && b > 0, "{}", message);
+        assert!(a + b < 50, "{}", message);
+    }
+}
@@ -45,10 +45,11 @@
 //!
 //! # Requirements on T
 //!
-//! In principle, any Clone + Eq type T whose equality operator and clone()
-//! implementation behave well even if the inner data is in a inconsistent state
-//! (e.g. filled with random bits) could be used. This is true of all primitive
-//! integral types and aggregates thereof, for example.
+//! In principle, any Clone type T which can be checked for identity, and whose
+//! identity check and clone() implementation behave well even if the inner
+//! data is in a inconsistent state (e.g. filled with random bits) could be
+//! used. This is true of all primitive integral types and aggregates thereof,
+//! for example, as well as floating-point types if they are compared bitwise.
 //!
 //! However, in practice, unsynchronized concurrent read/write access to
 //! arbitrary data from multiple threads constitutes a data race, which is
@@ -61,293 +62,5049 @@
 //! atomic load and store operations, implemented as part of an atomic wrapper.
 //! Note that although individual loads and stores to T are atomic, loads and
 //! stores to RaceCell<T> are still guaranteed not to be atomic.
+//!
+//! Structs whose fields are all supported can be supported as well, by
+//! implementing AtomicData and AtomicLoadStore for them. If the `derive`
+//! feature is enabled, `#[derive(AtomicData)]` does this for you, generating
+//! an atomic wrapper named after the struct (e.g. `AtomicSlot` for `Slot`)
+//! which loads and stores fields one by one.
+//!
+//! Other `Clone + Eq` types, such as `String`, can be put in a RaceCell by
+//! wrapping them into `Locked`, which protects each copy with a Mutex. This is
+//! slower, so it must be opted into explicitly.
+//!
+//! If the `crossbeam` feature is enabled, small `Copy + Eq` types such as
+//! `Option<u32>` or fieldless enums can also be put in a RaceCell by means of
+//! crossbeam's `AtomicCell`, see the `CrossbeamData` trait.
+//!
+//! Fieldless enums can be put in a RaceCell by storing their discriminant in
+//! the corresponding atomic integer, see the `impl_atomic_data_for_enum!`
+//! macro. Observing a discriminant which does not match any variant is then
+//! reported as an inconsistency.
+//!
+//! On targets without native 64-bit atomics, the `portable-atomic` feature
+//! makes the atomic wrappers of 64-bit (and 128-bit) data use the atomic types
+//! of the portable-atomic crate instead of those of the standard library.
+//!
+//! If the `serde` feature is enabled, RaceCells can be serialized and
+//! deserialized. Both copies of the data are serialized, so inconsistent
+//! RaceCells are deserialized as they were. The results of RaceCell reads,
+//! `Racey`, can be serialized too.
+//!
+//! When this crate is built with `RUSTFLAGS="--cfg loom"`, the types of this
+//! module use the atomics of the loom model checker instead of those of the
+//! standard library, so that they can be used inside of loom models, along
+//! with the helpers of the `loom` module of this crate.
+//!
+//! Similarly, building with `RUSTFLAGS="--cfg shuttle"` makes this module use
+//! the atomics of the shuttle randomized scheduler, see the `shuttle` module.
+//!
+//! # Thread safety
+//!
+//! RaceCells are meant to be shared between threads, but they have no
+//! `unsafe impl` of `Send` or `Sync`. Instead, these traits are inferred from
+//! the atomic wrapper of T: a `RaceCell<T>` is `Send` (resp. `Sync`) if and
+//! only if `T::AtomicWrapper` is. Since all accesses to a RaceCell go through
+//! the atomic wrapper, this is sound as long as the wrapper's own `Send` and
+//! `Sync` implementations are.
+//!
+//! In practice, RaceCells of primitive data are always `Send + Sync`, and
+//! RaceCells of `Locked<T>` and crossbeam-backed data are `Send + Sync` if T
+//! is `Send`, like the Mutex and `AtomicCell` that they use. A RaceCell that
+//! holds data which cannot be sent to another thread cannot be shared:
+//!
+//! ```compile_fail,E0277
+//! # use std::rc::Rc;
+//! # use testbench::race_cell::{Locked, RaceCell};
+//! fn share<T: Sync>(_: &T) {}
+//! share(&RaceCell::new(Locked(Rc::new(42))));
+//! ```
+//!
+//! Raw pointers are the notable case where the RaceCell is `Send + Sync` but
+//! T is not. Like `AtomicPtr`, a `RaceCell<*mut V>` only shares the address
+//! that it holds, and so do `RaceCell<NonNull<V>>` and
+//! `RaceCell<Option<NonNull<V>>>`, which are stored as `AtomicPtr`s too.
+//! Dereferencing that address requires `unsafe` code which
+//! must check that the pointee can be accessed from the current thread. So
+//! whatever V is, this compiles:
+//!
+//! ```
+//! # use std::{cell::Cell, ptr};
+//! # use testbench::race_cell::RaceCell;
+//! fn share<T: Send + Sync>(_: &T) {}
+//! share(&RaceCell::new(ptr::null_mut::<Cell<u8>>()));
+//! ```
+//!
+//! ...but the pointers that are read from the RaceCell are ordinary raw
+//! pointers, and so is the `Racey` that holds them:
+//!
+//! ```compile_fail,E0277
+//! # use std::ptr;
+//! # use testbench::race_cell::RaceCell;
+//! fn send<T: Send>(_: T) {}
+//! send(RaceCell::new(ptr::null_mut::<u8>()).get());
+//! ```
 
 #![deny(missing_docs)]
 
-use std::sync::atomic::{
-    AtomicBool, AtomicI16, AtomicI32, AtomicI64, AtomicI8, AtomicIsize, AtomicPtr, AtomicU16,
-    AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering,
+use crate::{timing, trace, util::CachePadded};
+#[cfg(feature = "crossbeam")]
+use crossbeam_utils::atomic::AtomicCell;
+#[cfg(loom)]
+use loom::{
+    hint,
+    sync::atomic::{
+        AtomicBool, AtomicI16, AtomicI32, AtomicI64, AtomicI8, AtomicIsize, AtomicPtr, AtomicU16,
+        AtomicU32, AtomicU64, AtomicU8, AtomicUsize,
+    },
+    thread::yield_now,
 };
+#[cfg(all(feature = "portable-atomic", not(any(loom, shuttle))))]
+use portable_atomic::{AtomicI64, AtomicU64};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+#[cfg(shuttle)]
+use shuttle::{
+    hint,
+    sync::atomic::{
+        AtomicBool, AtomicI16, AtomicI32, AtomicI64, AtomicI8, AtomicIsize, AtomicPtr, AtomicU16,
+        AtomicU32, AtomicU64, AtomicU8, AtomicUsize,
+    },
+    thread::{sleep, yield_now},
+};
+#[cfg(not(any(feature = "portable-atomic", loom, shuttle)))]
+use std::sync::atomic::{AtomicI64, AtomicU64};
+#[cfg(not(shuttle))]
+use std::thread::sleep;
+use std::{
+    any, cmp,
+    fmt::{self, Debug},
+    mem::{self, size_of},
+    num::{
+        NonZeroI16, NonZeroI32, NonZeroI64, NonZeroI8, NonZeroIsize, NonZeroU16, NonZeroU32,
+        NonZeroU64, NonZeroU8, NonZeroUsize,
+    },
+    ops::Deref,
+    ptr::{self, NonNull},
+    sync::{atomic::Ordering, Arc, Mutex, MutexGuard, PoisonError},
+    thread::{self, ThreadId},
+    time::{Duration, Instant},
+};
+#[cfg(not(any(loom, shuttle)))]
+use std::{
+    hint,
+    sync::atomic::{
+        AtomicBool, AtomicI16, AtomicI32, AtomicI8, AtomicIsize, AtomicPtr, AtomicU16, AtomicU32,
+        AtomicU8, AtomicUsize,
+    },
+    thread::yield_now,
+};
+#[cfg(feature = "derive")]
+pub use testbench_derive::AtomicData;
 
 /// Shareable mutable container for triggering and detecting write-after-read
 /// data races in a well-controlled fashion.
-#[derive(Debug, Default)]
+#[derive(Default)]
 pub struct RaceCell<T: AtomicData> {
-    /// Two copies of a value of type T are made. One is stored on the stack...
-    local_contents: T::AtomicWrapper,
+    /// Two copies of a value of type T are made. One is stored inline...
+    local_contents: CachePadded<T::AtomicWrapper>,
 
-    /// ...and one is stored on the heap, which in all popular OSs is too far
-    /// away from the stack to allow any significant probability of the hardware
-    /// writing both copies in a single atomic transactions.
+    /// ...and one is stored in a separate heap allocation, which is unlikely
+    /// to be close enough to the first copy to allow any significant
+    /// probability of the hardware writing both copies in a single atomic
+    /// transaction. Both copies are padded to a cache line, so they cannot
+    /// share one. If this is not enough, `with_min_distance()` can guarantee
+    /// a larger distance between the copies.
     ///
     /// Of course, a malicious optimizer could still use hardware transactional
     /// memory or a software emulation thereof to achieve this effect, but there
     /// are no performance benefits in doing so, and in fact it will rather have
     /// an averse effect on performance, so a realistic optimizer won't do it.
     ///
-    remote_version: Box<T::AtomicWrapper>,
+    remote_version: RemoteCopy<T::AtomicWrapper>,
 }
 //
 impl<T: AtomicData> RaceCell<T> {
     /// Create a new RaceCell with a certain initial content
+    ///
+    /// This is not a `const fn`, because the remote copy of the data must be
+    /// allocated on the heap, which cannot be done at compile time. Storing
+    /// the remote copy elsewhere, e.g. in a user-provided arena, would require
+    /// RaceCell to carry a lifetime or storage type parameter. For a global
+    /// RaceCell, use lazy initialization instead.
+    ///
     pub fn new(value: T) -> Self {
-        RaceCell {
-            local_contents: T::AtomicWrapper::new(value.clone()),
-            remote_version: Box::new(T::AtomicWrapper::new(value)),
+        Self::from_copies(value.clone(), value, 0)
+    }
+
+    /// Create `len` RaceCells with the same initial content
+    ///
+    /// This is handy for building tables of RaceCells in stress tests.
+    ///
+    pub fn new_vec(len: usize, value: T) -> Vec<Self> {
+        (0..len).map(|_| Self::new(value.clone())).collect()
+    }
+
+    /// Create a RaceCell which is inconsistent from the start, with the given
+    /// contents for its local and remote copies of the data
+    ///
+    /// **This is only meant for testing code which consumes the result of
+    /// RaceCell reads**, such as retry loops or race statistics, without
+    /// having to trigger an actual data race. A RaceCell which is used to
+    /// detect data races should be created with `new()`.
+    ///
+    pub fn new_inconsistent(local: T, remote: T) -> Self {
+        Self::from_copies(local, remote, 0)
+    }
+
+    /// Create a new RaceCell whose two copies of the data are guaranteed to be
+    /// more than `min_distance` bytes apart
+    ///
+    /// The second copy is stored in the middle of a padded allocation, so this
+    /// guarantee holds wherever the RaceCell is moved afterwards, including
+    /// into a `Box` or an `Arc`. Use a distance of 4096 or more to put the two
+    /// copies on different memory pages. The padding is made of extra copies
+    /// of the initial value, which are never accessed afterwards.
+    ///
+    /// Zero-sized data has no storage, so the distance is ignored for it.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the distance between the two copies does not
+    /// turn out to be larger than `min_distance`, which would be a bug.
+    ///
+    pub fn with_min_distance(value: T, min_distance: usize) -> Self {
+        let copy_size = size_of::<CachePadded<T::AtomicWrapper>>();
+        let padding = min_distance.checked_div(copy_size).map_or(0, |n| n + 1);
+        let cell = Self::from_copies(value.clone(), value, padding);
+        assert!(
+            copy_size == 0 || cell.copy_distance() > min_distance,
+            "Failed to put the copies of a RaceCell {} bytes apart",
+            min_distance
+        );
+        cell
+    }
+
+    /// Create a RaceCell from both copies of the data, with `padding` unused
+    /// copies on each side of the remote copy
+    fn from_copies(local: T, remote: T, padding: usize) -> Self {
+        Self {
+            local_contents: CachePadded::new(T::AtomicWrapper::new(local)),
+            remote_version: RemoteCopy::new(padding, || T::AtomicWrapper::new(remote.clone())),
         }
     }
 
+    /// Distance in bytes between the two copies of the data, for diagnostics
+    ///
+    /// This changes when the RaceCell is moved, since the first copy is stored
+    /// inline, whereas the second copy is not.
+    ///
+    pub fn copy_distance(&self) -> usize {
+        let (local, remote) = self.copy_addresses();
+        local.abs_diff(remote)
+    }
+
+    /// Addresses of the local and remote copies of the data, for diagnostics
+    ///
+    /// This can be used to check how the copies are laid out in memory. Like
+    /// `copy_distance()`, it changes when the RaceCell is moved.
+    ///
+    pub fn copy_addresses(&self) -> (usize, usize) {
+        let local: *const T::AtomicWrapper = &*self.local_contents;
+        let remote: *const T::AtomicWrapper = &*self.remote_version;
+        (local as usize, remote as usize)
+    }
+
+    /// Contents of the local and remote copies of the data, for diagnostics
+    ///
+    /// The local copy is loaded first, then the remote copy, without checking
+    /// them for consistency. A copy which is in an invalid state is loaded
+    /// using its wrapper's `relaxed_load()`, which may substitute a
+    /// placeholder value.
+    ///
+    /// **This is only meant for debugging code that uses RaceCells.** If the
+    /// RaceCell is concurrently written to, the result may be stale by the
+    /// time it is returned. Use `get()` to detect data races.
+    ///
+    pub fn read_copies(&self) -> (T, T) {
+        let local = self.local_contents.relaxed_load();
+        let remote = self.remote_version.relaxed_load();
+        (local, remote)
+    }
+
     /// Update the internal contents of the RaceCell in a non-atomic fashion
     pub fn set(&self, value: T) {
         self.local_contents.relaxed_store(value.clone());
         self.remote_version.relaxed_store(value);
     }
 
+    /// Update the internal contents of the RaceCell in a non-atomic fashion,
+    /// waiting between the writes to its two copies
+    ///
+    /// This widens the window during which the RaceCell is inconsistent, which
+    /// makes races much more likely to be detected in targeted tests.
+    ///
+    pub fn set_slowly(&self, value: T, delay: WriteDelay) {
+        self.local_contents.relaxed_store(value.clone());
+        delay.wait();
+        self.remote_version.relaxed_store(value);
+    }
+
+    /// Overwrite the local copy of the data only, which makes the RaceCell
+    /// inconsistent unless the remote copy holds the same value
+    ///
+    /// **This is only meant for testing code which consumes the result of
+    /// RaceCell reads**, see `new_inconsistent()`. Use `set()` to write to a
+    /// RaceCell which is used to detect data races.
+    ///
+    pub fn force_local(&self, value: T) {
+        self.local_contents.relaxed_store(value);
+    }
+
+    /// Overwrite the remote copy of the data only, which makes the RaceCell
+    /// inconsistent unless the local copy holds the same value
+    ///
+    /// **This is only meant for testing code which consumes the result of
+    /// RaceCell reads**, see `new_inconsistent()`. Use `set()` to write to a
+    /// RaceCell which is used to detect data races.
+    ///
+    pub fn force_remote(&self, value: T) {
+        self.remote_version.relaxed_store(value);
+    }
+
     /// Read the current contents of the RaceCell, detecting any data race
     /// caused by a concurrently occurring write along the way.
+    ///
+    /// If a race is detected, the contents of both copies are reported. A copy
+    /// which was observed in an invalid state is reloaded using its wrapper's
+    /// `relaxed_load()`, which may substitute a placeholder value.
+    ///
     pub fn get(&self) -> Racey<T> {
-        let local_data = self.local_contents.relaxed_load();
-        let remote_data = self.remote_version.relaxed_load();
-        if local_data == remote_data {
-            Racey::Consistent(local_data)
+        // The remote copy is compared with the local one in place, so that it
+        // is only cloned if it differs from the local copy
+        match self.local_contents.relaxed_try_load() {
+            Some(local) => match self.remote_version.relaxed_load_if_different(&local) {
+                None => Racey::Consistent(local),
+                Some(remote) => Racey::Inconsistent { local, remote },
+            },
+            None => Racey::Inconsistent {
+                local: self.local_contents.relaxed_load(),
+                remote: self.remote_version.relaxed_load(),
+            },
+        }
+    }
+
+    /// Read the current contents of the RaceCell like `get()`, recording any
+    /// detected data race into a RaceLatch
+    pub fn get_latched(&self, latch: &RaceLatch) -> Racey<T> {
+        let result = self.get();
+        if result.is_inconsistent() {
+            latch.record();
+        }
+        result
+    }
+
+    /// Read the current contents of the RaceCell like `get()`, recording any
+    /// detected data race into a RaceLog
+    pub fn get_logged(&self, log: &RaceLog<T>) -> Racey<T> {
+        let result = self.get();
+        if let Racey::Inconsistent { local, remote } = &result {
+            trace::race_detected(any::type_name::<T>());
+            log.record(local.clone(), remote.clone());
+        }
+        result
+    }
+
+    /// Truth that both copies of the data are currently consistent
+    ///
+    /// This detects data races like `get()`, but does not return the contents
+    /// of the RaceCell, which avoids cloning them when possible. Composite
+    /// contents, such as arrays, are compared part by part, stopping at the
+    /// first mismatch.
+    ///
+    pub fn is_consistent(&self) -> bool {
+        self.local_contents.relaxed_matches(&self.remote_version)
+    }
+
+    /// Check that both copies of the data are currently consistent
+    ///
+    /// This is a version of `is_consistent()` that can be used with the `?`
+    /// operator, in tests and helpers that return a `Result`.
+    ///
+    pub fn probe(&self) -> Result<(), Inconsistency> {
+        if self.is_consistent() {
+            Ok(())
         } else {
-            Racey::Inconsistent
+            Err(Inconsistency)
+        }
+    }
+
+    /// Assert that both copies of the data are currently consistent
+    ///
+    /// # Panics
+    ///
+    /// This function panics if a data race is detected. Both copies of the
+    /// data are then reloaded, so that they can be reported in the panic
+    /// message, which means that the reported values may come from a later
+    /// state of the RaceCell than the one that was checked.
+    ///
+    #[track_caller]
+    pub fn assert_consistent(&self)
+    where
+        T: Debug,
+    {
+        if !self.is_consistent() {
+            match self.get() {
+                Racey::Inconsistent { local, remote } => panic!(
+                    "RaceCell is inconsistent: local copy is {:?}, remote copy is {:?}",
+                    local, remote
+                ),
+                Racey::Consistent(value) => panic!(
+                    "RaceCell was inconsistent, then became consistent with contents {:?}",
+                    value
+                ),
+            }
+        }
+    }
+
+    /// Read the contents of the RaceCell, retrying if a data race is detected
+    ///
+    /// The RaceCell is read up to `max_retries + 1` times, with a spin loop
+    /// hint between attempts, until a consistent value is observed. If this
+    /// never happens, the last inconsistent observation is reported.
+    ///
+    pub fn get_consistent(&self, max_retries: usize) -> Result<T, RetriesExhausted<T>> {
+        self.retry_get(|attempts| attempts <= max_retries)
+    }
+
+    /// Read the contents of the RaceCell, retrying if a data race is detected
+    ///
+    /// This is like `get_consistent()`, but attempts are bounded by time rather
+    /// than by number. At least one attempt is made, even if the timeout is
+    /// zero.
+    ///
+    pub fn get_consistent_timeout(&self, timeout: Duration) -> Result<T, RetriesExhausted<T>> {
+        let start = timing::now();
+        self.retry_get(|_attempts| start.elapsed() < timeout)
+    }
+
+    /// Read the RaceCell until it consistently holds some target value
+    ///
+    /// This is meant for reader threads which wait for a writer to reach a
+    /// certain state, counting the data races that they observe along the way.
+    /// Values are compared with `AtomicData::identical()`. The first reads are
+    /// separated by a spin loop hint, and later ones by a yield to the OS
+    /// scheduler, so that waiting for a slow writer does not starve it on
+    /// machines with few CPUs.
+    ///
+    /// At least one read is made, even if the timeout is zero. If the target
+    /// value was not observed before the timeout, the last observation is
+    /// reported, along with the statistics of the reads made so far.
+    ///
+    pub fn wait_for(&self, target: &T, timeout: Duration) -> Result<WaitStats, WaitTimeout<T>> {
+        // Number of reads after which we start yielding instead of spinning
+        const SPIN_READS: usize = 64;
+
+        let start = timing::now();
+        let mut stats = WaitStats::default();
+        loop {
+            let observed = self.get();
+            stats.reads += 1;
+            match &observed {
+                Racey::Consistent(value) if value.identical(target) => return Ok(stats),
+                Racey::Consistent(_) => {}
+                Racey::Inconsistent { .. } => stats.races += 1,
+            }
+            if start.elapsed() >= timeout {
+                return Err(WaitTimeout {
+                    stats,
+                    last: observed,
+                });
+            }
+            if stats.reads < SPIN_READS {
+                hint::spin_loop();
+            } else {
+                yield_now();
+            }
+        }
+    }
+
+    /// Read the RaceCell until it is consistent or `keep_trying`, which is
+    /// given the number of attempts so far, returns false
+    fn retry_get(
+        &self,
+        mut keep_trying: impl FnMut(usize) -> bool,
+    ) -> Result<T, RetriesExhausted<T>> {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match self.get() {
+                Racey::Consistent(value) => return Ok(value),
+                Racey::Inconsistent { local, remote } => {
+                    if !keep_trying(attempts) {
+                        return Err(RetriesExhausted {
+                            attempts,
+                            local,
+                            remote,
+                        });
+                    }
+                }
+            }
+            hint::spin_loop();
+        }
+    }
+
+    /// Update the internal contents of the RaceCell, returning the contents
+    /// that were observed before the update
+    ///
+    /// Like `get()` followed by `set()`, this is not an atomic transaction:
+    /// other threads may write to the RaceCell between the read and the write,
+    /// or while each of them is in progress. In the latter case, the returned
+    /// contents will be `Racey::Inconsistent`.
+    ///
+    pub fn replace(&self, value: T) -> Racey<T> {
+        let previous = self.get();
+        self.set(value);
+        previous
+    }
+
+    /// Non-atomically update the contents of the RaceCell with a function,
+    /// returning the updated contents
+    ///
+    /// The RaceCell is read, then if its contents are consistent, `f` is
+    /// applied to them and the result is written back. If the contents are
+    /// inconsistent, nothing is written and the inconsistency is reported.
+    ///
+    /// Like `replace()`, this is intentionally not atomic, so concurrent
+    /// updates can be lost, which makes it handy for demonstrating that a
+    /// synchronization protocol prevents this from happening.
+    ///
+    pub fn update(&self, f: impl FnOnce(T) -> T) -> Racey<T> {
+        match self.get() {
+            Racey::Consistent(value) => {
+                let new_value = f(value);
+                self.set(new_value.clone());
+                Racey::Consistent(new_value)
+            }
+            inconsistent @ Racey::Inconsistent { .. } => inconsistent,
         }
     }
+
+    /// Non-atomically update the contents of the RaceCell with a function,
+    /// without checking their consistency, returning the updated contents
+    ///
+    /// Unlike with `update()`, `f` is always applied to the first copy of the
+    /// data and the result is written back, even if the RaceCell was
+    /// inconsistent.
+    ///
+    pub fn update_unchecked(&self, f: impl FnOnce(T) -> T) -> T {
+        let new_value = f(self.local_contents.relaxed_load());
+        self.set(new_value.clone());
+        new_value
+    }
+
+    /// Non-atomically exchange the contents of two RaceCells, returning the
+    /// contents that were observed before the exchange
+    ///
+    /// Both copies of the data of each RaceCell are loaded once, in the order
+    /// local copy of `self`, remote copy of `self`, local copy of `other`,
+    /// remote copy of `other`. The returned observations are built from these
+    /// loads. Then each copy is stored into the same copy of the other
+    /// RaceCell, using the following sequence of stores:
+    ///
+    /// 1. Local copy of `self`, from the local copy of `other`
+    /// 2. Local copy of `other`, from the local copy of `self`
+    /// 3. Remote copy of `self`, from the remote copy of `other`
+    /// 4. Remote copy of `other`, from the remote copy of `self`
+    ///
+    /// Concurrent readers of a RaceCell can thus observe it in an
+    /// inconsistent state, where its local copy has been swapped but its
+    /// remote copy has not, between stores 1 and 3 for `self` and between
+    /// stores 2 and 4 for `other`. A RaceCell which was inconsistent before
+    /// the swap leaves the other RaceCell equally inconsistent afterwards.
+    ///
+    pub fn swap(&self, other: &RaceCell<T>) -> (Racey<T>, Racey<T>) {
+        let (self_local, self_remote) = self.load_copies();
+        let (other_local, other_remote) = other.load_copies();
+        self.local_contents.relaxed_store(other_local.clone());
+        other.local_contents.relaxed_store(self_local.clone());
+        self.remote_version.relaxed_store(other_remote.clone());
+        other.remote_version.relaxed_store(self_remote.clone());
+        (
+            Racey::from_copies(self_local, self_remote),
+            Racey::from_copies(other_local, other_remote),
+        )
+    }
+
+    /// Load both copies of the data once, local copy first
+    ///
+    /// Like `relaxed_load()`, this may substitute placeholders for invalid
+    /// values.
+    ///
+    fn load_copies(&self) -> (T, T) {
+        let local = self.local_contents.relaxed_load();
+        let remote = self.remote_version.relaxed_load();
+        (local, remote)
+    }
+
+    /// Read the contents of a RaceCell that is exclusively accessed
+    ///
+    /// No write can occur concurrently, but the two copies of the data can
+    /// still differ if a race occurred before exclusive access was acquired,
+    /// which is why the result is still a `Racey`.
+    ///
+    pub fn get_mut(&mut self) -> Racey<T> {
+        self.get()
+    }
+
+    /// Update the contents of a RaceCell that is exclusively accessed
+    ///
+    /// Unlike with `set()`, no other thread can observe the RaceCell while
+    /// only one of its copies has been updated.
+    ///
+    pub fn set_mut(&mut self, value: T) {
+        self.set(value)
+    }
+
+    /// Extract the contents of a RaceCell, consuming it
+    ///
+    /// As with `get_mut()`, the two copies of the data may differ if a race
+    /// occurred before the RaceCell was consumed.
+    ///
+    pub fn into_inner(self) -> Racey<T> {
+        self.get()
+    }
+}
+//
+impl<T: AtomicData + Default> RaceCell<T> {
+    /// Reset the contents of the RaceCell to the default value, returning the
+    /// contents that were observed before the reset
+    ///
+    /// This is `replace(T::default())`, and is therefore not atomic either.
+    /// Afterwards, the RaceCell holds the same value as `RaceCell::default()`.
+    ///
+    pub fn take(&self) -> Racey<T> {
+        self.replace(T::default())
+    }
+
+    /// Reset the contents of the RaceCell to the default value
+    ///
+    /// Like `set()`, this is not atomic.
+    ///
+    pub fn set_default(&self) {
+        self.set(T::default())
+    }
+}
+//
+impl<T: AtomicData> From<T> for RaceCell<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+//
+/// RaceCells are printed as the contents of both copies of the data, followed
+/// by the truth that they are consistent, e.g.
+/// `RaceCell { local: 5, remote: 7, consistent: false }`
+///
+/// Copies which are observed in an invalid state are printed as the
+/// placeholder value substituted by `get()`.
+///
+impl<T: AtomicData + Debug> Debug for RaceCell<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (local, remote, consistent) = match self.get() {
+            Racey::Consistent(value) => (value.clone(), value, true),
+            Racey::Inconsistent { local, remote } => (local, remote, false),
+        };
+        f.debug_struct("RaceCell")
+            .field("local", &local)
+            .field("remote", &remote)
+            .field("consistent", &consistent)
+            .finish()
+    }
 }
 //
 impl<T: AtomicData> Clone for RaceCell<T> {
     /// Making RaceCells cloneable allows putting them in concurrent containers
     fn clone(&self) -> Self {
-        let local_copy = self.local_contents.relaxed_load();
-        let remote_copy = self.remote_version.relaxed_load();
-        RaceCell {
-            local_contents: T::AtomicWrapper::new(local_copy),
-            remote_version: Box::new(T::AtomicWrapper::new(remote_copy)),
+        Self::from_copies(
+            self.local_contents.relaxed_load(),
+            self.remote_version.relaxed_load(),
+            self.remote_version.padding,
+        )
+    }
+}
+//
+/// A RaceCell is equal to a value if both of its copies are identical to it,
+/// in the sense of `AtomicData::identical()`.
+///
+/// Copies which are observed in an invalid state are not identical to any
+/// value. Since both copies are read one after the other, the result is only
+/// meaningful if the RaceCell is not concurrently written to.
+///
+impl<T: AtomicData> PartialEq<T> for RaceCell<T> {
+    fn eq(&self, other: &T) -> bool {
+        let identical = |copy: Option<T>| copy.map_or(false, |copy| copy.identical(other));
+        identical(self.local_contents.relaxed_try_load())
+            && identical(self.remote_version.relaxed_try_load())
+    }
+}
+//
+/// Two RaceCells are equal if their local copies are identical and their
+/// remote copies are identical, in the sense of `AtomicData::identical()`.
+///
+/// This means that two RaceCells which are inconsistent in the same way
+/// compare equal. As with comparisons to a value, copies which are observed in
+/// an invalid state are not identical to anything, and the result is only
+/// meaningful if the RaceCells are not concurrently written to.
+///
+impl<T: AtomicData> PartialEq for RaceCell<T> {
+    fn eq(&self, other: &Self) -> bool {
+        let identical = |copy: &T::AtomicWrapper, other_copy: &T::AtomicWrapper| match (
+            copy.relaxed_try_load(),
+            other_copy.relaxed_try_load(),
+        ) {
+            (Some(copy), Some(other_copy)) => copy.identical(&other_copy),
+            _ => false,
+        };
+        identical(&self.local_contents, &other.local_contents)
+            && identical(&self.remote_version, &other.remote_version)
+    }
+}
+//
+#[cfg(feature = "serde")]
+impl<T: AtomicData + Serialize> Serialize for RaceCell<T> {
+    /// Both copies of the data are serialized, so that inconsistent RaceCells
+    /// can be deserialized as they were
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        RaceCellCopies {
+            local: self.local_contents.relaxed_load(),
+            remote: self.remote_version.relaxed_load(),
+        }
+        .serialize(serializer)
+    }
+}
+//
+#[cfg(feature = "serde")]
+impl<'de, T: AtomicData + Deserialize<'de>> Deserialize<'de> for RaceCell<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let copies = RaceCellCopies::deserialize(deserializer)?;
+        Ok(Self::from_copies(copies.local, copies.remote, 0))
+    }
+}
+
+/// Serialized form of a RaceCell
+#[cfg(feature = "serde")]
+#[derive(Deserialize, Serialize)]
+#[serde(rename = "RaceCell")]
+struct RaceCellCopies<T> {
+    /// Local copy of the data
+    local: T,
+
+    /// Remote copy of the data
+    remote: T,
+}
+
+/// What `RaceCell::set_slowly()` does between the writes to the two copies
+#[allow(variant_size_differences)] // Small enough to be passed around by value
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum WriteDelay {
+    /// Execute a spin loop hint a certain number of times
+    Spin(u32),
+
+    /// Yield to the operating system scheduler
+    Yield,
+
+    /// Sleep for a certain amount of time
+    Sleep(Duration),
+}
+//
+impl WriteDelay {
+    /// Wait once
+    pub(crate) fn wait(self) {
+        match self {
+            Self::Spin(count) => {
+                for _ in 0..count {
+                    hint::spin_loop();
+                }
+            }
+            Self::Yield => yield_now(),
+            Self::Sleep(duration) => sleep(duration),
+        }
+    }
+}
+
+/// RaceCell variant which keeps an arbitrary number of copies of its data
+///
+/// Each copy, or replica, is stored in its own heap allocation. `set()`
+/// writes the replicas in order, and `get()` reports an inconsistency unless
+/// all replicas agree. Using more replicas widens the window during which a
+/// write is in progress, which makes races more likely to be detected, at the
+/// expense of slower reads and writes.
+///
+/// When a race is detected, the first replica is reported as the `local`
+/// value and the first replica which disagrees with it as the `remote` value.
+///
+#[derive(Debug)]
+pub struct RaceCellN<T: AtomicData, const REPLICAS: usize> {
+    /// Independently allocated copies of the data
+    replicas: [Box<T::AtomicWrapper>; REPLICAS],
+}
+//
+impl<T: AtomicData, const REPLICAS: usize> RaceCellN<T, REPLICAS> {
+    /// Create a new RaceCellN with a certain initial content
+    ///
+    /// # Panics
+    ///
+    /// This function panics if there are less than 2 replicas, since races
+    /// cannot be detected with a single copy of the data.
+    ///
+    pub fn new(value: T) -> Self {
+        assert!(REPLICAS >= 2, "A RaceCellN needs at least 2 replicas");
+        Self {
+            replicas: std::array::from_fn(|_| Box::new(T::AtomicWrapper::new(value.clone()))),
+        }
+    }
+
+    /// Update the replicas in order, in a non-atomic fashion
+    pub fn set(&self, value: T) {
+        for replica in &self.replicas {
+            replica.relaxed_store(value.clone());
+        }
+    }
+
+    /// Read the current contents of the replicas, detecting any data race
+    /// caused by a concurrently occurring write along the way.
+    pub fn get(&self) -> Racey<T> {
+        let values: [Option<T>; REPLICAS] =
+            std::array::from_fn(|i| self.replicas[i].relaxed_try_load());
+        let load = |i: usize| {
+            values[i]
+                .clone()
+                .unwrap_or_else(|| self.replicas[i].relaxed_load())
+        };
+        let first = match &values[0] {
+            Some(first) => first,
+            None => {
+                return Racey::Inconsistent {
+                    local: load(0),
+                    remote: load(1),
+                }
+            }
+        };
+        let mismatch = values[1..].iter().position(|value| match value {
+            Some(value) => !value.identical(first),
+            None => true,
+        });
+        match mismatch {
+            None => Racey::Consistent(first.clone()),
+            Some(idx) => Racey::Inconsistent {
+                local: first.clone(),
+                remote: load(idx + 1),
+            },
+        }
+    }
+}
+//
+impl<T: AtomicData, const REPLICAS: usize> Clone for RaceCellN<T, REPLICAS> {
+    fn clone(&self) -> Self {
+        Self {
+            replicas: std::array::from_fn(|i| {
+                Box::new(T::AtomicWrapper::new(self.replicas[i].relaxed_load()))
+            }),
+        }
+    }
+}
+//
+impl<T: AtomicData + Default, const REPLICAS: usize> Default for RaceCellN<T, REPLICAS> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+/// RaceCell which counts how many reads were performed and how many of them
+/// detected a data race
+///
+/// This saves you from tallying the results of `get()` in stress tests. The
+/// counters are updated using relaxed atomic increments, which should not
+/// perturb the timing of the test much, but are still not free. Which is why
+/// the raw RaceCell does not maintain them.
+///
+pub struct CountingRaceCell<T: AtomicData> {
+    /// Underlying RaceCell
+    cell: RaceCell<T>,
+
+    /// Number of reads performed via `get()`
+    reads: AtomicUsize,
+
+    /// Number of reads which detected a data race
+    races: AtomicUsize,
+}
+//
+impl<T: AtomicData> CountingRaceCell<T> {
+    /// Create a new CountingRaceCell with a certain initial content
+    pub fn new(value: T) -> Self {
+        Self {
+            cell: RaceCell::new(value),
+            reads: AtomicUsize::new(0),
+            races: AtomicUsize::new(0),
+        }
+    }
+
+    /// Update the internal contents of the RaceCell in a non-atomic fashion
+    pub fn set(&self, value: T) {
+        self.cell.set(value)
+    }
+
+    /// Read the current contents of the RaceCell, counting the read and any
+    /// data race that it detects
+    pub fn get(&self) -> Racey<T> {
+        let result = self.cell.get();
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        if result.is_inconsistent() {
+            self.races.fetch_add(1, Ordering::Relaxed);
         }
+        result
+    }
+
+    /// Number of reads performed via `get()` since the last reset
+    pub fn reads(&self) -> usize {
+        self.reads.load(Ordering::Relaxed)
+    }
+
+    /// Number of reads which detected a data race since the last reset
+    pub fn races(&self) -> usize {
+        self.races.load(Ordering::Relaxed)
+    }
+
+    /// Fraction of reads which detected a data race, or 0.0 if no read was
+    /// performed since the last reset
+    pub fn race_fraction(&self) -> f64 {
+        let reads = self.reads();
+        if reads == 0 {
+            0.0
+        } else {
+            self.races() as f64 / reads as f64
+        }
+    }
+
+    /// Reset the read and race counters to zero
+    pub fn reset_counts(&self) {
+        self.reads.store(0, Ordering::Relaxed);
+        self.races.store(0, Ordering::Relaxed);
+    }
+
+    /// Access the underlying RaceCell, whose reads are not counted
+    pub fn as_race_cell(&self) -> &RaceCell<T> {
+        &self.cell
+    }
+}
+//
+impl<T: AtomicData> Debug for CountingRaceCell<T>
+where
+    RaceCell<T>: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CountingRaceCell")
+            .field("cell", &self.cell)
+            .field("reads", &self.reads)
+            .field("races", &self.races)
+            .finish()
+    }
+}
+//
+impl<T: AtomicData> Default for CountingRaceCell<T>
+where
+    RaceCell<T>: Default,
+{
+    fn default() -> Self {
+        Self {
+            cell: RaceCell::default(),
+            reads: AtomicUsize::new(0),
+            races: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// RaceCell which tags each write with a sequence number, in order to tell
+/// which copy of the data is newer when a data race is detected
+///
+/// Each copy of the data is paired with the sequence number of the write that
+/// produced it. Sequence numbers and values are loaded and stored one after
+/// the other, like the elements of a tuple, so the structure as a whole
+/// remains non-atomic. The initial contents have sequence number 0, and each
+/// call to `set()` allocates the next sequence number from an atomic counter.
+///
+pub struct VersionedRaceCell<T: AtomicData> {
+    /// Underlying RaceCell, holding (sequence number, value) pairs
+    cell: RaceCell<(u64, T)>,
+
+    /// Sequence number of the last write
+    last_version: AtomicU64,
+}
+//
+impl<T: AtomicData> VersionedRaceCell<T> {
+    /// Create a new VersionedRaceCell with a certain initial content
+    pub fn new(value: T) -> Self {
+        Self {
+            cell: RaceCell::new((0, value)),
+            last_version: AtomicU64::new(0),
+        }
+    }
+
+    /// Update the contents of the RaceCell in a non-atomic fashion, returning
+    /// the sequence number of this write
+    pub fn set(&self, value: T) -> u64 {
+        let version = self.last_version.fetch_add(1, Ordering::Relaxed) + 1;
+        self.cell.set((version, value));
+        version
+    }
+
+    /// Read the current contents of the RaceCell, detecting and classifying
+    /// any data race caused by a concurrently occurring write
+    pub fn get(&self) -> VersionedRead<T> {
+        match self.cell.get() {
+            Racey::Consistent((_version, value)) => VersionedRead::Consistent(value),
+            Racey::Inconsistent { local, remote } => {
+                if local.0 == remote.0 {
+                    VersionedRead::Reordered { local, remote }
+                } else if local.0 > remote.0 {
+                    VersionedRead::TornWrite {
+                        older: remote,
+                        newer: local,
+                    }
+                } else {
+                    VersionedRead::TornWrite {
+                        older: local,
+                        newer: remote,
+                    }
+                }
+            }
+        }
+    }
+}
+//
+impl<T: AtomicData> Debug for VersionedRaceCell<T>
+where
+    RaceCell<(u64, T)>: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VersionedRaceCell")
+            .field("cell", &self.cell)
+            .field("last_version", &self.last_version)
+            .finish()
+    }
+}
+//
+impl<T: AtomicData + Default> Default for VersionedRaceCell<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+/// Result of a VersionedRaceCell read
+///
+/// Inconsistent reads come with the (sequence number, value) pairs that were
+/// observed in each copy of the data.
+///
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum VersionedRead<U: AtomicData> {
+    /// The VersionedRaceCell was consistent, with a certain value
+    Consistent(U),
+
+    /// The copies come from different writes, which means that a write was
+    /// observed while in progress, or that the copies were observed at
+    /// different points of a sequence of writes
+    TornWrite {
+        /// Copy with the lowest sequence number
+        older: (u64, U),
+
+        /// Copy with the highest sequence number
+        newer: (u64, U),
+    },
+
+    /// The copies have the same sequence number, but different values, which
+    /// means that in at least one copy, the sequence number and the value
+    /// come from different writes
+    Reordered {
+        /// Local copy of the data
+        local: (u64, U),
+
+        /// Remote copy of the data
+        remote: (u64, U),
+    },
+}
+
+/// RaceCell which stores every byte of an integer separately, so that writes
+/// can be observed while they are in progress within a single copy
+///
+/// A RaceCell detects writes that were observed between the stores to its two
+/// copies, but each copy is stored using a single atomic operation if it fits
+/// in a machine word, so partial writes of this copy cannot be observed. Here,
+/// each byte of each copy is stored in its own atomic, tagged with the low
+/// bits of the sequence number of the write which stored it. Reads can thus
+/// tell apart inconsistencies between the copies, which are whole values from
+/// different writes, from tears within a copy, whose bytes come from different
+/// writes.
+///
+/// Writes store the bytes of the local copy from least significant to most
+/// significant, then those of the remote copy in the same order. So a torn
+/// copy that was observed during a single write holds a least significant
+/// prefix of the new value, followed by the rest of the old value.
+///
+/// Tags are 8-bit, so a copy whose bytes come from two writes that are a
+/// multiple of 256 writes apart will not be detected as torn.
+///
+pub struct StripedRaceCell<T: StripedData> {
+    /// Tagged bytes of the value
+    cell: RaceCell<T::Stripes>,
+
+    /// Tag of the last write
+    last_tag: AtomicU8,
+}
+//
+impl<T: StripedData> StripedRaceCell<T> {
+    /// Create a new StripedRaceCell with a certain initial content
+    pub fn new(value: T) -> Self {
+        Self {
+            cell: RaceCell::new(value.to_stripes(0)),
+            last_tag: AtomicU8::new(0),
+        }
+    }
+
+    /// Update the contents of the StripedRaceCell in a non-atomic fashion, one
+    /// byte at a time
+    pub fn set(&self, value: T) {
+        let tag = self
+            .last_tag
+            .fetch_add(1, Ordering::Relaxed)
+            .wrapping_add(1);
+        self.cell.set(value.to_stripes(tag));
+    }
+
+    /// Read the current contents of the StripedRaceCell, detecting and
+    /// classifying any data race caused by a concurrently occurring write
+    pub fn get(&self) -> StripedRead<T> {
+        let (local, remote, consistent) = match self.cell.get() {
+            Racey::Consistent(stripes) => (stripes.clone(), stripes, true),
+            Racey::Inconsistent { local, remote } => (local, remote, false),
+        };
+        let torn = |stripes: &T::Stripes| {
+            let mut tags = stripes.as_ref().iter().map(|&stripe| stripe_tag(stripe));
+            let first = tags.next();
+            tags.any(|tag| Some(tag) != first)
+        };
+        if torn(&local) || torn(&remote) {
+            StripedRead::IntraValueTear {
+                local: T::from_stripes(&local),
+                remote: T::from_stripes(&remote),
+            }
+        } else if !consistent {
+            StripedRead::InterCopyRace {
+                local: T::from_stripes(&local),
+                remote: T::from_stripes(&remote),
+            }
+        } else {
+            StripedRead::Consistent(T::from_stripes(&local))
+        }
+    }
+}
+//
+impl<T: StripedData> Debug for StripedRaceCell<T>
+where
+    RaceCell<T::Stripes>: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StripedRaceCell")
+            .field("cell", &self.cell)
+            .field("last_tag", &self.last_tag)
+            .finish()
+    }
+}
+//
+impl<T: StripedData + Default> Default for StripedRaceCell<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+/// Result of a StripedRaceCell read
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum StripedRead<T> {
+    /// The StripedRaceCell was consistent, with a certain value
+    Consistent(T),
+
+    /// Each copy was written by a single write, but the copies differ, which
+    /// means that a write was observed between the stores to both copies
+    InterCopyRace {
+        /// Local copy of the data
+        local: T,
+
+        /// Remote copy of the data
+        remote: T,
+    },
+
+    /// The bytes of at least one copy come from different writes, which means
+    /// that a write to this copy was observed while in progress
+    IntraValueTear {
+        /// Local copy of the data, as reassembled from its bytes
+        local: T,
+
+        /// Remote copy of the data, as reassembled from its bytes
+        remote: T,
+    },
+}
+
+/// Integers which can be put in a StripedRaceCell
+///
+/// Each byte of the integer is stored as a 16-bit stripe, whose high byte is
+/// the tag of the write which stored it and whose low byte is the data.
+///
+pub trait StripedData: Copy + Eq {
+    /// Stripes of the integer, from least significant to most significant
+    type Stripes: AtomicData + AsRef<[u16]>;
+
+    /// Split the integer into stripes, all bearing a certain tag
+    fn to_stripes(self, tag: u8) -> Self::Stripes;
+
+    /// Reassemble the integer from its stripes, ignoring their tags
+    fn from_stripes(stripes: &Self::Stripes) -> Self;
+}
+
+/// This macro implements support for integer types in StripedRaceCells
+macro_rules! impl_striped_data {
+    ($($data:ty),*) => ($(
+        impl StripedData for $data {
+            type Stripes = [u16; size_of::<$data>()];
+
+            fn to_stripes(self, tag: u8) -> Self::Stripes {
+                let bytes = self.to_le_bytes();
+                std::array::from_fn(|i| u16::from(tag) << 8 | u16::from(bytes[i]))
+            }
+
+            fn from_stripes(stripes: &Self::Stripes) -> Self {
+                <$data>::from_le_bytes(std::array::from_fn(|i| stripes[i] as u8))
+            }
+        }
+    )*)
+}
+//
+impl_striped_data! {
+    i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize
+}
+
+/// Tag of the write which stored a StripedRaceCell stripe
+fn stripe_tag(stripe: u16) -> u8 {
+    (stripe >> 8) as u8
+}
+
+/// Array of RaceCells which are meant to be written together, as a whole
+///
+/// Each slot of the array is a RaceCell, so `snapshot()` detects data races on
+/// individual slots. But if a snapshot overlaps with a `set_all()` write, it
+/// may also observe some slots before the write and some after it, with every
+/// slot being consistent. To detect this, every `set_all()` write is a new
+/// generation of the slice, and every slot is tagged with the generation of
+/// the write which produced it.
+///
+/// Writing individual slots with `set()` does not start a new generation, so
+/// only data races on individual slots can be detected for these writes.
+///
+pub struct RaceSlice<T: AtomicData> {
+    /// Slots, tagged with the generation in which they were last written
+    slots: Box<[RaceCell<(u64, T)>]>,
+
+    /// Current generation of the slice
+    generation: AtomicU64,
+}
+//
+impl<T: AtomicData> RaceSlice<T> {
+    /// Create a RaceSlice with a certain initial content
+    pub fn new(values: &[T]) -> Self {
+        Self {
+            slots: values
+                .iter()
+                .map(|value| RaceCell::new((0, value.clone())))
+                .collect(),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Number of slots in the RaceSlice
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Truth that the RaceSlice has no slot
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Update the contents of one slot in a non-atomic fashion
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `idx` is out of bounds.
+    ///
+    pub fn set(&self, idx: usize, value: T) {
+        let slot = &self.slots[idx];
+        slot.set((self.generation.load(Ordering::Relaxed), value));
+    }
+
+    /// Update the contents of every slot in a non-atomic fashion, starting a
+    /// new generation of the slice
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the number of values does not match the number
+    /// of slots.
+    ///
+    pub fn set_all(&self, values: &[T]) {
+        assert_eq!(
+            values.len(),
+            self.len(),
+            "Number of values does not match number of slots"
+        );
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed) + 1;
+        for (slot, value) in self.slots.iter().zip(values) {
+            slot.set((generation, value.clone()));
+        }
+    }
+
+    /// Read every slot, in order, and check that the resulting snapshot is
+    /// consistent as a whole
+    pub fn snapshot(&self) -> SliceSnapshot<T> {
+        let observations = self.slots.iter().map(RaceCell::get).collect::<Vec<_>>();
+        let mut generations = observations
+            .iter()
+            .filter_map(|observation| match observation {
+                Racey::Consistent((generation, _)) => Some(*generation),
+                Racey::Inconsistent { .. } => None,
+            });
+        let cross_generation = match generations.next() {
+            Some(first) => generations.any(|generation| generation != first),
+            None => false,
+        };
+        SliceSnapshot {
+            slots: observations
+                .into_iter()
+                .map(|observation| observation.map(|(_generation, value)| value))
+                .collect(),
+            cross_generation,
+        }
+    }
+}
+//
+impl<T: AtomicData> Debug for RaceSlice<T>
+where
+    RaceCell<(u64, T)>: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RaceSlice")
+            .field("slots", &self.slots)
+            .field("generation", &self.generation)
+            .finish()
+    }
+}
+
+/// Result of a RaceSlice snapshot
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct SliceSnapshot<T: AtomicData> {
+    /// Observation of each slot of the RaceSlice
+    pub slots: Vec<Racey<T>>,
+
+    /// Truth that the consistent slots were written in different generations
+    pub cross_generation: bool,
+}
+//
+impl<T: AtomicData> SliceSnapshot<T> {
+    /// Truth that every slot was consistent, and all slots were written in
+    /// the same generation
+    pub fn is_consistent(&self) -> bool {
+        !self.cross_generation && self.slots.iter().all(Racey::is_consistent)
+    }
+
+    /// Contents of the slots, if the snapshot was consistent
+    pub fn consistent(self) -> Option<Vec<T>> {
+        if self.cross_generation {
+            return None;
+        }
+        self.slots.into_iter().map(Racey::consistent).collect()
+    }
+}
+
+/// Sticky flag recording whether a data race was ever observed
+///
+/// In long-running tests with many reader threads, a single RaceLatch can be
+/// shared by all readers, and checked at the end of the test, instead of
+/// collecting per-reader race counters. Readers can record races manually, or
+/// let `RaceCell::get_latched()` do it for them.
+///
+/// Recording a race is cheap once the first race has been recorded, as it only
+/// takes a relaxed atomic increment. The first recording is more expensive, as
+/// it also records the time at which it occurred.
+///
+#[derive(Debug, Default)]
+pub struct RaceLatch {
+    /// Truth that a race was observed since the last reset
+    observed: AtomicBool,
+
+    /// Number of races observed since the last reset
+    count: AtomicUsize,
+
+    /// Time at which the first race was observed since the last reset
+    first_observed: Mutex<Option<Instant>>,
+}
+//
+impl RaceLatch {
+    /// Create a RaceLatch, in which no race has been recorded yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that a race was observed
+    pub fn record(&self) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        if !self.observed.load(Ordering::Relaxed) && !self.observed.swap(true, Ordering::Relaxed) {
+            *self.lock_first_observed() = Some(timing::now());
+        }
+    }
+
+    /// Truth that a race was observed since the last reset
+    pub fn observed(&self) -> bool {
+        self.observed.load(Ordering::Relaxed)
+    }
+
+    /// Number of races observed since the last reset
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Time at which the first race was observed since the last reset
+    ///
+    /// This may briefly be `None` after `observed()` has become true, while
+    /// the thread which recorded the first race is recording its timestamp.
+    ///
+    pub fn first_observed(&self) -> Option<Instant> {
+        *self.lock_first_observed()
+    }
+
+    /// Forget about previously observed races, e.g. between test phases
+    ///
+    /// Races which are recorded while the latch is being reset may or may not
+    /// be forgotten.
+    ///
+    pub fn reset(&self) {
+        let mut first_observed = self.lock_first_observed();
+        self.observed.store(false, Ordering::Relaxed);
+        self.count.store(0, Ordering::Relaxed);
+        *first_observed = None;
+    }
+
+    /// Lock the first race timestamp, ignoring poisoning since it is only ever
+    /// overwritten as a whole
+    fn lock_first_observed(&self) -> MutexGuard<'_, Option<Instant>> {
+        self.first_observed
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Bounded log of observed data races, with timestamps and thread IDs
+///
+/// Where a RaceLatch only tells whether races occurred, a RaceLog records
+/// when each race was observed, by which thread, and what the two copies of
+/// the RaceCell contained, so that one can check when races clustered during
+/// a long-running test. Readers can record races manually, or let
+/// `RaceCell::get_logged()` do it for them, then the log can be drained.
+///
+/// The log has a fixed capacity, which is allocated upfront. Races which are
+/// observed while the log is full are not recorded, only counted, so that a
+/// pathological test cannot exhaust memory. Recording a race takes a lock,
+/// which is acceptable as long as races are rare.
+///
+#[derive(Debug)]
+pub struct RaceLog<T> {
+    /// Races recorded since the last drain
+    races: Mutex<LoggedRaces<T>>,
+
+    /// Maximal number of recorded races
+    capacity: usize,
+}
+//
+impl<T> RaceLog<T> {
+    /// Create a RaceLog which can record a certain number of races
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            races: Mutex::new(LoggedRaces::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    /// Maximal number of races that can be recorded between two drains
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Record that the current thread observed a race, with the contents of
+    /// the two copies of the RaceCell at that time
+    pub fn record(&self, local: T, remote: T) {
+        let time = timing::now();
+        let thread = thread::current().id();
+        let mut races = self.lock();
+        if races.events.len() < self.capacity {
+            races.events.push(RaceEvent {
+                time,
+                thread,
+                local,
+                remote,
+            });
+        } else {
+            races.dropped += 1;
+        }
+    }
+
+    /// Number of races which were recorded since the last drain
+    pub fn len(&self) -> usize {
+        self.lock().events.len()
+    }
+
+    /// Truth that no race was recorded since the last drain
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of races which were not recorded because the log was full
+    pub fn dropped(&self) -> usize {
+        self.lock().dropped
+    }
+
+    /// Extract the races recorded since the last drain, and empty the log
+    pub fn drain(&self) -> LoggedRaces<T> {
+        mem::replace(&mut *self.lock(), LoggedRaces::with_capacity(self.capacity))
+    }
+
+    /// Lock the recorded races, ignoring poisoning since a panicking recorder
+    /// cannot leave them in an inconsistent state
+    fn lock(&self) -> MutexGuard<'_, LoggedRaces<T>> {
+        self.races.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Races extracted from a RaceLog
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct LoggedRaces<T> {
+    /// Recorded races, in the order where they were recorded
+    pub events: Vec<RaceEvent<T>>,
+
+    /// Number of races which were not recorded because the log was full
+    pub dropped: usize,
+}
+//
+impl<T> LoggedRaces<T> {
+    /// Prepare to record a certain number of races
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            events: Vec::with_capacity(capacity),
+            dropped: 0,
+        }
+    }
+}
+
+/// Data race recorded by a RaceLog
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct RaceEvent<T> {
+    /// Time at which the race was recorded
+    pub time: Instant,
+
+    /// Thread which observed the race
+    pub thread: ThreadId,
+
+    /// Contents of the first copy of the data
+    pub local: T,
+
+    /// Contents of the second copy of the data
+    pub remote: T,
+}
+
+/// Registry of named RaceCells, which tells which of them observed data races
+///
+/// When many shared memory locations are instrumented with RaceCells, knowing
+/// that a race occurred somewhere is not enough, one must also know where.
+/// Cells which are created using `register()` count their reads and the data
+/// races that they detect, and `report()` collects these counts, along with
+/// the name that each cell was registered with.
+///
+/// Counters are per-cell relaxed atomics, which are only aggregated when a
+/// report is produced, so reads of a registered cell cost about as much as
+/// reads of a CountingRaceCell.
+///
+#[derive(Debug, Default)]
+pub struct RaceRegistry {
+    /// Name and counters of each registered cell, in registration order
+    cells: Mutex<Vec<(String, Arc<RaceCounters>)>>,
+}
+//
+impl RaceRegistry {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a RaceCell whose reads and races are reported under some name
+    ///
+    /// Names do not need to be unique. Each registered cell gets its own line
+    /// in the report, even if several cells are registered with one name.
+    ///
+    pub fn register<T: AtomicData>(&self, name: &str, value: T) -> RegisteredRaceCell<T> {
+        let counters = Arc::new(RaceCounters::default());
+        self.lock().push((name.to_owned(), counters.clone()));
+        RegisteredRaceCell {
+            cell: RaceCell::new(value),
+            counters,
+        }
+    }
+
+    /// Number of reads and races of each registered cell, in registration
+    /// order, as of now
+    pub fn report(&self) -> Vec<(String, RaceStats)> {
+        self.lock()
+            .iter()
+            .map(|(name, counters)| (name.clone(), counters.stats()))
+            .collect()
+    }
+
+    /// Names of the registered cells which observed at least one data race
+    pub fn raced(&self) -> Vec<String> {
+        self.report()
+            .into_iter()
+            .filter(|(_, stats)| stats.races > 0)
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    /// Lock the list of registered cells, ignoring poisoning since a cell is
+    /// registered in a single push
+    fn lock(&self) -> MutexGuard<'_, Vec<(String, Arc<RaceCounters>)>> {
+        self.cells.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// RaceCell which reports its reads and data races to a RaceRegistry
+pub struct RegisteredRaceCell<T: AtomicData> {
+    /// Underlying RaceCell
+    cell: RaceCell<T>,
+
+    /// Counters shared with the registry
+    counters: Arc<RaceCounters>,
+}
+//
+impl<T: AtomicData> RegisteredRaceCell<T> {
+    /// Update the internal contents of the RaceCell in a non-atomic fashion
+    pub fn set(&self, value: T) {
+        self.cell.set(value)
+    }
+
+    /// Read the current contents of the RaceCell, reporting the read and any
+    /// data race that it detects to the registry
+    pub fn get(&self) -> Racey<T> {
+        let result = self.cell.get();
+        self.counters.record(&result);
+        result
+    }
+
+    /// Number of reads and races of this cell, as reported to the registry
+    pub fn stats(&self) -> RaceStats {
+        self.counters.stats()
+    }
+
+    /// Access the underlying RaceCell, whose reads are not reported
+    pub fn as_race_cell(&self) -> &RaceCell<T> {
+        &self.cell
+    }
+}
+//
+impl<T: AtomicData> Debug for RegisteredRaceCell<T>
+where
+    RaceCell<T>: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RegisteredRaceCell")
+            .field("cell", &self.cell)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+/// Read and race counters of a RegisteredRaceCell
+#[derive(Debug, Default)]
+struct RaceCounters {
+    /// Number of reads performed via `get()`
+    reads: AtomicUsize,
+
+    /// Number of reads which detected a data race
+    races: AtomicUsize,
+}
+//
+impl RaceCounters {
+    /// Count a read, and the data race that it detected, if any
+    fn record<T: AtomicData>(&self, result: &Racey<T>) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        if result.is_inconsistent() {
+            self.races.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Current values of the counters
+    fn stats(&self) -> RaceStats {
+        RaceStats {
+            reads: self.reads.load(Ordering::Relaxed),
+            races: self.races.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Number of reads of a RegisteredRaceCell, and of data races that they
+/// detected
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct RaceStats {
+    /// Number of reads performed via `get()`
+    pub reads: usize,
+
+    /// Number of reads which detected a data race
+    pub races: usize,
+}
+//
+impl RaceStats {
+    /// Fraction of reads which detected a data race, or 0.0 if no read was
+    /// performed
+    pub fn race_fraction(&self) -> f64 {
+        if self.reads == 0 {
+            0.0
+        } else {
+            self.races as f64 / self.reads as f64
+        }
+    }
+}
+
+/// Look for data races on a RaceCell, writing it from one thread and reading
+/// it from another
+///
+/// The writer thread calls `writer(i)` for i in `0..max_writes`, while the
+/// reader thread reads `cell` until it observes an inconsistency or the writer
+/// is done. The writer stops early once a race has been found.
+///
+/// This answers the question "can this write pattern ever expose a torn
+/// state to an unsynchronized reader?". For protocols where the reader must
+/// also follow some synchronization protocol, use `search_races()`.
+///
+/// # Panics
+///
+/// This function will propagate panics from the writer.
+///
+pub fn run_until_race<T: AtomicData + Send>(
+    cell: &RaceCell<T>,
+    writer: impl FnMut(usize) + Send,
+    max_writes: usize,
+) -> RaceSearch<T>
+where
+    RaceCell<T>: Sync,
+{
+    search_races(writer, || cell.get(), max_writes)
+}
+
+/// Look for data races, performing writes from one thread and reads from
+/// another
+///
+/// This is a generalization of `run_until_race()` where reads are performed
+/// by calling `reader()`, which can e.g. lock a Mutex before reading a
+/// RaceCell.
+///
+/// # Panics
+///
+/// This function will propagate panics from the writer and the reader.
+///
+pub fn search_races<T: AtomicData + Send>(
+    mut writer: impl FnMut(usize) + Send,
+    mut reader: impl FnMut() -> Racey<T> + Send,
+    max_writes: usize,
+) -> RaceSearch<T> {
+    let found = AtomicBool::new(false);
+    let done = AtomicBool::new(false);
+    let mut writes = 0;
+    let mut reads = 0;
+    let mut race = None;
+    crate::concurrent_test_2(
+        || {
+            while writes < max_writes && !found.load(Ordering::Relaxed) {
+                writer(writes);
+                writes += 1;
+            }
+            done.store(true, Ordering::Release);
+        },
+        || loop {
+            let finished = done.load(Ordering::Acquire);
+            reads += 1;
+            if let Racey::Inconsistent { local, remote } = reader() {
+                found.store(true, Ordering::Relaxed);
+                race = Some((local, remote));
+                break;
+            }
+            if finished {
+                break;
+            }
+        },
+    );
+    match race {
+        Some((local, remote)) => RaceSearch::Found {
+            reads,
+            writes,
+            local,
+            remote,
+        },
+        None => RaceSearch::NotFound { reads, writes },
+    }
+}
+
+/// Check that no data race is observed while performing writes from one
+/// thread and reads from another
+///
+/// This is the protected-protocol counterpart of `search_races()`.
+///
+/// # Panics
+///
+/// This function panics if a data race is observed, and propagates panics
+/// from the writer and the reader.
+///
+pub fn assert_no_race_within<T: AtomicData + Debug + Send>(
+    writer: impl FnMut(usize) + Send,
+    reader: impl FnMut() -> Racey<T> + Send,
+    max_writes: usize,
+) {
+    if let RaceSearch::Found {
+        reads,
+        writes,
+        local,
+        remote,
+    } = search_races(writer, reader, max_writes)
+    {
+        panic!(
+            "Observed a data race after {} reads and {} writes (local: {:?}, remote: {:?})",
+            reads, writes, local, remote
+        );
+    }
+}
+
+/// Outcome of a search for data races
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum RaceSearch<T> {
+    /// A data race was observed
+    Found {
+        /// Number of reads, including the one which observed the race
+        reads: usize,
+
+        /// Number of writes performed before the writer stopped
+        writes: usize,
+
+        /// Local copy of the data, as observed by the racing read
+        local: T,
+
+        /// Remote copy of the data, as observed by the racing read
+        remote: T,
+    },
+
+    /// The writer completed all of its writes without a race being observed
+    NotFound {
+        /// Number of reads
+        reads: usize,
+
+        /// Number of writes
+        writes: usize,
+    },
+}
+//
+impl<T> RaceSearch<T> {
+    /// Truth that a data race was observed
+    pub fn is_found(&self) -> bool {
+        matches!(self, Self::Found { .. })
+    }
+}
+
+/// Second copy of the data of a RaceCell
+///
+/// The copy is stored in the middle of a heap allocation, surrounded on each
+/// side by `padding` cache-padded copies which are never accessed again.
+///
+struct RemoteCopy<W> {
+    /// Padded allocation
+    storage: Box<[CachePadded<W>]>,
+
+    /// Number of padding elements on each side of the copy
+    padding: usize,
+}
+//
+impl<W> RemoteCopy<W> {
+    /// Allocate a copy with some padding on each side
+    fn new(padding: usize, mut make: impl FnMut() -> W) -> Self {
+        Self {
+            storage: (0..2 * padding + 1)
+                .map(|_| CachePadded::new(make()))
+                .collect(),
+            padding,
+        }
+    }
+}
+//
+impl<W: Default> Default for RemoteCopy<W> {
+    fn default() -> Self {
+        Self::new(0, W::default)
+    }
+}
+//
+impl<W> Deref for RemoteCopy<W> {
+    type Target = W;
+
+    fn deref(&self) -> &W {
+        &self.storage[self.padding]
+    }
+}
+
+/// This is the result of a RaceCell read
+///
+/// A consistent result can be directly compared with a value of the data
+/// type, e.g. `assert_eq!(cell.get(), 42)`, whereas an inconsistent result
+/// never compares equal to such a value.
+///
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum Racey<U: AtomicData> {
+    /// The RaceCell was internally consistent, and its content was copied
+    Consistent(U),
+
+    /// The RaceCell was internally inconsistent: a data race has occurred
+    Inconsistent {
+        /// Contents of the first copy of the data
+        local: U,
+
+        /// Contents of the second copy of the data
+        remote: U,
+    },
+}
+//
+impl<U: AtomicData> Racey<U> {
+    /// Build the observation of a RaceCell from both copies of its data
+    fn from_copies(local: U, remote: U) -> Self {
+        if local.identical(&remote) {
+            Racey::Consistent(local)
+        } else {
+            Racey::Inconsistent { local, remote }
+        }
+    }
+
+    /// Truth that the RaceCell was consistent
+    ///
+    /// ```
+    /// # use testbench::race_cell::RaceCell;
+    /// assert!(RaceCell::new(42).get().is_consistent());
+    /// ```
+    ///
+    pub fn is_consistent(&self) -> bool {
+        matches!(self, Racey::Consistent(_))
+    }
+
+    /// Truth that the RaceCell was inconsistent
+    ///
+    /// ```
+    /// # use testbench::race_cell::RaceCell;
+    /// assert!(!RaceCell::new(42).get().is_inconsistent());
+    /// ```
+    ///
+    pub fn is_inconsistent(&self) -> bool {
+        !self.is_consistent()
+    }
+
+    /// Contents of the RaceCell, if it was consistent
+    ///
+    /// ```
+    /// # use testbench::race_cell::RaceCell;
+    /// assert_eq!(RaceCell::new(42).get().consistent(), Some(42));
+    /// ```
+    ///
+    pub fn consistent(self) -> Option<U> {
+        match self {
+            Racey::Consistent(value) => Some(value),
+            Racey::Inconsistent { .. } => None,
+        }
+    }
+
+    /// Contents of both copies of the data, if they were inconsistent
+    ///
+    /// ```
+    /// # use testbench::race_cell::RaceCell;
+    /// assert_eq!(RaceCell::new(42).get().values(), None);
+    /// ```
+    ///
+    pub fn values(self) -> Option<(U, U)> {
+        match self {
+            Racey::Consistent(_) => None,
+            Racey::Inconsistent { local, remote } => Some((local, remote)),
+        }
+    }
+
+    /// Contents of the RaceCell, which is expected to be consistent
+    ///
+    /// ```
+    /// # use testbench::race_cell::RaceCell;
+    /// assert_eq!(RaceCell::new(42).get().unwrap_consistent(), 42);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the RaceCell was inconsistent, reporting the
+    /// contents of both copies of the data.
+    ///
+    pub fn unwrap_consistent(self) -> U
+    where
+        U: Debug,
+    {
+        self.expect_consistent("Expected a consistent RaceCell")
+    }
+
+    /// Contents of the RaceCell, which is expected to be consistent
+    ///
+    /// ```
+    /// # use testbench::race_cell::RaceCell;
+    /// let value = RaceCell::new(42).get().expect_consistent("Data race!");
+    /// assert_eq!(value, 42);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// This function panics with the provided message if the RaceCell was
+    /// inconsistent, also reporting the contents of both copies of the data.
+    ///
+    pub fn expect_consistent(self, msg: &str) -> U
+    where
+        U: Debug,
+    {
+        match self {
+            Racey::Consistent(value) => value,
+            Racey::Inconsistent { local, remote } => {
+                panic!(
+                    "{}: local copy is {:?}, remote copy is {:?}",
+                    msg, local, remote
+                )
+            }
+        }
+    }
+
+    /// Transform the contents of the RaceCell
+    ///
+    /// If the RaceCell was inconsistent, both copies of the data are
+    /// transformed, so the result remains inconsistent.
+    ///
+    /// ```
+    /// # use testbench::race_cell::{RaceCell, Racey};
+    /// assert_eq!(RaceCell::new(42).get().map(|x| x * 2), Racey::Consistent(84));
+    /// ```
+    ///
+    pub fn map<V: AtomicData>(self, mut f: impl FnMut(U) -> V) -> Racey<V> {
+        match self {
+            Racey::Consistent(value) => Racey::Consistent(f(value)),
+            Racey::Inconsistent { local, remote } => Racey::Inconsistent {
+                local: f(local),
+                remote: f(remote),
+            },
+        }
+    }
+}
+//
+impl<U: AtomicData> From<Racey<U>> for Option<U> {
+    fn from(racey: Racey<U>) -> Self {
+        racey.consistent()
+    }
+}
+//
+/// Consistent reads are printed as their value, and inconsistent reads as
+/// `inconsistent(local=5, remote=7)`
+impl<U: AtomicData + fmt::Display> fmt::Display for Racey<U> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Racey::Consistent(value) => value.fmt(f),
+            Racey::Inconsistent { local, remote } => {
+                write!(f, "inconsistent(local={}, remote={})", local, remote)
+            }
+        }
+    }
+}
+//
+impl<U: AtomicData + PartialEq> PartialEq<U> for Racey<U> {
+    fn eq(&self, other: &U) -> bool {
+        match self {
+            Racey::Consistent(value) => value == other,
+            Racey::Inconsistent { .. } => false,
+        }
+    }
+}
+//
+// Coherence does not allow implementing the reflected comparison for any data
+// type, so we only do it for the primitive types.
+macro_rules! impl_reflected_eq {
+    ($($data:ty),*) => ($(
+        impl PartialEq<Racey<$data>> for $data {
+            fn eq(&self, other: &Racey<$data>) -> bool {
+                other == self
+            }
+        }
+    )*)
+}
+//
+impl_reflected_eq! {
+    bool, char, f32, f64, i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize
+}
+
+/// Error returned by `RaceCell::probe()` when a data race was detected
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct Inconsistency;
+//
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the copies of a RaceCell are inconsistent")
+    }
+}
+//
+impl std::error::Error for Inconsistency {}
+
+/// Error returned when retried RaceCell reads never observed consistent data
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct RetriesExhausted<T> {
+    /// Number of times the RaceCell was read
+    pub attempts: usize,
+
+    /// Local copy of the data, as observed by the last attempt
+    pub local: T,
+
+    /// Remote copy of the data, as observed by the last attempt
+    pub remote: T,
+}
+//
+impl<T> fmt::Display for RetriesExhausted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the copies of a RaceCell were still inconsistent after {} attempts",
+            self.attempts
+        )
+    }
+}
+//
+impl<T: Debug> std::error::Error for RetriesExhausted<T> {}
+
+/// Statistics of the reads made by `RaceCell::wait_for()`
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct WaitStats {
+    /// Number of times the RaceCell was read
+    pub reads: usize,
+
+    /// Number of reads which detected a data race
+    pub races: usize,
+}
+
+/// Error returned when `RaceCell::wait_for()` did not observe its target value
+/// before the timeout
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct WaitTimeout<T: AtomicData> {
+    /// Statistics of the reads made before the timeout
+    pub stats: WaitStats,
+
+    /// Contents of the RaceCell, as observed by the last read
+    pub last: Racey<T>,
+}
+//
+impl<T: AtomicData> fmt::Display for WaitTimeout<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "a RaceCell did not hold the expected value after {} reads ({} races)",
+            self.stats.reads, self.stats.races
+        )
+    }
+}
+//
+impl<T: AtomicData + Debug> std::error::Error for WaitTimeout<T> {}
+
+/// Requirements on the data held by a RaceCell
+pub trait AtomicData: Clone + Sized {
+    /// Atomic wrapper type for this data implementing relaxed atomic load/store
+    type AtomicWrapper: AtomicLoadStore<Content = Self>;
+
+    /// Truth that two values are identical
+    ///
+    /// This is used to check that both copies of a RaceCell are consistent.
+    /// It must be reflexive, unlike the `==` operator of floating-point types,
+    /// which is why the latter compare their bit patterns instead.
+    ///
+    fn identical(&self, other: &Self) -> bool;
+}
+///
+/// Atomic wrapper type for a certain kind of value
+///
+/// For the implementation of RaceCell not to be considered as undefined
+/// behaviour and trashed by the Rust compiler in release mode, the underlying
+/// type must be loaded and stored atomically. This requires synchronizing
+/// accesses to it using things like the std::sync::atomic::AtomicXyz wrappers.
+///
+/// The only guarantee that we need is that loads and stores are atomic. We do
+/// not need any other memory ordering guarantee. This is why we allow for
+/// more wrapper type implementation freedom by not specifying memory orderings,
+/// and internally relying only on relaxed ordering.
+///
+pub trait AtomicLoadStore: Sized {
+    /// Type of data that is being wrapped
+    type Content: AtomicData<AtomicWrapper = Self>;
+
+    /// Create an atomic wrapper for a value of type U
+    fn new(v: Self::Content) -> Self;
+
+    /// Atomically load a value from the wrapper
+    fn relaxed_load(&self) -> Self::Content;
+
+    /// Atomically load a value from the wrapper, if it is valid
+    ///
+    /// Wrappers which store values in a representation that has invalid
+    /// states, such as a `u32` for a `char`, should override this to return
+    /// `None` when they observe such an invalid state. RaceCell reads treat
+    /// this as an inconsistency.
+    ///
+    fn relaxed_try_load(&self) -> Option<Self::Content> {
+        Some(self.relaxed_load())
+    }
+
+    /// Truth that this wrapper and another one hold identical, valid values
+    ///
+    /// This is used to check that both copies of a RaceCell are consistent
+    /// without returning their contents. Wrappers of composite data should
+    /// override it to compare their parts one by one, stopping at the first
+    /// mismatch, and wrappers for which loading is expensive should override
+    /// it to compare values in place.
+    ///
+    fn relaxed_matches(&self, other: &Self) -> bool {
+        match (self.relaxed_try_load(), other.relaxed_try_load()) {
+            (Some(value), Some(other_value)) => value.identical(&other_value),
+            _ => false,
+        }
+    }
+
+    /// Atomically load a value from the wrapper, unless it is valid and
+    /// identical to an expected value
+    ///
+    /// This is used by RaceCell reads to check the second copy of the data
+    /// against the first one. Wrappers for which loading is expensive should
+    /// override it to compare values in place, and only load them if they
+    /// differ. Like `relaxed_load()`, this may substitute a placeholder for
+    /// an invalid value.
+    ///
+    fn relaxed_load_if_different(&self, expected: &Self::Content) -> Option<Self::Content> {
+        match self.relaxed_try_load() {
+            Some(value) if value.identical(expected) => None,
+            Some(value) => Some(value),
+            None => Some(self.relaxed_load()),
+        }
+    }
+
+    /// Atomically store a new value into the wrapper
+    fn relaxed_store(&self, val: Self::Content);
+}
+///
+/// This macro implements support for non-generic standard atomic types
+///
+macro_rules! impl_atomic_data {
+    ($($data:ty => $wrapper:ty),*) => ($(
+        impl AtomicData for $data {
+            type AtomicWrapper = $wrapper;
+
+            fn identical(&self, other: &Self) -> bool {
+                self == other
+            }
+        }
+
+        impl AtomicLoadStore for $wrapper {
+            type Content = $data;
+
+            fn new(v: $data) -> $wrapper {
+                <$wrapper>::new(v)
+            }
+
+            fn relaxed_load(&self) -> $data {
+                <$wrapper>::load(self, Ordering::Relaxed)
+            }
+
+            fn relaxed_store(&self, val: $data) {
+                <$wrapper>::store(self, val, Ordering::Relaxed)
+            }
+        }
+    )*)
+}
+//
+impl_atomic_data! {
+    bool  => AtomicBool,
+    i8    => AtomicI8,
+    i16   => AtomicI16,
+    i32   => AtomicI32,
+    i64   => AtomicI64,
+    isize => AtomicIsize,
+    u8    => AtomicU8,
+    u16   => AtomicU16,
+    u32   => AtomicU32,
+    u64   => AtomicU64,
+    usize => AtomicUsize
+}
+//
+// Atomic pointers are a bit special as they are generic, for now we will just
+// treat them as a special case.
+//
+impl<V> AtomicData for *mut V {
+    type AtomicWrapper = AtomicPtr<V>;
+
+    fn identical(&self, other: &Self) -> bool {
+        self == other
+    }
+}
+//
+impl<V> AtomicLoadStore for AtomicPtr<V> {
+    type Content = *mut V;
+
+    fn new(v: *mut V) -> AtomicPtr<V> {
+        <AtomicPtr<V>>::new(v)
+    }
+
+    fn relaxed_load(&self) -> *mut V {
+        <AtomicPtr<V>>::load(self, Ordering::Relaxed)
+    }
+
+    fn relaxed_store(&self, val: *mut V) {
+        <AtomicPtr<V>>::store(self, val, Ordering::Relaxed)
+    }
+}
+
+/// Atomic wrapper for `NonNull<V>`, storing it as a raw pointer
+///
+/// Null cannot be stored, so observing it means that something went wrong.
+/// `relaxed_try_load()` then returns `None`, which RaceCell reads report as an
+/// inconsistency, and `relaxed_load()` falls back to `NonNull::dangling()`.
+///
+pub struct AtomicNonNull<V>(AtomicPtr<V>);
+//
+impl<V> AtomicData for NonNull<V> {
+    type AtomicWrapper = AtomicNonNull<V>;
+
+    fn identical(&self, other: &Self) -> bool {
+        self == other
+    }
+}
+//
+impl<V> AtomicLoadStore for AtomicNonNull<V> {
+    type Content = NonNull<V>;
+
+    fn new(v: NonNull<V>) -> Self {
+        Self(AtomicPtr::new(v.as_ptr()))
+    }
+
+    fn relaxed_load(&self) -> NonNull<V> {
+        self.relaxed_try_load().unwrap_or_else(NonNull::dangling)
+    }
+
+    fn relaxed_try_load(&self) -> Option<NonNull<V>> {
+        NonNull::new(self.0.load(Ordering::Relaxed))
+    }
+
+    fn relaxed_store(&self, val: NonNull<V>) {
+        self.0.store(val.as_ptr(), Ordering::Relaxed)
+    }
+}
+//
+impl<V> Debug for AtomicNonNull<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("AtomicNonNull").field(&self.0).finish()
+    }
+}
+
+/// Atomic wrapper for `Option<NonNull<V>>`, storing `None` as a null pointer
+pub struct AtomicOptionNonNull<V>(AtomicPtr<V>);
+//
+impl<V> AtomicData for Option<NonNull<V>> {
+    type AtomicWrapper = AtomicOptionNonNull<V>;
+
+    fn identical(&self, other: &Self) -> bool {
+        self == other
+    }
+}
+//
+impl<V> AtomicLoadStore for AtomicOptionNonNull<V> {
+    type Content = Option<NonNull<V>>;
+
+    fn new(v: Option<NonNull<V>>) -> Self {
+        Self(AtomicPtr::new(to_raw_pointer(v)))
+    }
+
+    fn relaxed_load(&self) -> Option<NonNull<V>> {
+        NonNull::new(self.0.load(Ordering::Relaxed))
+    }
+
+    fn relaxed_store(&self, val: Option<NonNull<V>>) {
+        self.0.store(to_raw_pointer(val), Ordering::Relaxed)
+    }
+}
+//
+impl<V> Debug for AtomicOptionNonNull<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("AtomicOptionNonNull").field(&self.0).finish()
+    }
+}
+//
+/// Convert an optional non-null pointer to a raw pointer, mapping `None` to
+/// the null pointer
+fn to_raw_pointer<V>(pointer: Option<NonNull<V>>) -> *mut V {
+    pointer.map_or(ptr::null_mut(), NonNull::as_ptr)
+}
+
+/// This macro implements support for floating-point types, which are stored
+/// as their bit patterns in an atomic integer
+macro_rules! impl_atomic_float {
+    ($($data:ty => $wrapper:ident($bits:ty)),*) => ($(
+        #[doc = concat!("Atomic wrapper for `", stringify!($data), "`, storing its bit pattern")]
+        #[derive(Debug, Default)]
+        pub struct $wrapper($bits);
+
+        impl AtomicData for $data {
+            type AtomicWrapper = $wrapper;
+
+            fn identical(&self, other: &Self) -> bool {
+                self.to_bits() == other.to_bits()
+            }
+        }
+
+        impl AtomicLoadStore for $wrapper {
+            type Content = $data;
+
+            fn new(v: $data) -> $wrapper {
+                $wrapper(<$bits>::new(v.to_bits()))
+            }
+
+            fn relaxed_load(&self) -> $data {
+                <$data>::from_bits(self.0.load(Ordering::Relaxed))
+            }
+
+            fn relaxed_store(&self, val: $data) {
+                self.0.store(val.to_bits(), Ordering::Relaxed)
+            }
+        }
+    )*)
+}
+//
+impl_atomic_float! {
+    f32 => AtomicF32(AtomicU32),
+    f64 => AtomicF64(AtomicU64)
+}
+
+/// Atomic wrapper for `char`, storing its scalar value
+#[derive(Debug, Default)]
+pub struct AtomicChar(AtomicU32);
+//
+impl AtomicData for char {
+    type AtomicWrapper = AtomicChar;
+
+    fn identical(&self, other: &Self) -> bool {
+        self == other
+    }
+}
+//
+impl AtomicLoadStore for AtomicChar {
+    type Content = char;
+
+    fn new(v: char) -> AtomicChar {
+        AtomicChar(AtomicU32::new(v.into()))
+    }
+
+    /// Invalid scalar values cannot be stored, but if one were somehow
+    /// observed, it would be loaded as `char::REPLACEMENT_CHARACTER`
+    fn relaxed_load(&self) -> char {
+        self.relaxed_try_load()
+            .unwrap_or(char::REPLACEMENT_CHARACTER)
+    }
+
+    fn relaxed_try_load(&self) -> Option<char> {
+        char::from_u32(self.0.load(Ordering::Relaxed))
+    }
+
+    fn relaxed_store(&self, val: char) {
+        self.0.store(val.into(), Ordering::Relaxed)
+    }
+}
+
+/// This macro implements support for nonzero integer types, which are stored
+/// as plain integers in the corresponding atomic integer type
+///
+/// Zero cannot be stored, so observing it means that something went wrong.
+/// `relaxed_try_load()` then returns `None`, which RaceCell reads report as
+/// an inconsistency, and `relaxed_load()` falls back to the maximal value.
+///
+macro_rules! impl_atomic_nonzero {
+    ($($data:ident($int:ty) => $wrapper:ident($atomic:ty)),*) => ($(
+        #[doc = concat!("Atomic wrapper for `", stringify!($data), "`, storing its integer value")]
+        #[derive(Debug)]
+        pub struct $wrapper($atomic);
+
+        impl AtomicData for $data {
+            type AtomicWrapper = $wrapper;
+
+            fn identical(&self, other: &Self) -> bool {
+                self == other
+            }
+        }
+
+        impl AtomicLoadStore for $wrapper {
+            type Content = $data;
+
+            fn new(v: $data) -> $wrapper {
+                $wrapper(<$atomic>::new(v.get()))
+            }
+
+            fn relaxed_load(&self) -> $data {
+                self.relaxed_try_load().unwrap_or_else(|| {
+                    $data::new(<$int>::MAX).expect("The maximal integer is not zero")
+                })
+            }
+
+            fn relaxed_try_load(&self) -> Option<$data> {
+                $data::new(self.0.load(Ordering::Relaxed))
+            }
+
+            fn relaxed_store(&self, val: $data) {
+                self.0.store(val.get(), Ordering::Relaxed)
+            }
+        }
+    )*)
+}
+//
+impl_atomic_nonzero! {
+    NonZeroI8(i8) => AtomicNonZeroI8(AtomicI8),
+    NonZeroI16(i16) => AtomicNonZeroI16(AtomicI16),
+    NonZeroI32(i32) => AtomicNonZeroI32(AtomicI32),
+    NonZeroI64(i64) => AtomicNonZeroI64(AtomicI64),
+    NonZeroIsize(isize) => AtomicNonZeroIsize(AtomicIsize),
+    NonZeroU8(u8) => AtomicNonZeroU8(AtomicU8),
+    NonZeroU16(u16) => AtomicNonZeroU16(AtomicU16),
+    NonZeroU32(u32) => AtomicNonZeroU32(AtomicU32),
+    NonZeroU64(u64) => AtomicNonZeroU64(AtomicU64),
+    NonZeroUsize(usize) => AtomicNonZeroUsize(AtomicUsize)
+}
+
+/// This macro implements support for 128-bit integers, which are stored as
+/// two separate 64-bit halves
+///
+/// Each half is loaded and stored separately, so even a single copy of the
+/// value can be torn. This makes 128-bit RaceCells even more race-prone than
+/// others, which is fine since the point of a RaceCell is to be non-atomic.
+///
+macro_rules! impl_atomic_split {
+    ($($data:ty => $wrapper:ident),*) => ($(
+        #[doc = concat!("Atomic wrapper for `", stringify!($data), "`, storing it as two 64-bit halves")]
+        #[derive(Debug, Default)]
+        pub struct $wrapper {
+            /// High-order 64 bits
+            high: AtomicU64,
+
+            /// Low-order 64 bits
+            low: AtomicU64,
+        }
+
+        impl AtomicData for $data {
+            type AtomicWrapper = $wrapper;
+
+            fn identical(&self, other: &Self) -> bool {
+                self == other
+            }
+        }
+
+        impl AtomicLoadStore for $wrapper {
+            type Content = $data;
+
+            fn new(v: $data) -> $wrapper {
+                let bits = u128::from_ne_bytes(v.to_ne_bytes());
+                $wrapper {
+                    high: AtomicU64::new((bits >> 64) as u64),
+                    low: AtomicU64::new(bits as u64),
+                }
+            }
+
+            fn relaxed_load(&self) -> $data {
+                let high = self.high.load(Ordering::Relaxed);
+                let low = self.low.load(Ordering::Relaxed);
+                let bits = (u128::from(high) << 64) | u128::from(low);
+                <$data>::from_ne_bytes(bits.to_ne_bytes())
+            }
+
+            fn relaxed_store(&self, val: $data) {
+                let bits = u128::from_ne_bytes(val.to_ne_bytes());
+                self.high.store((bits >> 64) as u64, Ordering::Relaxed);
+                self.low.store(bits as u64, Ordering::Relaxed);
+            }
+        }
+    )*)
+}
+//
+impl_atomic_split! {
+    i128 => AtomicSplitI128,
+    u128 => AtomicSplitU128
+}
+
+/// This macro implements support for tuples of supported types, which are
+/// stored as tuples of atomic wrappers, and accessed element by element
+macro_rules! impl_atomic_tuple {
+    ($(($($data:ident / $wrapper:ident / $idx:tt),*)),*) => ($(
+        impl<$($data: AtomicData),*> AtomicData for ($($data,)*) {
+            type AtomicWrapper = ($($data::AtomicWrapper,)*);
+
+            fn identical(&self, other: &Self) -> bool {
+                $(self.$idx.identical(&other.$idx))&&*
+            }
+        }
+
+        impl<$($wrapper: AtomicLoadStore),*> AtomicLoadStore for ($($wrapper,)*) {
+            type Content = ($($wrapper::Content,)*);
+
+            fn new(v: Self::Content) -> Self {
+                ($($wrapper::new(v.$idx),)*)
+            }
+
+            fn relaxed_load(&self) -> Self::Content {
+                ($(self.$idx.relaxed_load(),)*)
+            }
+
+            fn relaxed_try_load(&self) -> Option<Self::Content> {
+                Some(($(self.$idx.relaxed_try_load()?,)*))
+            }
+
+            fn relaxed_matches(&self, other: &Self) -> bool {
+                $(self.$idx.relaxed_matches(&other.$idx))&&*
+            }
+
+            fn relaxed_store(&self, val: Self::Content) {
+                $(self.$idx.relaxed_store(val.$idx);)*
+            }
+        }
+    )*)
+}
+//
+impl_atomic_tuple! {
+    (A / WA / 0, B / WB / 1),
+    (A / WA / 0, B / WB / 1, C / WC / 2),
+    (A / WA / 0, B / WB / 1, C / WC / 2, D / WD / 3)
+}
+
+// Arrays of supported types are stored as arrays of atomic wrappers, and
+// accessed element by element, in order. Note that `RaceCell::default()` is
+// only available for arrays that implement `Default`, i.e. up to 32 elements,
+// use `RaceCell::new()` for larger arrays.
+//
+impl<T: AtomicData, const N: usize> AtomicData for [T; N] {
+    type AtomicWrapper = [T::AtomicWrapper; N];
+
+    fn identical(&self, other: &Self) -> bool {
+        self.iter().zip(other).all(|(x, y)| x.identical(y))
+    }
+}
+//
+impl<W: AtomicLoadStore, const N: usize> AtomicLoadStore for [W; N] {
+    type Content = [W::Content; N];
+
+    fn new(v: Self::Content) -> Self {
+        v.map(W::new)
+    }
+
+    fn relaxed_load(&self) -> Self::Content {
+        std::array::from_fn(|i| self[i].relaxed_load())
+    }
+
+    fn relaxed_try_load(&self) -> Option<Self::Content> {
+        let elements: [Option<W::Content>; N] = std::array::from_fn(|i| self[i].relaxed_try_load());
+        if elements.iter().all(Option::is_some) {
+            Some(elements.map(|element| element.expect("Checked above")))
+        } else {
+            None
+        }
+    }
+
+    fn relaxed_matches(&self, other: &Self) -> bool {
+        self.iter().zip(other).all(|(x, y)| x.relaxed_matches(y))
+    }
+
+    fn relaxed_load_if_different(&self, expected: &Self::Content) -> Option<Self::Content> {
+        // Elements are only loaded once: those before the first mismatch are
+        // identical to the expected ones, and those after it are loaded anew
+        for (idx, (wrapper, element)) in self.iter().zip(expected).enumerate() {
+            if let Some(mismatch) = wrapper.relaxed_load_if_different(element) {
+                let mut mismatch = Some(mismatch);
+                return Some(std::array::from_fn(|i| match i.cmp(&idx) {
+                    cmp::Ordering::Less => expected[i].clone(),
+                    cmp::Ordering::Equal => mismatch.take().expect("Only taken once"),
+                    cmp::Ordering::Greater => self[i].relaxed_load(),
+                }));
+            }
+        }
+        None
+    }
+
+    fn relaxed_store(&self, val: Self::Content) {
+        for (wrapper, element) in self.iter().zip(val) {
+            wrapper.relaxed_store(element);
+        }
+    }
+}
+
+// The unit type has no storage, so its atomic wrapper is itself. More
+// generally, zero-sized data, such as empty arrays and structs with no field,
+// have zero-sized atomic wrappers which do not allocate any memory. Since
+// there is no memory to race on, RaceCells of such data are always consistent.
+//
+impl AtomicData for () {
+    type AtomicWrapper = ();
+
+    fn identical(&self, _other: &Self) -> bool {
+        true
+    }
+}
+//
+impl AtomicLoadStore for () {
+    type Content = ();
+
+    fn new(_v: ()) {}
+
+    fn relaxed_load(&self) {}
+
+    fn relaxed_store(&self, _val: ()) {}
+}
+
+/// Opt-in wrapper for putting arbitrary `Clone + Eq` data in a RaceCell
+///
+/// Types which have no atomic representation, such as `String`, can be put in
+/// a RaceCell by wrapping them into `Locked`. Each copy of the data is then
+/// protected by its own Mutex, which makes accesses to an individual copy
+/// atomic, while the pair of copies is still read and written non-atomically.
+/// This is exactly the property that RaceCell needs to detect races.
+///
+/// Locking is much slower than atomic loads and stores, and may hide races by
+/// synchronizing threads more than the code under test does, which is why it
+/// must be chosen explicitly. See also the `LockedRaceCell` type alias.
+///
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize), serde(transparent))]
+pub struct Locked<T>(pub T);
+//
+impl<T> Locked<T> {
+    /// Extract the wrapped value
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+//
+impl<T> From<T> for Locked<T> {
+    fn from(value: T) -> Self {
+        Self(value)
+    }
+}
+//
+impl<T: Clone + Eq> AtomicData for Locked<T> {
+    type AtomicWrapper = LockedData<T>;
+
+    fn identical(&self, other: &Self) -> bool {
+        self == other
+    }
+}
+
+/// Mutex-based wrapper for data that is put in a RaceCell via `Locked`
+#[derive(Debug, Default)]
+pub struct LockedData<T>(Mutex<T>);
+//
+impl<T> LockedData<T> {
+    /// Lock the inner data, ignoring poisoning since every access either
+    /// clones the data or overwrites it as a whole
+    fn lock(&self) -> MutexGuard<'_, T> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+//
+impl<T: Clone + Eq> AtomicLoadStore for LockedData<T> {
+    type Content = Locked<T>;
+
+    fn new(v: Locked<T>) -> Self {
+        Self(Mutex::new(v.0))
+    }
+
+    fn relaxed_load(&self) -> Locked<T> {
+        Locked(self.lock().clone())
+    }
+
+    fn relaxed_matches(&self, other: &Self) -> bool {
+        // Compare in place rather than cloning, without locking a Mutex twice
+        ptr::eq(self, other) || *self.lock() == *other.lock()
+    }
+
+    fn relaxed_load_if_different(&self, expected: &Locked<T>) -> Option<Locked<T>> {
+        let data = self.lock();
+        (*data != expected.0).then(|| Locked(data.clone()))
+    }
+
+    fn relaxed_store(&self, val: Locked<T>) {
+        *self.lock() = val.0;
+    }
+}
+
+/// RaceCell holding arbitrary `Clone + Eq` data, with one Mutex per copy
+pub type LockedRaceCell<T> = RaceCell<Locked<T>>;
+
+/// Copy types that can be put in a RaceCell via crossbeam's `AtomicCell`
+///
+/// `AtomicCell` loads and stores any `Copy` type atomically, using hardware
+/// atomics if the type has the size and alignment of a primitive atomic type
+/// and a lock otherwise. Implementing this marker trait for a small `Copy`
+/// type, such as a fieldless enum, lets it be put in a RaceCell. It is already
+/// implemented for `Option`s of primitive types.
+///
+/// Note that, unlike for the `Locked` wrapper, lock-based `AtomicCell`s are
+/// chosen automatically, so a type which is too large for hardware atomics
+/// will silently incur extra synchronization.
+///
+#[cfg(feature = "crossbeam")]
+pub trait CrossbeamData: Copy + Eq {}
+//
+#[cfg(feature = "crossbeam")]
+impl<T: CrossbeamData> AtomicData for T {
+    type AtomicWrapper = CrossbeamWrapper<T>;
+
+    fn identical(&self, other: &Self) -> bool {
+        self == other
+    }
+}
+//
+#[cfg(feature = "crossbeam")]
+macro_rules! impl_crossbeam_option {
+    ($($data:ty),*) => ($(
+        impl CrossbeamData for Option<$data> {}
+    )*)
+}
+//
+#[cfg(feature = "crossbeam")]
+impl_crossbeam_option! {
+    bool, char, i8, i16, i32, i64, isize, u8, u16, u32, u64, usize,
+    NonZeroI8, NonZeroI16, NonZeroI32, NonZeroI64, NonZeroIsize,
+    NonZeroU8, NonZeroU16, NonZeroU32, NonZeroU64, NonZeroUsize
+}
+
+/// Atomic wrapper for `CrossbeamData`, based on crossbeam's `AtomicCell`
+#[cfg(feature = "crossbeam")]
+#[derive(Debug, Default)]
+pub struct CrossbeamWrapper<T: Copy>(AtomicCell<T>);
+//
+#[cfg(feature = "crossbeam")]
+impl<T: CrossbeamData> AtomicLoadStore for CrossbeamWrapper<T> {
+    type Content = T;
+
+    fn new(v: T) -> Self {
+        Self(AtomicCell::new(v))
+    }
+
+    fn relaxed_load(&self) -> T {
+        self.0.load()
+    }
+
+    fn relaxed_store(&self, val: T) {
+        self.0.store(val)
+    }
+}
+
+/// Fieldless enum which is stored in a RaceCell as its discriminant
+///
+/// Use `impl_atomic_data_for_enum!` to implement this trait along with
+/// `AtomicData`, rather than implementing it manually.
+///
+/// Since a RaceCell read can observe integers which do not correspond to any
+/// variant, the conversion from the discriminant is fallible. Observing an
+/// invalid discriminant is reported as an inconsistency, and `relaxed_load()`
+/// falls back to the `PLACEHOLDER` variant.
+///
+pub trait EnumData: Copy + Eq {
+    /// Integer type of the discriminant
+    type Discriminant: AtomicData;
+
+    /// Variant which is reported when an invalid discriminant is observed
+    const PLACEHOLDER: Self;
+
+    /// Discriminant of this variant
+    fn discriminant(self) -> Self::Discriminant;
+
+    /// Variant with a certain discriminant, if any
+    fn from_discriminant(discriminant: Self::Discriminant) -> Option<Self>;
+}
+
+/// Atomic wrapper for `EnumData`, storing the discriminant of the variant
+pub struct EnumWrapper<E: EnumData>(<E::Discriminant as AtomicData>::AtomicWrapper);
+//
+impl<E: EnumData> AtomicLoadStore for EnumWrapper<E>
+where
+    E: AtomicData<AtomicWrapper = Self>,
+{
+    type Content = E;
+
+    fn new(v: E) -> Self {
+        Self(AtomicLoadStore::new(v.discriminant()))
+    }
+
+    fn relaxed_load(&self) -> E {
+        self.relaxed_try_load().unwrap_or(E::PLACEHOLDER)
+    }
+
+    fn relaxed_try_load(&self) -> Option<E> {
+        E::from_discriminant(self.0.relaxed_try_load()?)
+    }
+
+    fn relaxed_store(&self, val: E) {
+        self.0.relaxed_store(val.discriminant())
+    }
+}
+//
+impl<E: EnumData> Debug for EnumWrapper<E>
+where
+    <E::Discriminant as AtomicData>::AtomicWrapper: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("EnumWrapper").field(&self.0).finish()
+    }
+}
+
+/// Put a fieldless enum in RaceCells, by storing its discriminant
+///
+/// This implements `EnumData` and `AtomicData` for an enum, given the integer
+/// type and value of the discriminant of each of its variants. The first
+/// variant is reported when an invalid discriminant is observed.
+///
+/// ```
+/// # use testbench::{impl_atomic_data_for_enum, race_cell::RaceCell};
+/// #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+/// #[repr(u8)]
+/// enum State {
+///     Empty = 0,
+///     Writing = 1,
+///     Ready = 2,
+/// }
+///
+/// impl_atomic_data_for_enum!(State: u8 { Empty = 0, Writing = 1, Ready = 2 });
+///
+/// let cell = RaceCell::new(State::Empty);
+/// cell.set(State::Ready);
+/// assert_eq!(cell.get(), State::Ready);
+/// ```
+///
+#[macro_export]
+macro_rules! impl_atomic_data_for_enum {
+    ($enum:ident: $repr:ty { $first:ident = $first_value:literal $(, $variant:ident = $value:literal)* $(,)? }) => {
+        impl $crate::race_cell::EnumData for $enum {
+            type Discriminant = $repr;
+
+            const PLACEHOLDER: Self = $enum::$first;
+
+            fn discriminant(self) -> $repr {
+                match self {
+                    $enum::$first => $first_value,
+                    $($enum::$variant => $value,)*
+                }
+            }
+
+            fn from_discriminant(discriminant: $repr) -> Option<Self> {
+                match discriminant {
+                    $first_value => Some($enum::$first),
+                    $($value => Some($enum::$variant),)*
+                    _ => None,
+                }
+            }
+        }
+
+        impl $crate::race_cell::AtomicData for $enum {
+            type AtomicWrapper = $crate::race_cell::EnumWrapper<$enum>;
+
+            fn identical(&self, other: &Self) -> bool {
+                self == other
+            }
+        }
+    };
+}
+
+/// Here are some RaceCell tests
+#[cfg(test)]
+mod tests {
+    use super::{
+        AtomicData, AtomicLoadStore, CountingRaceCell, Inconsistency, Locked, LockedRaceCell,
+        RaceCell, RaceCellN, RaceLatch, RaceLog, RaceRegistry, RaceSearch, RaceSlice, Racey,
+        StripedData, StripedRaceCell, StripedRead, VersionedRaceCell, VersionedRead, WriteDelay,
+    };
+    use crate::util::CACHE_LINE_SIZE;
+    use std::{
+        cell::Cell,
+        collections::HashSet,
+        fmt::Debug,
+        mem::size_of_val,
+        num::{NonZeroI64, NonZeroU32},
+        ptr::{self, NonNull},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        time::{Duration, Instant},
+    };
+
+    /// RaceCells should be shareable between threads exactly when their
+    /// atomic wrapper is
+    #[test]
+    fn auto_traits() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<RaceCell<bool>>();
+        assert_send_sync::<RaceCell<u64>>();
+        assert_send_sync::<RaceCell<NonZeroU32>>();
+        assert_send_sync::<RaceCell<(u8, [i64; 4])>>();
+        assert_send_sync::<RaceCell<*mut u8>>();
+        assert_send_sync::<RaceCell<*mut Cell<u8>>>();
+        assert_send_sync::<RaceCell<NonNull<Cell<u8>>>>();
+        assert_send_sync::<RaceCell<Option<NonNull<Cell<u8>>>>>();
+        assert_send_sync::<RaceCell<Locked<String>>>();
+        assert_send_sync::<RaceCell<Locked<Cell<u8>>>>();
+        assert_send_sync::<VersionedRaceCell<u32>>();
+        assert_send_sync::<RaceSlice<u32>>();
+        assert_send_sync::<RaceLatch>();
+        assert_send_sync::<Racey<u32>>();
+    }
+
+    /// A RaceCell should be created in a consistent and correct state
+    #[test]
+    fn initial_state() {
+        let cell = RaceCell::new(true);
+        assert_eq!(cell.read_copies(), (true, true));
+    }
+
+    /// Reading a consistent RaceCell should work as expected
+    #[test]
+    fn consistent_read() {
+        let cell = RaceCell::new(-42_isize);
+        assert_eq!(cell.get(), Racey::Consistent(-42));
+    }
+
+    /// Reading an inconsistent RaceCell should work as expected
+    #[test]
+    fn inconsistent_read() {
+        let cell = RaceCell::new(0xbad_usize);
+        cell.force_local(0xdead);
+        assert_eq!(
+            cell.get(),
+            Racey::Inconsistent {
+                local: 0xdead,
+                remote: 0xbad
+            }
+        );
+        cell.force_remote(0xdead);
+        assert_eq!(cell.get(), Racey::Consistent(0xdead));
+
+        let cell = RaceCell::new_inconsistent(1u8, 2);
+        assert_eq!(
+            cell.get(),
+            Racey::Inconsistent {
+                local: 1,
+                remote: 2
+            }
+        );
+        cell.force_remote(1);
+        assert_eq!(cell.get(), Racey::Consistent(1));
+    }
+
+    /// Racey accessors should handle both consistent and inconsistent reads
+    #[test]
+    fn racey_accessors() {
+        let consistent = || Racey::Consistent(42u8);
+        let inconsistent = || Racey::Inconsistent {
+            local: 1u8,
+            remote: 2,
+        };
+
+        assert!(consistent().is_consistent());
+        assert!(!consistent().is_inconsistent());
+        assert!(!inconsistent().is_consistent());
+        assert!(inconsistent().is_inconsistent());
+
+        assert_eq!(consistent().consistent(), Some(42));
+        assert_eq!(inconsistent().consistent(), None);
+        assert_eq!(Option::from(consistent()), Some(42));
+        assert_eq!(Option::<u8>::from(inconsistent()), None);
+
+        assert_eq!(consistent().unwrap_consistent(), 42);
+        assert_eq!(consistent().expect_consistent("Should not fail"), 42);
+
+        assert_eq!(consistent().map(u16::from), Racey::Consistent(42u16));
+        assert_eq!(
+            inconsistent().map(|x| x * 10),
+            Racey::Inconsistent {
+                local: 10,
+                remote: 20
+            }
+        );
+    }
+
+    /// Racey values should be hashable, e.g. for interleaving histograms
+    #[test]
+    fn racey_hash() {
+        let results = [
+            Racey::Consistent(1u8),
+            Racey::Consistent(1),
+            Racey::Inconsistent {
+                local: 1,
+                remote: 2,
+            },
+            Racey::Inconsistent {
+                local: 1,
+                remote: 2,
+            },
+            Racey::Inconsistent {
+                local: 2,
+                remote: 1,
+            },
+        ];
+        let histogram = results.iter().copied().collect::<HashSet<_>>();
+        assert_eq!(histogram.len(), 3);
+        assert!(histogram.contains(&Racey::Consistent(1)));
+    }
+
+    /// Racey values should be comparable with bare data, and only equal to it
+    /// if they are consistent
+    #[test]
+    fn racey_eq_data() {
+        let cell = RaceCell::new(42u32);
+        assert_eq!(cell.get(), 42);
+        assert_eq!(42, cell.get());
+        assert_ne!(cell.get(), 24);
+        assert_ne!(24, cell.get());
+
+        cell.force_local(24);
+        assert_ne!(cell.get(), 24);
+        assert_ne!(cell.get(), 42);
+        assert_ne!(24, cell.get());
+        assert_ne!(42, cell.get());
+
+        let locked = LockedRaceCell::new(Locked(String::from("hello")));
+        assert_eq!(locked.get(), Locked(String::from("hello")));
+    }
+
+    /// Unwrapping an inconsistent Racey should report both copies
+    #[test]
+    #[should_panic(expected = "local copy is 1, remote copy is 2")]
+    fn racey_unwrap_inconsistent() {
+        Racey::Inconsistent {
+            local: 1u8,
+            remote: 2,
+        }
+        .unwrap_consistent();
+    }
+
+    /// Expecting an inconsistent Racey to be consistent should report the
+    /// user-provided message
+    #[test]
+    #[should_panic(expected = "Data race in test")]
+    fn racey_expect_inconsistent() {
+        Racey::Inconsistent {
+            local: 1u8,
+            remote: 2,
+        }
+        .expect_consistent("Data race in test");
+    }
+
+    /// Exclusive accesses should behave like shared ones, including when the
+    /// copies were left inconsistent by a previous race
+    #[test]
+    fn exclusive_access() {
+        let mut cell = RaceCell::new(42u64);
+        assert_eq!(cell.get_mut(), 42);
+        cell.set_mut(24);
+        assert_eq!(cell.get_mut(), 24);
+        assert_eq!(cell.clone().into_inner(), 24);
+
+        cell.force_remote(42);
+        assert_eq!(cell.get_mut().values(), Some((24, 42)));
+        assert_eq!(cell.clone().into_inner().values(), Some((24, 42)));
+        cell.set_mut(1);
+        assert_eq!(cell.into_inner(), 1);
+    }
+
+    /// Replacing the contents of a RaceCell should return the previous ones
+    #[test]
+    fn replace() {
+        let cell = RaceCell::new(1u32);
+        assert_eq!(cell.replace(2), 1);
+        assert_eq!(cell.replace(3), 2);
+        assert_eq!(cell.get(), 3);
+
+        cell.force_local(4);
+        assert_eq!(cell.replace(5).values(), Some((4, 3)));
+        assert_eq!(cell.get(), 5);
+    }
+
+    /// Taking the contents of a RaceCell should reset it to the default
+    #[test]
+    fn take() {
+        let cell = RaceCell::new(42usize);
+        assert_eq!(cell.take(), 42);
+        assert_eq!(cell.get(), RaceCell::<usize>::default().get());
+        assert_eq!(cell.take(), 0);
+
+        cell.set(24);
+        cell.set_default();
+        assert_eq!(cell.get(), 0);
+
+        cell.force_local(1);
+        assert_eq!(cell.take().values(), Some((1, 0)));
+        assert_eq!(cell.get(), 0);
+    }
+
+    /// Updates should only be applied to consistent RaceCells, unless
+    /// consistency checks are explicitly disabled
+    #[test]
+    fn update() {
+        let cell = RaceCell::new(1u32);
+        assert_eq!(cell.update(|x| x + 1), 2);
+        assert_eq!(cell.update_unchecked(|x| x * 10), 20);
+        assert_eq!(cell.get(), 20);
+
+        cell.force_remote(30);
+        assert_eq!(cell.update(|x| x + 1).values(), Some((20, 30)));
+        assert_eq!(cell.get().values(), Some((20, 30)));
+        assert_eq!(cell.update_unchecked(|x| x + 1), 21);
+        assert_eq!(cell.get(), 21);
+    }
+
+    /// Concurrent updates of a Mutex-protected RaceCell should not be lost
+    #[test]
+    fn protected_updates() {
+        const UPDATES_COUNT: usize = 10_000;
+        let cell = Mutex::new(RaceCell::new(0));
+        let increment = || {
+            for _ in 0..UPDATES_COUNT {
+                cell.lock().unwrap().update(|x| x + 1).unwrap_consistent();
+            }
+        };
+        crate::concurrent_test_2(increment, increment);
+        assert_eq!(cell.into_inner().unwrap().get(), 2 * UPDATES_COUNT);
+    }
+
+    /// Swapping RaceCells should exchange each copy of their data
+    #[test]
+    fn swap() {
+        let front = RaceCell::new(1u8);
+        let back = RaceCell::new(2u8);
+        assert_eq!(
+            front.swap(&back),
+            (Racey::Consistent(1), Racey::Consistent(2))
+        );
+        assert_eq!(
+            (front.get(), back.get()),
+            (Racey::Consistent(2), Racey::Consistent(1))
+        );
+
+        // Inconsistencies are moved along with the contents
+        front.force_remote(3);
+        let (front_previous, back_previous) = front.swap(&back);
+        assert_eq!(front_previous.values(), Some((2, 3)));
+        assert_eq!(back_previous, 1);
+        assert_eq!(front.get(), Racey::Consistent(1));
+        assert_eq!(back.get().values(), Some((2, 3)));
+        back.set(2);
+
+        assert_eq!(
+            front.swap(&front),
+            (Racey::Consistent(1), Racey::Consistent(1))
+        );
+        assert_eq!(front.get(), 1);
+    }
+
+    /// CountingRaceCell should count reads and detected races
+    #[test]
+    fn counting() {
+        let cell = CountingRaceCell::new(42u16);
+        assert_eq!((cell.reads(), cell.races()), (0, 0));
+        assert_eq!(cell.race_fraction(), 0.0);
+
+        assert_eq!(cell.get(), 42);
+        cell.as_race_cell().force_local(24);
+        assert!(cell.get().is_inconsistent());
+        assert!(cell.get().is_inconsistent());
+        cell.set(24);
+        assert_eq!(cell.get(), 24);
+        assert_eq!((cell.reads(), cell.races()), (4, 2));
+        assert_eq!(cell.race_fraction(), 0.5);
+
+        cell.reset_counts();
+        assert_eq!((cell.reads(), cell.races()), (0, 0));
+        assert_eq!(cell.as_race_cell().get(), 24);
+        assert_eq!(cell.reads(), 0);
+    }
+
+    /// RaceCellN should only be consistent if all replicas agree
+    #[test]
+    fn replicas() {
+        let cell = RaceCellN::<u32, 4>::new(42);
+        assert_eq!(cell.get(), 42);
+        cell.set(24);
+        assert_eq!(cell.clone().get(), 24);
+        for idx in 0..4 {
+            cell.replicas[idx].relaxed_store(1);
+            let (local, remote) = cell.get().values().unwrap();
+            if idx == 0 {
+                assert_eq!((local, remote), (1, 24));
+            } else {
+                assert_eq!((local, remote), (24, 1));
+            }
+            cell.replicas[idx].relaxed_store(24);
+        }
+        assert_eq!(RaceCellN::<char, 3>::default().get(), '\0');
+    }
+
+    /// RaceCellN needs at least two replicas
+    #[test]
+    #[should_panic]
+    fn single_replica() {
+        RaceCellN::<u32, 1>::new(42);
+    }
+
+    /// Slow writes should leave the RaceCell consistent once done
+    #[test]
+    fn slow_writes() {
+        let cell = RaceCell::new(0u8);
+        let delays = [
+            WriteDelay::Spin(100),
+            WriteDelay::Yield,
+            WriteDelay::Sleep(Duration::from_millis(1)),
+        ];
+        for (i, &delay) in (1..).zip(&delays) {
+            cell.set_slowly(i, delay);
+            assert_eq!(cell.get(), i);
+        }
+    }
+
+    /// Floating-point RaceCells should compare bit patterns
+    #[test]
+    fn float_bits() {
+        let nan = f64::from_bits(0x7ff8_0000_0000_0001);
+        let cell = RaceCell::new(nan);
+        match cell.get() {
+            Racey::Consistent(value) => assert_eq!(value.to_bits(), nan.to_bits()),
+            Racey::Inconsistent { .. } => panic!("NaN should be consistent with itself"),
+        }
+
+        let other_nan = f64::from_bits(0x7ff8_0000_0000_0002);
+        cell.force_local(other_nan);
+        assert!(matches!(cell.get(), Racey::Inconsistent { .. }));
+
+        let zero = RaceCell::new(0.0f32);
+        zero.force_local(-0.0);
+        assert!(matches!(zero.get(), Racey::Inconsistent { .. }));
+    }
+
+    /// Characters from all planes should round-trip through a RaceCell, and
+    /// invalid scalar values should be reported as inconsistencies
+    #[test]
+    fn char_contents() {
+        for &c in &['a', 'é', '€', '🦀'] {
+            assert_eq!(RaceCell::new(c).get(), Racey::Consistent(c));
+        }
+        let cell = RaceCell::new('x');
+        cell.local_contents.0.store(0xD800, Ordering::Relaxed);
+        assert_eq!(
+            cell.get(),
+            Racey::Inconsistent {
+                local: char::REPLACEMENT_CHARACTER,
+                remote: 'x'
+            }
+        );
+        assert_eq!(cell.read_copies().0, char::REPLACEMENT_CHARACTER);
+    }
+
+    /// Nonzero integers should round-trip through a RaceCell, and observing
+    /// zero should be reported as an inconsistency
+    #[test]
+    fn nonzero_contents() {
+        let id = NonZeroU32::new(42).unwrap();
+        let cell = RaceCell::new(id);
+        assert_eq!(cell.get(), Racey::Consistent(id));
+
+        cell.remote_version.0.store(0, Ordering::Relaxed);
+        assert_eq!(
+            cell.get(),
+            Racey::Inconsistent {
+                local: id,
+                remote: NonZeroU32::new(u32::MAX).unwrap()
+            }
+        );
+        assert_eq!(cell.read_copies().1.get(), u32::MAX);
+
+        let signed = NonZeroI64::new(-1).unwrap();
+        assert_eq!(RaceCell::new(signed).get(), Racey::Consistent(signed));
+    }
+
+    /// Non-null pointers should round-trip through a RaceCell, and observing a
+    /// null pointer should be reported as an inconsistency
+    #[test]
+    fn non_null_contents() {
+        let mut data = [1u8, 2];
+        let first = NonNull::from(&mut data[0]);
+        let second = NonNull::from(&mut data[1]);
+        let cell = RaceCell::new(first);
+        assert_eq!(cell.get(), Racey::Consistent(first));
+        cell.set(second);
+        assert_eq!(cell.get(), Racey::Consistent(second));
+
+        cell.remote_version
+            .0
+            .store(ptr::null_mut(), Ordering::Relaxed);
+        assert_eq!(
+            cell.get(),
+            Racey::Inconsistent {
+                local: second,
+                remote: NonNull::dangling()
+            }
+        );
+    }
+
+    /// Optional non-null pointers should round-trip through a RaceCell, with
+    /// `None` stored as a null pointer
+    #[test]
+    fn option_non_null_contents() {
+        let mut data = 42u32;
+        let pointer = NonNull::from(&mut data);
+        let cell = RaceCell::<Option<NonNull<u32>>>::new(None);
+        assert_eq!(cell.get(), Racey::Consistent(None));
+        assert!(cell.local_contents.0.load(Ordering::Relaxed).is_null());
+        cell.set(Some(pointer));
+        assert_eq!(cell.get(), Racey::Consistent(Some(pointer)));
+        assert_eq!(
+            cell.local_contents.0.load(Ordering::Relaxed),
+            pointer.as_ptr()
+        );
+        cell.set(None);
+        assert_eq!(cell.read_copies(), (None, None));
+    }
+
+    /// Fieldless enums should round-trip through a RaceCell, and observing an
+    /// invalid discriminant should be reported as an inconsistency
+    #[test]
+    fn enum_contents() {
+        #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+        #[repr(u8)]
+        enum State {
+            Empty = 0,
+            Writing = 1,
+            Ready = 2,
+            Reading = 3,
+        }
+        crate::impl_atomic_data_for_enum!(State: u8 {
+            Empty = 0,
+            Writing = 1,
+            Ready = 2,
+            Reading = 3,
+        });
+
+        let cell = RaceCell::new(State::Empty);
+        for state in [State::Writing, State::Ready, State::Reading] {
+            cell.set(state);
+            assert_eq!(cell.get(), Racey::Consistent(state));
+        }
+        cell.force_local(State::Ready);
+        assert_eq!(cell.get().values(), Some((State::Ready, State::Reading)));
+
+        cell.set(State::Ready);
+        cell.remote_version.0.store(42, Ordering::Relaxed);
+        assert_eq!(
+            cell.get(),
+            Racey::Inconsistent {
+                local: State::Ready,
+                remote: State::Empty
+            }
+        );
+        assert!(!cell.is_consistent());
+    }
+
+    /// Zero-sized data should always be consistent, and not allocate memory
+    #[test]
+    fn zero_sized_contents() {
+        let cell = RaceCell::new(());
+        assert_eq!(cell.get(), Racey::Consistent(()));
+        cell.set(());
+        assert!(cell.is_consistent());
+        assert_eq!(size_of_val(&*cell.remote_version.storage), 0);
+
+        let cell = RaceCell::with_min_distance((), 4096);
+        assert_eq!(cell.get(), Racey::Consistent(()));
+        assert_eq!(RaceCell::new([0u8; 0]).get(), Racey::Consistent([]));
+    }
+
+    /// Generic code should be able to use RaceCells of zero-sized data
+    #[test]
+    fn generic_zero_sized() {
+        fn round_trip<T: AtomicData + Debug + PartialEq>(value: T) {
+            let cell = RaceCell::new(value.clone());
+            cell.set(value.clone());
+            assert_eq!(cell.get().unwrap_consistent(), value);
+        }
+        round_trip(());
+        round_trip(((), [(); 3]));
+        round_trip(42u32);
+    }
+
+    /// Tuples should be consistent only if all of their elements are
+    #[test]
+    fn tuple_contents() {
+        let cell = RaceCell::new((1u32, 2u64));
+        assert_eq!(cell.get(), Racey::Consistent((1, 2)));
+        cell.remote_version.1.relaxed_store(3);
+        assert_eq!(cell.get().values(), Some(((1, 2), (1, 3))));
+
+        let quad = RaceCell::new((true, 'q', -4i8, 0.5f32));
+        match quad.get() {
+            Racey::Consistent(value) => assert_eq!(value, (true, 'q', -4, 0.5)),
+            Racey::Inconsistent { .. } => panic!("Tuple should be consistent"),
+        }
+    }
+
+    /// Arrays should be consistent only if all of their elements are
+    #[test]
+    fn array_contents() {
+        let cell = RaceCell::new([1u32, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(cell.get(), Racey::Consistent([1, 2, 3, 4, 5, 6, 7, 8]));
+        cell.local_contents[7].relaxed_store(0);
+        assert_eq!(
+            cell.get().values(),
+            Some(([1, 2, 3, 4, 5, 6, 7, 0], [1, 2, 3, 4, 5, 6, 7, 8]))
+        );
+        for idx in 0..8 {
+            let mut remote = [1, 2, 3, 4, 5, 6, 7, 8];
+            remote[idx] = 0;
+            cell.set([1, 2, 3, 4, 5, 6, 7, 8]);
+            cell.remote_version[idx].relaxed_store(0);
+            assert_eq!(
+                cell.get().values(),
+                Some(([1, 2, 3, 4, 5, 6, 7, 8], remote))
+            );
+        }
+
+        let empty = RaceCell::<[u8; 0]>::default();
+        assert_eq!(empty.get(), Racey::Consistent([]));
+
+        let large = RaceCell::new([0u16; 64]);
+        assert_eq!(large.get(), Racey::Consistent([0; 64]));
+    }
+
+    /// Consistency can be checked without returning the contents
+    #[test]
+    fn consistency_probe() {
+        let cell = RaceCell::new(42u32);
+        assert!(cell.is_consistent());
+        assert_eq!(cell.probe(), Ok(()));
+        cell.assert_consistent();
+        cell.force_remote(24);
+        assert!(!cell.is_consistent());
+        assert_eq!(cell.probe(), Err(Inconsistency));
+
+        // Composite data is compared part by part
+        let cell = RaceCell::new([(1u8, 'a'); 16]);
+        assert!(cell.is_consistent());
+        cell.local_contents[7].1 .0.store(0xD800, Ordering::Relaxed);
+        assert!(!cell.is_consistent());
+
+        // Floats are compared by bit pattern, so NaN is consistent
+        let cell = RaceCell::new(f64::NAN);
+        assert!(cell.is_consistent());
+        cell.force_local(-f64::NAN);
+        assert!(!cell.is_consistent());
+    }
+
+    /// Consistency assertions should report both copies of the data
+    #[test]
+    #[should_panic(expected = "local copy is 24, remote copy is 42")]
+    fn assert_consistent() {
+        let cell = RaceCell::new(42u32);
+        cell.force_local(24);
+        cell.assert_consistent();
+    }
+
+    /// Reads can be retried until the RaceCell is consistent
+    #[test]
+    fn get_consistent() {
+        // Consistent cells are read on the first attempt
+        let cell = RaceCell::new(42u32);
+        assert_eq!(cell.get_consistent(0), Ok(42));
+        assert_eq!(cell.get_consistent_timeout(Duration::ZERO), Ok(42));
+
+        // Inconsistent cells are retried a bounded number of times...
+        cell.force_local(24);
+        let error = cell.get_consistent(3).unwrap_err();
+        assert_eq!((error.attempts, error.local, error.remote), (4, 24, 42));
+
+        // ...or for a bounded amount of time
+        let timeout = Duration::from_millis(10);
+        let start = Instant::now();
+        let error = cell.get_consistent_timeout(timeout).unwrap_err();
+        assert!(start.elapsed() >= timeout);
+        assert!(error.attempts >= 1);
+        assert_eq!((error.local, error.remote), (24, 42));
+
+        // Reads succeed once another thread clears the inconsistency
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                std::thread::sleep(Duration::from_millis(10));
+                cell.set(1);
+            });
+            assert_eq!(cell.get_consistent(usize::MAX), Ok(1));
+        });
+        cell.force_remote(2);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                std::thread::sleep(Duration::from_millis(10));
+                cell.set(3);
+            });
+            assert_eq!(cell.get_consistent_timeout(Duration::from_secs(10)), Ok(3));
+        });
+    }
+
+    /// Integers should round-trip through a StripedRaceCell
+    #[test]
+    fn striped_contents() {
+        let cell = StripedRaceCell::new(0x0123_4567_89AB_CDEFu64);
+        assert_eq!(cell.get(), StripedRead::Consistent(0x0123_4567_89AB_CDEF));
+        cell.set(42);
+        assert_eq!(cell.get(), StripedRead::Consistent(42));
+        assert_eq!(cell.cell.read_copies().0[0], 0x012A);
+
+        assert_eq!(
+            StripedRaceCell::new(-2i16).get(),
+            StripedRead::Consistent(-2)
+        );
+        assert_eq!(
+            StripedRaceCell::new(u128::MAX).get(),
+            StripedRead::Consistent(u128::MAX)
+        );
+    }
+
+    /// StripedRaceCells should tell inconsistencies between copies apart from
+    /// tears within a copy
+    #[test]
+    fn striped_classification() {
+        let cell = StripedRaceCell::new(0u32);
+        cell.set(0x0101_0101);
+
+        // The local copy is a whole value from a later write
+        cell.cell.force_local(0x0202_0202u32.to_stripes(2));
+        assert_eq!(
+            cell.get(),
+            StripedRead::InterCopyRace {
+                local: 0x0202_0202,
+                remote: 0x0101_0101
+            }
+        );
+
+        // The local copy was observed while its two low bytes were written
+        let mut stripes = 0x0101_0101u32.to_stripes(1);
+        stripes[..2].copy_from_slice(&0x0202_0202u32.to_stripes(2)[..2]);
+        cell.cell.force_local(stripes);
+        assert_eq!(
+            cell.get(),
+            StripedRead::IntraValueTear {
+                local: 0x0101_0202,
+                remote: 0x0101_0101
+            }
+        );
+
+        // Tears are detected even if both copies are identically torn
+        cell.cell.set(stripes);
+        assert_eq!(
+            cell.get(),
+            StripedRead::IntraValueTear {
+                local: 0x0101_0202,
+                remote: 0x0101_0202
+            }
+        );
+    }
+
+    /// Race searches should report the first observed race...
+    #[test]
+    fn race_search_found() {
+        let cell = RaceCell::new(1u8);
+        cell.force_local(2);
+        match super::run_until_race(&cell, |_| {}, 1000) {
+            RaceSearch::Found {
+                reads,
+                writes,
+                local,
+                remote,
+            } => {
+                assert_eq!(reads, 1);
+                assert!(writes <= 1000);
+                assert_eq!((local, remote), (2, 1));
+            }
+            not_found => panic!("Unexpected {:?}", not_found),
+        }
+    }
+    //
+    #[test]
+    #[should_panic(expected = "Observed a data race after 1 reads")]
+    fn race_search_assert_found() {
+        let cell = RaceCell::new(1u8);
+        cell.force_remote(2);
+        super::assert_no_race_within(|_| {}, || cell.get(), 1000);
+    }
+
+    /// ...or the totals when the writer finished without a race being observed
+    #[test]
+    fn race_search_not_found() {
+        const WRITES_COUNT: usize = 1000;
+        let cell = Mutex::new(RaceCell::new(0));
+        let result = super::search_races(
+            |i| cell.lock().unwrap().set(i),
+            || cell.lock().unwrap().get(),
+            WRITES_COUNT,
+        );
+        assert!(!result.is_found());
+        match result {
+            RaceSearch::NotFound { reads, writes } => {
+                assert!(reads >= 1);
+                assert_eq!(writes, WRITES_COUNT);
+            }
+            found => panic!("Unexpected {:?}", found),
+        }
+        assert_eq!(cell.into_inner().unwrap().get(), WRITES_COUNT - 1);
+        super::assert_no_race_within(|_| {}, || Racey::Consistent(0), WRITES_COUNT);
+    }
+
+    /// Readers can wait for a RaceCell to consistently hold some value
+    #[test]
+    fn wait_for() {
+        // Waiting for the current value succeeds on the first read
+        let cell = RaceCell::new(42u32);
+        let stats = cell.wait_for(&42, Duration::ZERO).unwrap();
+        assert_eq!((stats.reads, stats.races), (1, 0));
+
+        // Waiting for another value times out, reporting the last observation
+        let timeout = Duration::from_millis(10);
+        let start = Instant::now();
+        let error = cell.wait_for(&24, timeout).unwrap_err();
+        assert!(start.elapsed() >= timeout);
+        assert!(error.stats.reads >= 1);
+        assert_eq!(error.stats.races, 0);
+        assert_eq!(error.last, Racey::Consistent(42));
+
+        // Inconsistent reads are counted as races, and never match the target
+        cell.force_local(24);
+        let error = cell.wait_for(&24, timeout).unwrap_err();
+        assert_eq!(error.stats.races, error.stats.reads);
+        assert_eq!(
+            error.last,
+            Racey::Inconsistent {
+                local: 24,
+                remote: 42
+            }
+        );
+
+        // Waiting succeeds once a concurrent writer reaches the target
+        const WRITES_COUNT: u32 = 1000;
+        crate::concurrent_test_2(
+            || {
+                for i in 1..=WRITES_COUNT {
+                    cell.set(i);
+                }
+            },
+            || {
+                let stats = cell
+                    .wait_for(&WRITES_COUNT, Duration::from_secs(10))
+                    .unwrap();
+                assert!(stats.reads >= 1);
+                assert!(stats.races < stats.reads);
+            },
+        );
+    }
+
+    /// Versioned reads should tell which copy of the data is newer
+    #[test]
+    fn versioned_reads() {
+        let cell = VersionedRaceCell::new(10u32);
+        assert_eq!(cell.get(), VersionedRead::Consistent(10));
+        assert_eq!(cell.set(20), 1);
+        assert_eq!(cell.get(), VersionedRead::Consistent(20));
+
+        cell.cell.force_local((2, 30));
+        assert_eq!(
+            cell.get(),
+            VersionedRead::TornWrite {
+                older: (1, 20),
+                newer: (2, 30)
+            }
+        );
+        cell.cell.force_remote((3, 40));
+        assert_eq!(
+            cell.get(),
+            VersionedRead::TornWrite {
+                older: (2, 30),
+                newer: (3, 40)
+            }
+        );
+        cell.cell.force_local((3, 50));
+        assert_eq!(
+            cell.get(),
+            VersionedRead::Reordered {
+                local: (3, 50),
+                remote: (3, 40)
+            }
+        );
+
+        assert_eq!(cell.set(60), 2);
+        assert_eq!(cell.get(), VersionedRead::Consistent(60));
+    }
+
+    /// RaceLatches should record races from any number of threads
+    #[test]
+    fn race_latch() {
+        let latch = RaceLatch::new();
+        assert!(!latch.observed());
+        assert_eq!(latch.count(), 0);
+        assert_eq!(latch.first_observed(), None);
+
+        // Consistent reads are not recorded, inconsistent ones are
+        let cell = RaceCell::new(42u32);
+        assert_eq!(cell.get_latched(&latch), Racey::Consistent(42));
+        assert!(!latch.observed());
+        cell.force_local(24);
+        let start = Instant::now();
+        assert!(cell.get_latched(&latch).is_inconsistent());
+        assert!(latch.observed());
+        assert_eq!(latch.count(), 1);
+        let first_observed = latch.first_observed().unwrap();
+        assert!(first_observed >= start);
+
+        // Recording from several threads
+        const RECORDS_PER_THREAD: usize = 1000;
+        let record = || {
+            for _ in 0..RECORDS_PER_THREAD {
+                latch.record();
+            }
+        };
+        crate::concurrent_test_3(record, record, record);
+        assert_eq!(latch.count(), 3 * RECORDS_PER_THREAD + 1);
+        assert_eq!(latch.first_observed(), Some(first_observed));
+
+        // Resetting the latch between test phases
+        latch.reset();
+        assert!(!latch.observed());
+        assert_eq!(latch.count(), 0);
+        assert_eq!(latch.first_observed(), None);
+        crate::concurrent_test_3(
+            || latch.record(),
+            || assert!(cell.get_latched(&latch).is_inconsistent()),
+            || assert!(cell.get_latched(&latch).is_inconsistent()),
+        );
+        assert_eq!(latch.count(), 3);
+        assert!(latch.first_observed().unwrap() >= first_observed);
+    }
+
+    /// RaceLogs should record races from several threads
+    #[test]
+    fn race_log() {
+        let log = RaceLog::with_capacity(100);
+        assert_eq!(log.capacity(), 100);
+        assert!(log.is_empty());
+
+        // Consistent reads are not logged, inconsistent ones are
+        let cell = RaceCell::new(42u32);
+        assert_eq!(cell.get_logged(&log), Racey::Consistent(42));
+        assert!(log.is_empty());
+        cell.force_local(24);
+        const READS_PER_THREAD: usize = 10;
+        let start = Instant::now();
+        let read = || {
+            for _ in 0..READS_PER_THREAD {
+                assert!(cell.get_logged(&log).is_inconsistent());
+            }
+        };
+        crate::concurrent_test_2(read, read);
+        assert_eq!(log.len(), 2 * READS_PER_THREAD);
+
+        // Events record when, where and what was observed
+        let races = log.drain();
+        assert!(log.is_empty());
+        assert_eq!(races.dropped, 0);
+        let threads = races
+            .events
+            .iter()
+            .map(|event| {
+                assert!(event.time >= start);
+                assert_eq!((event.local, event.remote), (24, 42));
+                event.thread
+            })
+            .collect::<HashSet<_>>();
+        assert_eq!(threads.len(), 2);
+    }
+
+    /// Races which do not fit in a RaceLog should be counted
+    #[test]
+    fn race_log_overflow() {
+        let log = RaceLog::with_capacity(3);
+        let cell = RaceCell::new_inconsistent(1u8, 2);
+        for _ in 0..5 {
+            assert!(cell.get_logged(&log).is_inconsistent());
+        }
+        assert_eq!(log.len(), 3);
+        assert_eq!(log.dropped(), 2);
+
+        // Draining the log makes room for new races
+        let races = log.drain();
+        assert_eq!(races.events.len(), 3);
+        assert_eq!(races.dropped, 2);
+        assert_eq!(log.dropped(), 0);
+        log.record(3, 4);
+        assert_eq!(log.len(), 1);
+    }
+
+    /// RaceRegistries should tell which of their cells observed races
+    #[test]
+    fn race_registry() {
+        let registry = RaceRegistry::new();
+        let quiet = registry.register("quiet", 1u32);
+        let raced = registry.register("raced", 2u32);
+        let written = registry.register("written", 3u32);
+        raced.as_race_cell().force_local(4);
+
+        const READS_PER_THREAD: usize = 100;
+        crate::concurrent_test_3(
+            || {
+                for _ in 0..READS_PER_THREAD {
+                    assert_eq!(quiet.get(), Racey::Consistent(1));
+                }
+            },
+            || {
+                for _ in 0..READS_PER_THREAD {
+                    assert!(raced.get().is_inconsistent());
+                }
+            },
+            || {
+                for i in 0..READS_PER_THREAD as u32 {
+                    written.set(i);
+                    assert_eq!(written.get(), Racey::Consistent(i));
+                }
+            },
+        );
+
+        let report = registry.report();
+        let names = report
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["quiet", "raced", "written"]);
+        for (name, stats) in &report {
+            assert_eq!(stats.reads, READS_PER_THREAD);
+            if name == "raced" {
+                assert_eq!(stats.races, READS_PER_THREAD);
+                assert_eq!(stats.race_fraction(), 1.0);
+            } else {
+                assert_eq!(stats.races, 0);
+            }
+        }
+        assert_eq!(registry.raced(), ["raced"]);
+        assert_eq!(raced.stats(), report[1].1);
+    }
+
+    /// RaceSlices should detect races on individual slots and across slots
+    #[test]
+    fn race_slice() {
+        let slice = RaceSlice::new(&[1u32, 2, 3]);
+        assert_eq!(slice.len(), 3);
+        assert!(!slice.is_empty());
+        let snapshot = slice.snapshot();
+        assert!(snapshot.is_consistent());
+        assert_eq!(snapshot.consistent(), Some(vec![1, 2, 3]));
+
+        slice.set(1, 4);
+        slice.set_all(&[5, 6, 7]);
+        assert_eq!(slice.snapshot().consistent(), Some(vec![5, 6, 7]));
+
+        // Race on an individual slot
+        slice.slots[2].force_local((1, 8));
+        let snapshot = slice.snapshot();
+        assert!(!snapshot.is_consistent());
+        assert!(!snapshot.cross_generation);
+        assert_eq!(
+            snapshot.slots,
+            vec![
+                Racey::Consistent(5),
+                Racey::Consistent(6),
+                Racey::Inconsistent {
+                    local: 8,
+                    remote: 7
+                }
+            ]
+        );
+        assert_eq!(snapshot.consistent(), None);
+
+        // Slots which are consistent, but from different generations
+        slice.slots[2].set((1, 7));
+        slice.slots[0].set((2, 9));
+        let snapshot = slice.snapshot();
+        assert!(snapshot.slots.iter().all(Racey::is_consistent));
+        assert!(snapshot.cross_generation);
+        assert!(!snapshot.is_consistent());
+        assert_eq!(snapshot.consistent(), None);
+
+        // Empty slices are always consistent
+        let empty = RaceSlice::<u8>::new(&[]);
+        assert!(empty.is_empty());
+        assert_eq!(empty.snapshot().consistent(), Some(Vec::new()));
+    }
+
+    /// RaceSlice indices should be bounds-checked
+    #[test]
+    #[should_panic]
+    fn race_slice_out_of_bounds() {
+        RaceSlice::new(&[0u8; 4]).set(4, 1);
+    }
+
+    /// RaceSlice writes should cover every slot
+    #[test]
+    #[should_panic(expected = "Number of values does not match number of slots")]
+    fn race_slice_length_mismatch() {
+        RaceSlice::new(&[0u8; 4]).set_all(&[1; 3]);
+    }
+
+    /// Serialization should preserve both copies of the data
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let cell = RaceCell::new(0xdead_beef_u64);
+        let json = serde_json::to_string(&cell).unwrap();
+        assert_eq!(json, r#"{"local":3735928559,"remote":3735928559}"#);
+        let cell: RaceCell<u64> = serde_json::from_str(&json).unwrap();
+        assert_eq!(cell.get(), Racey::Consistent(0xdead_beef));
+
+        cell.force_local(u64::MAX);
+        let json = serde_json::to_string(&cell).unwrap();
+        let copy: RaceCell<u64> = serde_json::from_str(&json).unwrap();
+        assert_eq!(copy.read_copies(), (u64::MAX, 0xdead_beef));
+
+        let cell = LockedRaceCell::new(Locked(String::from("hello")));
+        cell.force_remote(Locked(String::from("world")));
+        let json = serde_json::to_string(&cell).unwrap();
+        assert_eq!(json, r#"{"local":"hello","remote":"world"}"#);
+        let copy: LockedRaceCell<String> = serde_json::from_str(&json).unwrap();
+        assert_eq!(copy.get(), cell.get());
+
+        assert_eq!(
+            serde_json::to_string(&RaceCell::new(42u8).get()).unwrap(),
+            r#"{"Consistent":42}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&copy.get()).unwrap(),
+            r#"{"Inconsistent":{"local":"hello","remote":"world"}}"#
+        );
+    }
+
+    /// RaceCells can be compared with values and with each other
+    #[test]
+    fn equality() {
+        // Equal and consistent
+        let cell = RaceCell::new(42u32);
+        assert_eq!(cell, 42);
+        assert_ne!(cell, 24);
+        assert_eq!(cell, RaceCell::new(42));
+        assert_ne!(cell, RaceCell::new(24));
+
+        // Equally inconsistent
+        cell.force_local(24);
+        assert_ne!(cell, 42);
+        assert_ne!(cell, 24);
+        let other = RaceCell::new(42u32);
+        other.force_local(24);
+        assert_eq!(cell, other);
+
+        // Differently inconsistent
+        let swapped = RaceCell::new(24u32);
+        swapped.force_local(42);
+        assert_ne!(cell, swapped);
+        assert_ne!(cell, RaceCell::new(24));
+
+        // Types without Eq are compared using AtomicData::identical()
+        let nan = RaceCell::new(f64::NAN);
+        assert_eq!(nan, f64::NAN);
+        assert_eq!(nan, nan.clone());
+        assert_ne!(nan, -f64::NAN);
+
+        // Invalid copies are not equal to anything
+        let cell = RaceCell::new('x');
+        cell.local_contents.0.store(0xD800, Ordering::Relaxed);
+        assert_ne!(cell, char::REPLACEMENT_CHARACTER);
+        assert_ne!(cell, cell.clone());
+    }
+
+    /// RaceCells and their reads should be printed on a single line
+    #[test]
+    fn formatting() {
+        let cell = RaceCell::new(5u32);
+        assert_eq!(
+            format!("{:?}", cell),
+            "RaceCell { local: 5, remote: 5, consistent: true }"
+        );
+        assert_eq!(format!("{}", cell.get()), "5");
+        cell.force_remote(7);
+        assert_eq!(
+            format!("{:?}", cell),
+            "RaceCell { local: 5, remote: 7, consistent: false }"
+        );
+        assert_eq!(format!("{}", cell.get()), "inconsistent(local=5, remote=7)");
+
+        let cell = LockedRaceCell::new(Locked(String::from("hello")));
+        cell.force_local(Locked(String::from("world")));
+        assert_eq!(
+            format!("{:?}", cell),
+            r#"RaceCell { local: Locked("world"), remote: Locked("hello"), consistent: false }"#
+        );
+
+        let cell = RaceCell::new((1.5f32, 'x'));
+        assert_eq!(
+            format!("{:?}", cell),
+            "RaceCell { local: (1.5, 'x'), remote: (1.5, 'x'), consistent: true }"
+        );
+        assert_eq!(format!("{:.2}", RaceCell::new(1.5f32).get()), "1.50");
+    }
+
+    /// Tables of RaceCells can be built in one go
+    #[test]
+    fn new_vec() {
+        const CELLS: usize = 1000;
+        let cells = RaceCell::new_vec(CELLS, 0u32);
+        assert_eq!(cells.len(), CELLS);
+        assert!(cells.iter().all(|cell| *cell == 0));
+        assert_eq!(RaceCell::from(42u32), 42);
+        assert!(RaceCell::new_vec(0, 0u8).is_empty());
+
+        // Concurrent writes to all cells should eventually be observed
+        crate::concurrent_test_2(
+            || {
+                for cell in &cells {
+                    cell.set(1);
+                }
+            },
+            || {
+                for cell in &cells {
+                    while cell.get() != 1 {
+                        std::hint::spin_loop();
+                    }
+                }
+            },
+        );
+        assert!(cells.iter().all(|cell| *cell == 1));
+    }
+
+    /// RaceCells should be cloned as-is, even if in an inconsistent state
+    #[test]
+    fn clone() {
+        let cell = RaceCell::new_inconsistent(0xdeaf_usize, 0xbeef);
+        let clone = cell.clone();
+        assert_eq!(
+            clone.get(),
+            Racey::Inconsistent {
+                local: 0xdeaf,
+                remote: 0xbeef
+            }
+        );
+    }
+
+    /// The two copies of a RaceCell should never share a cache line
+    #[test]
+    fn copy_placement() {
+        let cell = RaceCell::new(0u8);
+        let (local, remote) = cell.copy_addresses();
+        assert_eq!(local % CACHE_LINE_SIZE, 0);
+        assert_eq!(remote % CACHE_LINE_SIZE, 0);
+        assert!(cell.copy_distance() >= CACHE_LINE_SIZE);
+        let clone = cell.clone();
+        assert!(clone.copy_distance() >= CACHE_LINE_SIZE);
+    }
+
+    /// Copies can be kept far apart, wherever the RaceCell is moved
+    #[test]
+    fn min_distance() {
+        const PAGE_SIZE: usize = 4096;
+        let cell = RaceCell::with_min_distance(42u64, PAGE_SIZE);
+        assert!(cell.copy_distance() > PAGE_SIZE);
+        assert_eq!(cell.get(), Racey::Consistent(42));
+
+        let boxed = Box::new(cell);
+        assert!(boxed.copy_distance() > PAGE_SIZE);
+        let clone = boxed.clone();
+        assert!(clone.copy_distance() > PAGE_SIZE);
+
+        let shared = Arc::new(*boxed);
+        assert!(shared.copy_distance() > PAGE_SIZE);
+        let writer = shared.clone();
+        std::thread::spawn(move || writer.set(24)).join().unwrap();
+        assert_eq!(shared.get(), Racey::Consistent(24));
+    }
+
+    /// Unprotected concurrent reads and writes to a RaceCell should trigger
+    /// detectable race conditions, illustrating its non-atomic nature.
+    ///
+    /// To maximize the odds of race conditions, this kind of test should be run
+    /// in single-threaded mode.
+    ///
+    #[test]
+    #[ignore]
+    fn unprotected_race() {
+        // Amount of writes to carry out
+        let writes_count = crate::scale_iters(100_000_000);
+
+        // RaceCell in which the writes will be carried out
+        let cell = CountingRaceCell::new(0);
+
+        // Make sure that RaceCell does expose existing data races, with a
+        // detection probability better than 1% for very obvious ones :)
+        crate::concurrent_test_2(
+            || {
+                for i in 1..=writes_count {
+                    cell.set(i);
+                }
+            },
+            || {
+                let mut last_value = 0;
+                while last_value != writes_count {
+                    if let Racey::Consistent(value) = cell.get() {
+                        last_value = value;
+                    }
+                }
+                print!("{} races detected: ", cell.races());
+                assert!(cell.races() > writes_count / 100);
+            },
+        );
+    }
+
+    /// Unprotected concurrent writes of two distinct pointers to a RaceCell
+    /// should be detected, and consistent reads should only ever observe
+    /// one of these pointers.
+    ///
+    /// To maximize the odds of race conditions, this kind of test should be run
+    /// in single-threaded mode.
+    ///
+    #[test]
+    #[ignore]
+    fn unprotected_race_non_null() {
+        // Amount of writes to carry out
+        let writes_count = crate::scale_iters(100_000_000);
+
+        // Two distinct heap allocations, and a RaceCell pointing to either
+        let first = Box::new(1u64);
+        let second = Box::new(2u64);
+        let cell = CountingRaceCell::new(NonNull::from(&*first));
+        let done = AtomicBool::new(false);
+
+        // Alternate between both pointers while reading
+        crate::concurrent_test_2(
+            || {
+                let pointers = [NonNull::from(&*second), NonNull::from(&*first)];
+                for i in 0..writes_count {
+                    cell.set(pointers[i % 2]);
+                }
+                done.store(true, Ordering::Release);
+            },
+            || {
+                while !done.load(Ordering::Acquire) {
+                    if let Racey::Consistent(pointer) = cell.get() {
+                        let pointer = pointer.as_ptr() as *const u64;
+                        assert!(ptr::eq(pointer, &*first) || ptr::eq(pointer, &*second));
+                    }
+                }
+                print!("{} races detected: ", cell.races());
+                assert!(cell.races() > 0);
+            },
+        );
+    }
+
+    /// Unprotected concurrent reads and writes to a StripedRaceCell should
+    /// expose both inconsistencies between copies and tears within a copy.
+    /// Every byte of each write is the same counter, so torn values are easy
+    /// to recognize.
+    ///
+    /// To maximize the odds of race conditions, this kind of test should be run
+    /// in single-threaded mode.
+    ///
+    #[test]
+    #[ignore]
+    fn unprotected_race_striped() {
+        // Amount of writes to carry out
+        let writes_count = crate::scale_iters(10_000_000);
+
+        // Value whose bytes are all equal to a counter, and check thereof
+        let pattern = |counter: u8| u64::from_le_bytes([counter; 8]);
+        let is_pattern = |value: u64| value == pattern(value as u8);
+
+        // StripedRaceCell in which the writes will be carried out
+        let cell = StripedRaceCell::new(pattern(0));
+        let done = AtomicBool::new(false);
+
+        // Make sure that reads are classified correctly
+        crate::concurrent_test_2(
+            || {
+                for i in 1..=writes_count {
+                    cell.set(pattern(i as u8));
+                }
+                done.store(true, Ordering::Release);
+            },
+            || {
+                let mut inter_copy_races = 0usize;
+                let mut intra_value_tears = 0usize;
+                while !done.load(Ordering::Acquire) {
+                    match cell.get() {
+                        StripedRead::Consistent(value) => assert!(is_pattern(value)),
+                        StripedRead::InterCopyRace { local, remote } => {
+                            assert!(is_pattern(local) && is_pattern(remote));
+                            inter_copy_races += 1;
+                        }
+                        StripedRead::IntraValueTear { local, remote } => {
+                            assert!(!is_pattern(local) || !is_pattern(remote));
+                            intra_value_tears += 1;
+                        }
+                    }
+                }
+                print!(
+                    "{} inter-copy races and {} intra-value tears detected: ",
+                    inter_copy_races, intra_value_tears
+                );
+                assert!(inter_copy_races > 0);
+                assert!(intra_value_tears > 0);
+            },
+        );
+    }
+
+    /// Unprotected concurrent reads and writes to a RaceSlice should expose
+    /// snapshots which are inconsistent as a whole, even though each of their
+    /// slots looks consistent.
+    ///
+    /// To maximize the odds of race conditions, this kind of test should be run
+    /// in single-threaded mode.
+    ///
+    #[test]
+    #[ignore]
+    fn unprotected_race_slice() {
+        // Amount of writes to carry out
+        let writes_count = crate::scale_iters(10_000_000);
+
+        // Number of slots in the RaceSlice
+        const SLOTS: usize = 8;
+
+        // RaceSlice in which the writes will be carried out
+        let slice = RaceSlice::new(&[0; SLOTS]);
+
+        // Make sure that cross-generation snapshots are detected
+        crate::concurrent_test_2(
+            || {
+                for i in 1..=writes_count {
+                    slice.set_all(&[i; SLOTS]);
+                }
+            },
+            || {
+                let mut last_value = 0;
+                let mut cross_generation = 0usize;
+                while last_value != writes_count {
+                    let snapshot = slice.snapshot();
+                    if snapshot.cross_generation && snapshot.slots.iter().all(Racey::is_consistent)
+                    {
+                        cross_generation += 1;
+                    }
+                    if let Some(values) = snapshot.consistent() {
+                        assert!(values.iter().all(|&value| value == values[0]));
+                        last_value = values[0];
+                    }
+                }
+                print!(
+                    "{} snapshots with consistent slots from different generations: ",
+                    cross_generation
+                );
+                assert!(cross_generation > 0);
+            },
+        );
+    }
+
+    /// 128-bit RaceCells should round-trip values, including both halves
+    #[test]
+    fn split_128() {
+        let value = (0x0123_4567_89ab_cdef_u128 << 64) | 0xfedc_ba98_7654_3210;
+        assert_eq!(RaceCell::new(value).get(), Racey::Consistent(value));
+        let signed = -0x0123_4567_89ab_cdef_0123_4567_i128;
+        assert_eq!(RaceCell::new(signed).get(), Racey::Consistent(signed));
+    }
+
+    /// Unprotected concurrent replacements of the contents of a RaceCell
+    /// should report inconsistent previous contents.
+    ///
+    /// To maximize the odds of race conditions, this kind of test should be run
+    /// in single-threaded mode.
+    ///
+    #[test]
+    #[ignore]
+    fn unprotected_race_replace() {
+        // Amount of races to detect, and time after which we give up
+        const RACES_COUNT: usize = 100;
+        const TIMEOUT: Duration = Duration::from_secs(10);
+
+        // RaceCell in which the writes will be carried out, and notification
+        // that the writer should stop
+        let cell = RaceCell::new(0);
+        let done = AtomicBool::new(false);
+
+        // Make sure that the races are detected. Unlike a read, a replacement
+        // makes the RaceCell consistent again, so races are detected less
+        // often than in unprotected_race.
+        crate::concurrent_test_2(
+            || {
+                let mut i = 0;
+                while !done.load(Ordering::Relaxed) {
+                    i += 1;
+                    cell.set(i);
+                }
+            },
+            || {
+                let start = Instant::now();
+                let mut data_race_count = 0usize;
+                while data_race_count < RACES_COUNT && start.elapsed() < TIMEOUT {
+                    if cell.replace(usize::MAX).is_inconsistent() {
+                        data_race_count += 1;
+                    }
+                }
+                done.store(true, Ordering::Relaxed);
+                print!("{} races detected: ", data_race_count);
+                assert!(data_race_count > 0);
+            },
+        );
+    }
+
+    /// Unprotected concurrent takes and writes should also report
+    /// inconsistent previous contents.
+    ///
+    /// To maximize the odds of race conditions, this kind of test should be run
+    /// in single-threaded mode.
+    ///
+    #[test]
+    #[ignore]
+    fn unprotected_race_take() {
+        // Amount of races to detect, and time after which we give up
+        const RACES_COUNT: usize = 100;
+        const TIMEOUT: Duration = Duration::from_secs(10);
+
+        // RaceCell in which the writes will be carried out, and notification
+        // that the writer should stop
+        let cell = RaceCell::new(0);
+        let done = AtomicBool::new(false);
+
+        // Make sure that the races are detected, as in unprotected_race_replace
+        crate::concurrent_test_2(
+            || {
+                let mut i = 0;
+                while !done.load(Ordering::Relaxed) {
+                    i += 1;
+                    cell.set(i);
+                }
+            },
+            || {
+                let start = Instant::now();
+                let mut data_race_count = 0usize;
+                while data_race_count < RACES_COUNT && start.elapsed() < TIMEOUT {
+                    if cell.take().is_inconsistent() {
+                        data_race_count += 1;
+                    }
+                }
+                done.store(true, Ordering::Relaxed);
+                print!("{} races detected: ", data_race_count);
+                assert!(data_race_count > 0);
+            },
+        );
+    }
+
+    /// Unprotected concurrent updates of a RaceCell should lose some updates.
+    ///
+    /// To maximize the odds of race conditions, this kind of test should be run
+    /// in single-threaded mode.
+    ///
+    #[test]
+    #[ignore]
+    fn unprotected_updates() {
+        // Amount of updates to carry out on each thread
+        let updates_count = crate::scale_iters(100_000_000);
+
+        // RaceCell in which the updates will be carried out
+        let cell = RaceCell::new(0usize);
+
+        // Make sure that some updates are lost
+        let increment = || {
+            for _ in 0..updates_count {
+                cell.update_unchecked(|x| x + 1);
+            }
+        };
+        crate::concurrent_test_2(increment, increment);
+        // The copies may be inconsistent if a thread was interrupted in the
+        // middle of its last update, so only look at the first one
+        let final_value = cell.read_copies().0;
+        print!("{} updates lost: ", 2 * updates_count - final_value);
+        assert!(final_value < 2 * updates_count);
+    }
+
+    /// Concurrent readers of repeatedly swapped RaceCells should observe
+    /// partially swapped states.
+    ///
+    /// To maximize the odds of race conditions, this kind of test should be run
+    /// in single-threaded mode.
+    ///
+    #[test]
+    #[ignore]
+    fn unprotected_race_swap() {
+        // Amount of races to detect, and time after which we give up
+        const RACES_COUNT: usize = 100;
+        const TIMEOUT: Duration = Duration::from_secs(10);
+
+        // RaceCells which will be swapped, and notification that the swapping
+        // thread should stop
+        let front = RaceCell::new(0);
+        let back = RaceCell::new(1);
+        let done = AtomicBool::new(false);
+
+        // Make sure that intermediate states are detected
+        crate::concurrent_test_2(
+            || {
+                while !done.load(Ordering::Relaxed) {
+                    front.swap(&back);
+                }
+            },
+            || {
+                let start = Instant::now();
+                let mut data_race_count = 0usize;
+                while data_race_count < RACES_COUNT && start.elapsed() < TIMEOUT {
+                    for cell in &[&front, &back] {
+                        match cell.get() {
+                            Racey::Consistent(value) => assert!(value <= 1),
+                            Racey::Inconsistent { local, remote } => {
+                                assert_eq!(local + remote, 1);
+                                data_race_count += 1;
+                            }
+                        }
+                    }
+                }
+                done.store(true, Ordering::Relaxed);
+                print!("{} races detected: ", data_race_count);
+                assert!(data_race_count > 0);
+            },
+        );
+    }
+
+    /// Using more replicas should make races more likely to be detected.
+    ///
+    /// To maximize the odds of race conditions, this kind of test should be run
+    /// in single-threaded mode.
+    ///
+    #[test]
+    #[ignore]
+    fn unprotected_race_replicas() {
+        // Fraction of reads which detect a race in the unprotected_race
+        // scenario, for a certain number of replicas
+        fn race_fraction<const REPLICAS: usize>() -> f64 {
+            let writes_count = crate::scale_iters(10_000_000);
+            let cell = RaceCellN::<usize, REPLICAS>::new(0);
+            let mut reads = 0usize;
+            let mut races = 0usize;
+            crate::concurrent_test_2(
+                || {
+                    for i in 1..=writes_count {
+                        cell.set(i);
+                    }
+                },
+                || {
+                    let mut last_value = 0;
+                    while last_value != writes_count {
+                        reads += 1;
+                        match cell.get() {
+                            Racey::Consistent(value) => last_value = value,
+                            Racey::Inconsistent { .. } => races += 1,
+                        }
+                    }
+                },
+            );
+            races as f64 / reads as f64
+        }
+
+        // Compare the detection rate of 2 and 8 replicas, averaged over a
+        // few runs to reduce the influence of scheduling noise
+        const RUNS: usize = 5;
+        let two = (0..RUNS).map(|_| race_fraction::<2>()).sum::<f64>() / RUNS as f64;
+        let eight = (0..RUNS).map(|_| race_fraction::<8>()).sum::<f64>() / RUNS as f64;
+        print!(
+            "race fractions: {} with 2 replicas, {} with 8: ",
+            two, eight
+        );
+        assert!(eight > two);
     }
-}
 
-/// This is the result of a RaceCell read
-#[derive(Debug, Eq, PartialEq)]
-pub enum Racey<U: AtomicData> {
-    /// The RaceCell was internally consistent, and its content was copied
-    Consistent(U),
+    /// Delaying the second write of each update should make most reads detect
+    /// a race in the unprotected_race scenario.
+    ///
+    /// To maximize the odds of race conditions, this kind of test should be run
+    /// in single-threaded mode.
+    ///
+    #[test]
+    #[ignore]
+    fn unprotected_race_slow_writes() {
+        // Amount of writes to carry out
+        const WRITES_COUNT: usize = 1_000;
 
-    /// The RaceCell was internally inconsistent: a data race has occurred
-    Inconsistent,
-}
+        // RaceCell in which the writes will be carried out
+        let cell = CountingRaceCell::new(0);
 
-/// Requirements on the data held by a RaceCell
-pub trait AtomicData: Clone + Eq + Sized {
-    /// Atomic wrapper type for this data implementing relaxed atomic load/store
-    type AtomicWrapper: AtomicLoadStore<Content = Self>;
-}
-///
-/// Atomic wrapper type for a certain kind of value
-///
-/// For the implementation of RaceCell not to be considered as undefined
-/// behaviour and trashed by the Rust compiler in release mode, the underlying
-/// type must be loaded and stored atomically. This requires synchronizing
-/// accesses to it using things like the std::sync::atomic::AtomicXyz wrappers.
-///
-/// The only guarantee that we need is that loads and stores are atomic. We do
-/// not need any other memory ordering guarantee. This is why we allow for
-/// more wrapper type implementation freedom by not specifying memory orderings,
-/// and internally relying only on relaxed ordering.
-///
-pub trait AtomicLoadStore: Sized {
-    /// Type of data that is being wrapped
-    type Content: AtomicData<AtomicWrapper = Self>;
+        // Make sure that most reads detect a race
+        crate::concurrent_test_2(
+            || {
+                for i in 1..=WRITES_COUNT {
+                    cell.as_race_cell().set_slowly(i, WriteDelay::Yield);
+                }
+            },
+            || {
+                let mut last_value = 0;
+                while last_value != WRITES_COUNT {
+                    if let Racey::Consistent(value) = cell.get() {
+                        last_value = value;
+                    }
+                }
+                print!(
+                    "{:.1}% of reads detected a race: ",
+                    cell.race_fraction() * 100.0
+                );
+                assert!(cell.race_fraction() > 0.5);
+            },
+        );
+    }
 
-    /// Create an atomic wrapper for a value of type U
-    fn new(v: Self::Content) -> Self;
+    /// Torn writes to a VersionedRaceCell should mostly be classified in the
+    /// direction of the writes, since the writer keeps increasing the value.
+    ///
+    /// To maximize the odds of race conditions, this kind of test should be run
+    /// in single-threaded mode.
+    ///
+    #[test]
+    #[ignore]
+    fn unprotected_race_versioned() {
+        // Amount of writes to carry out per attempt
+        let writes_count = crate::scale_iters(10_000_000) as u64;
 
-    /// Atomically load a value from the wrapper
-    fn relaxed_load(&self) -> Self::Content;
+        // Maximal amount of attempts at observing torn writes
+        const MAX_ATTEMPTS: usize = 10;
 
-    /// Atomically store a new value into the wrapper
-    fn relaxed_store(&self, val: Self::Content);
-}
-///
-/// This macro implements support for non-generic standard atomic types
-///
-macro_rules! impl_atomic_data {
-    ($($data:ty => $wrapper:ty),*) => ($(
-        impl AtomicData for $data {
-            type AtomicWrapper = $wrapper;
+        // Check that the newer copy usually holds the larger value, retrying
+        // until some torn writes have been observed
+        let mut forward = 0usize;
+        let mut backward = 0usize;
+        for _ in 0..MAX_ATTEMPTS {
+            let cell = VersionedRaceCell::new(0);
+            crate::concurrent_test_2(
+                || {
+                    for i in 1..=writes_count {
+                        cell.set(i);
+                    }
+                },
+                || {
+                    let mut last_value = 0;
+                    while last_value != writes_count {
+                        match cell.get() {
+                            VersionedRead::Consistent(value) => last_value = value,
+                            VersionedRead::TornWrite { older, newer } => {
+                                assert!(newer.0 > older.0);
+                                if newer.1 >= older.1 {
+                                    forward += 1;
+                                } else {
+                                    backward += 1;
+                                }
+                            }
+                            VersionedRead::Reordered { .. } => {}
+                        }
+                    }
+                },
+            );
+            if forward + backward > 0 {
+                break;
+            }
         }
+        print!("{} torn writes forward, {} backward: ", forward, backward);
+        assert!(forward + backward > 0);
+        assert!(forward > backward);
+    }
 
-        impl AtomicLoadStore for $wrapper {
-            type Content = $data;
-
-            fn new(v: $data) -> $wrapper {
-                <$wrapper>::new(v)
-            }
+    /// Unprotected concurrent reads and writes to a 128-bit RaceCell should
+    /// also trigger detectable race conditions.
+    ///
+    /// To maximize the odds of race conditions, this kind of test should be run
+    /// in single-threaded mode.
+    ///
+    #[test]
+    #[ignore]
+    fn unprotected_race_128() {
+        // Amount of writes to carry out
+        let writes_count = crate::scale_iters(100_000_000) as u128;
 
-            fn relaxed_load(&self) -> $data {
-                <$wrapper>::load(self, Ordering::Relaxed)
-            }
+        // Both halves of the value are modified on every write
+        let value = |i: u128| (i << 64) | i;
 
-            fn relaxed_store(&self, val: $data) {
-                <$wrapper>::store(self, val, Ordering::Relaxed)
-            }
-        }
-    )*)
-}
-//
-impl_atomic_data! {
-    bool  => AtomicBool,
-    i8    => AtomicI8,
-    i16   => AtomicI16,
-    i32   => AtomicI32,
-    i64   => AtomicI64,
-    isize => AtomicIsize,
-    u8    => AtomicU8,
-    u16   => AtomicU16,
-    u32   => AtomicU32,
-    u64   => AtomicU64,
-    usize => AtomicUsize
-}
-//
-// Atomic pointers are a bit special as they are generic, for now we will just
-// treat them as a special case.
-//
-impl<V> AtomicData for *mut V {
-    type AtomicWrapper = AtomicPtr<V>;
-}
-//
-impl<V> AtomicLoadStore for AtomicPtr<V> {
-    type Content = *mut V;
+        // RaceCell in which the writes will be carried out
+        let cell = CountingRaceCell::new(value(0));
 
-    fn new(v: *mut V) -> AtomicPtr<V> {
-        <AtomicPtr<V>>::new(v)
+        // Make sure that the races are detected, as in unprotected_race
+        crate::concurrent_test_2(
+            || {
+                for i in 1..=writes_count {
+                    cell.set(value(i));
+                }
+            },
+            || {
+                let mut last_value = 0;
+                while last_value != value(writes_count) {
+                    if let Racey::Consistent(value) = cell.get() {
+                        last_value = value;
+                    }
+                }
+                print!("{} races detected: ", cell.races());
+                assert!(cell.races() > (writes_count / 100) as usize);
+            },
+        );
     }
 
-    fn relaxed_load(&self) -> *mut V {
-        <AtomicPtr<V>>::load(self, Ordering::Relaxed)
+    /// Example of a struct deriving AtomicData
+    #[cfg(feature = "derive")]
+    #[derive(Clone, Debug, PartialEq, super::AtomicData)]
+    struct Slot {
+        idx: u32,
+        gen: u32,
+        live: bool,
     }
 
-    fn relaxed_store(&self, val: *mut V) {
-        <AtomicPtr<V>>::store(self, val, Ordering::Relaxed)
-    }
-}
+    /// Derived structs should round-trip through a RaceCell, and any field
+    /// mismatch or invalid field should be reported as an inconsistency
+    #[cfg(feature = "derive")]
+    #[test]
+    fn derived_contents() {
+        let slot = Slot {
+            idx: 3,
+            gen: 7,
+            live: true,
+        };
+        let cell = RaceCell::new(slot.clone());
+        assert_eq!(cell.get(), Racey::Consistent(slot));
+        assert!(cell.is_consistent());
+        cell.local_contents.gen.relaxed_store(8);
+        assert!(!cell.is_consistent());
+        let (local, remote) = cell.get().values().unwrap();
+        assert_eq!((local.gen, remote.gen), (8, 7));
 
-// FIXME: The astute reader will have noted that any data could be theoretically
-//        put in a RaceCell by using a Mutex as the AtomicWrapper. However, this
-//        will only be implemented once Rust has specialization, to avoid
-//        pessimizing the common case where a primitive type is enough.
+        #[derive(Clone, Debug, PartialEq, super::AtomicData)]
+        struct Handle<T>(NonZeroU32, T);
+        let handle = Handle(NonZeroU32::new(42).unwrap(), 'h');
+        let cell = RaceCell::new(handle.clone());
+        assert_eq!(cell.get(), Racey::Consistent(handle));
+        cell.remote_version.1 .0.store(0xD800, Ordering::Relaxed);
+        assert!(!cell.is_consistent());
+        let (local, remote) = cell.get().values().unwrap();
+        assert_eq!((local.1, remote.1), ('h', char::REPLACEMENT_CHARACTER));
 
-/// Here are some RaceCell tests
-#[cfg(test)]
-mod tests {
-    use super::{AtomicLoadStore, RaceCell, Racey};
-    use std::sync::Mutex;
+        #[derive(Clone, Debug, PartialEq, super::AtomicData)]
+        struct Marker;
+        assert_eq!(RaceCell::new(Marker).get(), Racey::Consistent(Marker));
+        assert!(RaceCell::new(Marker).is_consistent());
+    }
 
-    /// A RaceCell should be created in a consistent and correct state
+    /// Unprotected concurrent reads and writes to a RaceCell of a derived
+    /// struct should trigger detectable race conditions, like primitive types.
+    ///
+    /// To maximize the odds of race conditions, this kind of test should be run
+    /// in single-threaded mode.
+    ///
+    #[cfg(feature = "derive")]
     #[test]
-    fn initial_state() {
-        let cell = RaceCell::new(true);
-        assert!(cell.local_contents.relaxed_load());
-        assert!(cell.remote_version.relaxed_load());
+    #[ignore]
+    fn unprotected_race_derived() {
+        // Amount of writes to carry out
+        let writes_count = crate::scale_iters(100_000_000) as u32;
+
+        // All fields of the struct are modified on every write
+        let slot = |i: u32| Slot {
+            idx: i,
+            gen: i,
+            live: i % 2 == 0,
+        };
+
+        // RaceCell in which the writes will be carried out
+        let cell = CountingRaceCell::new(slot(0));
+
+        // Make sure that the races are detected, as in unprotected_race
+        crate::concurrent_test_2(
+            || {
+                for i in 1..=writes_count {
+                    cell.set(slot(i));
+                }
+            },
+            || {
+                let mut last_value = slot(0);
+                while last_value != slot(writes_count) {
+                    match cell.get() {
+                        Racey::Consistent(value) => {
+                            assert_eq!(value.idx, value.gen);
+                            last_value = value;
+                        }
+                        Racey::Inconsistent { .. } => {}
+                    }
+                }
+                print!("{} races detected: ", cell.races());
+                assert!(cell.races() > (writes_count / 100) as usize);
+            },
+        );
     }
 
-    /// Reading a consistent RaceCell should work as expected
+    /// Arbitrary Clone + Eq data should round-trip through a LockedRaceCell,
+    /// and mismatched copies should be reported as an inconsistency
     #[test]
-    fn consistent_read() {
-        let cell = RaceCell::new(-42_isize);
-        assert_eq!(cell.get(), Racey::Consistent(-42));
+    fn locked_contents() {
+        let cell = LockedRaceCell::new(Locked(String::from("hello")));
+        assert_eq!(cell.get(), Racey::Consistent(Locked(String::from("hello"))));
+        cell.set(Locked(String::from("world")));
+        assert_eq!(cell.get(), Racey::Consistent(Locked(String::from("world"))));
+        assert!(cell.is_consistent());
+        cell.force_local(Locked(String::from("hello")));
+        assert!(!cell.is_consistent());
+        assert_eq!(
+            cell.get(),
+            Racey::Inconsistent {
+                local: Locked(String::from("hello")),
+                remote: Locked(String::from("world"))
+            }
+        );
+
+        #[derive(Clone, Debug, Eq, PartialEq)]
+        struct Message {
+            id: u64,
+            payload: Vec<u8>,
+        }
+        let message = Message {
+            id: 42,
+            payload: vec![1, 2, 3],
+        };
+        let cell = RaceCell::new(Locked::from(message.clone()));
+        assert_eq!(cell.clone().get(), Racey::Consistent(Locked(message)));
+        cell.force_remote(Locked(Message {
+            id: 42,
+            payload: Vec::new(),
+        }));
+        let (local, remote) = cell.get().values().unwrap();
+        assert_eq!((local.0.payload.len(), remote.0.payload.len()), (3, 0));
     }
 
-    /// Reading an inconsistent RaceCell should work as expected
+    /// Unprotected concurrent reads and writes to a LockedRaceCell should also
+    /// trigger detectable race conditions, even though each copy is locked.
+    ///
+    /// To maximize the odds of race conditions, this kind of test should be run
+    /// in single-threaded mode.
+    ///
     #[test]
-    fn inconsistent_read() {
-        let cell = RaceCell::new(0xbad_usize);
-        cell.local_contents.relaxed_store(0xdead);
-        assert_eq!(cell.get(), Racey::Inconsistent);
+    #[ignore]
+    fn unprotected_race_locked() {
+        // Amount of writes to carry out
+        let writes_count = crate::scale_iters(10_000_000);
+
+        // RaceCell in which the writes will be carried out
+        let cell = CountingRaceCell::new(Locked(0.to_string()));
+
+        // Make sure that the races are detected, as in unprotected_race
+        crate::concurrent_test_2(
+            || {
+                for i in 1..=writes_count {
+                    cell.set(Locked(i.to_string()));
+                }
+            },
+            || {
+                let last = writes_count.to_string();
+                let mut last_value = String::new();
+                while last_value != last {
+                    match cell.get() {
+                        Racey::Consistent(value) => last_value = value.into_inner(),
+                        Racey::Inconsistent { .. } => {}
+                    }
+                }
+                print!("{} races detected: ", cell.races());
+                assert!(cell.races() > writes_count / 100);
+            },
+        );
     }
 
-    /// RaceCells should be cloned as-is, even if in an inconsistent state
+    /// Types supported via crossbeam's AtomicCell should round-trip through a
+    /// RaceCell, and mismatched copies should be reported as an inconsistency
+    #[cfg(feature = "crossbeam")]
     #[test]
-    fn clone() {
-        let cell = RaceCell::new(0xbeef_usize);
-        cell.local_contents.relaxed_store(0xdeaf);
-        let clone = cell.clone();
-        assert_eq!(clone.local_contents.relaxed_load(), 0xdeaf);
-        assert_eq!(clone.remote_version.relaxed_load(), 0xbeef);
+    fn crossbeam_contents() {
+        let cell = RaceCell::new(Some(42u32));
+        assert_eq!(cell.get(), Racey::Consistent(Some(42)));
+        cell.force_local(None);
+        assert_eq!(cell.get().values(), Some((None, Some(42))));
+
+        #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+        enum State {
+            Idle,
+            Busy,
+        }
+        impl super::CrossbeamData for State {}
+        let cell = RaceCell::new(State::Idle);
+        cell.set(State::Busy);
+        assert_eq!(cell.get(), Racey::Consistent(State::Busy));
+        cell.force_remote(State::Idle);
+        assert_eq!(cell.get().values(), Some((State::Busy, State::Idle)));
     }
 
-    /// Unprotected concurrent reads and writes to a RaceCell should trigger
-    /// detectable race conditions, illustrating its non-atomic nature.
+    /// Unprotected concurrent reads and writes to a RaceCell backed by
+    /// crossbeam's AtomicCell should also trigger detectable race conditions.
     ///
     /// To maximize the odds of race conditions, this kind of test should be run
     /// in single-threaded mode.
     ///
+    #[cfg(feature = "crossbeam")]
     #[test]
     #[ignore]
-    fn unprotected_race() {
+    fn unprotected_race_crossbeam() {
         // Amount of writes to carry out
-        const WRITES_COUNT: usize = 100_000_000;
+        let writes_count = crate::scale_iters(100_000_000) as u64;
 
         // RaceCell in which the writes will be carried out
-        let cell = RaceCell::new(0);
+        let cell = CountingRaceCell::new(Some(0u64));
 
-        // Make sure that RaceCell does expose existing data races, with a
-        // detection probability better than 1% for very obvious ones :)
+        // Make sure that the races are detected, as in unprotected_race
         crate::concurrent_test_2(
             || {
-                for i in 1..=WRITES_COUNT {
-                    cell.set(i);
+                for i in 1..=writes_count {
+                    cell.set(Some(i));
                 }
             },
             || {
-                let mut last_value = 0;
-                let mut data_race_count = 0usize;
-                while last_value != WRITES_COUNT {
-                    match cell.get() {
-                        Racey::Consistent(value) => last_value = value,
-                        Racey::Inconsistent => data_race_count += 1,
+                let mut last_value = Some(0);
+                while last_value != Some(writes_count) {
+                    if let Racey::Consistent(value) = cell.get() {
+                        last_value = value;
                     }
                 }
-                print!("{} races detected: ", data_race_count);
-                assert!(data_race_count > WRITES_COUNT / 100);
+                print!("{} races detected: ", cell.races());
+                assert!(cell.races() > (writes_count / 100) as usize);
             },
         );
     }
 
-    /// Appropriately protected concurrent reads and writes to a RaceCell should
-    /// not yield any detectable race conditions.
+    /// Unprotected concurrent reads and writes to an array RaceCell should be
+    /// especially likely to trigger detectable race conditions.
     ///
     /// To maximize the odds of race conditions, this kind of test should be run
     /// in single-threaded mode.
     ///
     #[test]
     #[ignore]
-    fn protected_transaction() {
+    fn unprotected_race_array() {
         // Amount of writes to carry out
-        const WRITES_COUNT: usize = 10_000_000;
+        let writes_count = crate::scale_iters(10_000_000) as u32;
 
-        // Mutex-protected RaceCell in which the writes will be carried out
-        let cell = Mutex::new(RaceCell::new(0));
+        // Each write stores an ascending pattern starting at the write index
+        let pattern = |i: u32| std::array::from_fn::<u32, 8, _>(|j| i + j as u32);
 
-        // Make sure that RaceCell does not incorrectly detect race conditions
+        // RaceCell in which the writes will be carried out
+        let cell = CountingRaceCell::new(pattern(0));
+
+        // Make sure that the races are detected, as in unprotected_race
         crate::concurrent_test_2(
             || {
-                for i in 1..=WRITES_COUNT {
-                    cell.lock().unwrap().set(i);
+                for i in 1..=writes_count {
+                    cell.set(pattern(i));
                 }
             },
             || {
-                let mut last_value = 0;
-                let mut data_race_count = 0usize;
-                while last_value != WRITES_COUNT {
-                    match cell.lock().unwrap().get() {
-                        Racey::Consistent(value) => last_value = value,
-                        Racey::Inconsistent => data_race_count += 1,
+                let mut last_value = pattern(0);
+                while last_value != pattern(writes_count) {
+                    if let Racey::Consistent(value) = cell.get() {
+                        last_value = value;
                     }
                 }
-                assert_eq!(data_race_count, 0);
+                print!("{} races detected: ", cell.races());
+                assert!(cell.races() > (writes_count / 100) as usize);
             },
         );
     }
+
+    /// Appropriately protected concurrent reads and writes to a RaceCell should
+    /// not yield any detectable race conditions.
+    ///
+    /// To maximize the odds of race conditions, this kind of test should be run
+    /// in single-threaded mode.
+    ///
+    #[test]
+    #[ignore]
+    fn protected_transaction() {
+        // Amount of writes to carry out
+        let writes_count = crate::scale_iters(10_000_000);
+
+        // Mutex-protected RaceCell in which the writes will be carried out
+        let cell = Mutex::new(RaceCell::new(0));
+
+        // Make sure that RaceCell does not incorrectly detect race conditions
+        super::assert_no_race_within(
+            |i| cell.lock().unwrap().set(i),
+            || cell.lock().unwrap().get(),
+            writes_count,
+        );
+    }
+
+    /// Unprotected concurrent writes to a RaceCell should be found by a race
+    /// search.
+    ///
+    /// To maximize the odds of race conditions, this kind of test should be run
+    /// in single-threaded mode.
+    ///
+    #[test]
+    #[ignore]
+    fn unprotected_race_search() {
+        // Maximal amount of writes to carry out
+        let writes_count = crate::scale_iters(100_000_000);
+
+        // Look for a race, which should happen long before the writes are done
+        let cell = RaceCell::new(0);
+        match super::run_until_race(&cell, |i| cell.set(i), writes_count) {
+            RaceSearch::Found {
+                reads,
+                writes,
+                local,
+                remote,
+            } => {
+                print!("race found after {} reads and {} writes: ", reads, writes);
+                assert!(writes < writes_count);
+                assert_ne!(local, remote);
+            }
+            not_found => panic!("No race found: {:?}", not_found),
+        }
+    }
 }
@@ -0,0 +1,498 @@
+//! Machine-readable export of measurement results
+//!
+//! Benchmark results are often post-processed with other tools, e.g. to plot
+//! them or compare them across machines. This module exports them as CSV,
+//! with one row per result and one column per field. Result types describe
+//! their columns by implementing the `Record` trait, which user-defined types
+//! can implement too. A pair of records is also a record, whose columns are
+//! those of both members, which allows user data to be joined to this crate's
+//! results in the same CSV row.
+//!
+//! ```
+//! use std::time::Duration;
+//! use testbench::{contention::contention_sweep, report};
+//!
+//! let points = contention_sweep(&[0, 1], |_| || {}, || Duration::from_micros(1));
+//! let mut csv = Vec::new();
+//! report::to_csv(&points, &mut csv).unwrap();
+//! let csv = String::from_utf8(csv).unwrap();
+//! assert!(csv.starts_with("antagonists,repetition,measurement_ns,"));
+//! assert_eq!(csv.lines().count(), 3);
+//! ```
+//!
+//! If the `serde` feature is enabled, the same results can also be serialized
+//! with any serde data format, such as JSON.
+
+use crate::{
+    contention::{AntagonistStats, Backoff, SweepPoint},
+    stats::SampleStats,
+    topology::CorePairLatency,
+};
+use std::{
+    fmt::{self, Write as _},
+    io::{self, Write},
+    time::Duration,
+};
+
+/// Result which can be exported as a row of a table
+pub trait Record {
+    /// Names of the columns, which must be the same for all records
+    fn columns() -> Vec<&'static str>;
+
+    /// Values of the columns, in the same order as `columns()`
+    fn values(&self) -> Vec<Value>;
+}
+//
+impl<A: Record, B: Record> Record for (A, B) {
+    fn columns() -> Vec<&'static str> {
+        let mut columns = A::columns();
+        columns.extend(B::columns());
+        columns
+    }
+
+    fn values(&self) -> Vec<Value> {
+        let mut values = self.0.values();
+        values.extend(self.1.values());
+        values
+    }
+}
+//
+impl Record for SweepPoint {
+    fn columns() -> Vec<&'static str> {
+        let mut columns = vec!["antagonists", "repetition", "measurement_ns"];
+        columns.extend(AntagonistStats::columns());
+        columns
+    }
+
+    fn values(&self) -> Vec<Value> {
+        let mut values = vec![
+            self.antagonists.into(),
+            self.repetition.into(),
+            self.measurement.into(),
+        ];
+        values.extend(self.antagonist_stats.values());
+        values
+    }
+}
+//
+impl Record for AntagonistStats {
+    /// Per-thread iteration counts are exported as a semicolon-separated list,
+    /// and sleep durations of the backoff are in nanoseconds
+    fn columns() -> Vec<&'static str> {
+        vec![
+            "iterations",
+            "overlapping_iterations",
+            "backoff",
+            "stop_latency_ns",
+            "available_parallelism",
+        ]
+    }
+
+    fn values(&self) -> Vec<Value> {
+        vec![
+            Value::list(&self.iterations),
+            Value::list(&self.overlapping_iterations),
+            Value::Text(match self.backoff {
+                Backoff::None => "None".to_owned(),
+                Backoff::Spin(count) => format!("Spin({})", count),
+                Backoff::Yield => "Yield".to_owned(),
+                Backoff::Sleep(duration) => format!("Sleep({}ns)", duration.as_nanos()),
+            }),
+            self.stop_latency.into(),
+            self.available_parallelism.into(),
+        ]
+    }
+}
+//
+impl Record for CorePairLatency {
+    /// Round-trip latency statistics are those of `SampleStats`
+    fn columns() -> Vec<&'static str> {
+        let mut columns = vec!["cpu_a", "cpu_b"];
+        columns.extend(SampleStats::columns());
+        columns
+    }
+
+    fn values(&self) -> Vec<Value> {
+        let mut values = vec![self.cpu_a.into(), self.cpu_b.into()];
+        values.extend(self.round_trip.values());
+        values
+    }
+}
+//
+impl Record for SampleStats {
+    /// Summary statistics are missing if there are no samples
+    fn columns() -> Vec<&'static str> {
+        vec![
+            "samples",
+            "min_ns",
+            "median_ns",
+            "mean_ns",
+            "p95_ns",
+            "max_ns",
+            "std_dev_ns",
+            "mad_ns",
+        ]
+    }
+
+    fn values(&self) -> Vec<Value> {
+        let mut values = vec![self.len().into()];
+        if self.is_empty() {
+            values.resize(Self::columns().len(), Value::Missing);
+        } else {
+            values.extend(
+                [
+                    self.min(),
+                    self.median(),
+                    self.mean(),
+                    self.p95(),
+                    self.max(),
+                    self.std_dev(),
+                    self.median_absolute_deviation(),
+                ]
+                .iter()
+                .map(|&duration| Value::from(duration)),
+            );
+        }
+        values
+    }
+}
+
+/// Value of a column of a `Record`
+///
+/// Durations are converted to an integer amount of nanoseconds, which is
+/// exact, and their column names should end with `_ns` to make this clear.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    /// No value, exported as an empty CSV field
+    Missing,
+
+    /// Boolean
+    Bool(bool),
+
+    /// Integer
+    Integer(i128),
+
+    /// Floating-point number
+    ///
+    /// This is exported with the shortest representation that parses back to
+    /// the same number, so no precision is lost. Non-finite numbers are
+    /// exported as `NaN`, `inf` and `-inf`.
+    ///
+    Float(f64),
+
+    /// Text
+    Text(String),
+}
+//
+impl Value {
+    /// Semicolon-separated list of integers
+    fn list(integers: &[u64]) -> Self {
+        let mut text = String::new();
+        for (idx, integer) in integers.iter().enumerate() {
+            if idx > 0 {
+                text.push(';');
+            }
+            write!(text, "{}", integer).expect("Writing to a String cannot fail");
+        }
+        Self::Text(text)
+    }
+}
+//
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing => Ok(()),
+            Self::Bool(value) => write!(f, "{}", value),
+            Self::Integer(value) => write!(f, "{}", value),
+            // Debug is used because it switches to scientific notation for
+            // very large and very small numbers, and prints the shortest
+            // representation that round-trips like Display does.
+            Self::Float(value) => write!(f, "{:?}", value),
+            Self::Text(value) => f.write_str(value),
+        }
+    }
+}
+//
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+//
+macro_rules! value_from_integer {
+    ($($integer:ty),*) => {
+        $(
+            impl From<$integer> for Value {
+                fn from(value: $integer) -> Self {
+                    Self::Integer(value as i128)
+                }
+            }
+        )*
+    };
+}
+value_from_integer!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+//
+impl From<f32> for Value {
+    fn from(value: f32) -> Self {
+        Self::Float(value.into())
+    }
+}
+//
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Self::Float(value)
+    }
+}
+//
+impl From<Duration> for Value {
+    fn from(value: Duration) -> Self {
+        Self::Integer(value.as_nanos() as i128)
+    }
+}
+//
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Self::Text(value)
+    }
+}
+//
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Self::Text(value.to_owned())
+    }
+}
+//
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Self::Missing, Into::into)
+    }
+}
+
+/// Write records as CSV, with a header row that names the columns
+///
+/// Fields are quoted as specified by RFC 4180 when they contain a comma, a
+/// double quote or a line break, and rows are terminated by a line feed. The
+/// header is written even if there are no records.
+///
+/// # Panics
+///
+/// This function panics if a record has a different number of values than
+/// it has columns.
+///
+pub fn to_csv<R: Record>(records: &[R], mut writer: impl Write) -> io::Result<()> {
+    let columns = R::columns();
+    write_csv_row(&mut writer, columns.iter().copied())?;
+    for record in records {
+        let values = record.values();
+        assert_eq!(
+            values.len(),
+            columns.len(),
+            "Record has a different number of values than it has columns"
+        );
+        write_csv_row(&mut writer, values.iter().map(ToString::to_string))?;
+    }
+    Ok(())
+}
+
+/// Write a row of CSV fields
+fn write_csv_row<S: AsRef<str>>(
+    writer: &mut impl Write,
+    fields: impl Iterator<Item = S>,
+) -> io::Result<()> {
+    for (idx, field) in fields.enumerate() {
+        if idx > 0 {
+            writer.write_all(b",")?;
+        }
+        let field = field.as_ref();
+        if field.contains(&[',', '"', '\n', '\r'][..]) {
+            write!(writer, "\"{}\"", field.replace('"', "\"\""))?;
+        } else {
+            writer.write_all(field.as_bytes())?;
+        }
+    }
+    writer.write_all(b"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sweep result with known values, matching the golden files
+    fn sweep() -> Vec<SweepPoint> {
+        vec![
+            SweepPoint {
+                antagonists: 0,
+                repetition: 0,
+                measurement: Duration::from_nanos(1_234),
+                antagonist_stats: AntagonistStats {
+                    available_parallelism: 4,
+                    ..AntagonistStats::default()
+                },
+            },
+            SweepPoint {
+                antagonists: 2,
+                repetition: 1,
+                measurement: Duration::new(1, 5),
+                antagonist_stats: AntagonistStats {
+                    iterations: vec![10, 20],
+                    overlapping_iterations: vec![9, 18],
+                    backoff: Backoff::Sleep(Duration::from_micros(15)),
+                    stop_latency: Duration::from_micros(42),
+                    available_parallelism: 4,
+                },
+            },
+        ]
+    }
+
+    /// Export records as a CSV string
+    fn csv_string<R: Record>(records: &[R]) -> String {
+        let mut csv = Vec::new();
+        to_csv(records, &mut csv).unwrap();
+        String::from_utf8(csv).unwrap()
+    }
+
+    /// Minimal RFC 4180 parser, used to check that exported fields round-trip
+    fn parse_csv(csv: &str) -> Vec<Vec<String>> {
+        let mut rows = Vec::new();
+        let mut row = Vec::new();
+        let mut field = String::new();
+        let mut chars = csv.chars().peekable();
+        let mut quoted = false;
+        while let Some(c) = chars.next() {
+            match (quoted, c) {
+                (true, '"') if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                (_, '"') => quoted = !quoted,
+                (false, ',') => row.push(std::mem::take(&mut field)),
+                (false, '\n') => {
+                    row.push(std::mem::take(&mut field));
+                    rows.push(std::mem::take(&mut row));
+                }
+                (_, c) => field.push(c),
+            }
+        }
+        assert!(field.is_empty() && row.is_empty(), "Unterminated CSV row");
+        rows
+    }
+
+    #[test]
+    fn sweep_csv() {
+        let csv = csv_string(&sweep());
+        assert_eq!(csv, include_str!("../tests/golden/sweep.csv"));
+        let rows = parse_csv(&csv);
+        assert_eq!(rows[0], SweepPoint::columns());
+        for (row, point) in rows[1..].iter().zip(sweep()) {
+            let values = point.values();
+            assert_eq!(row.len(), values.len());
+            for (field, value) in row.iter().zip(values) {
+                assert_eq!(*field, value.to_string());
+            }
+        }
+    }
+
+    #[test]
+    fn sample_stats_csv() {
+        let stats = [
+            SampleStats::from_durations(Vec::new()),
+            SampleStats::from_durations(vec![Duration::from_nanos(3), Duration::from_nanos(1)]),
+        ];
+        assert_eq!(
+            csv_string(&stats),
+            "samples,min_ns,median_ns,mean_ns,p95_ns,max_ns,std_dev_ns,mad_ns\n\
+             0,,,,,,,\n\
+             2,1,1,2,3,3,1,0\n"
+        );
+    }
+
+    #[test]
+    fn core_latency_csv() {
+        let pairs = [CorePairLatency {
+            cpu_a: 0,
+            cpu_b: 2,
+            round_trip: SampleStats::from_durations(vec![Duration::from_nanos(80)]),
+        }];
+        assert_eq!(
+            csv_string(&pairs),
+            "cpu_a,cpu_b,samples,min_ns,median_ns,mean_ns,p95_ns,max_ns,std_dev_ns,mad_ns\n\
+             0,2,1,80,80,80,80,80,0,0\n"
+        );
+    }
+
+    /// User-defined record, which is joined to sweep points below
+    struct Custom {
+        label: &'static str,
+        ratio: f64,
+    }
+    //
+    impl Record for Custom {
+        fn columns() -> Vec<&'static str> {
+            vec!["label", "ratio"]
+        }
+
+        fn values(&self) -> Vec<Value> {
+            vec![self.label.into(), self.ratio.into()]
+        }
+    }
+
+    #[test]
+    fn quoting_and_floats() {
+        let tricky = [
+            "plain",
+            "comma, inside",
+            "\"quoted\"",
+            "line\nbreak",
+            "carriage\rreturn",
+            "",
+        ];
+        let floats = [0.1, -1.0 / 3.0, 1e-300, f64::MAX, f64::MIN_POSITIVE, 0.0];
+        let records = tricky
+            .iter()
+            .zip(floats.iter())
+            .map(|(&label, &ratio)| Custom { label, ratio })
+            .collect::<Vec<_>>();
+        let rows = parse_csv(&csv_string(&records));
+        assert_eq!(rows[0], ["label", "ratio"]);
+        for (row, record) in rows[1..].iter().zip(&records) {
+            assert_eq!(row[0], record.label);
+            assert_eq!(
+                row[1].parse::<f64>().unwrap().to_bits(),
+                record.ratio.to_bits()
+            );
+        }
+    }
+
+    #[test]
+    fn joined_records() {
+        let records = sweep()
+            .into_iter()
+            .map(|point| {
+                (
+                    point,
+                    Custom {
+                        label: "x",
+                        ratio: 0.5,
+                    },
+                )
+            })
+            .collect::<Vec<_>>();
+        let rows = parse_csv(&csv_string(&records));
+        assert_eq!(rows[0].len(), SweepPoint::columns().len() + 2);
+        assert_eq!(rows[0].last().unwrap(), "ratio");
+        assert_eq!(rows[2].last().unwrap(), "0.5");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn sweep_json() {
+        let json = serde_json::to_string_pretty(&sweep()).unwrap();
+        let golden = include_str!("../tests/golden/sweep.json");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&json).unwrap(),
+            serde_json::from_str::<serde_json::Value>(golden).unwrap()
+        );
+        assert_eq!(json, golden.trim_end());
+    }
+}
@@ -0,0 +1,263 @@
+//! Seeded pseudorandom number generation for tests
+//!
+//! Randomized tests are only useful if their failures can be replayed, which
+//! requires all of their randomness to be derived from a seed that is reported
+//! when they fail. `TestRng` is the pseudorandom number generator that the
+//! randomness-consuming features of this crate are built on, and that tests can
+//! use for the same purpose.
+//!
+//! ```
+//! use testbench::rng::TestRng;
+//!
+//! let (mut rng, seed) = TestRng::from_entropy();
+//! println!("Random seed: {}", seed);
+//! let index = rng.next_bounded(10);
+//! assert!(index < 10);
+//!
+//! // The same seed yields the same sequence
+//! let mut replay = TestRng::from_seed(seed);
+//! assert_eq!(replay.next_bounded(10), index);
+//! ```
+//!
+//! This is not a cryptographically secure generator, and its output sequence
+//! may change between releases of this crate.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hash, Hasher},
+    thread,
+};
+
+/// Increment of the state of generators created by `from_seed()`
+const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// Seeded pseudorandom number generator
+///
+/// This is an implementation of the SplitMix64 algorithm by Steele, Lea and
+/// Flood. Its state is a 64-bit counter that is incremented by an odd "gamma"
+/// constant on each step, and whose successive values are hashed to produce
+/// the output. It is fast, passes the BigCrush statistical test suite, and can
+/// be split into independent streams with `fork()`.
+///
+/// Cloning a generator yields a copy which produces the same sequence. Use
+/// `fork()` to get a generator which produces a different sequence.
+///
+// Copying a generator by accident would duplicate its sequence
+#[allow(missing_copy_implementations)]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TestRng {
+    /// Counter that is hashed to produce the output
+    state: u64,
+
+    /// Odd increment of the counter, which selects the stream
+    gamma: u64,
+}
+//
+impl TestRng {
+    /// Create a generator from a seed
+    ///
+    /// Two generators created from the same seed produce the same sequence.
+    ///
+    pub fn from_seed(seed: u64) -> Self {
+        Self {
+            state: seed,
+            gamma: GOLDEN_GAMMA,
+        }
+    }
+
+    /// Create a generator from a random seed, which is returned for logging
+    ///
+    /// The seed is drawn from the same source of entropy that the standard
+    /// library uses to protect its `HashMap`s against collision attacks. It
+    /// should be printed or otherwise recorded, so that the sequence can be
+    /// reproduced with `from_seed()` if a test fails.
+    ///
+    pub fn from_entropy() -> (Self, u64) {
+        let mut hasher = RandomState::new().build_hasher();
+        thread::current().id().hash(&mut hasher);
+        let seed = hasher.finish();
+        (Self::from_seed(seed), seed)
+    }
+
+    /// Generate a pseudorandom integer
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(self.gamma);
+        mix64(self.state)
+    }
+
+    /// Generate a pseudorandom integer between 0 (included) and `n` (excluded)
+    ///
+    /// All values in this range are equally likely, which is not the case of
+    /// the naive `next_u64() % n` when `n` is not a power of two.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `n` is zero.
+    ///
+    pub fn next_bounded(&mut self, n: u64) -> u64 {
+        assert!(n > 0, "Cannot generate a number in an empty range");
+        // Lemire's nearly divisionless method: the high half of the product
+        // is uniform once the low half is rejected from its biased range.
+        let mut product = u128::from(self.next_u64()) * u128::from(n);
+        if (product as u64) < n {
+            let threshold = n.wrapping_neg() % n;
+            while (product as u64) < threshold {
+                product = u128::from(self.next_u64()) * u128::from(n);
+            }
+        }
+        (product >> 64) as u64
+    }
+
+    /// Generate a pseudorandom number between 0 (included) and 1 (excluded)
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u32 << 24) as f32
+    }
+
+    /// Generate a pseudorandom number between 0 (included) and 1 (excluded)
+    ///
+    /// This has more precision than `next_f32()`.
+    ///
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Derive a generator which produces an independent sequence
+    ///
+    /// This advances the current generator, and uses its output to select
+    /// both the starting point and the gamma of the new generator, so that
+    /// the two sequences do not overlap or correlate in practice. This is
+    /// the intended way to give each thread of a test its own generator.
+    ///
+    pub fn fork(&mut self) -> Self {
+        let state = self.next_u64();
+        self.state = self.state.wrapping_add(self.gamma);
+        let gamma = mix_gamma(self.state);
+        Self { state, gamma }
+    }
+}
+
+/// Output hash function of SplitMix64, David Stafford's "Mix13" variant
+fn mix64(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Derive a gamma from a counter value
+///
+/// Gammas must be odd so that the counter goes through all 2^64 values.
+/// Gammas whose bits rarely change from one position to the next are rejected
+/// as they produce lower-quality sequences, following the original paper.
+///
+fn mix_gamma(z: u64) -> u64 {
+    let gamma = mix64(z) | 1;
+    if (gamma ^ (gamma >> 1)).count_ones() < 24 {
+        gamma ^ 0xaaaa_aaaa_aaaa_aaaa
+    } else {
+        gamma
+    }
+}
+
+/// Statistical smoke tests of the test PRNG
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Number of values drawn by the statistical tests
+    ///
+    /// Below a few thousand draws, the statistics would be too noisy to check.
+    ///
+    fn num_draws() -> usize {
+        crate::scale_iters(100_000).max(5_000)
+    }
+
+    // The first outputs for seed 0 should match the SplitMix64 reference
+    #[test]
+    fn reference_sequence() {
+        let mut rng = TestRng::from_seed(0);
+        assert_eq!(rng.next_u64(), 0xe220_a839_7b1d_cdaf);
+        assert_eq!(rng.next_u64(), 0x6e78_9e6a_a1b9_65f4);
+        assert_eq!(rng.next_u64(), 0x06c4_5d18_8009_454f);
+    }
+
+    // The same seed should reproduce the same sequence
+    #[test]
+    fn reproducible() {
+        let (mut rng, seed) = TestRng::from_entropy();
+        let mut replay = TestRng::from_seed(seed);
+        for _ in 0..100 {
+            assert_eq!(rng.next_u64(), replay.next_u64());
+        }
+    }
+
+    // Different calls to from_entropy() should yield different seeds
+    #[test]
+    fn entropy() {
+        let (_, seed1) = TestRng::from_entropy();
+        let (_, seed2) = TestRng::from_entropy();
+        assert_ne!(seed1, seed2);
+    }
+
+    // Bounded outputs should be in range, and roughly uniformly distributed
+    #[test]
+    fn bounded() {
+        const BUCKETS: u64 = 10;
+        let mut rng = TestRng::from_seed(42);
+        let mut counts = [0usize; BUCKETS as usize];
+        for _ in 0..num_draws() {
+            let value = rng.next_bounded(BUCKETS);
+            assert!(value < BUCKETS);
+            counts[value as usize] += 1;
+        }
+        let expected = num_draws() / BUCKETS as usize;
+        for &count in &counts {
+            assert!(count > expected * 8 / 10 && count < expected * 12 / 10);
+        }
+        assert_eq!(rng.next_bounded(1), 0);
+        assert!(rng.next_bounded(u64::MAX) < u64::MAX);
+    }
+
+    // Empty ranges should be rejected
+    #[test]
+    #[should_panic(expected = "empty range")]
+    fn bounded_empty() {
+        TestRng::from_seed(42).next_bounded(0);
+    }
+
+    // Floating-point outputs should be in [0; 1[ with a mean around 0.5
+    #[test]
+    fn floats() {
+        let mut rng = TestRng::from_seed(42);
+        let (mut sum32, mut sum64) = (0.0, 0.0);
+        for _ in 0..num_draws() {
+            let value32 = rng.next_f32();
+            assert!((0.0..1.0).contains(&value32));
+            sum32 += f64::from(value32);
+            let value64 = rng.next_f64();
+            assert!((0.0..1.0).contains(&value64));
+            sum64 += value64;
+        }
+        for &sum in &[sum32, sum64] {
+            let mean = sum / num_draws() as f64;
+            assert!((mean - 0.5).abs() < 0.02, "{}", mean);
+        }
+    }
+
+    // Forked generators should produce different, uncorrelated sequences
+    #[test]
+    fn fork() {
+        let mut parent = TestRng::from_seed(42);
+        let mut children = [parent.fork(), parent.fork()];
+        assert_ne!(children[0], children[1]);
+        let mut agreeing_bits = 0;
+        for _ in 0..1000 {
+            let value = parent.next_u64();
+            let value0 = children[0].next_u64();
+            let value1 = children[1].next_u64();
+            assert!(value != value0 && value != value1 && value0 != value1);
+            agreeing_bits += (!(value0 ^ value1)).count_ones();
+        }
+        let agreement = f64::from(agreeing_bits) / (1000.0 * 64.0);
+        assert!((agreement - 0.5).abs() < 0.01, "{}", agreement);
+    }
+}
@@ -0,0 +1,457 @@
+//! Persistent worker threads for repeated concurrent tests
+//!
+//! `concurrent_test_2()` and friends spawn fresh threads on every call. This is
+//! fine for tests which run once, but property-based tests and other tests that
+//! run many concurrent cases can spend most of their time spawning and joining
+//! threads. A `ConcurrentRunner` spawns its worker threads once, parks them
+//! between runs, and hands them closures to run on demand.
+//!
+//! ```
+//! use std::sync::atomic::{AtomicUsize, Ordering};
+//! use testbench::runner::ConcurrentRunner;
+//!
+//! let mut runner = ConcurrentRunner::new();
+//! for _ in 0..100 {
+//!     let counter = AtomicUsize::new(0);
+//!     runner.run_2(
+//!         || {
+//!             counter.fetch_add(1, Ordering::Relaxed);
+//!         },
+//!         || {
+//!             counter.fetch_add(1, Ordering::Relaxed);
+//!         },
+//!     );
+//!     assert_eq!(counter.load(Ordering::Relaxed), 2);
+//! }
+//! ```
+//!
+//! Like with `concurrent_test_2()`, the last closure runs on the calling
+//! thread, the other ones run on worker threads, and all of them are started
+//! together by a barrier. The closures may borrow data from the caller, since
+//! the `run_*()` methods wait for them all to finish before returning.
+
+use crate::{noinline, sync::SpinBarrier, trace, util::panic_message};
+use std::{
+    fmt, mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError},
+    thread::{self, JoinHandle},
+};
+
+/// Closure to be run by a concurrent test participant
+type Task<'a> = Box<dyn FnOnce() + Send + 'a>;
+
+/// Outcome of a closure, as returned by `panic::catch_unwind()`
+type TaskResult = thread::Result<()>;
+
+/// Pool of worker threads for running concurrent tests repeatedly
+///
+/// The `run_*()` methods are equivalent to the `concurrent_test_*()` free
+/// functions, except that the closures are run by the worker threads of the
+/// runner instead of freshly spawned threads. Workers are spawned on demand
+/// when a run needs more of them than the runner has, and are then kept for
+/// later runs.
+///
+/// The closures are boxed and handed to the workers with their lifetime
+/// erased, which is sound because the `run_*()` methods do not return, even
+/// by unwinding, until every worker is done with its closure. This costs one
+/// allocation per closure, which is much cheaper than spawning a thread.
+///
+/// # Panics
+///
+/// If a closure panics, the `run_*()` methods wait for the other closures to
+/// finish, then panic with a message that tells which closure panicked,
+/// counting from 0. The runner can still be used afterwards.
+///
+/// Dropping the runner stops and joins its worker threads, which is also done
+/// when the runner is dropped during unwinding.
+///
+pub struct ConcurrentRunner {
+    /// Worker threads, the first ones of which run the closures of a run
+    workers: Vec<Worker>,
+}
+//
+impl ConcurrentRunner {
+    /// Create a runner with one worker thread per CPU, minus one
+    ///
+    /// The calling thread participates in every run, so this is the number of
+    /// workers needed to keep all CPUs busy. At least one worker is spawned,
+    /// so that `run_2()` does not need to spawn any more.
+    ///
+    pub fn new() -> Self {
+        Self::with_workers((crate::parallelism().get() - 1).max(1))
+    }
+
+    /// Create a runner with a certain number of worker threads
+    ///
+    /// # Panics
+    ///
+    /// This function panics if a worker thread cannot be spawned.
+    ///
+    pub fn with_workers(workers: usize) -> Self {
+        let mut result = Self {
+            workers: Vec::with_capacity(workers),
+        };
+        result.reserve_workers(workers);
+        result
+    }
+
+    /// Number of worker threads
+    pub fn workers(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Run two closures concurrently, like `concurrent_test_2()`
+    ///
+    /// # Panics
+    ///
+    /// See the type-level documentation.
+    ///
+    pub fn run_2(&mut self, f1: impl FnOnce() + Send, f2: impl FnOnce() + Send) {
+        self.run(vec![Box::new(f1), Box::new(f2)]);
+    }
+
+    /// Run three closures concurrently, like `concurrent_test_3()`
+    ///
+    /// # Panics
+    ///
+    /// See the type-level documentation.
+    ///
+    pub fn run_3(
+        &mut self,
+        f1: impl FnOnce() + Send,
+        f2: impl FnOnce() + Send,
+        f3: impl FnOnce() + Send,
+    ) {
+        self.run(vec![Box::new(f1), Box::new(f2), Box::new(f3)]);
+    }
+
+    /// Run any number of closures concurrently
+    ///
+    /// Closures of different types can be boxed into a
+    /// `Box<dyn FnOnce() + Send>`.
+    ///
+    /// # Panics
+    ///
+    /// See the type-level documentation.
+    ///
+    pub fn run_n(&mut self, closures: Vec<impl FnOnce() + Send>) {
+        self.run(
+            closures
+                .into_iter()
+                .map(|closure| -> Task<'_> { Box::new(closure) })
+                .collect(),
+        );
+    }
+
+    /// Run closures concurrently, the last one on the calling thread
+    fn run(&mut self, mut tasks: Vec<Task<'_>>) {
+        let last = match tasks.pop() {
+            Some(last) => last,
+            None => return,
+        };
+        self.reserve_workers(tasks.len());
+
+        let participants = tasks.len() + 1;
+        let mut barrier = SpinBarrier::new(participants);
+        if participants > crate::parallelism().get() {
+            // Spinning would keep the workers from running, see SpinBarrier
+            barrier = barrier.spin_budget(Some(0));
+        }
+        // Nothing may panic from the moment where the first task is submitted
+        // to the moment where all tasks are done, otherwise the workers would
+        // be left waiting at the barrier or accessing freed caller data.
+        let parent = trace::Parent::current();
+        for (idx, (worker, task)) in self.workers.iter().zip(tasks).enumerate() {
+            let (barrier, parent) = (&barrier, &parent);
+            let job: Task<'_> = Box::new(move || {
+                let _span = trace::participant(parent, idx);
+                barrier.wait();
+                trace::barrier_released();
+                noinline::call_once(task);
+            });
+            // Safe because this function does not return or unwind until the
+            // worker is done with the job, as checked by `wait()` below, so
+            // the data that the job borrows outlives it.
+            let job = unsafe { mem::transmute::<Task<'_>, Task<'static>>(job) };
+            worker.submit(job);
+        }
+        let last = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _span = trace::participant(&parent, participants - 1);
+            barrier.wait();
+            trace::barrier_released();
+            noinline::call_once(last);
+        }));
+        let mut results = Vec::with_capacity(participants);
+        results.extend(self.workers[..participants - 1].iter().map(Worker::wait));
+        results.push(last);
+
+        for (idx, result) in results.into_iter().enumerate() {
+            if let Err(payload) = result {
+                panic!("Closure {} panicked: {}", idx, panic_message(&*payload));
+            }
+        }
+    }
+
+    /// Make sure that there are at least `count` worker threads
+    fn reserve_workers(&mut self, count: usize) {
+        while self.workers.len() < count {
+            self.workers.push(Worker::spawn(self.workers.len()));
+        }
+    }
+}
+//
+impl Default for ConcurrentRunner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+//
+impl fmt::Debug for ConcurrentRunner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConcurrentRunner")
+            .field("workers", &self.workers.len())
+            .finish()
+    }
+}
+//
+impl Drop for ConcurrentRunner {
+    fn drop(&mut self) {
+        for worker in &self.workers {
+            worker.shared.lock().shutdown = true;
+            worker.shared.condvar.notify_all();
+        }
+        for worker in &mut self.workers {
+            if let Some(handle) = worker.handle.take() {
+                // Workers catch the panics of their jobs, so they cannot panic
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
+/// Worker thread of a `ConcurrentRunner`
+struct Worker {
+    /// State shared with the worker thread
+    shared: Arc<WorkerShared>,
+
+    /// Handle to the worker thread, until it is joined
+    handle: Option<JoinHandle<()>>,
+}
+//
+impl Worker {
+    /// Spawn a worker thread
+    fn spawn(idx: usize) -> Self {
+        let shared = Arc::new(WorkerShared {
+            state: Mutex::new(WorkerState::default()),
+            condvar: Condvar::new(),
+        });
+        let worker_shared = shared.clone();
+        let handle = thread::Builder::new()
+            .name(format!("testbench-runner-{}", idx))
+            .spawn(move || worker_shared.work())
+            .expect("Failed to spawn a worker thread");
+        Self {
+            shared,
+            handle: Some(handle),
+        }
+    }
+
+    /// Hand a job to the worker thread
+    fn submit(&self, job: Task<'static>) {
+        let mut state = self.shared.lock();
+        debug_assert!(state.job.is_none() && state.result.is_none());
+        state.job = Some(job);
+        drop(state);
+        self.shared.condvar.notify_all();
+    }
+
+    /// Wait for the worker thread to finish its job
+    fn wait(&self) -> TaskResult {
+        let mut state = self.shared.lock();
+        loop {
+            if let Some(result) = state.result.take() {
+                return result;
+            }
+            state = self.shared.wait(state);
+        }
+    }
+}
+
+/// State shared between a `Worker` and its thread
+struct WorkerShared {
+    /// Job and result exchange area
+    state: Mutex<WorkerState>,
+
+    /// Signaled when a job is submitted or done, and on shutdown
+    condvar: Condvar,
+}
+//
+impl WorkerShared {
+    /// Main loop of the worker thread
+    fn work(&self) {
+        loop {
+            let job = {
+                let mut state = self.lock();
+                loop {
+                    if let Some(job) = state.job.take() {
+                        break job;
+                    }
+                    if state.shutdown {
+                        return;
+                    }
+                    state = self.wait(state);
+                }
+            };
+            // The job is consumed, and thus dropped, before the result is
+            // published, so that it does not outlive the data it borrows.
+            let result = panic::catch_unwind(AssertUnwindSafe(job));
+            self.lock().result = Some(result);
+            self.condvar.notify_all();
+        }
+    }
+
+    /// Access the state, ignoring poisoning as it is never left inconsistent
+    fn lock(&self) -> MutexGuard<'_, WorkerState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Wait for the state to change
+    fn wait<'a>(&self, state: MutexGuard<'a, WorkerState>) -> MutexGuard<'a, WorkerState> {
+        self.condvar
+            .wait(state)
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Mutable state of a worker thread
+#[derive(Default)]
+struct WorkerState {
+    /// Job to be run by the worker thread
+    job: Option<Task<'static>>,
+
+    /// Outcome of the last job, until it is collected
+    result: Option<TaskResult>,
+
+    /// Truth that the worker thread should exit
+    shutdown: bool,
+}
+
+/// Tests of the concurrent test runner
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::Latch;
+    use std::{
+        collections::HashSet,
+        sync::atomic::{AtomicUsize, Ordering},
+        thread::ThreadId,
+    };
+
+    // The closures should run concurrently: each of them waits for the other
+    #[test]
+    fn concurrent() {
+        let mut runner = ConcurrentRunner::with_workers(1);
+        for _ in 0..10 {
+            let latches = [Latch::new(), Latch::new()];
+            runner.run_2(
+                || {
+                    latches[0].signal();
+                    latches[1].wait();
+                },
+                || {
+                    latches[1].signal();
+                    latches[0].wait();
+                },
+            );
+        }
+    }
+
+    // Worker threads should be reused across runs, and spawned on demand
+    #[test]
+    fn reuse() {
+        let mut runner = ConcurrentRunner::with_workers(1);
+        let caller = thread::current().id();
+        let threads = Mutex::new(HashSet::<ThreadId>::new());
+        let record = || {
+            threads.lock().unwrap().insert(thread::current().id());
+        };
+        for _ in 0..10 {
+            runner.run_2(record, record);
+        }
+        assert_eq!(threads.lock().unwrap().len(), 2);
+        assert!(threads.lock().unwrap().contains(&caller));
+
+        runner.run_n(vec![record; 4]);
+        assert_eq!(runner.workers(), 3);
+        assert_eq!(threads.lock().unwrap().len(), 4);
+        runner.run_3(record, record, record);
+        assert_eq!(threads.lock().unwrap().len(), 4);
+        runner.run_n(Vec::<fn()>::new());
+    }
+
+    // Closures should be able to mutate borrowed data
+    #[test]
+    fn borrowing() {
+        let mut runner = ConcurrentRunner::new();
+        let (mut a, mut b) = (0, 0);
+        for _ in 0..10 {
+            runner.run_2(|| a += 1, || b += 2);
+        }
+        assert_eq!((a, b), (10, 20));
+    }
+
+    // Panics should be reported with the index of the closure, once all
+    // closures are done, and leave the runner usable
+    #[test]
+    fn panics() {
+        let mut runner = ConcurrentRunner::with_workers(2);
+        for panicking in 0..3 {
+            let finished = AtomicUsize::new(0);
+            let closure = |idx: usize| {
+                let finished = &finished;
+                move || {
+                    if idx == panicking {
+                        panic!("Expected");
+                    }
+                    finished.fetch_add(1, Ordering::Relaxed);
+                }
+            };
+            let payload = panic::catch_unwind(AssertUnwindSafe(|| {
+                runner.run_3(closure(0), closure(1), closure(2))
+            }))
+            .unwrap_err();
+            assert_eq!(
+                payload.downcast_ref::<String>().map(String::as_str),
+                Some(format!("Closure {} panicked: Expected", panicking).as_str())
+            );
+            assert_eq!(finished.load(Ordering::Relaxed), 2);
+        }
+        let counter = AtomicUsize::new(0);
+        let increment = || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        };
+        runner.run_3(increment, increment, increment);
+        assert_eq!(counter.load(Ordering::Relaxed), 3);
+    }
+
+    // Dropping the runner should join its workers, even during unwinding
+    #[test]
+    fn drop_joins_workers() {
+        let mut runner = ConcurrentRunner::with_workers(1);
+        let shared = runner.workers[0].shared.clone();
+        runner.run_2(|| {}, || {});
+        drop(runner);
+        assert_eq!(Arc::strong_count(&shared), 1);
+
+        let mut shared = None;
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut runner = ConcurrentRunner::with_workers(2);
+            runner.run_3(|| {}, || {}, || {});
+            shared = Some(runner.workers[1].shared.clone());
+            panic!("Expected")
+        }));
+        assert!(result.is_err());
+        assert_eq!(Arc::strong_count(&shared.unwrap()), 1);
+    }
+}
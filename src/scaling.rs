@@ -0,0 +1,307 @@
+//! Scaling of iteration counts to the speed of the build and test intensity
+
+use std::{
+    env,
+    ffi::OsStr,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+/// Name of the environment variable that overrides the iteration divisor
+const SCALE_VAR: &str = "TESTBENCH_ITER_SCALE";
+
+/// Name of the environment variable that selects the test intensity
+const INTENSITY_VAR: &str = "TESTBENCH_INTENSITY";
+
+/// How thoroughly concurrent tests should exercise the code
+///
+/// The intensity is resolved once from the `TESTBENCH_INTENSITY` environment
+/// variable, which can be set to `quick`, `normal` or `soak` (case does not
+/// matter). If the variable is unset or has another value, the intensity is
+/// `Normal`. It can also be set programmatically with `set_for_test()`.
+///
+/// The intensity only affects default parameters: `scale_iters()` counts,
+/// `contention::Sweep` repetitions and, with the `proptest` feature,
+/// `proptest::ConcurrentProp` repetitions. Parameters which are explicitly
+/// set always take precedence.
+///
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Intensity {
+    /// Fast runs for local development, with 10x fewer iterations
+    Quick,
+
+    /// Default intensity
+    Normal,
+
+    /// Thorough runs, e.g. nightly, with 10x more iterations
+    Soak,
+}
+//
+impl Intensity {
+    /// Current test intensity
+    pub fn current() -> Self {
+        let mut state = lock();
+        *state
+            .intensity
+            .get_or_insert_with(|| parse_intensity(env::var_os(INTENSITY_VAR).as_deref()))
+    }
+
+    /// Override the test intensity for the rest of the process
+    ///
+    /// This takes precedence over the `TESTBENCH_INTENSITY` environment
+    /// variable. Since the intensity is process-wide, it affects all tests
+    /// running in the same process, so it is best used in a dedicated test
+    /// binary or at the start of `main()`.
+    ///
+    pub fn set_for_test(intensity: Self) {
+        lock().intensity = Some(intensity);
+    }
+
+    /// Pick a default parameter according to this intensity
+    pub(crate) fn pick<T>(self, quick: T, normal: T, soak: T) -> T {
+        match self {
+            Self::Quick => quick,
+            Self::Normal => normal,
+            Self::Soak => soak,
+        }
+    }
+}
+
+/// Scale down an iteration count for slow builds
+///
+/// Concurrent tests need many iterations to observe rare interleavings, but
+/// iteration counts that take a second in an optimized build can take hours
+/// under Miri or a sanitizer. This function divides a nominal iteration count
+/// by a factor which depends on how the code was built, with the following
+/// precedence:
+///
+/// 1. If the `TESTBENCH_ITER_SCALE` environment variable is set to a positive
+///    integer, the nominal count is divided by this integer, whatever the
+///    build configuration. Other values are ignored.
+/// 2. Otherwise, the nominal count is divided by the largest factor among
+///    those of the detected build configurations: running under Miri, using a
+///    sanitizer, and having debug assertions enabled. The factors can be set
+///    once with `IterScale::install()`, otherwise `IterScale::new()`'s
+///    defaults are used.
+/// 3. If no slow build configuration is detected, the nominal count is used.
+///
+/// Before this division, the nominal count is divided by 10 at `Quick`
+/// `Intensity` and multiplied by 10 at `Soak` intensity.
+///
+/// Sanitizers are detected at build time through the `CARGO_CFG_SANITIZE`
+/// variable that cargo sets when building with `-Zsanitizer`. A sanitized
+/// build can also be flagged manually with `--cfg testbench_sanitize` in
+/// `RUSTFLAGS`.
+///
+/// The divisor is computed on first use and cached afterwards. The result is
+/// never zero, unless `nominal` is zero.
+///
+pub fn scale_iters(nominal: usize) -> usize {
+    let divisor = {
+        let mut state = lock();
+        match state.divisor {
+            Some(divisor) => divisor,
+            None => {
+                let divisor = divisor(env::var_os(SCALE_VAR).as_deref(), &state.scale);
+                state.divisor = Some(divisor);
+                divisor
+            }
+        }
+    };
+    if nominal == 0 {
+        0
+    } else {
+        let nominal = Intensity::current().pick(nominal / 10, nominal, nominal.saturating_mul(10));
+        (nominal / divisor).max(1)
+    }
+}
+
+/// Factors by which `scale_iters()` divides iteration counts
+///
+/// See `scale_iters()` for how these factors are used.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct IterScale {
+    /// Factor used when running under Miri
+    miri: usize,
+
+    /// Factor used when a sanitizer is enabled
+    sanitizer: usize,
+
+    /// Factor used when debug assertions are enabled
+    debug: usize,
+}
+//
+impl IterScale {
+    /// Start from the default factors
+    ///
+    /// These are 10000 under Miri, and 10 for sanitizers and debug builds.
+    ///
+    pub const fn new() -> Self {
+        Self {
+            miri: 10_000,
+            sanitizer: 10,
+            debug: 10,
+        }
+    }
+
+    /// Set the factor used when running under Miri (0 is treated as 1)
+    pub fn miri(mut self, factor: usize) -> Self {
+        self.miri = factor.max(1);
+        self
+    }
+
+    /// Set the factor used when a sanitizer is enabled (0 is treated as 1)
+    pub fn sanitizer(mut self, factor: usize) -> Self {
+        self.sanitizer = factor.max(1);
+        self
+    }
+
+    /// Set the factor used when debug assertions are enabled (0 is treated
+    /// as 1)
+    pub fn debug(mut self, factor: usize) -> Self {
+        self.debug = factor.max(1);
+        self
+    }
+
+    /// Make `scale_iters()` use these factors
+    ///
+    /// # Panics
+    ///
+    /// This function panics if factors were already installed, or if
+    /// `scale_iters()` was already called, as the factors would otherwise
+    /// change in the middle of a test run.
+    ///
+    pub fn install(self) {
+        let mut state = lock();
+        assert!(
+            !state.installed && state.divisor.is_none(),
+            "Iteration scaling factors must be installed once, before first use"
+        );
+        state.scale = self;
+        state.installed = true;
+    }
+}
+//
+impl Default for IterScale {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Global iteration scaling configuration
+struct State {
+    /// Factors in use
+    scale: IterScale,
+
+    /// Truth that the factors were installed by the user
+    installed: bool,
+
+    /// Divisor of iteration counts, once computed
+    divisor: Option<usize>,
+
+    /// Test intensity, once resolved or set
+    intensity: Option<Intensity>,
+}
+
+/// Access the global configuration, ignoring poisoning as it is always
+/// consistent
+fn lock() -> MutexGuard<'static, State> {
+    static STATE: Mutex<State> = Mutex::new(State {
+        scale: IterScale::new(),
+        installed: false,
+        divisor: None,
+        intensity: None,
+    });
+    STATE.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Compute the iteration divisor, given the value of the override variable
+fn divisor(override_var: Option<&OsStr>, scale: &IterScale) -> usize {
+    if let Some(divisor) = override_var.and_then(parse_override) {
+        return divisor;
+    }
+    let mut divisor = 1;
+    if cfg!(miri) {
+        divisor = divisor.max(scale.miri);
+    }
+    if cfg!(testbench_sanitize) {
+        divisor = divisor.max(scale.sanitizer);
+    }
+    if cfg!(debug_assertions) {
+        divisor = divisor.max(scale.debug);
+    }
+    divisor
+}
+
+/// Parse the override variable, ignoring zero and invalid values
+fn parse_override(value: &OsStr) -> Option<usize> {
+    value
+        .to_str()?
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .filter(|&divisor| divisor > 0)
+}
+
+/// Parse the intensity variable, defaulting to `Normal`
+fn parse_intensity(value: Option<&OsStr>) -> Intensity {
+    match value
+        .and_then(OsStr::to_str)
+        .map(|value| value.trim().to_ascii_lowercase())
+        .as_deref()
+    {
+        Some("quick") => Intensity::Quick,
+        Some("soak") => Intensity::Soak,
+        _ => Intensity::Normal,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsString;
+
+    #[test]
+    fn override_var() {
+        let scale = IterScale::new();
+        assert_eq!(divisor(Some(&OsString::from("7")), &scale), 7);
+        assert_eq!(divisor(Some(&OsString::from(" 1 ")), &scale), 1);
+        let detected = divisor(None, &scale);
+        for invalid in ["0", "-3", "ten", ""].iter() {
+            assert_eq!(divisor(Some(&OsString::from(invalid)), &scale), detected);
+        }
+    }
+
+    #[test]
+    fn detection() {
+        let scale = IterScale::new().miri(3).sanitizer(5).debug(0);
+        let expected = if cfg!(testbench_sanitize) {
+            5
+        } else if cfg!(miri) {
+            3
+        } else {
+            1
+        };
+        assert_eq!(divisor(None, &scale), expected);
+    }
+
+    #[test]
+    fn intensity_var() {
+        let parse = |value: &str| parse_intensity(Some(&OsString::from(value)));
+        assert_eq!(parse_intensity(None), Intensity::Normal);
+        assert_eq!(parse("quick"), Intensity::Quick);
+        assert_eq!(parse(" Soak "), Intensity::Soak);
+        assert_eq!(parse("NORMAL"), Intensity::Normal);
+        for invalid in ["", "fast", "soak2", "1"].iter() {
+            assert_eq!(parse(invalid), Intensity::Normal);
+        }
+    }
+
+    #[test]
+    fn scaling() {
+        assert_eq!(scale_iters(0), 0);
+        assert_eq!(scale_iters(1), 1);
+        assert!(scale_iters(1_000_000) <= 1_000_000);
+        assert!(scale_iters(1_000_000) >= 1);
+    }
+}
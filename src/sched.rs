@@ -0,0 +1,454 @@
+//! Realtime scheduling of latency-critical threads
+//!
+//! When measuring tail latencies, a benchmark thread which gets preempted by
+//! the operating system in the middle of an operation produces outliers that
+//! say nothing about the code under test. Running the benchmark thread with a
+//! realtime scheduling policy protects it from being preempted by ordinary
+//! threads, including the antagonists of the `contention` module.
+//!
+//! Realtime scheduling requires the `rt` feature, and is currently available
+//! on Linux, where the `SCHED_FIFO` policy is used, and Windows, where the
+//! thread priority is raised to `THREAD_PRIORITY_TIME_CRITICAL`. In other
+//! configurations, the functions of this module report a
+//! `SchedError::Unsupported` error.
+//!
+//! On Linux, unprivileged processes usually cannot use realtime scheduling,
+//! which requires the `CAP_SYS_NICE` capability or a sufficient
+//! `RLIMIT_RTPRIO` resource limit. This is reported as a
+//! `SchedError::PermissionDenied` error, which tests may treat as a reason to
+//! skip a measurement:
+//!
+//! ```
+//! use testbench::sched::{self, RtPriority, SchedError};
+//!
+//! match sched::with_realtime(RtPriority::MIN, || 42) {
+//!     Ok(result) => assert_eq!(result, 42),
+//!     Err(SchedError::PermissionDenied | SchedError::Unsupported) => {
+//!         println!("Skipping realtime measurement");
+//!     }
+//!     Err(error) => panic!("Unexpected error: {}", error),
+//! }
+//! ```
+//!
+//! A realtime thread which never blocks can keep ordinary threads from running
+//! on its CPU. As a safety net, while a thread of the process runs under
+//! `with_realtime()` on Linux, the `RLIMIT_RTTIME` resource limit is lowered
+//! to `RTTIME_LIMIT` if it was unlimited, so that a runaway realtime thread
+//! gets the process killed by `SIGXCPU` instead of hanging the machine.
+
+use std::{fmt, io, time::Duration};
+
+/// Limit on the CPU time that a realtime thread may use without blocking
+///
+/// See the module-level documentation for more information. This is only
+/// enforced on Linux, and only if no lower limit was set beforehand.
+///
+pub const RTTIME_LIMIT: Duration = Duration::from_secs(60);
+
+/// Run a closure with realtime scheduling on the current thread
+///
+/// The current thread's scheduling policy and priority are restored once the
+/// closure returns, or if it panics. Threads spawned by the closure run with
+/// ordinary scheduling.
+///
+/// # Errors
+///
+/// This function fails without running the closure if the thread cannot be
+/// switched to realtime scheduling, including when this is not supported.
+///
+/// # Panics
+///
+/// This function propagates panics from the closure.
+///
+pub fn with_realtime<R>(priority: RtPriority, body: impl FnOnce() -> R) -> Result<R, SchedError> {
+    let _restore = set_realtime_scoped(priority)?;
+    Ok(body())
+}
+
+/// Realtime scheduling priority
+///
+/// On Linux, this is the `SCHED_FIFO` priority, which ranges from 1 (lowest)
+/// to 99 (highest). Any realtime priority takes precedence over ordinary
+/// threads, so higher priorities only matter when competing with other
+/// realtime threads. Windows has a single relevant priority level, so the
+/// priority is ignored there.
+///
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct RtPriority(u8);
+//
+impl RtPriority {
+    /// Lowest realtime priority, which is the default
+    pub const MIN: Self = Self(1);
+
+    /// Highest realtime priority
+    pub const MAX: Self = Self(99);
+
+    /// Select a realtime priority
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `priority` is not between 1 and 99.
+    ///
+    pub fn new(priority: u8) -> Self {
+        assert!(
+            (Self::MIN.0..=Self::MAX.0).contains(&priority),
+            "Realtime priorities must be between 1 and 99"
+        );
+        Self(priority)
+    }
+
+    /// Numerical value of this priority
+    pub fn get(self) -> u8 {
+        self.0
+    }
+}
+//
+impl Default for RtPriority {
+    fn default() -> Self {
+        Self::MIN
+    }
+}
+
+/// Error while changing the scheduling of a thread
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum SchedError {
+    /// Realtime scheduling is not supported in this configuration
+    Unsupported,
+
+    /// The operating system did not allow us to use realtime scheduling
+    PermissionDenied,
+
+    /// Other operating system error
+    Os(io::Error),
+}
+//
+impl fmt::Display for SchedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unsupported => write!(f, "realtime scheduling is not supported"),
+            Self::PermissionDenied => write!(f, "not allowed to use realtime scheduling"),
+            Self::Os(error) => write!(f, "thread scheduling error: {}", error),
+        }
+    }
+}
+//
+impl std::error::Error for SchedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Os(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Switch the current thread to realtime scheduling until the returned guard
+/// is dropped
+pub(crate) fn set_realtime_scoped(priority: RtPriority) -> Result<RestoreScheduling, SchedError> {
+    backend::set_realtime(priority.0).map(RestoreScheduling)
+}
+
+/// Restores the former scheduling of the current thread when dropped
+pub(crate) struct RestoreScheduling(backend::Scheduling);
+//
+impl Drop for RestoreScheduling {
+    fn drop(&mut self) {
+        backend::restore(&self.0);
+    }
+}
+
+/// Linux implementation, based on sched_setscheduler
+#[cfg(all(feature = "rt", target_os = "linux"))]
+mod backend {
+    use super::{SchedError, RTTIME_LIMIT};
+    use std::{
+        io, mem,
+        os::raw::c_int,
+        sync::{Mutex, PoisonError},
+    };
+
+    /// Former scheduling policy and parameters of a thread
+    pub(super) struct Scheduling {
+        /// Scheduling policy, as returned by sched_getscheduler
+        policy: c_int,
+
+        /// Scheduling parameters, as returned by sched_getparam
+        param: libc::sched_param,
+    }
+
+    pub(super) fn set_realtime(priority: u8) -> Result<Scheduling, SchedError> {
+        // Safe because sched_param is plain data, and all-zeroes is valid
+        let mut param: libc::sched_param = unsafe { mem::zeroed() };
+        // Safe because 0 designates the current thread, and param is valid
+        if unsafe { libc::sched_getparam(0, &mut param) } != 0 {
+            return Err(last_error());
+        }
+        // Safe because 0 designates the current thread
+        let policy = unsafe { libc::sched_getscheduler(0) };
+        if policy < 0 {
+            return Err(last_error());
+        }
+        let previous = Scheduling { policy, param };
+
+        // Safe because sched_param is plain data, and all-zeroes is valid
+        let mut realtime: libc::sched_param = unsafe { mem::zeroed() };
+        realtime.sched_priority = c_int::from(priority);
+        limit_rttime()?;
+        // Resetting on fork keeps threads spawned by this one from inheriting
+        // the realtime policy.
+        //
+        // Safe because 0 designates the current thread, and realtime is valid
+        let result = unsafe {
+            libc::sched_setscheduler(0, libc::SCHED_FIFO | libc::SCHED_RESET_ON_FORK, &realtime)
+        };
+        if result != 0 {
+            let error = last_error();
+            unlimit_rttime();
+            return Err(error);
+        }
+        Ok(previous)
+    }
+
+    pub(super) fn restore(previous: &Scheduling) {
+        // Going back from realtime to the former policy does not require any
+        // privilege, so this should not fail, and there is nothing sensible
+        // to do if it does.
+        //
+        // Safe because 0 designates the current thread, and param is valid
+        unsafe { libc::sched_setscheduler(0, previous.policy, &previous.param) };
+        unlimit_rttime();
+    }
+
+    /// State of the RLIMIT_RTTIME safety net
+    struct RtTimeLimit {
+        /// Number of threads which are currently using realtime scheduling
+        users: usize,
+
+        /// Former limit, if it was replaced by the safety net
+        previous: Option<libc::rlimit>,
+    }
+
+    /// Process-wide state of the RLIMIT_RTTIME safety net
+    static RTTIME: Mutex<RtTimeLimit> = Mutex::new(RtTimeLimit {
+        users: 0,
+        previous: None,
+    });
+
+    /// Enforce RTTIME_LIMIT if RLIMIT_RTTIME is unlimited
+    fn limit_rttime() -> Result<(), SchedError> {
+        let mut state = RTTIME.lock().unwrap_or_else(PoisonError::into_inner);
+        if state.users == 0 {
+            // Safe because rlimit is plain data, and all-zeroes is valid
+            let mut limit: libc::rlimit = unsafe { mem::zeroed() };
+            // Safe because the limit pointer is valid
+            if unsafe { libc::getrlimit(libc::RLIMIT_RTTIME, &mut limit) } != 0 {
+                return Err(last_error());
+            }
+            if limit.rlim_cur == libc::RLIM_INFINITY {
+                let safety_limit = libc::rlimit {
+                    rlim_cur: (RTTIME_LIMIT.as_micros() as libc::rlim_t).min(limit.rlim_max),
+                    rlim_max: limit.rlim_max,
+                };
+                // Safe because the limit pointer is valid
+                if unsafe { libc::setrlimit(libc::RLIMIT_RTTIME, &safety_limit) } != 0 {
+                    return Err(last_error());
+                }
+                state.previous = Some(limit);
+            }
+        }
+        state.users += 1;
+        Ok(())
+    }
+
+    /// Restore the former RLIMIT_RTTIME once no thread uses realtime scheduling
+    fn unlimit_rttime() {
+        let mut state = RTTIME.lock().unwrap_or_else(PoisonError::into_inner);
+        state.users -= 1;
+        if state.users == 0 {
+            if let Some(previous) = state.previous.take() {
+                // Raising the soft limit back up to the hard limit does not
+                // require any privilege, so this should not fail.
+                //
+                // Safe because the limit pointer is valid
+                unsafe { libc::setrlimit(libc::RLIMIT_RTTIME, &previous) };
+            }
+        }
+    }
+
+    /// Translate the last OS error into a SchedError
+    fn last_error() -> SchedError {
+        let error = io::Error::last_os_error();
+        match error.raw_os_error() {
+            Some(libc::EPERM) => SchedError::PermissionDenied,
+            _ => SchedError::Os(error),
+        }
+    }
+}
+
+/// Windows implementation, based on thread priorities
+#[cfg(all(feature = "rt", windows))]
+mod backend {
+    use super::SchedError;
+    use std::io;
+    use windows_sys::Win32::{
+        Foundation::ERROR_ACCESS_DENIED,
+        System::Threading::{
+            GetCurrentThread, GetThreadPriority, SetThreadPriority, THREAD_PRIORITY_TIME_CRITICAL,
+        },
+    };
+
+    /// Value returned by GetThreadPriority on failure
+    const THREAD_PRIORITY_ERROR_RETURN: i32 = 0x7fff_ffff;
+
+    /// Former priority of a thread
+    pub(super) struct Scheduling(i32);
+
+    pub(super) fn set_realtime(_priority: u8) -> Result<Scheduling, SchedError> {
+        // Safe because the current thread pseudo-handle is always valid
+        let previous = unsafe { GetThreadPriority(GetCurrentThread()) };
+        if previous == THREAD_PRIORITY_ERROR_RETURN {
+            return Err(last_error());
+        }
+        // Safe because the current thread pseudo-handle is always valid
+        let result =
+            unsafe { SetThreadPriority(GetCurrentThread(), THREAD_PRIORITY_TIME_CRITICAL) };
+        if result == 0 {
+            return Err(last_error());
+        }
+        Ok(Scheduling(previous))
+    }
+
+    pub(super) fn restore(previous: &Scheduling) {
+        // The former priority was accepted by the OS before, so this should
+        // not fail, and there is nothing sensible to do if it does.
+        //
+        // Safe because the current thread pseudo-handle is always valid
+        unsafe { SetThreadPriority(GetCurrentThread(), previous.0) };
+    }
+
+    /// Translate the last OS error into a SchedError
+    fn last_error() -> SchedError {
+        let error = io::Error::last_os_error();
+        match error.raw_os_error().map(|code| code as u32) {
+            Some(ERROR_ACCESS_DENIED) => SchedError::PermissionDenied,
+            _ => SchedError::Os(error),
+        }
+    }
+}
+
+/// Fallback implementation for unsupported configurations
+#[cfg(not(all(feature = "rt", any(target_os = "linux", windows))))]
+mod backend {
+    use super::SchedError;
+
+    /// Former scheduling of a thread, which is never changed
+    pub(super) struct Scheduling;
+
+    pub(super) fn set_realtime(_priority: u8) -> Result<Scheduling, SchedError> {
+        Err(SchedError::Unsupported)
+    }
+
+    pub(super) fn restore(_previous: &Scheduling) {}
+}
+
+/// Tests of realtime scheduling
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(all(feature = "rt", target_os = "linux"))]
+    use std::os::raw::c_int;
+    use std::{panic, thread};
+
+    /// Current scheduling policy and priority of the current thread
+    #[cfg(all(feature = "rt", target_os = "linux"))]
+    fn current_scheduling() -> (c_int, c_int) {
+        // Safe because 0 designates the current thread
+        let policy = unsafe { libc::sched_getscheduler(0) };
+        assert!(policy >= 0);
+        // Safe because sched_param is plain data, and all-zeroes is valid
+        let mut param: libc::sched_param = unsafe { std::mem::zeroed() };
+        // Safe because 0 designates the current thread, and param is valid
+        assert_eq!(unsafe { libc::sched_getparam(0, &mut param) }, 0);
+        (policy, param.sched_priority)
+    }
+
+    // The closure should run with realtime scheduling if this is allowed, and
+    // the former scheduling should be restored afterwards in any case
+    #[test]
+    fn with_realtime() {
+        thread::spawn(|| {
+            #[cfg(all(feature = "rt", target_os = "linux"))]
+            let initial = current_scheduling();
+            match super::with_realtime(RtPriority::new(10), || {
+                #[cfg(all(feature = "rt", target_os = "linux"))]
+                {
+                    let (policy, priority) = current_scheduling();
+                    assert_eq!(policy & !libc::SCHED_RESET_ON_FORK, libc::SCHED_FIFO);
+                    assert_eq!(priority, 10);
+                }
+                42
+            }) {
+                Ok(result) => assert_eq!(result, 42),
+                Err(SchedError::PermissionDenied) => {
+                    println!("Realtime scheduling is not allowed, skipping success path")
+                }
+                #[cfg(not(all(feature = "rt", any(target_os = "linux", windows))))]
+                Err(SchedError::Unsupported) => {}
+                Err(error) => panic!("Unexpected error: {}", error),
+            }
+            #[cfg(all(feature = "rt", target_os = "linux"))]
+            assert_eq!(current_scheduling(), initial);
+        })
+        .join()
+        .unwrap();
+    }
+
+    // Former scheduling should be restored when the closure panics
+    #[test]
+    fn panic() {
+        thread::spawn(|| {
+            #[cfg(all(feature = "rt", target_os = "linux"))]
+            let initial = current_scheduling();
+            let result = panic::catch_unwind(|| {
+                super::with_realtime(RtPriority::MIN, || panic!("Expected"))
+            });
+            match result {
+                Err(_) => {}
+                Ok(Err(SchedError::PermissionDenied | SchedError::Unsupported)) => {}
+                Ok(other) => panic!("Unexpected result: {:?}", other),
+            }
+            #[cfg(all(feature = "rt", target_os = "linux"))]
+            assert_eq!(current_scheduling(), initial);
+        })
+        .join()
+        .unwrap();
+    }
+
+    // Threads spawned by a realtime thread should use ordinary scheduling
+    #[test]
+    #[cfg(all(feature = "rt", target_os = "linux"))]
+    fn no_inheritance() {
+        let initial = thread::spawn(current_scheduling).join().unwrap();
+        let result = thread::spawn(|| {
+            super::with_realtime(RtPriority::MIN, || {
+                thread::spawn(current_scheduling).join().unwrap()
+            })
+        })
+        .join()
+        .unwrap();
+        match result {
+            Ok(spawned) => assert_eq!(spawned, initial),
+            Err(SchedError::PermissionDenied) => {}
+            Err(error) => panic!("Unexpected error: {}", error),
+        }
+    }
+
+    // Priorities should be restricted to the range supported by Linux
+    #[test]
+    fn priorities() {
+        assert_eq!(RtPriority::default(), RtPriority::MIN);
+        assert_eq!(RtPriority::new(99), RtPriority::MAX);
+        assert!(panic::catch_unwind(|| RtPriority::new(0)).is_err());
+        assert!(panic::catch_unwind(|| RtPriority::new(100)).is_err());
+    }
+}
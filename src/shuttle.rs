@@ -0,0 +1,51 @@
+//! Randomized schedule exploration with shuttle
+//!
+//! When this crate is built with `RUSTFLAGS="--cfg shuttle"`, the threads
+//! spawned by `concurrent_test_2()`, `concurrent_test_3()` and the contention
+//! harness are shuttle threads, and the types of the `race_cell` module use
+//! shuttle's atomics. Existing tests can thus be run under shuttle's
+//! randomized scheduler by wrapping them into `explore()`.
+//!
+//! Unlike loom, shuttle does not try every possible interleaving, so it can
+//! handle tests which are too large for loom, at the expense of only finding
+//! bugs with some probability. When a test fails, shuttle prints the failing
+//! schedule, which can be passed to `replay()` in order to reproduce the
+//! failure deterministically.
+//!
+//! Types which use shuttle's atomics may only be used inside of a shuttle
+//! test, so run your shuttle tests separately from other tests, e.g. using a
+//! dedicated integration test:
+//!
+//! ```text
+//! RUSTFLAGS="--cfg shuttle" cargo test --release --test shuttle
+//! ```
+//!
+//! The loom and shuttle backends cannot be enabled at the same time.
+
+/// Run a test under shuttle's randomized scheduler
+///
+/// The test is run the specified number of times, each time with a different
+/// random schedule.
+///
+/// # Panics
+///
+/// This function will propagate panics from the test, after printing the
+/// schedule that led to them.
+///
+pub fn explore(iterations: usize, test: impl Fn() + Send + Sync + 'static) {
+    shuttle::check_random(test, iterations)
+}
+
+/// Replay a schedule printed by a failing shuttle test
+///
+/// This is deterministic as long as the only source of nondeterminism in the
+/// test is thread scheduling.
+///
+/// # Panics
+///
+/// This function will propagate panics from the test, and panic if the
+/// schedule is invalid or does not match the test.
+///
+pub fn replay(schedule: &str, test: impl Fn() + Send + Sync + 'static) {
+    shuttle::replay(test, schedule)
+}
@@ -0,0 +1,418 @@
+//! Summary statistics of repeated measurements
+
+#[cfg(feature = "serde")]
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+use std::{fmt, time::Duration};
+
+/// Samples of a repeated duration measurement, with summary statistics
+///
+/// Quantiles are computed using the nearest-rank method, like in
+/// `LatencyStats`. The `Debug` output starts with the summary statistics,
+/// which makes it convenient to dump in test logs.
+///
+/// Samples are kept sorted, so quantiles are computed in constant time, while
+/// other statistics take linear time, or `O(n log n)` for those based on the
+/// median absolute deviation. Batches of samples can be combined with
+/// `merge()`.
+///
+#[derive(Clone, PartialEq)]
+pub struct SampleStats {
+    /// Samples, in the order where they were measured
+    samples: Vec<Duration>,
+
+    /// Samples, sorted in increasing order
+    sorted: Vec<Duration>,
+}
+//
+impl SampleStats {
+    /// Compute statistics from raw duration samples
+    pub fn from_durations(samples: Vec<Duration>) -> Self {
+        let mut sorted = samples.clone();
+        sorted.sort_unstable();
+        Self { samples, sorted }
+    }
+
+    /// Samples, in the order where they were measured
+    pub fn samples(&self) -> &[Duration] {
+        &self.samples[..]
+    }
+
+    /// Number of samples
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Truth that there are no samples
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Smallest sample
+    ///
+    /// # Panics
+    ///
+    /// All statistics accessors panic if there are no samples.
+    ///
+    pub fn min(&self) -> Duration {
+        self.quantile(0.0)
+    }
+
+    /// Median sample
+    pub fn median(&self) -> Duration {
+        self.quantile(0.5)
+    }
+
+    /// 90th percentile of the samples
+    pub fn p90(&self) -> Duration {
+        self.quantile(0.9)
+    }
+
+    /// 95th percentile of the samples
+    pub fn p95(&self) -> Duration {
+        self.quantile(0.95)
+    }
+
+    /// 99th percentile of the samples
+    pub fn p99(&self) -> Duration {
+        self.quantile(0.99)
+    }
+
+    /// Largest sample
+    pub fn max(&self) -> Duration {
+        self.quantile(1.0)
+    }
+
+    /// Quantile of the samples, for a quantile between 0.0 and 1.0
+    ///
+    /// This uses the nearest-rank method: the result is the smallest sample
+    /// such that a fraction `q` of the samples is smaller or equal to it.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if there are no samples or `q` is out of range.
+    ///
+    pub fn quantile(&self, q: f64) -> Duration {
+        assert!(!self.sorted.is_empty(), "No sample available");
+        assert!((0.0..=1.0).contains(&q), "Quantile must be in [0, 1]");
+        let rank = (q * self.sorted.len() as f64).ceil() as usize;
+        self.sorted[rank.saturating_sub(1)]
+    }
+
+    /// Arithmetic mean of the samples, rounded down to the nanosecond
+    pub fn mean(&self) -> Duration {
+        duration_from_nanos(self.total_nanos() / self.samples.len() as u128)
+    }
+
+    /// Sample standard deviation, using Bessel's correction
+    ///
+    /// This is zero if there is only one sample. Deviations are computed from
+    /// the exact mean, rather than the rounded one returned by `mean()`.
+    ///
+    pub fn std_dev(&self) -> Duration {
+        let mean = self.total_nanos() as f64 / self.samples.len() as f64;
+        if self.samples.len() < 2 {
+            return Duration::ZERO;
+        }
+        let sum_of_squares = self
+            .samples
+            .iter()
+            .map(|sample| (sample.as_nanos() as f64 - mean).powi(2))
+            .sum::<f64>();
+        let variance = sum_of_squares / (self.samples.len() - 1) as f64;
+        Duration::from_secs_f64(variance.sqrt() / 1e9)
+    }
+
+    /// Median absolute deviation from the median
+    ///
+    /// This measures the dispersion of the samples in a way that is much less
+    /// sensitive to outliers than the standard deviation.
+    ///
+    pub fn median_absolute_deviation(&self) -> Duration {
+        SampleStats::from_durations(self.absolute_deviations().collect()).median()
+    }
+
+    /// Samples that deviate from the median by more than `k` times the
+    /// median absolute deviation, in the order where they were measured
+    ///
+    /// A `k` of 3 to 5 is a common choice for flagging outliers.
+    ///
+    pub fn outliers(&self, k: f64) -> Vec<Duration> {
+        let threshold = self.outlier_threshold(k);
+        self.samples
+            .iter()
+            .zip(self.absolute_deviations())
+            .filter(|(_, deviation)| deviation.as_secs_f64() > threshold)
+            .map(|(&sample, _)| sample)
+            .collect()
+    }
+
+    /// Number of samples that `outliers(k)` would return
+    pub fn outlier_count(&self, k: f64) -> usize {
+        let threshold = self.outlier_threshold(k);
+        self.absolute_deviations()
+            .filter(|deviation| deviation.as_secs_f64() > threshold)
+            .count()
+    }
+
+    /// Add the samples of another batch to these ones
+    ///
+    /// The result is the same as if all samples had been passed to
+    /// `from_durations()`, with the samples of `other` coming last. This takes
+    /// linear time, since both sets of samples are already sorted.
+    ///
+    pub fn merge(&mut self, other: &Self) {
+        self.samples.extend_from_slice(&other.samples);
+        let mut sorted = Vec::with_capacity(self.samples.len());
+        let (mut left, mut right) = (
+            self.sorted.iter().peekable(),
+            other.sorted.iter().peekable(),
+        );
+        while let (Some(&&l), Some(&&r)) = (left.peek(), right.peek()) {
+            if l <= r {
+                sorted.push(l);
+                left.next();
+            } else {
+                sorted.push(r);
+                right.next();
+            }
+        }
+        sorted.extend(left.chain(right));
+        self.sorted = sorted;
+    }
+
+    /// Sum of the samples, in nanoseconds
+    fn total_nanos(&self) -> u128 {
+        assert!(!self.samples.is_empty(), "No sample available");
+        self.samples.iter().map(Duration::as_nanos).sum()
+    }
+
+    /// Deviation from the median above which a sample is an outlier, in
+    /// seconds
+    fn outlier_threshold(&self, k: f64) -> f64 {
+        self.median_absolute_deviation().as_secs_f64() * k
+    }
+
+    /// Absolute deviation of each sample from the median
+    fn absolute_deviations(&self) -> impl Iterator<Item = Duration> + '_ {
+        let median = self.median();
+        self.samples.iter().map(move |&sample| {
+            if sample > median {
+                sample - median
+            } else {
+                median - sample
+            }
+        })
+    }
+}
+//
+impl fmt::Debug for SampleStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("SampleStats");
+        debug.field("len", &self.len());
+        if !self.is_empty() {
+            debug
+                .field("min", &self.min())
+                .field("median", &self.median())
+                .field("mean", &self.mean())
+                .field("p90", &self.p90())
+                .field("p95", &self.p95())
+                .field("p99", &self.p99())
+                .field("max", &self.max())
+                .field("std_dev", &self.std_dev())
+                .field("mad", &self.median_absolute_deviation());
+        }
+        debug.field("samples", &self.samples).finish()
+    }
+}
+//
+#[cfg(feature = "serde")]
+impl Serialize for SampleStats {
+    /// The samples are serialized along with summary statistics, which are
+    /// missing if there are no samples
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let summary = |stat: fn(&Self) -> Duration| (!self.is_empty()).then(|| stat(self));
+        let mut state = serializer.serialize_struct("SampleStats", 11)?;
+        state.serialize_field("len", &self.len())?;
+        state.serialize_field("min", &summary(Self::min))?;
+        state.serialize_field("median", &summary(Self::median))?;
+        state.serialize_field("mean", &summary(Self::mean))?;
+        state.serialize_field("p90", &summary(Self::p90))?;
+        state.serialize_field("p95", &summary(Self::p95))?;
+        state.serialize_field("p99", &summary(Self::p99))?;
+        state.serialize_field("max", &summary(Self::max))?;
+        state.serialize_field("std_dev", &summary(Self::std_dev))?;
+        state.serialize_field("mad", &summary(Self::median_absolute_deviation))?;
+        state.serialize_field("samples", &self.samples)?;
+        state.end()
+    }
+}
+
+/// Build a Duration from a number of nanoseconds that may not fit in a u64
+fn duration_from_nanos(nanos: u128) -> Duration {
+    const NANOS_PER_SEC: u128 = 1_000_000_000;
+    Duration::new(
+        (nanos / NANOS_PER_SEC) as u64,
+        (nanos % NANOS_PER_SEC) as u32,
+    )
+}
+
+/// Tests of summary statistics
+#[cfg(test)]
+mod tests {
+    use super::SampleStats;
+    use ::proptest::{collection::vec, prelude::*};
+    use std::time::Duration;
+
+    /// Build statistics from nanosecond samples
+    fn from_nanos(nanos: &[u64]) -> SampleStats {
+        SampleStats::from_durations(nanos.iter().copied().map(Duration::from_nanos).collect())
+    }
+
+    // Statistics should match hand-computed values
+    #[test]
+    fn summary() {
+        let stats = from_nanos(&[5, 1, 4, 2, 3, 100]);
+        assert_eq!(stats.len(), 6);
+        assert_eq!(stats.samples()[0], Duration::from_nanos(5));
+        assert_eq!(stats.min(), Duration::from_nanos(1));
+        assert_eq!(stats.median(), Duration::from_nanos(3));
+        assert_eq!(stats.p95(), Duration::from_nanos(100));
+        assert_eq!(stats.max(), Duration::from_nanos(100));
+        assert_eq!(stats.mean(), Duration::from_nanos(19));
+        // Deviations from the mean are -14, -18, -15, -17, -16 and 81
+        let std_dev = ((14f64.powi(2)
+            + 18f64.powi(2)
+            + 15f64.powi(2)
+            + 17f64.powi(2)
+            + 16f64.powi(2)
+            + 81f64.powi(2))
+            / 5.0)
+            .sqrt();
+        let error = stats.std_dev().as_secs_f64() * 1e9 - std_dev;
+        assert!(error.abs() < 1.0);
+        // Absolute deviations from the median are 2, 2, 1, 1, 0 and 97
+        assert_eq!(stats.median_absolute_deviation(), Duration::from_nanos(1));
+        assert_eq!(stats.outliers(3.0), vec![Duration::from_nanos(100)]);
+        assert_eq!(stats.outlier_count(3.0), 1);
+        // With a threshold of 1.5ns, the samples at 1ns and 5ns are outliers too
+        assert_eq!(stats.outlier_count(1.5), 3);
+    }
+
+    // Percentiles should follow the nearest-rank method
+    #[test]
+    fn percentiles() {
+        let stats = from_nanos(&(1..=20).rev().collect::<Vec<_>>());
+        // The p-th percentile of 20 samples has rank ceil(20 * p / 100)
+        assert_eq!(stats.p90(), Duration::from_nanos(18));
+        assert_eq!(stats.p95(), Duration::from_nanos(19));
+        assert_eq!(stats.p99(), Duration::from_nanos(20));
+        assert_eq!(stats.quantile(0.26), Duration::from_nanos(6));
+        assert_eq!(stats.quantile(0.25), Duration::from_nanos(5));
+        assert_eq!(stats.median(), Duration::from_nanos(10));
+        assert_eq!(stats.min(), Duration::from_nanos(1));
+    }
+
+    // Merging should keep samples in measurement order and sorted
+    #[test]
+    fn merge() {
+        let mut stats = from_nanos(&[4, 2, 6]);
+        stats.merge(&from_nanos(&[5, 1, 3]));
+        assert_eq!(stats, from_nanos(&[4, 2, 6, 5, 1, 3]));
+        assert_eq!(stats.median(), Duration::from_nanos(3));
+        stats.merge(&from_nanos(&[]));
+        assert_eq!(stats.len(), 6);
+    }
+
+    // The mean should not lose precision on large durations
+    #[test]
+    fn exact_mean() {
+        let stats = SampleStats::from_durations(vec![Duration::MAX, Duration::MAX]);
+        assert_eq!(stats.mean(), Duration::MAX);
+        assert_eq!(stats.std_dev(), Duration::ZERO);
+    }
+
+    // A million samples should be processed quickly
+    #[test]
+    fn many_samples() {
+        let count = crate::scale_iters(1_000_000) as u64;
+        let mut stats = from_nanos(&(0..count).rev().collect::<Vec<_>>());
+        stats.merge(&stats.clone());
+        assert_eq!(stats.len() as u64, 2 * count);
+        assert_eq!(stats.median(), Duration::from_nanos((count - 1) / 2));
+        assert_eq!(stats.outlier_count(3.0), 0);
+    }
+
+    // A single sample has no dispersion
+    #[test]
+    fn single_sample() {
+        let stats = SampleStats::from_durations(vec![Duration::from_secs(1)]);
+        assert_eq!(stats.mean(), Duration::from_secs(1));
+        assert_eq!(stats.std_dev(), Duration::ZERO);
+        assert!(stats.outliers(3.0).is_empty());
+        assert!(format!("{:?}", stats).contains("median"));
+    }
+
+    // Serialized statistics should have the same fields as the Debug output
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialize() {
+        let stats = from_nanos(&[5, 1, 4, 2, 3, 100]);
+        let json = serde_json::to_value(&stats).unwrap();
+        let to_json = |duration| serde_json::to_value(duration).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "len": 6,
+                "min": to_json(stats.min()),
+                "median": to_json(stats.median()),
+                "mean": to_json(stats.mean()),
+                "p90": to_json(stats.p90()),
+                "p95": to_json(stats.p95()),
+                "p99": to_json(stats.p99()),
+                "max": to_json(stats.max()),
+                "std_dev": to_json(stats.std_dev()),
+                "mad": to_json(stats.median_absolute_deviation()),
+                "samples": serde_json::to_value(stats.samples()).unwrap(),
+            })
+        );
+
+        let empty = serde_json::to_value(SampleStats::from_durations(Vec::new())).unwrap();
+        assert_eq!(empty["len"], 0);
+        assert!(empty["p90"].is_null());
+        assert!(empty["p99"].is_null());
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig {
+            cases: crate::scale_iters(256) as u32,
+            failure_persistence: None,
+            ..ProptestConfig::default()
+        })]
+
+        // Order statistics should be consistent with each other
+        #[test]
+        fn ordered_statistics(nanos in vec(0..1_000_000_000u64, 1..100)) {
+            let stats = from_nanos(&nanos);
+            prop_assert!(stats.min() <= stats.median());
+            prop_assert!(stats.median() <= stats.p90());
+            prop_assert!(stats.p90() <= stats.p95());
+            prop_assert!(stats.p95() <= stats.p99());
+            prop_assert!(stats.p99() <= stats.max());
+            prop_assert!(stats.min() <= stats.mean() && stats.mean() <= stats.max());
+            prop_assert!(stats.median_absolute_deviation() <= stats.max() - stats.min());
+            prop_assert!(stats.outlier_count(3.0) < stats.len());
+        }
+
+        // Merging batches should be equivalent to concatenating them
+        #[test]
+        fn merge_is_concatenation(
+            first in vec(0..1_000u64, 0..50),
+            second in vec(0..1_000u64, 0..50),
+        ) {
+            let mut merged = from_nanos(&first);
+            merged.merge(&from_nanos(&second));
+            let concatenated = first.iter().chain(&second).copied().collect::<Vec<_>>();
+            prop_assert_eq!(merged, from_nanos(&concatenated));
+        }
+    }
+}
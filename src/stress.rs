@@ -0,0 +1,232 @@
+//! Scheduler stress, by running more threads than there are CPUs
+//!
+//! Some concurrency bugs only manifest when a thread gets preempted at an
+//! inopportune time, which rarely happens when every thread has a CPU of its
+//! own. `oversubscribe()` makes preemption frequent by keeping more filler
+//! threads runnable than the system has CPUs while a test body runs.
+//!
+//! ```
+//! use std::sync::atomic::{AtomicUsize, Ordering};
+//! use testbench::stress::{self, Filler};
+//!
+//! let counter = AtomicUsize::new(0);
+//! let result = stress::oversubscribe_with(2, Filler::Yield, || {
+//!     testbench::concurrent_test_2(
+//!         || {
+//!             counter.fetch_add(1, Ordering::Relaxed);
+//!         },
+//!         || {
+//!             counter.fetch_add(1, Ordering::Relaxed);
+//!         },
+//!     );
+//!     counter.load(Ordering::Relaxed)
+//! });
+//! assert_eq!(result, 2);
+//! ```
+//!
+//! This is a specialization of `run_under_contention()`, where antagonists
+//! compete with the test for CPU time rather than for shared data. The
+//! `Contention::oversubscribe()` option provides the same kind of stress with
+//! user-defined antagonists, and statistics about what they did.
+
+use crate::contention::Contention;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use std::{hint, thread, time::Duration};
+
+/// Number of filler iterations between two yields to the OS scheduler
+const ITERATIONS_PER_YIELD: u32 = 64;
+
+/// Number of spin loop hints in an iteration of `Filler::Spin`
+const SPINS_PER_ITERATION: u32 = 1 << 10;
+
+/// Run a test body while surplus filler threads compete for CPU time
+///
+/// `testbench::parallelism()` + `surplus` filler threads are spawned, which
+/// run `filler` in a loop until `body` is done, yielding to the OS scheduler
+/// every few iterations so that the threads of `body` do get to run. `body`
+/// itself runs on the calling thread, and its result is returned.
+///
+/// The filler threads are stopped and joined before this function returns,
+/// even if `body` panics.
+///
+/// # Panics
+///
+/// This function propagates panics from the filler and the body.
+///
+pub fn oversubscribe<R>(surplus: usize, filler: impl Fn() + Sync, body: impl FnOnce() -> R) -> R {
+    let filler = &filler;
+    let (result, _stats) = Contention::new()
+        .antagonists(crate::parallelism().get() + surplus)
+        .run(
+            |_| {
+                let mut iterations = 0u32;
+                move || {
+                    filler();
+                    iterations = iterations.wrapping_add(1);
+                    if iterations % ITERATIONS_PER_YIELD == 0 {
+                        thread::yield_now();
+                    }
+                }
+            },
+            body,
+        );
+    result
+}
+
+/// Variant of `oversubscribe()` that uses a built-in filler
+pub fn oversubscribe_with<R>(surplus: usize, filler: Filler, body: impl FnOnce() -> R) -> R {
+    oversubscribe(surplus, move || filler.run_once(), body)
+}
+
+/// Built-in filler thread behaviors for `oversubscribe_with()`
+#[allow(variant_size_differences)] // Small enough to be passed around by value
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[non_exhaustive]
+pub enum Filler {
+    /// Spin on the CPU, only yielding every now and then
+    ///
+    /// This keeps all CPUs busy, so the threads of the test body only run
+    /// when the OS scheduler preempts a filler thread.
+    ///
+    #[default]
+    Spin,
+
+    /// Yield to the OS scheduler in a loop
+    ///
+    /// This causes a storm of context switches, and puts a lot of pressure
+    /// on the OS scheduler itself.
+    ///
+    Yield,
+
+    /// Sleep for a certain amount of time in a loop
+    ///
+    /// Short sleeps, of the order of tens of microseconds, cause frequent
+    /// wakeups which preempt the threads of the test body at irregular times,
+    /// while leaving them most of the CPU time.
+    ///
+    Sleep(Duration),
+}
+//
+impl Filler {
+    /// Run one iteration of this filler
+    fn run_once(self) {
+        match self {
+            Self::Spin => {
+                for _ in 0..SPINS_PER_ITERATION {
+                    hint::spin_loop();
+                }
+            }
+            Self::Yield => thread::yield_now(),
+            Self::Sleep(duration) => thread::sleep(duration),
+        }
+    }
+}
+
+/// Tests of scheduler stress
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        cell::RefCell,
+        panic,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    /// Number of filler threads which started and exited, and of filler calls
+    #[derive(Default)]
+    struct Counts {
+        started: AtomicUsize,
+        exited: AtomicUsize,
+        calls: AtomicUsize,
+    }
+    //
+    impl Counts {
+        /// Check that no filler runs anymore
+        fn assert_stopped(&self) {
+            let calls = self.calls.load(Ordering::Relaxed);
+            thread::sleep(Duration::from_millis(10));
+            assert_eq!(self.calls.load(Ordering::Relaxed), calls);
+        }
+    }
+
+    /// Counts the exit of a filler thread when dropped
+    struct ExitCounter(Arc<Counts>);
+    //
+    impl Drop for ExitCounter {
+        fn drop(&mut self) {
+            self.0.exited.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    thread_local! {
+        static EXIT_COUNTER: RefCell<Option<ExitCounter>> = const { RefCell::new(None) };
+    }
+
+    /// Filler which counts the threads running it
+    ///
+    /// Thread-local destructors run before a thread can be joined, so once
+    /// `oversubscribe()` has joined the filler threads, the thread counts
+    /// match. This is not the case when the body panics, as the threads are
+    /// then joined by the end of a `thread::scope()`, which only waits for
+    /// them to have run their closure.
+    ///
+    fn counting_filler(counts: &Arc<Counts>) -> impl Fn() + Sync + '_ {
+        move || {
+            EXIT_COUNTER.with(|counter| {
+                counter.borrow_mut().get_or_insert_with(|| {
+                    counts.started.fetch_add(1, Ordering::Relaxed);
+                    ExitCounter(counts.clone())
+                });
+            });
+            counts.calls.fetch_add(1, Ordering::Relaxed);
+            Filler::Yield.run_once()
+        }
+    }
+
+    // The result of the body should be returned, and all fillers joined
+    #[test]
+    fn result_and_join() {
+        let counts = Arc::new(Counts::default());
+        let result = oversubscribe(2, counting_filler(&counts), || {
+            thread::sleep(Duration::from_millis(50));
+            "result".to_owned()
+        });
+        assert_eq!(result, "result");
+        let started = counts.started.load(Ordering::Relaxed);
+        assert!(started > 0 && started <= crate::parallelism().get() + 2);
+        assert_eq!(counts.exited.load(Ordering::Relaxed), started);
+        counts.assert_stopped();
+    }
+
+    // Fillers should be stopped and joined even if the body panics
+    #[test]
+    fn panicking_body() {
+        let counts = Arc::new(Counts::default());
+        let result = panic::catch_unwind(|| {
+            oversubscribe(1, counting_filler(&counts), || {
+                thread::sleep(Duration::from_millis(50));
+                panic!("Expected")
+            })
+        });
+        assert!(result.is_err());
+        assert!(counts.started.load(Ordering::Relaxed) > 0);
+        counts.assert_stopped();
+    }
+
+    // All built-in fillers should let the body run to completion
+    #[test]
+    fn builtin_fillers() {
+        for &filler in &[
+            Filler::Spin,
+            Filler::Yield,
+            Filler::Sleep(Duration::from_micros(50)),
+        ] {
+            assert_eq!(oversubscribe_with(1, filler, || 42), 42);
+        }
+    }
+}
@@ -0,0 +1,311 @@
+//! Running test scenarios in a subprocess
+//!
+//! Some behaviors cannot be tested within the test process, because they end
+//! it: a watchdog aborting the process, a crash, a stack overflow... This
+//! module runs such scenarios in a fresh copy of the current test binary, and
+//! reports its output and exit status so that the parent test can check them.
+//!
+//! Scenarios are declared with the `scenario_registry!` macro, which must be
+//! invoked once per test binary, with all the scenarios of that binary:
+//!
+//! ```
+//! use std::time::Duration;
+//! use testbench::subprocess;
+//!
+//! testbench::scenario_registry! {
+//!     "greeting" => || println!("Hello from a subprocess"),
+//!     "crash" => || panic!("Something went wrong"),
+//! }
+//!
+//! // This should be called from a test
+//! fn check_crash_is_reported() {
+//!     let outcome = subprocess::run_scenario("crash", Duration::from_secs(10));
+//!     assert!(!outcome.success());
+//!     assert!(outcome.stderr.contains("Something went wrong"));
+//! }
+//! ```
+//!
+//! `run_scenario()` re-executes the test binary with the `TESTBENCH_SCENARIO`
+//! environment variable set to the name of the scenario, and with command-line
+//! arguments that make the libtest harness only run a hook test which the
+//! macro generates. That hook runs the scenario, then exits the process.
+//!
+//! Test binaries which do not use the libtest harness (`harness = false`) must
+//! instead call `dispatch()` at the start of their `main()` function, with the
+//! list of their scenarios. The arguments that `run_scenario()` passes to the
+//! subprocess are meant for libtest, and should be ignored by such binaries.
+//!
+//! Scenarios run in a child process, so Miri does not support this module.
+
+use std::{
+    env,
+    io::{self, Read, Write},
+    panic,
+    process::{self, Child, Command, ExitStatus, Stdio},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// Environment variable which selects the scenario to be run by `dispatch()`
+pub const SCENARIO_VAR: &str = "TESTBENCH_SCENARIO";
+
+/// Name of the hook test generated by `scenario_registry!`
+#[doc(hidden)]
+pub const HOOK_TEST_NAME: &str = "testbench_scenario_dispatch";
+
+/// Exit code of subprocesses whose scenario panicked
+///
+/// This is the exit code of Rust programs whose main thread panics.
+///
+pub const PANIC_EXIT_CODE: i32 = 101;
+
+/// Exit code of subprocesses which were asked to run an unknown scenario
+pub const UNKNOWN_SCENARIO_EXIT_CODE: i32 = 102;
+
+/// Declare the scenarios that `run_scenario()` can run in this test binary
+///
+/// The macro takes a list of `"name" => function` pairs, where each function
+/// is a `fn()` or a closure which does not capture anything. It generates a
+/// hook test, which does nothing in normal test runs, and runs the scenario
+/// selected by `run_scenario()` in subprocesses. See the `subprocess` module
+/// for more information.
+///
+#[macro_export]
+macro_rules! scenario_registry {
+    ($($name:expr => $scenario:expr),* $(,)?) => {
+        #[test]
+        fn testbench_scenario_dispatch() {
+            let scenarios: &[(&str, fn())] = &[$(($name, $scenario)),*];
+            $crate::subprocess::dispatch(scenarios);
+        }
+    };
+}
+
+/// Run the scenario selected by `run_scenario()`, if any, then exit
+///
+/// If the `TESTBENCH_SCENARIO` environment variable is not set, this function
+/// does nothing, so that it can be called unconditionally. Otherwise, it runs
+/// the scenario of that name, then exits the process with code 0 if the
+/// scenario succeeded, `PANIC_EXIT_CODE` if it panicked, and
+/// `UNKNOWN_SCENARIO_EXIT_CODE` if there is no scenario of that name.
+///
+/// `scenario_registry!` calls this function from a hook test. Test binaries
+/// that do not use the libtest harness must call it from `main()`.
+///
+pub fn dispatch(scenarios: &[(&str, fn())]) {
+    let name = match env::var(SCENARIO_VAR) {
+        Ok(name) => name,
+        Err(_) => return,
+    };
+    let code = match scenarios.iter().find(|(known, _)| *known == name) {
+        Some((_, scenario)) => match panic::catch_unwind(scenario) {
+            Ok(()) => 0,
+            Err(_) => PANIC_EXIT_CODE,
+        },
+        None => {
+            eprintln!("Unknown test scenario {:?}", name);
+            UNKNOWN_SCENARIO_EXIT_CODE
+        }
+    };
+    let _ = io::stdout().flush();
+    process::exit(code);
+}
+
+/// Run a scenario in a subprocess, killing it if it exceeds a timeout
+///
+/// The scenario must have been declared with `scenario_registry!`, or passed
+/// to `dispatch()`. Since the libtest harness may run in the subprocess, the
+/// output of the scenario may be surrounded by the harness' own output.
+///
+/// # Panics
+///
+/// This function panics if the subprocess cannot be started, which is always
+/// the case under Miri.
+///
+pub fn run_scenario(name: &str, timeout: Duration) -> ScenarioOutcome {
+    let start = Instant::now();
+    let mut child = Command::new(env::current_exe().expect("Failed to locate the test binary"))
+        .args([HOOK_TEST_NAME, "--nocapture", "--test-threads=1", "--quiet"])
+        .env(SCENARIO_VAR, name)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start the scenario subprocess");
+    let stdout = collect_output(child.stdout.take());
+    let stderr = collect_output(child.stderr.take());
+    let (status, timed_out) = wait_or_kill(&mut child, start + timeout);
+    ScenarioOutcome {
+        status,
+        timed_out,
+        stdout: stdout.join().expect("Output collection should not panic"),
+        stderr: stderr.join().expect("Output collection should not panic"),
+        duration: start.elapsed(),
+    }
+}
+
+/// Outcome of a scenario run by `run_scenario()`
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct ScenarioOutcome {
+    /// Exit status of the subprocess
+    pub status: ExitStatus,
+
+    /// Truth that the subprocess exceeded its timeout and was killed
+    pub timed_out: bool,
+
+    /// Standard output of the subprocess, with invalid UTF-8 replaced
+    pub stdout: String,
+
+    /// Standard error of the subprocess, with invalid UTF-8 replaced
+    pub stderr: String,
+
+    /// Time from the start of the subprocess to its exit
+    pub duration: Duration,
+}
+//
+impl ScenarioOutcome {
+    /// Truth that the scenario ran to completion without panicking
+    pub fn success(&self) -> bool {
+        self.status.success() && !self.timed_out
+    }
+
+    /// Truth that the scenario panicked without crashing the process
+    pub fn panicked(&self) -> bool {
+        self.exit_code() == Some(PANIC_EXIT_CODE)
+    }
+
+    /// Exit code of the subprocess, if it exited normally
+    pub fn exit_code(&self) -> Option<i32> {
+        self.status.code()
+    }
+
+    /// Signal which killed the subprocess, if any
+    ///
+    /// Only Unix reports signals, so this is always `None` on other systems.
+    /// Scenarios which are killed because they exceeded their timeout report
+    /// `SIGKILL`.
+    ///
+    pub fn signal(&self) -> Option<i32> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            self.status.signal()
+        }
+        #[cfg(not(unix))]
+        {
+            None
+        }
+    }
+}
+
+/// Read a subprocess output stream to completion on a background thread
+///
+/// This must happen while waiting for the subprocess, otherwise it could
+/// block forever after filling the pipe buffer.
+///
+fn collect_output(stream: Option<impl Read + Send + 'static>) -> JoinHandle<String> {
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        if let Some(mut stream) = stream {
+            // Errors can only come from the subprocess dying, which will be
+            // reported by its exit status, so partial output is fine.
+            let _ = stream.read_to_end(&mut output);
+        }
+        String::from_utf8_lossy(&output).into_owned()
+    })
+}
+
+/// Wait for a subprocess to exit, killing it if the deadline passes
+///
+/// Returns the exit status, and the truth that the subprocess was killed.
+///
+fn wait_or_kill(child: &mut Child, deadline: Instant) -> (ExitStatus, bool) {
+    let mut poll_interval = Duration::from_millis(1);
+    loop {
+        if let Some(status) = child.try_wait().expect("Failed to poll the subprocess") {
+            return (status, false);
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            // This can only fail if the subprocess already exited
+            let _ = child.kill();
+            let status = child.wait().expect("Failed to wait for the subprocess");
+            return (status, true);
+        }
+        thread::sleep(poll_interval.min(deadline - now));
+        poll_interval = (poll_interval * 2).min(Duration::from_millis(50));
+    }
+}
+
+/// Tests of subprocess scenarios
+///
+/// Miri does not support starting subprocesses, so none of these can run there.
+///
+#[cfg(all(test, not(miri)))]
+mod tests {
+    use super::*;
+    use crate::watchdog::Watchdog;
+
+    crate::scenario_registry! {
+        "pass" => || println!("Scenario output"),
+        "panic" => || panic!("Scenario failure"),
+        "hang" => || thread::sleep(Duration::from_secs(60)),
+        "watchdog" => || {
+            Watchdog::new(Duration::from_millis(10))
+                .grace_period(Duration::from_millis(10))
+                .run(|| thread::sleep(Duration::from_secs(60)))
+        },
+    }
+
+    /// Timeout for scenarios that are not expected to hang
+    const TIMEOUT: Duration = Duration::from_secs(60);
+
+    // Successful scenarios should report their output
+    #[test]
+    fn pass() {
+        let outcome = run_scenario("pass", TIMEOUT);
+        assert!(outcome.success(), "{:?}", outcome);
+        assert_eq!(outcome.exit_code(), Some(0));
+        assert!(!outcome.panicked() && !outcome.timed_out);
+        assert!(outcome.stdout.contains("Scenario output"));
+    }
+
+    // Panicking scenarios should be reported with their panic message
+    #[test]
+    fn panic() {
+        let outcome = run_scenario("panic", TIMEOUT);
+        assert!(!outcome.success());
+        assert!(outcome.panicked(), "{:?}", outcome);
+        assert!(outcome.stderr.contains("Scenario failure"));
+    }
+
+    // Hanging scenarios should be killed once they exceed their timeout
+    #[test]
+    fn timeout() {
+        let outcome = run_scenario("hang", Duration::from_millis(200));
+        assert!(outcome.timed_out, "{:?}", outcome);
+        assert!(!outcome.success());
+        assert!(outcome.duration < Duration::from_secs(30));
+        #[cfg(unix)]
+        assert_eq!(outcome.signal(), Some(9));
+    }
+
+    // The watchdog should abort the process when the grace period expires
+    #[test]
+    fn watchdog_abort() {
+        let outcome = run_scenario("watchdog", TIMEOUT);
+        assert!(!outcome.success() && !outcome.panicked() && !outcome.timed_out);
+        assert!(outcome.stderr.contains("deadline"), "{:?}", outcome);
+        #[cfg(unix)]
+        assert_eq!(outcome.signal(), Some(6));
+    }
+
+    // Unknown scenarios should be reported as such
+    #[test]
+    fn unknown() {
+        let outcome = run_scenario("nonexistent", TIMEOUT);
+        assert_eq!(outcome.exit_code(), Some(UNKNOWN_SCENARIO_EXIT_CODE));
+        assert!(outcome.stderr.contains("nonexistent"));
+    }
+}
@@ -0,0 +1,1093 @@
+//! Synchronization primitives for concurrent tests and benchmarks
+//!
+//! The synchronization primitives of the standard library put waiting threads
+//! to sleep, and waking them up takes a few microseconds. This is fine for
+//! most purposes, but it dominates the duration of short benchmark phases and
+//! lets threads start the next phase at noticeably different times. The
+//! primitives from this module busy-wait instead, trading CPU time for release
+//! latency.
+//!
+//! The exceptions are `TimeoutBarrier`, which trades release latency for the
+//! ability to give up waiting when a participating thread never shows up, and
+//! the `Latch` and `CountdownLatch` one-shot events, which threads may wait for
+//! during a long time.
+
+use std::{
+    error::Error,
+    fmt, hint,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Condvar, Mutex, MutexGuard, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Barrier which busy-waits for all participating threads to arrive
+///
+/// This works like `std::sync::Barrier`, but waiting threads spin instead of
+/// going to sleep, so that they are released as closely together as possible.
+/// The barrier can be reused for any number of rounds.
+///
+/// Spinning is counterproductive when there are more participating threads
+/// than CPUs, as a spinning thread can then prevent the thread that it waits
+/// for from running. Therefore, by default, waiting threads start yielding to
+/// the OS scheduler after spinning for a while. See `spin_budget()`.
+///
+/// # Panics
+///
+/// Waiting on the barrier does not panic, and a participating thread which
+/// panics before reaching the barrier does not corrupt its state for others.
+/// However, like with `std::sync::Barrier`, the other threads will then wait
+/// forever for the missing thread to arrive. Tests which need to survive this
+/// should use a timeout.
+///
+#[derive(Debug)]
+pub struct SpinBarrier {
+    /// Number of threads that must arrive before the barrier is released
+    participants: usize,
+
+    /// Number of threads which arrived during the current round
+    arrived: AtomicUsize,
+
+    /// Number of rounds which have completed, modulo `usize::MAX + 1`
+    generation: AtomicUsize,
+
+    /// Number of times a waiting thread spins before it starts yielding
+    spin_budget: Option<u32>,
+}
+//
+impl SpinBarrier {
+    /// Default number of spins before waiting threads start yielding
+    pub const DEFAULT_SPIN_BUDGET: u32 = 1 << 12;
+
+    /// Create a barrier that waits for `participants` threads
+    ///
+    /// As with `std::sync::Barrier`, a barrier for zero participants behaves
+    /// like a barrier for one participant: `wait()` returns immediately.
+    ///
+    pub fn new(participants: usize) -> Self {
+        Self {
+            participants: participants.max(1),
+            arrived: AtomicUsize::new(0),
+            generation: AtomicUsize::new(0),
+            spin_budget: Some(Self::DEFAULT_SPIN_BUDGET),
+        }
+    }
+
+    /// Set how many times waiting threads spin before they start yielding
+    ///
+    /// `None` means that waiting threads spin forever, which minimizes release
+    /// latency when every participating thread has a CPU to itself, but can
+    /// slow things down dramatically otherwise. `Some(0)` means that waiting
+    /// threads yield right away. The default is `DEFAULT_SPIN_BUDGET`.
+    ///
+    pub fn spin_budget(mut self, spins: Option<u32>) -> Self {
+        self.spin_budget = spins;
+        self
+    }
+
+    /// Number of threads that the barrier waits for
+    pub fn participants(&self) -> usize {
+        self.participants
+    }
+
+    /// Wait for all participating threads to reach the barrier
+    ///
+    /// Like `std::sync::BarrierWaitResult::is_leader()`, the result is true for
+    /// exactly one thread per round, namely the last one to arrive.
+    ///
+    pub fn wait(&self) -> bool {
+        // The generation cannot change until this thread has arrived, so
+        // reading it first tells which round this thread participates in.
+        let generation = self.generation.load(Ordering::Acquire);
+        let arrived = self.arrived.fetch_add(1, Ordering::AcqRel) + 1;
+        if arrived == self.participants {
+            // Reset the arrival count before releasing the other threads, so
+            // that they see it zeroed if they come back for the next round.
+            self.arrived.store(0, Ordering::Relaxed);
+            self.generation
+                .store(generation.wrapping_add(1), Ordering::Release);
+            return true;
+        }
+        wait_for_change(&self.generation, generation, self.spin_budget);
+        false
+    }
+}
+
+/// Number of a phase of a `Phaser`, starting from 0
+///
+/// Phase numbers wrap around after `usize::MAX`.
+///
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct PhaseNumber(usize);
+//
+impl PhaseNumber {
+    /// Numerical value of the phase number
+    pub fn get(self) -> usize {
+        self.0
+    }
+}
+//
+impl From<PhaseNumber> for usize {
+    fn from(phase: PhaseNumber) -> Self {
+        phase.get()
+    }
+}
+//
+impl fmt::Display for PhaseNumber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "phase {}", self.0)
+    }
+}
+
+/// Hook which a `Phaser` runs at the end of each phase
+type PhaseHook<'hook> = Box<dyn FnMut(PhaseNumber) + Send + 'hook>;
+
+/// Barrier for multi-phase tests with a varying number of participants
+///
+/// Multi-phase concurrent tests have all participating threads (or "parties")
+/// do one step of work, synchronize, do the next step, synchronize again, and
+/// so on. A `Phaser` is a `SpinBarrier` with extra features for this use case:
+///
+/// - Phases are numbered, see `PhaseNumber`.
+/// - Parties can join with `register()`, and leave with `deregister()` when
+///   they are done, without blocking the remaining parties.
+/// - A hook set with `on_phase_complete()` can check the outcome of each
+///   phase, single-threadedly, before the parties move on to the next one.
+///
+/// It is meant to be shared by reference between the closures of
+/// `concurrent_test_2()`, `concurrent_test_3()` or scoped threads.
+///
+/// # Panics
+///
+/// If a party panics before arriving, the other parties wait forever, as with
+/// `SpinBarrier`. A party which can panic should deregister in that case.
+///
+/// If the phase completion hook panics, the phase still completes, and the
+/// panic is propagated to the party which ran the hook.
+///
+pub struct Phaser<'hook> {
+    /// Party bookkeeping and phase completion hook
+    state: Mutex<PhaserState<'hook>>,
+
+    /// Number of the current phase, which waiting parties spin on
+    phase: AtomicUsize,
+
+    /// Number of times a waiting party spins before it starts yielding
+    spin_budget: Option<u32>,
+}
+//
+/// Mutable state of a `Phaser`
+struct PhaserState<'hook> {
+    /// Number of the current phase
+    phase: usize,
+
+    /// Number of parties which take part in the current phase
+    registered: usize,
+
+    /// Number of parties which arrived at the end of the current phase
+    arrived: usize,
+
+    /// Hook to be run at the end of each phase
+    hook: Option<PhaseHook<'hook>>,
+}
+//
+impl<'hook> Phaser<'hook> {
+    /// Create a phaser with a certain number of initially registered parties
+    pub fn new(parties: usize) -> Self {
+        Self {
+            state: Mutex::new(PhaserState {
+                phase: 0,
+                registered: parties,
+                arrived: 0,
+                hook: None,
+            }),
+            phase: AtomicUsize::new(0),
+            spin_budget: Some(SpinBarrier::DEFAULT_SPIN_BUDGET),
+        }
+    }
+
+    /// Run a hook at the end of each phase, before the parties are released
+    ///
+    /// The hook is run by the last party to arrive, or by the party whose
+    /// deregistration completed the phase, exactly once per phase. It receives
+    /// the number of the phase which is being completed.
+    ///
+    /// The hook must not call methods of the `Phaser`, as this would deadlock.
+    ///
+    pub fn on_phase_complete(mut self, hook: impl FnMut(PhaseNumber) + Send + 'hook) -> Self {
+        self.state_mut().hook = Some(Box::new(hook));
+        self
+    }
+
+    /// Set how many times waiting parties spin before they start yielding
+    ///
+    /// This works like `SpinBarrier::spin_budget()`, with the same default.
+    ///
+    pub fn spin_budget(mut self, spins: Option<u32>) -> Self {
+        self.spin_budget = spins;
+        self
+    }
+
+    /// Number of the current phase
+    pub fn phase(&self) -> PhaseNumber {
+        PhaseNumber(self.phase.load(Ordering::Acquire))
+    }
+
+    /// Number of registered parties
+    pub fn registered(&self) -> usize {
+        self.lock().registered
+    }
+
+    /// Register a new party, which takes part in the current phase
+    pub fn register(&self) -> PhaseNumber {
+        let mut state = self.lock();
+        state.registered += 1;
+        PhaseNumber(state.phase)
+    }
+
+    /// Deregister a party, which does not need to arrive anymore
+    ///
+    /// If all other parties have already arrived at the end of the current
+    /// phase, this completes the phase, which includes running the phase
+    /// completion hook on this thread.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if no party is registered.
+    ///
+    pub fn deregister(&self) {
+        let mut state = self.lock();
+        assert!(state.registered > state.arrived, "No party is registered");
+        state.registered -= 1;
+        if state.arrived > 0 && state.arrived == state.registered {
+            self.complete_phase(state);
+        }
+    }
+
+    /// Arrive at the end of the current phase and wait for other parties
+    ///
+    /// The result is the number of the phase which completed. The last party
+    /// to arrive runs the phase completion hook before others are released.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if more parties arrive than are registered.
+    ///
+    pub fn arrive_and_wait(&self) -> PhaseNumber {
+        let mut state = self.lock();
+        assert!(
+            state.arrived < state.registered,
+            "More parties arrived than were registered"
+        );
+        state.arrived += 1;
+        let phase = state.phase;
+        if state.arrived == state.registered {
+            self.complete_phase(state);
+        } else {
+            drop(state);
+            wait_for_change(&self.phase, phase, self.spin_budget);
+        }
+        PhaseNumber(phase)
+    }
+
+    /// Run the phase completion hook, then release parties into the next phase
+    fn complete_phase(&self, mut state: MutexGuard<'_, PhaserState<'hook>>) {
+        let phase = state.phase;
+        let result = match &mut state.hook {
+            Some(hook) => panic::catch_unwind(AssertUnwindSafe(|| hook(PhaseNumber(phase)))),
+            None => Ok(()),
+        };
+        state.arrived = 0;
+        state.phase = phase.wrapping_add(1);
+        self.phase.store(state.phase, Ordering::Release);
+        drop(state);
+        if let Err(payload) = result {
+            panic::resume_unwind(payload);
+        }
+    }
+
+    /// Access the state, ignoring poisoning as it is never left inconsistent
+    fn lock(&self) -> MutexGuard<'_, PhaserState<'hook>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Exclusively access the state, ignoring poisoning
+    fn state_mut(&mut self) -> &mut PhaserState<'hook> {
+        self.state.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+//
+impl fmt::Debug for Phaser<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.lock();
+        f.debug_struct("Phaser")
+            .field("phase", &PhaseNumber(state.phase))
+            .field("registered", &state.registered)
+            .field("arrived", &state.arrived)
+            .field("has_hook", &state.hook.is_some())
+            .field("spin_budget", &self.spin_budget)
+            .finish()
+    }
+}
+
+/// Barrier whose waiting threads give up after a timeout
+///
+/// This works like `std::sync::Barrier`, except that `wait_timeout()` returns
+/// an error if the other participating threads do not all arrive in time. This
+/// keeps a concurrent test from hanging forever when one of its threads panics
+/// or deadlocks before reaching the barrier. Unlike `SpinBarrier`, waiting
+/// threads go to sleep, as a barrier that may wait for a long time should not
+/// burn CPU time.
+///
+/// # Broken barriers
+///
+/// When a waiting thread times out, the barrier becomes broken, and stays so
+/// forever. This mimics Java's `CyclicBarrier`, and is needed for the other
+/// threads to notice that a round can no longer complete:
+///
+/// - The thread that timed out returns a `BarrierTimeout` whose `timed_out`
+///   field is true.
+/// - All threads that were waiting for the same round, whatever their own
+///   timeout, are woken up and return a `BarrierTimeout` whose `timed_out`
+///   field is false.
+/// - All later calls to `wait()` and `wait_timeout()` return the same error
+///   right away, without waiting.
+///
+/// Threads from a round that completed before the barrier broke are not
+/// affected, even if they have not returned from `wait_timeout()` yet. As long
+/// as no thread times out, the barrier can be reused for any number of rounds.
+///
+#[derive(Debug)]
+pub struct TimeoutBarrier {
+    /// Number of threads that must arrive before the barrier is released
+    participants: usize,
+
+    /// Progress of the current round
+    state: Mutex<TimeoutBarrierState>,
+
+    /// Signaled when a round completes or the barrier breaks
+    condvar: Condvar,
+}
+//
+/// Mutable state of a `TimeoutBarrier`
+#[derive(Debug, Default)]
+struct TimeoutBarrierState {
+    /// Number of threads which arrived during the current round
+    arrived: usize,
+
+    /// Number of rounds which have completed, modulo `usize::MAX + 1`
+    generation: usize,
+
+    /// Truth that a waiting thread timed out
+    broken: bool,
+}
+//
+impl TimeoutBarrier {
+    /// Create a barrier that waits for `participants` threads
+    ///
+    /// As with `std::sync::Barrier`, a barrier for zero participants behaves
+    /// like a barrier for one participant: waiting returns immediately.
+    ///
+    pub fn new(participants: usize) -> Self {
+        Self {
+            participants: participants.max(1),
+            state: Mutex::new(TimeoutBarrierState::default()),
+            condvar: Condvar::new(),
+        }
+    }
+
+    /// Number of threads that the barrier waits for
+    pub fn participants(&self) -> usize {
+        self.participants
+    }
+
+    /// Truth that a waiting thread timed out, see the type-level docs
+    pub fn is_broken(&self) -> bool {
+        self.lock().broken
+    }
+
+    /// Wait for all participating threads to reach the barrier, without a
+    /// timeout of its own
+    ///
+    /// This can still return early if another thread times out.
+    ///
+    /// # Errors
+    ///
+    /// This function fails if the barrier is or becomes broken before all
+    /// participating threads arrive.
+    ///
+    pub fn wait(&self) -> Result<BarrierWaitOutcome, BarrierTimeout> {
+        self.wait_until(None)
+    }
+
+    /// Wait for all participating threads to reach the barrier, for at most
+    /// `timeout`
+    ///
+    /// # Errors
+    ///
+    /// This function fails if the barrier is or becomes broken before all
+    /// participating threads arrive, including because `timeout` elapsed.
+    ///
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<BarrierWaitOutcome, BarrierTimeout> {
+        // A deadline too far away to be represented amounts to no deadline
+        self.wait_until(Instant::now().checked_add(timeout))
+    }
+
+    /// Wait for all participating threads to reach the barrier, giving up at
+    /// the deadline if there is one
+    fn wait_until(&self, deadline: Option<Instant>) -> Result<BarrierWaitOutcome, BarrierTimeout> {
+        let mut state = self.lock();
+        if state.broken {
+            return Err(BarrierTimeout { timed_out: false });
+        }
+        let generation = state.generation;
+        state.arrived += 1;
+        if state.arrived == self.participants {
+            state.arrived = 0;
+            state.generation = generation.wrapping_add(1);
+            self.condvar.notify_all();
+            return Ok(BarrierWaitOutcome { is_leader: true });
+        }
+        loop {
+            // Rounds that completed stay completed, even if the barrier broke
+            // before this thread got to notice.
+            if state.generation != generation {
+                return Ok(BarrierWaitOutcome { is_leader: false });
+            }
+            if state.broken {
+                return Err(BarrierTimeout { timed_out: false });
+            }
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        state.broken = true;
+                        self.condvar.notify_all();
+                        return Err(BarrierTimeout { timed_out: true });
+                    }
+                    self.condvar
+                        .wait_timeout(state, deadline - now)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+                None => self
+                    .condvar
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner),
+            };
+        }
+    }
+
+    /// Access the state, ignoring poisoning as it is never left inconsistent
+    fn lock(&self) -> MutexGuard<'_, TimeoutBarrierState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Successful outcome of waiting on a `TimeoutBarrier`
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct BarrierWaitOutcome {
+    /// Truth that this thread was the last one to arrive
+    is_leader: bool,
+}
+//
+impl BarrierWaitOutcome {
+    /// Truth that this thread was the last one to arrive
+    ///
+    /// Like `std::sync::BarrierWaitResult::is_leader()`, this is true for
+    /// exactly one thread per round.
+    ///
+    pub fn is_leader(&self) -> bool {
+        self.is_leader
+    }
+}
+
+/// Error returned when a `TimeoutBarrier` is broken
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct BarrierTimeout {
+    /// Truth that the timeout of this thread broke the barrier
+    ///
+    /// This is false when the barrier was broken by another thread, before or
+    /// while this thread was waiting.
+    ///
+    pub timed_out: bool,
+}
+//
+impl fmt::Display for BarrierTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.timed_out {
+            write!(f, "timed out waiting for other threads at a barrier")
+        } else {
+            write!(f, "barrier was broken by another thread's timeout")
+        }
+    }
+}
+//
+impl Error for BarrierTimeout {}
+
+/// One-shot event which threads can wait for
+///
+/// A latch starts out unsignaled. Threads which `wait()` on it block until
+/// another thread calls `signal()`, after which the latch stays signaled
+/// forever and waiting returns immediately. This is the "thread B waits until
+/// thread A says go" pattern of many concurrent tests.
+///
+/// Checking a signaled latch only takes an atomic load, while waiting threads
+/// go to sleep instead of burning CPU time. Signaling happens-before the
+/// return of every `wait()`, so data written before `signal()` can be read
+/// after `wait()` without further synchronization.
+///
+/// This is a `CountdownLatch` with a count of one.
+///
+#[derive(Debug)]
+pub struct Latch(CountdownLatch);
+//
+impl Latch {
+    /// Create an unsignaled latch
+    pub fn new() -> Self {
+        Self(CountdownLatch::new(1))
+    }
+
+    /// Signal the latch, releasing all current and future waiters
+    ///
+    /// Signaling a latch which is already signaled does nothing.
+    ///
+    pub fn signal(&self) {
+        self.0.count_down();
+    }
+
+    /// Truth that the latch was signaled
+    pub fn is_signaled(&self) -> bool {
+        self.0.is_released()
+    }
+
+    /// Wait for the latch to be signaled
+    pub fn wait(&self) {
+        self.0.wait();
+    }
+
+    /// Wait for the latch to be signaled, for at most `timeout`
+    ///
+    /// Returns the truth that the latch was signaled.
+    ///
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        self.0.wait_timeout(timeout)
+    }
+}
+//
+impl Default for Latch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Latch which is released after a certain number of signals
+///
+/// This works like `Latch`, except that `count_down()` must be called a
+/// certain number of times before waiting threads are released, which is
+/// convenient when a thread must wait for several others to reach a certain
+/// point. Unlike with a barrier, the threads which count down do not wait.
+///
+#[derive(Debug)]
+pub struct CountdownLatch {
+    /// Number of `count_down()` calls left before the latch is released
+    remaining: AtomicUsize,
+
+    /// Mutex which waiting threads hold while checking `remaining`
+    mutex: Mutex<()>,
+
+    /// Signaled when the latch is released
+    condvar: Condvar,
+}
+//
+impl CountdownLatch {
+    /// Create a latch which is released after `count` calls to `count_down()`
+    ///
+    /// A latch with a count of zero is released from the start.
+    ///
+    pub fn new(count: usize) -> Self {
+        Self {
+            remaining: AtomicUsize::new(count),
+            mutex: Mutex::new(()),
+            condvar: Condvar::new(),
+        }
+    }
+
+    /// Decrement the count, releasing waiting threads once it reaches zero
+    ///
+    /// Counting down a latch which is already released does nothing.
+    ///
+    pub fn count_down(&self) {
+        let previous =
+            self.remaining
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |remaining| {
+                    remaining.checked_sub(1)
+                });
+        if previous == Ok(1) {
+            // Waiting threads check the count with the mutex held, and keep
+            // holding it until they sleep on the condvar, so locking it here
+            // ensures that they either saw the count reach zero, or are asleep
+            // and will receive the notification.
+            drop(self.lock());
+            self.condvar.notify_all();
+        }
+    }
+
+    /// Number of `count_down()` calls left before the latch is released
+    pub fn count(&self) -> usize {
+        self.remaining.load(Ordering::Acquire)
+    }
+
+    /// Truth that the count reached zero
+    pub fn is_released(&self) -> bool {
+        self.count() == 0
+    }
+
+    /// Wait for the count to reach zero
+    pub fn wait(&self) {
+        if self.is_released() {
+            return;
+        }
+        let mut guard = self.lock();
+        while !self.is_released() {
+            guard = self
+                .condvar
+                .wait(guard)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Wait for the count to reach zero, for at most `timeout`
+    ///
+    /// Returns the truth that the latch was released.
+    ///
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        if self.is_released() {
+            return true;
+        }
+        // A deadline too far away to be represented amounts to no deadline
+        let deadline = match Instant::now().checked_add(timeout) {
+            Some(deadline) => deadline,
+            None => {
+                self.wait();
+                return true;
+            }
+        };
+        let mut guard = self.lock();
+        while !self.is_released() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            guard = self
+                .condvar
+                .wait_timeout(guard, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+        true
+    }
+
+    /// Lock the mutex, ignoring poisoning as it protects no data
+    fn lock(&self) -> MutexGuard<'_, ()> {
+        self.mutex.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Wait for an atomic variable to change from a certain value
+///
+/// Spins `spin_budget` times, or forever if `None`, then starts yielding.
+///
+fn wait_for_change(atomic: &AtomicUsize, value: usize, spin_budget: Option<u32>) {
+    let mut spins = 0;
+    while atomic.load(Ordering::Acquire) == value {
+        if spin_budget.map_or(true, |budget| spins < budget) {
+            hint::spin_loop();
+            spins += 1;
+        } else {
+            thread::yield_now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::TestRng;
+    use std::sync::atomic::AtomicBool;
+
+    // Synchronization primitives should be shareable between threads
+    #[test]
+    fn auto_traits() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SpinBarrier>();
+        assert_send_sync::<Phaser<'_>>();
+        assert_send_sync::<TimeoutBarrier>();
+        assert_send_sync::<Latch>();
+        assert_send_sync::<CountdownLatch>();
+    }
+
+    // A barrier with a single participant should never block
+    #[test]
+    fn single_participant() {
+        for participants in [0, 1] {
+            let barrier = SpinBarrier::new(participants);
+            assert_eq!(barrier.participants(), 1);
+            for _ in 0..3 {
+                assert!(barrier.wait());
+            }
+        }
+    }
+
+    // Nobody should be released before every participant has arrived, and
+    // exactly one participant should be the leader
+    #[test]
+    fn release_after_arrival() {
+        let barrier = SpinBarrier::new(2);
+        let arrived = AtomicBool::new(false);
+        let leaders = AtomicUsize::new(0);
+        crate::concurrent_test_2(
+            || {
+                thread::sleep(Duration::from_millis(10));
+                arrived.store(true, Ordering::Relaxed);
+                if barrier.wait() {
+                    leaders.fetch_add(1, Ordering::Relaxed);
+                }
+            },
+            || {
+                if barrier.wait() {
+                    leaders.fetch_add(1, Ordering::Relaxed);
+                }
+                assert!(arrived.load(Ordering::Relaxed));
+            },
+        );
+        assert_eq!(leaders.load(Ordering::Relaxed), 1);
+    }
+
+    /// Run many rounds of a barrier, checking that no thread ever gets ahead
+    /// of the others and that each round has exactly one leader
+    fn check_rounds(barrier: SpinBarrier, rounds: usize) {
+        let threads = barrier.participants();
+        let arrivals = AtomicUsize::new(0);
+        let leaders = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..threads {
+                s.spawn(|| {
+                    for round in 0..rounds {
+                        arrivals.fetch_add(1, Ordering::Relaxed);
+                        if barrier.wait() {
+                            leaders.fetch_add(1, Ordering::Relaxed);
+                        }
+                        // Everyone arrived at this round, and nobody can have
+                        // arrived past the next one.
+                        let arrivals = arrivals.load(Ordering::Relaxed);
+                        assert!(arrivals >= (round + 1) * threads);
+                        assert!(arrivals <= (round + 2) * threads);
+                    }
+                });
+            }
+        });
+        assert_eq!(arrivals.load(Ordering::Relaxed), rounds * threads);
+        assert_eq!(leaders.load(Ordering::Relaxed), rounds);
+    }
+
+    // Barriers should be reusable for many rounds, whether waiting threads
+    // yield or not
+    #[test]
+    fn rounds() {
+        check_rounds(SpinBarrier::new(4), 1_000);
+        check_rounds(SpinBarrier::new(3).spin_budget(Some(0)), 1_000);
+    }
+
+    // Barriers should also work when waiting threads never yield
+    #[test]
+    fn rounds_without_yielding() {
+        let threads = crate::parallelism().get().clamp(2, 4);
+        check_rounds(SpinBarrier::new(threads).spin_budget(None), 100);
+    }
+
+    /// Check barrier correctness with many threads and rounds
+    ///
+    /// Oversubscribing the CPUs makes it more likely that a thread gets
+    /// preempted at an inopportune point of `wait()`, so this test spawns more
+    /// threads than the system can run in parallel.
+    ///
+    #[test]
+    #[ignore]
+    fn stress() {
+        let threads = 2 * crate::parallelism().get() + 1;
+        let rounds = crate::scale_iters(100_000);
+        check_rounds(SpinBarrier::new(threads), rounds);
+        check_rounds(SpinBarrier::new(threads).spin_budget(Some(0)), rounds);
+    }
+
+    // Parties should go through phases in lockstep, with the completion hook
+    // running once per phase
+    #[test]
+    fn phaser_phases() {
+        const PHASES: usize = 4;
+        let progress = [0, 1, 2].map(|_| AtomicUsize::new(0));
+        let completed = Mutex::new(Vec::new());
+        let phaser = Phaser::new(3).on_phase_complete(|phase| {
+            // Every party has finished this phase, and none started the next
+            for party in &progress {
+                assert_eq!(party.load(Ordering::Relaxed), phase.get() + 1);
+            }
+            completed.lock().unwrap().push(phase);
+        });
+        let party = |idx: usize| {
+            let progress = &progress[idx];
+            let phaser = &phaser;
+            move || {
+                for expected in 0..PHASES {
+                    progress.fetch_add(1, Ordering::Relaxed);
+                    assert_eq!(phaser.arrive_and_wait(), PhaseNumber(expected));
+                }
+            }
+        };
+        crate::concurrent_test_3(party(0), party(1), party(2));
+        assert_eq!(phaser.phase(), PhaseNumber(PHASES));
+        drop(phaser);
+        let completed = completed.into_inner().unwrap();
+        assert_eq!(completed, (0..PHASES).map(PhaseNumber).collect::<Vec<_>>());
+    }
+
+    // Deregistered parties should no longer hold back the others
+    #[test]
+    fn phaser_deregistration() {
+        let hook_runs = AtomicUsize::new(0);
+        let phaser = Phaser::new(3).on_phase_complete(|_| {
+            hook_runs.fetch_add(1, Ordering::Relaxed);
+        });
+        let party = || {
+            for _ in 0..4 {
+                phaser.arrive_and_wait();
+            }
+        };
+        crate::concurrent_test_3(party, party, || {
+            // Leave after the first phase, possibly completing the second
+            phaser.arrive_and_wait();
+            thread::sleep(Duration::from_millis(10));
+            phaser.deregister();
+        });
+        assert_eq!(phaser.registered(), 2);
+        assert_eq!(phaser.phase(), PhaseNumber(4));
+        assert_eq!(hook_runs.load(Ordering::Relaxed), 4);
+    }
+
+    // Parties registered midway should join the next phase
+    #[test]
+    fn phaser_registration() {
+        let phaser = Phaser::new(1);
+        assert_eq!(phaser.arrive_and_wait(), PhaseNumber(0));
+        assert_eq!(phaser.register(), PhaseNumber(1));
+        assert_eq!(phaser.registered(), 2);
+        crate::concurrent_test_2(
+            || assert_eq!(phaser.arrive_and_wait(), PhaseNumber(1)),
+            || assert_eq!(phaser.arrive_and_wait(), PhaseNumber(1)),
+        );
+        phaser.deregister();
+        phaser.deregister();
+        assert_eq!(phaser.registered(), 0);
+        assert_eq!(phaser.phase(), PhaseNumber(2));
+    }
+
+    // A panicking completion hook should still complete the phase
+    #[test]
+    fn phaser_hook_panic() {
+        let phaser = Phaser::new(1).on_phase_complete(|phase| {
+            if phase.get() == 0 {
+                panic::panic_any(phase);
+            }
+        });
+        let payload = panic::catch_unwind(|| phaser.arrive_and_wait()).unwrap_err();
+        assert_eq!(payload.downcast_ref(), Some(&PhaseNumber(0)));
+        assert_eq!(phaser.phase(), PhaseNumber(1));
+        assert_eq!(phaser.arrive_and_wait(), PhaseNumber(1));
+    }
+
+    /// Timeout for barrier waits that are not expected to time out
+    const LONG_TIMEOUT: Duration = Duration::from_secs(60);
+
+    /// Timeout for barrier waits that are expected to time out
+    const SHORT_TIMEOUT: Duration = Duration::from_millis(50);
+
+    // When everyone arrives in time, a timeout barrier should work like a
+    // normal barrier, for any number of rounds
+    #[test]
+    fn timeout_barrier_all_arrive() {
+        const THREADS: usize = 3;
+        let rounds = crate::scale_iters(1_000).max(10);
+        let barrier = TimeoutBarrier::new(THREADS);
+        let arrivals = AtomicUsize::new(0);
+        let leaders = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for round in 0..rounds {
+                        arrivals.fetch_add(1, Ordering::Relaxed);
+                        let outcome = barrier.wait_timeout(LONG_TIMEOUT).unwrap();
+                        if outcome.is_leader() {
+                            leaders.fetch_add(1, Ordering::Relaxed);
+                        }
+                        let arrivals = arrivals.load(Ordering::Relaxed);
+                        assert!(arrivals >= (round + 1) * THREADS);
+                        assert!(arrivals <= (round + 2) * THREADS);
+                    }
+                });
+            }
+        });
+        assert_eq!(leaders.load(Ordering::Relaxed), rounds);
+        assert!(!barrier.is_broken());
+        let single = TimeoutBarrier::new(0);
+        assert_eq!(single.participants(), 1);
+        assert!(single.wait().unwrap().is_leader());
+    }
+
+    // When a thread never arrives, the first waiter to time out should break
+    // the barrier, and release all other waiters promptly
+    #[test]
+    fn timeout_barrier_missing_thread() {
+        let barrier = TimeoutBarrier::new(4);
+        let start = Instant::now();
+        let (short, long, unbounded) = thread::scope(|s| {
+            let short = s.spawn(|| barrier.wait_timeout(SHORT_TIMEOUT));
+            let long = s.spawn(|| barrier.wait_timeout(LONG_TIMEOUT));
+            let unbounded = s.spawn(|| barrier.wait());
+            (
+                short.join().unwrap(),
+                long.join().unwrap(),
+                unbounded.join().unwrap(),
+            )
+        });
+        assert!(start.elapsed() >= SHORT_TIMEOUT);
+        assert!(barrier.is_broken());
+        assert_eq!(short, Err(BarrierTimeout { timed_out: true }));
+        assert_eq!(long, Err(BarrierTimeout { timed_out: false }));
+        assert_eq!(unbounded, Err(BarrierTimeout { timed_out: false }));
+    }
+
+    // Waiting on a broken barrier should fail right away
+    #[test]
+    fn timeout_barrier_arrive_after_broken() {
+        let barrier = TimeoutBarrier::new(2);
+        let error = barrier.wait_timeout(SHORT_TIMEOUT).unwrap_err();
+        assert!(error.timed_out);
+        assert!(barrier.is_broken());
+        for _ in 0..2 {
+            let start = Instant::now();
+            let error = barrier.wait_timeout(LONG_TIMEOUT).unwrap_err();
+            assert!(!error.timed_out);
+            assert!(start.elapsed() < LONG_TIMEOUT / 2);
+            assert_eq!(barrier.wait(), Err(error));
+        }
+    }
+
+    // A latch should only release its waiters once signaled
+    #[test]
+    fn latch_basics() {
+        let latch = Latch::default();
+        assert!(!latch.is_signaled());
+        assert!(!latch.wait_timeout(Duration::from_millis(10)));
+        latch.signal();
+        assert!(latch.is_signaled());
+        latch.wait();
+        assert!(latch.wait_timeout(Duration::ZERO));
+        latch.signal();
+        assert!(latch.is_signaled());
+    }
+
+    // A countdown latch should only release its waiters at the last count
+    #[test]
+    fn countdown_latch_basics() {
+        let latch = CountdownLatch::new(2);
+        assert_eq!(latch.count(), 2);
+        latch.count_down();
+        assert_eq!(latch.count(), 1);
+        assert!(!latch.is_released());
+        assert!(!latch.wait_timeout(Duration::from_millis(10)));
+        latch.count_down();
+        assert!(latch.is_released());
+        latch.wait();
+        assert!(latch.wait_timeout(Duration::MAX));
+        latch.count_down();
+        assert_eq!(latch.count(), 0);
+        assert!(CountdownLatch::new(0).wait_timeout(Duration::ZERO));
+    }
+
+    /// Busy-wait for a random amount of time, to randomize interleavings
+    fn random_delay(rng: &mut TestRng) {
+        for _ in 0..rng.next_bounded(1_000) {
+            hint::spin_loop();
+        }
+    }
+
+    // Waiters should be released by a signal arriving at a random time, and
+    // see the data written before it
+    #[test]
+    fn latch_stress() {
+        const WAITERS_PER_THREAD: usize = 4;
+        let (mut rng, seed) = TestRng::from_entropy();
+        for _ in 0..crate::scale_iters(1_000).max(10) {
+            let latch = Latch::new();
+            let data = AtomicUsize::new(0);
+            let released = AtomicUsize::new(0);
+            let waiter = |mut rng: TestRng| {
+                let (latch, data, released) = (&latch, &data, &released);
+                move || {
+                    thread::scope(|s| {
+                        for _ in 0..WAITERS_PER_THREAD {
+                            let mut rng = rng.fork();
+                            s.spawn(move || {
+                                random_delay(&mut rng);
+                                latch.wait();
+                                assert_eq!(data.load(Ordering::Relaxed), 42, "seed {}", seed);
+                                released.fetch_add(1, Ordering::Relaxed);
+                            });
+                        }
+                    })
+                }
+            };
+            let mut signaler_rng = rng.fork();
+            crate::concurrent_test_3(waiter(rng.fork()), waiter(rng.fork()), || {
+                random_delay(&mut signaler_rng);
+                data.store(42, Ordering::Relaxed);
+                latch.signal();
+            });
+            assert_eq!(released.load(Ordering::Relaxed), 2 * WAITERS_PER_THREAD);
+        }
+    }
+
+    // Waiters should only be released once every thread has counted down
+    #[test]
+    fn countdown_latch_stress() {
+        let (mut rng, seed) = TestRng::from_entropy();
+        for _ in 0..crate::scale_iters(1_000).max(10) {
+            let latch = CountdownLatch::new(2);
+            let counted = AtomicUsize::new(0);
+            let counter = |mut rng: TestRng| {
+                let (latch, counted) = (&latch, &counted);
+                move || {
+                    random_delay(&mut rng);
+                    counted.fetch_add(1, Ordering::Relaxed);
+                    latch.count_down();
+                }
+            };
+            crate::concurrent_test_3(counter(rng.fork()), counter(rng.fork()), || {
+                thread::scope(|s| {
+                    for _ in 0..4 {
+                        s.spawn(|| {
+                            latch.wait();
+                            assert_eq!(counted.load(Ordering::Relaxed), 2, "seed {}", seed);
+                        });
+                    }
+                })
+            });
+            assert!(latch.is_released());
+        }
+    }
+}
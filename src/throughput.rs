@@ -0,0 +1,164 @@
+//! Throughput measurements of repeated operations
+
+use crate::{env, timing};
+use std::time::Duration;
+
+/// Measure how many times per second an operation can be performed
+///
+/// The operation is called in a loop until the target duration has elapsed.
+/// To keep the cost of reading the clock negligible, the operation is called
+/// in batches between clock reads, whose size grows until a batch takes a
+/// sizeable fraction of the target duration.
+///
+/// This can be used as the benchmark of `run_under_contention()` in order to
+/// measure throughput under contention. The same precautions as with
+/// antagonists apply concerning the need to prevent the operation from being
+/// optimized out, see the `noinline` module for some tools.
+///
+/// # Panics
+///
+/// This function will propagate panics from the operation.
+///
+pub fn measure_throughput(mut operation: impl FnMut(), target: Duration) -> Throughput {
+    let start = timing::now();
+    let mut batch_size = 1u64;
+    let mut batch_start = start;
+    let mut iterations = 0;
+    loop {
+        for _ in 0..batch_size {
+            operation();
+        }
+        iterations += batch_size;
+        let now = timing::now();
+        let elapsed = now - start;
+        if elapsed >= target {
+            return Throughput {
+                iterations,
+                elapsed,
+                unreliable_timing: !env::timing_reliable(),
+            };
+        }
+        if now - batch_start < target / 100 {
+            batch_size *= 2;
+        }
+        batch_start = now;
+    }
+}
+
+/// Determine how many times an operation can be run in a target duration
+///
+/// Hard-coded iteration counts in tests are problematic because the speed of
+/// the machine that they run on can vary greatly, from a fast desktop to a
+/// slow laptop to an instrumented execution environment. This function allows
+/// tuning iteration counts to a target run time instead.
+///
+/// The probe is called once as a warmup, then in growing batches until a
+/// batch takes long enough to be reliably timed. The measured time, minus the
+/// timer overhead, is then extrapolated to the target duration. The result is
+/// clamped between 1 and 2^32 iterations.
+///
+/// Note that the probe is only run on one thread, so if it is used to size a
+/// concurrent test, the test will run more slowly than the probe did.
+///
+/// # Panics
+///
+/// This function will propagate panics from the probe.
+///
+pub fn calibrate_iterations(mut probe: impl FnMut(), target: Duration) -> usize {
+    const MIN_BATCH_DURATION: Duration = Duration::from_millis(1);
+    const MAX_ITERATIONS: u64 = 1 << 32;
+
+    // Warm up caches, lazy initialization, etc.
+    probe();
+
+    // Time batches of increasing size until one takes long enough
+    let timer_overhead = timing::timer_overhead();
+    let mut batch_size = 1u64;
+    let mut batch_time;
+    loop {
+        let start = timing::now();
+        for _ in 0..batch_size {
+            probe();
+        }
+        batch_time = start.elapsed().saturating_sub(timer_overhead);
+        if batch_time >= MIN_BATCH_DURATION.min(target) || batch_size >= MAX_ITERATIONS {
+            break;
+        }
+        batch_size *= 2;
+    }
+
+    // Extrapolate to the target duration
+    let iteration_time = batch_time.as_secs_f64() / batch_size as f64;
+    let iterations = if iteration_time > 0.0 {
+        (target.as_secs_f64() / iteration_time) as u64
+    } else {
+        MAX_ITERATIONS
+    };
+    iterations.clamp(1, MAX_ITERATIONS) as usize
+}
+
+/// Result of a throughput measurement
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct Throughput {
+    /// Number of times the operation was performed
+    pub iterations: u64,
+
+    /// Time that it took to perform the operation this many times
+    pub elapsed: Duration,
+
+    /// Truth that the measurement was taken in an environment where
+    /// wall-clock measurements are not meaningful, such as Miri or valgrind
+    ///
+    /// See `env::timing_reliable()` for details.
+    ///
+    pub unreliable_timing: bool,
+}
+//
+impl Throughput {
+    /// Number of operations per second
+    pub fn ops_per_sec(&self) -> f64 {
+        self.iterations as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// Tests of throughput measurements
+#[cfg(test)]
+mod tests {
+    use super::{calibrate_iterations, measure_throughput};
+    use std::time::Duration;
+
+    // An operation of known duration should yield a plausible rate
+    #[test]
+    fn known_duration() {
+        let target = Duration::from_millis(100);
+        let throughput =
+            measure_throughput(|| std::thread::sleep(Duration::from_millis(1)), target);
+        assert!(throughput.elapsed >= target);
+        assert!(throughput.iterations > 10);
+        assert!(throughput.ops_per_sec() <= 1000.0);
+    }
+
+    // Throughput can be measured under contention
+    #[test]
+    fn under_contention() {
+        let throughput = crate::run_under_contention(
+            || (),
+            || measure_throughput(|| (), Duration::from_millis(10)),
+        );
+        assert!(throughput.iterations > 0);
+        assert!(throughput.ops_per_sec() > 0.0);
+    }
+
+    // Calibration should extrapolate the duration of a known operation
+    #[test]
+    fn calibration() {
+        let iterations = calibrate_iterations(
+            || std::thread::sleep(Duration::from_millis(1)),
+            Duration::from_millis(100),
+        );
+        assert!(iterations >= 1);
+        assert!(iterations <= 100);
+        assert_eq!(calibrate_iterations(|| (), Duration::ZERO), 1);
+    }
+}
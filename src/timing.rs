@@ -0,0 +1,334 @@
+//! Time measurement primitives shared by the rest of this crate
+//!
+//! Every measurement in this crate is based on the functions of this module,
+//! so that they all follow the same methodology: timestamps are taken with a
+//! monotonic clock, and the overhead of reading the clock is estimated once
+//! and subtracted from short measurements where it matters.
+//!
+//! With the `cycles` feature, on x86_64 and aarch64, a raw hardware counter
+//! is also available for measurements that are too short for `Instant`.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Once,
+    },
+    time::{Duration, Instant},
+};
+
+/// Current value of the monotonic clock used by this crate
+#[inline]
+pub fn now() -> Instant {
+    Instant::now()
+}
+
+/// Estimated overhead of measuring a duration with `now()`
+///
+/// This is the median duration of a few thousand empty timed regions, measured
+/// on first use and cached afterwards.
+///
+pub fn timer_overhead() -> Duration {
+    static CALIBRATION: Once = Once::new();
+    static OVERHEAD_NANOS: AtomicU64 = AtomicU64::new(0);
+    CALIBRATION.call_once(|| {
+        let nanos = estimate_timer_overhead().as_nanos() as u64;
+        OVERHEAD_NANOS.store(nanos, Ordering::Relaxed);
+    });
+    Duration::from_nanos(OVERHEAD_NANOS.load(Ordering::Relaxed))
+}
+
+/// Measure how long an operation takes, minus the timer overhead
+///
+/// Results shorter than the timer overhead are clamped to zero.
+///
+pub fn measure(operation: impl FnOnce()) -> Duration {
+    let timer_overhead = timer_overhead();
+    let start = now();
+    operation();
+    start.elapsed().saturating_sub(timer_overhead)
+}
+
+/// Measure how long an operation takes on average, by running it in a batch
+///
+/// The operation is run `batch_size` times between two clock reads, and the
+/// duration of the batch minus the timer overhead is divided by `batch_size`.
+/// This is the recommended way to measure operations which are not much
+/// longer than the timer overhead, such as a single atomic operation, since
+/// the timer overhead and its fluctuations are then amortized over the batch.
+///
+/// # Panics
+///
+/// This function panics if `batch_size` is zero.
+///
+pub fn measure_batch(mut operation: impl FnMut(), batch_size: u32) -> Duration {
+    assert!(
+        batch_size > 0,
+        "Batches must contain at least one operation"
+    );
+    measure(|| {
+        for _ in 0..batch_size {
+            operation();
+        }
+    }) / batch_size
+}
+
+/// Estimate the overhead of measuring a duration with `now()`, without caching
+fn estimate_timer_overhead() -> Duration {
+    const CALIBRATION_RUNS: usize = 4000;
+    let mut samples = (0..CALIBRATION_RUNS)
+        .map(|_| now().elapsed())
+        .collect::<Vec<_>>();
+    samples.sort_unstable();
+    samples[CALIBRATION_RUNS / 2]
+}
+
+/// Read the hardware cycle counter
+///
+/// This uses `lfence; rdtsc` on x86_64 and `isb; mrs cntvct_el0` on aarch64,
+/// which prevents the read from being reordered before earlier instructions.
+/// Note that the counter ticks at a constant rate on modern CPUs, which is
+/// not necessarily the CPU clock rate. Use `cycles_to_duration()` to convert
+/// counter differences to durations.
+///
+#[cfg(all(
+    feature = "cycles",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+#[inline]
+pub fn cycles() -> u64 {
+    backend::cycles()
+}
+
+/// Read the hardware cycle counter at the start of a measured region
+///
+/// This uses `lfence; rdtsc; lfence` on x86_64 and `isb; mrs cntvct_el0; isb`
+/// on aarch64. Unlike `cycles()`, the read is also serialized with respect to
+/// later instructions, so the measured region cannot start executing before
+/// the counter is read. Pair it with `cycles_end()`, which waits for the
+/// measured region to complete before reading the counter.
+///
+#[cfg(all(
+    feature = "cycles",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+#[inline]
+pub fn cycles_start() -> u64 {
+    backend::cycles_start()
+}
+
+/// Read the hardware cycle counter at the end of a measured region
+///
+/// This is the same as `cycles()`, whose read waits for earlier instructions
+/// to complete. See `cycles_start()` for the beginning of the region.
+///
+#[cfg(all(
+    feature = "cycles",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+#[inline]
+pub fn cycles_end() -> u64 {
+    backend::cycles()
+}
+
+/// Estimated overhead of measuring a region with `cycles_start()` and
+/// `cycles_end()`, in counter ticks
+///
+/// This is the median counter difference across a few thousand empty
+/// measured regions, measured on first use and cached afterwards.
+///
+#[cfg(all(
+    feature = "cycles",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub fn cycles_overhead() -> u64 {
+    static CALIBRATION: Once = Once::new();
+    static OVERHEAD: AtomicU64 = AtomicU64::new(0);
+    CALIBRATION.call_once(|| {
+        const CALIBRATION_RUNS: usize = 4000;
+        let mut samples = (0..CALIBRATION_RUNS)
+            .map(|_| {
+                let start = cycles_start();
+                cycles_end().wrapping_sub(start)
+            })
+            .collect::<Vec<_>>();
+        samples.sort_unstable();
+        OVERHEAD.store(samples[CALIBRATION_RUNS / 2], Ordering::Relaxed);
+    });
+    OVERHEAD.load(Ordering::Relaxed)
+}
+
+/// Convert a difference of `cycles()` readings to a duration
+///
+/// The counter frequency is calibrated against `Instant` on first use, which
+/// takes a few milliseconds, and cached afterwards.
+///
+#[cfg(all(
+    feature = "cycles",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub fn cycles_to_duration(cycles: u64) -> Duration {
+    static CALIBRATION: Once = Once::new();
+    static FREQUENCY_BITS: AtomicU64 = AtomicU64::new(0);
+    CALIBRATION.call_once(|| {
+        const CALIBRATION_TIME: Duration = Duration::from_millis(10);
+        let (start_instant, start_cycles) = (now(), backend::cycles());
+        let mut elapsed = start_instant.elapsed();
+        while elapsed < CALIBRATION_TIME {
+            elapsed = start_instant.elapsed();
+        }
+        let frequency = (backend::cycles() - start_cycles) as f64 / elapsed.as_secs_f64();
+        FREQUENCY_BITS.store(frequency.to_bits(), Ordering::Relaxed);
+    });
+    let frequency = f64::from_bits(FREQUENCY_BITS.load(Ordering::Relaxed));
+    Duration::from_secs_f64(cycles as f64 / frequency)
+}
+
+/// x86_64 cycle counter
+#[cfg(all(feature = "cycles", target_arch = "x86_64"))]
+mod backend {
+    use std::arch::x86_64::{_mm_lfence, _rdtsc};
+
+    #[inline]
+    pub(super) fn cycles() -> u64 {
+        // Safe because lfence and rdtsc are available on all x86_64 CPUs
+        unsafe {
+            _mm_lfence();
+            _rdtsc()
+        }
+    }
+
+    #[inline]
+    pub(super) fn cycles_start() -> u64 {
+        // Safe for the same reason as cycles()
+        unsafe {
+            _mm_lfence();
+            let counter = _rdtsc();
+            _mm_lfence();
+            counter
+        }
+    }
+}
+
+/// aarch64 cycle counter
+#[cfg(all(feature = "cycles", target_arch = "aarch64"))]
+mod backend {
+    use std::arch::asm;
+
+    #[inline]
+    pub(super) fn cycles() -> u64 {
+        let counter: u64;
+        // Safe because the virtual counter is readable from user mode
+        unsafe {
+            asm!("isb", "mrs {}, cntvct_el0", out(reg) counter, options(nostack));
+        }
+        counter
+    }
+
+    #[inline]
+    pub(super) fn cycles_start() -> u64 {
+        let counter: u64;
+        // Safe for the same reason as cycles()
+        unsafe {
+            asm!(
+                "isb",
+                "mrs {}, cntvct_el0",
+                "isb",
+                out(reg) counter,
+                options(nostack)
+            );
+        }
+        counter
+    }
+}
+
+/// Tests of time measurement primitives
+#[cfg(test)]
+mod tests {
+    use super::{estimate_timer_overhead, measure, measure_batch, timer_overhead};
+    use std::time::Duration;
+
+    // The timer overhead estimate should be positive, small, stable, and
+    // cached
+    //
+    // Miri's clock does not reflect real time, so this cannot be checked there.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn overhead() {
+        let overhead = timer_overhead();
+        assert!(overhead > Duration::ZERO);
+        assert!(overhead < Duration::from_micros(100));
+        assert_eq!(timer_overhead(), overhead);
+        let fresh = estimate_timer_overhead();
+        assert!(fresh < 10 * overhead + Duration::from_micros(1));
+        assert!(overhead < 10 * fresh + Duration::from_micros(1));
+    }
+
+    // Measurements should cover the measured operation
+    #[test]
+    fn measurement() {
+        let duration = measure(|| std::thread::sleep(Duration::from_millis(10)));
+        assert!(duration >= Duration::from_millis(10) - timer_overhead());
+        assert!(measure(|| ()) < Duration::from_millis(1));
+    }
+
+    // Batches should be measured as a whole, then divided
+    #[test]
+    fn batch_measurement() {
+        let mut calls = 0;
+        let duration = measure_batch(
+            || {
+                calls += 1;
+                std::thread::sleep(Duration::from_millis(1));
+            },
+            5,
+        );
+        assert_eq!(calls, 5);
+        assert!(duration >= Duration::from_millis(1) - timer_overhead() / 5);
+        assert!(measure_batch(|| (), 1000) < Duration::from_micros(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one operation")]
+    fn empty_batch() {
+        measure_batch(|| (), 0);
+    }
+
+    // The cycle counter should be monotonic and convertible to durations
+    #[test]
+    #[cfg(all(
+        feature = "cycles",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    fn cycles() {
+        use super::{cycles, cycles_to_duration};
+        let start = cycles();
+        std::thread::sleep(Duration::from_millis(10));
+        let elapsed = cycles_to_duration(cycles() - start);
+        assert!(elapsed >= Duration::from_millis(9));
+        assert!(elapsed < Duration::from_secs(1));
+    }
+
+    // Serialized counter readings should be monotonic, cheap, and convertible
+    #[test]
+    #[cfg(all(
+        feature = "cycles",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    fn serialized_cycles() {
+        use super::{cycles_end, cycles_overhead, cycles_start, cycles_to_duration};
+        let mut last = cycles_start();
+        for _ in 0..1000 {
+            let start = cycles_start();
+            let end = cycles_end();
+            assert!(last <= start && start <= end);
+            last = end;
+        }
+        assert!(cycles_to_duration(cycles_overhead()) < Duration::from_micros(100));
+
+        let start = cycles_start();
+        std::thread::sleep(Duration::from_millis(10));
+        let elapsed = cycles_to_duration(cycles_end() - start);
+        assert!(elapsed >= Duration::from_millis(9));
+        assert!(elapsed < Duration::from_millis(500));
+    }
+}
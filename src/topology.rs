@@ -0,0 +1,259 @@
+//! Measurement of communication latency between CPU cores
+//!
+//! How much contention costs depends on how long it takes for a cache line to
+//! travel from one CPU core to another, which varies a lot between two
+//! hyperthreads of a core, two cores sharing a last-level cache, and two CPU
+//! packages. This module measures this latency by ping-ponging a cache line
+//! between two threads pinned to the CPUs of interest, which helps
+//! interpreting the results of contention benchmarks.
+//!
+//! Thread pinning requires the `affinity` feature and a supported operating
+//! system, see the `affinity` module. Otherwise, the functions of this module
+//! report an `AffinityError::Unsupported` error.
+//!
+//! ```
+//! use testbench::{affinity, topology};
+//!
+//! let cpus = affinity::logical_cpus();
+//! match topology::core_latency_matrix(&cpus[..cpus.len().min(2)], 10) {
+//!     Ok(pairs) => {
+//!         for pair in pairs {
+//!             println!("{} <-> {}: {:?}", pair.cpu_a, pair.cpu_b, pair.round_trip.median());
+//!         }
+//!     }
+//!     Err(error) => println!("Cannot measure core latency: {}", error),
+//! }
+//! ```
+
+use crate::{
+    affinity::{self, AffinityError},
+    stats::SampleStats,
+    timing,
+    util::CachePadded,
+};
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use std::{
+    hint,
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+};
+
+/// Number of round trips which are timed together to produce one sample
+pub const ROUND_TRIPS_PER_SAMPLE: usize = 100;
+
+/// Number of samples which are measured, then discarded, before measuring
+const WARMUP_SAMPLES: usize = 10;
+
+/// Number of times a thread checks the counter before yielding to the OS
+const SPIN_BUDGET: u32 = 1 << 10;
+
+/// Counter value which tells the other thread to give up
+const ABORT: u64 = u64::MAX;
+
+/// Measure the round-trip latency of a cache line between two logical CPUs
+///
+/// Two threads, respectively pinned to `cpu_a` and `cpu_b`, take turns
+/// incrementing a `CachePadded<AtomicU64>`: the first one waits for the
+/// counter to become even, the second one waits for it to become odd. Each
+/// increment thus transfers ownership of the cache line to the other CPU.
+///
+/// The result contains `rounds` samples, each of which is the average duration
+/// of a round trip across a batch of `ROUND_TRIPS_PER_SAMPLE` round trips,
+/// which keeps the timer overhead negligible. A few warmup samples are
+/// measured and discarded beforehand.
+///
+/// Measuring the latency between a CPU and itself is allowed, but since the
+/// threads then take turns running on the CPU, this measures the cost of
+/// switching between threads rather than that of cache coherence.
+///
+/// # Errors
+///
+/// This function fails if either CPU cannot be pinned to, including when
+/// thread pinning is not supported.
+///
+pub fn core_latency(
+    cpu_a: usize,
+    cpu_b: usize,
+    rounds: usize,
+) -> Result<SampleStats, AffinityError> {
+    affinity::check_cpu(cpu_a)?;
+    affinity::check_cpu(cpu_b)?;
+    let counter = CachePadded::new(AtomicU64::new(0));
+    let total_rounds = ((WARMUP_SAMPLES + rounds) * ROUND_TRIPS_PER_SAMPLE) as u64;
+    thread::scope(|s| {
+        let counter = &counter;
+        let responder = s.spawn(move || {
+            let _pin = affinity::pin_current_thread_scoped(cpu_b).map_err(|error| {
+                counter.store(ABORT, Ordering::Release);
+                error
+            })?;
+            for round in 0..total_rounds {
+                if !wait_for(counter, 2 * round + 1) {
+                    break;
+                }
+                counter.store(2 * round + 2, Ordering::Release);
+            }
+            Ok(())
+        });
+
+        let measurement = affinity::pin_current_thread_scoped(cpu_a).map(|_pin| {
+            let timer_overhead = timing::timer_overhead();
+            let mut round = 0;
+            let mut samples = Vec::with_capacity(rounds);
+            for sample in 0..WARMUP_SAMPLES + rounds {
+                let start = timing::now();
+                for _ in 0..ROUND_TRIPS_PER_SAMPLE {
+                    // This fails if the responder gave up
+                    counter
+                        .compare_exchange(
+                            2 * round,
+                            2 * round + 1,
+                            Ordering::AcqRel,
+                            Ordering::Relaxed,
+                        )
+                        .ok()?;
+                    if !wait_for(counter, 2 * round + 2) {
+                        return None;
+                    }
+                    round += 1;
+                }
+                if sample >= WARMUP_SAMPLES {
+                    let elapsed = start.elapsed().saturating_sub(timer_overhead);
+                    samples.push(elapsed / ROUND_TRIPS_PER_SAMPLE as u32);
+                }
+            }
+            Some(SampleStats::from_durations(samples))
+        });
+        if measurement.is_err() {
+            counter.store(ABORT, Ordering::Release);
+        }
+
+        let responder = responder
+            .join()
+            .unwrap_or_else(|payload| std::panic::resume_unwind(payload));
+        match (measurement?, responder) {
+            (Some(stats), Ok(())) => Ok(stats),
+            (_, Err(error)) => Err(error),
+            (None, Ok(())) => unreachable!("Responder cannot abort after pinning"),
+        }
+    })
+}
+
+/// Measure the round-trip latency between all pairs of some logical CPUs
+///
+/// Each unordered pair of distinct CPUs from `cpus` is measured once with
+/// `core_latency()`, as the latency is symmetrical. Results are ordered like
+/// `cpus`, e.g. `[0, 1, 2]` yields the pairs `(0, 1)`, `(0, 2)` and `(1, 2)`.
+/// Pass `affinity::logical_cpus()` to measure all pairs of CPUs, keeping in
+/// mind that the number of pairs grows quadratically with the number of CPUs.
+///
+/// The results can be exported with the `report` module.
+///
+/// # Errors
+///
+/// This function fails if any CPU cannot be pinned to.
+///
+pub fn core_latency_matrix(
+    cpus: &[usize],
+    rounds: usize,
+) -> Result<Vec<CorePairLatency>, AffinityError> {
+    for &cpu in cpus {
+        affinity::check_cpu(cpu)?;
+    }
+    let mut pairs = Vec::with_capacity(cpus.len() * cpus.len().saturating_sub(1) / 2);
+    for (idx, &cpu_a) in cpus.iter().enumerate() {
+        for &cpu_b in &cpus[idx + 1..] {
+            pairs.push(CorePairLatency {
+                cpu_a,
+                cpu_b,
+                round_trip: core_latency(cpu_a, cpu_b, rounds)?,
+            });
+        }
+    }
+    Ok(pairs)
+}
+
+/// Round-trip latency between two logical CPUs
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[non_exhaustive]
+pub struct CorePairLatency {
+    /// First logical CPU
+    pub cpu_a: usize,
+
+    /// Second logical CPU
+    pub cpu_b: usize,
+
+    /// Round-trip latency samples, as measured by `core_latency()`
+    pub round_trip: SampleStats,
+}
+
+/// Wait for the counter to reach some value, spinning then yielding
+///
+/// Returns false if the other thread gave up.
+///
+fn wait_for(counter: &AtomicU64, value: u64) -> bool {
+    let mut spins = 0;
+    loop {
+        match counter.load(Ordering::Acquire) {
+            current if current == value => return true,
+            ABORT => return false,
+            _ if spins < SPIN_BUDGET => {
+                spins += 1;
+                hint::spin_loop();
+            }
+            _ => thread::yield_now(),
+        }
+    }
+}
+
+/// Tests of core-to-core latency measurements
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // A pair of CPUs should be measurable where pinning is supported
+    #[test]
+    fn single_pair() {
+        let cpus = affinity::logical_cpus();
+        let (cpu_a, cpu_b) = (cpus[0], cpus[cpus.len() - 1]);
+        match core_latency(cpu_a, cpu_b, 10) {
+            Ok(stats) => {
+                assert_eq!(stats.len(), 10);
+                assert!(stats.min() > Duration::ZERO);
+                assert!(stats.median() < Duration::from_millis(1));
+            }
+            Err(AffinityError::Unsupported) => {}
+            Err(error) => panic!("Unexpected error: {}", error),
+        }
+    }
+
+    // All pairs of distinct CPUs should be measured once
+    #[test]
+    fn matrix() {
+        let cpus = affinity::logical_cpus();
+        let cpus = &cpus[..cpus.len().min(3)];
+        match core_latency_matrix(cpus, 2) {
+            Ok(pairs) => {
+                assert_eq!(pairs.len(), cpus.len() * (cpus.len() - 1) / 2);
+                for pair in pairs {
+                    assert!(pair.cpu_a != pair.cpu_b);
+                    assert_eq!(pair.round_trip.len(), 2);
+                }
+            }
+            Err(AffinityError::Unsupported) => {}
+            Err(error) => panic!("Unexpected error: {}", error),
+        }
+    }
+
+    // Invalid CPUs should be reported before any thread is spawned
+    #[test]
+    fn invalid_cpu() {
+        assert!(matches!(
+            core_latency(0, usize::MAX, 1),
+            Err(AffinityError::InvalidCpu(usize::MAX) | AffinityError::Unsupported)
+        ));
+    }
+}
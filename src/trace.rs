@@ -0,0 +1,308 @@
+//! Optional instrumentation with the `tracing` crate
+//!
+//! With the `tracing` feature, the harnesses of this crate emit the spans and
+//! events that are listed in the crate-level documentation. Without it, every
+//! function of this module is an empty inline function, so that the
+//! instrumentation compiles down to nothing.
+//!
+//! Threads spawned by the harnesses do not inherit the current span of the
+//! thread that spawned them, so their spans are explicitly nested into it,
+//! which is what the `Parent` type is about.
+
+#![cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+
+use std::time::Duration;
+
+/// Truth that instrumentation is enabled
+///
+/// This lets code which would need extra work to be instrumented skip it.
+///
+pub(crate) const ENABLED: bool = cfg!(feature = "tracing");
+
+/// Span which was entered by one of the functions of this module
+///
+/// The span is exited when this guard is dropped.
+///
+#[must_use]
+pub(crate) struct Entered {
+    /// Span being entered
+    #[cfg(feature = "tracing")]
+    _span: tracing::span::EnteredSpan,
+}
+
+/// Span under which the spans of other threads should be nested
+#[derive(Clone)]
+pub(crate) struct Parent {
+    /// Span of the thread which created this
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+//
+impl Parent {
+    /// Capture the current span of the calling thread
+    #[inline(always)]
+    pub(crate) fn current() -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            span: tracing::Span::current(),
+        }
+    }
+}
+
+/// Enter the span of a concurrent test participant
+#[inline(always)]
+pub(crate) fn participant(parent: &Parent, index: usize) -> Entered {
+    Entered {
+        #[cfg(feature = "tracing")]
+        _span: tracing::debug_span!(
+            parent: &parent.span,
+            "participant",
+            index,
+            thread = thread_name()
+        )
+        .entered(),
+    }
+}
+
+/// Enter the span of an antagonist loop
+#[inline(always)]
+pub(crate) fn antagonist(parent: &Parent) -> Entered {
+    Entered {
+        #[cfg(feature = "tracing")]
+        _span: tracing::debug_span!(parent: &parent.span, "antagonist", thread = thread_name())
+            .entered(),
+    }
+}
+
+/// Enter the span of a benchmark running under contention
+#[inline(always)]
+pub(crate) fn benchmark() -> Entered {
+    Entered {
+        #[cfg(feature = "tracing")]
+        _span: tracing::debug_span!("benchmark").entered(),
+    }
+}
+
+/// Report that the start barrier of concurrent threads was released
+#[inline(always)]
+pub(crate) fn barrier_released() {
+    #[cfg(feature = "tracing")]
+    tracing::trace!("start barrier released");
+}
+
+/// Report that antagonists were told to stop
+#[inline(always)]
+pub(crate) fn stop_flag_set() {
+    #[cfg(feature = "tracing")]
+    tracing::debug!("antagonist stop flag set");
+}
+
+/// Report that a watchdog's deadline expired
+#[inline(always)]
+pub(crate) fn watchdog_fired(parent: &Parent, deadline: Duration, elapsed: Duration) {
+    #[cfg(feature = "tracing")]
+    tracing::warn!(
+        parent: &parent.span,
+        ?deadline,
+        ?elapsed,
+        "watchdog deadline exceeded"
+    );
+}
+
+/// Report that a watchdog's grace period expired, right before aborting
+#[inline(always)]
+pub(crate) fn watchdog_abort(parent: &Parent, elapsed: Duration) {
+    #[cfg(feature = "tracing")]
+    tracing::error!(
+        parent: &parent.span,
+        ?elapsed,
+        "watchdog grace period exceeded, aborting"
+    );
+}
+
+/// Report that a RaceCell was observed in an inconsistent state
+#[inline(always)]
+pub(crate) fn race_detected(contents: &'static str) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(contents, "RaceCell inconsistency detected");
+}
+
+/// Name of the current thread, for span fields
+#[cfg(feature = "tracing")]
+fn thread_name() -> String {
+    std::thread::current()
+        .name()
+        .unwrap_or("<unnamed>")
+        .to_owned()
+}
+
+/// Tests of the tracing instrumentation
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use crate::{
+        race_cell::{RaceCell, RaceLog},
+        watchdog::Watchdog,
+    };
+    use std::{
+        fmt, panic,
+        sync::{Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+    use tracing::{
+        field::{Field, Visit},
+        span, Event, Subscriber,
+    };
+    use tracing_subscriber::{
+        layer::{Context, SubscriberExt},
+        registry::LookupSpan,
+        Layer, Registry,
+    };
+
+    /// Span or event recorded by `Recorder`
+    #[derive(Clone, Debug, Default)]
+    struct Record {
+        /// Name of the span, or message of the event
+        name: String,
+
+        /// Other fields, formatted with `Debug` unless they are strings
+        fields: Vec<(&'static str, String)>,
+
+        /// Names of the enclosing spans, innermost first
+        scope: Vec<&'static str>,
+    }
+    //
+    impl Record {
+        /// Value of a field, if present
+        fn field(&self, name: &str) -> Option<&str> {
+            self.fields
+                .iter()
+                .find(|(field, _)| *field == name)
+                .map(|(_, value)| value.as_str())
+        }
+    }
+    //
+    impl Visit for Record {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.fields.push((field.name(), value.to_owned()));
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            let value = format!("{:?}", value);
+            if field.name() == "message" {
+                self.name = value;
+            } else {
+                self.fields.push((field.name(), value));
+            }
+        }
+    }
+
+    /// Layer which records all spans and events
+    #[derive(Clone, Default)]
+    struct Recorder {
+        /// Spans, in order of creation
+        spans: Arc<Mutex<Vec<Record>>>,
+
+        /// Events, in order of emission
+        events: Arc<Mutex<Vec<Record>>>,
+    }
+    //
+    impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Recorder {
+        fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+            let mut record = Record {
+                name: attrs.metadata().name().to_owned(),
+                ..Record::default()
+            };
+            attrs.record(&mut record);
+            if let Some(span) = ctx.span(id) {
+                record.scope = span.scope().skip(1).map(|span| span.name()).collect();
+            }
+            self.spans.lock().unwrap().push(record);
+        }
+
+        fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+            let mut record = Record::default();
+            event.record(&mut record);
+            if let Some(scope) = ctx.event_scope(event) {
+                record.scope = scope.map(|span| span.name()).collect();
+            }
+            self.events.lock().unwrap().push(record);
+        }
+    }
+
+    // The harnesses should emit the expected spans and events, nested into
+    // the span of the test, whichever thread they come from
+    //
+    // This is the only test which installs a global subscriber, and other
+    // tests may emit spans and events concurrently, so records which are
+    // not nested into the span of this test are ignored.
+    #[test]
+    fn spans_and_events() {
+        let recorder = Recorder::default();
+        tracing::subscriber::set_global_default(Registry::default().with(recorder.clone()))
+            .expect("No other test should install a global subscriber");
+        tracing::info_span!("trace_test").in_scope(|| {
+            crate::concurrent_test_2(|| {}, || {});
+            crate::run_under_contention(|| {}, || {});
+            let cell = RaceCell::new_inconsistent(1u8, 2);
+            assert!(cell
+                .get_logged(&RaceLog::with_capacity(1))
+                .is_inconsistent());
+            let late = panic::catch_unwind(|| {
+                Watchdog::new(Duration::from_millis(1))
+                    .grace_period(Duration::from_secs(60))
+                    .run(|| thread::sleep(Duration::from_millis(50)))
+            });
+            assert!(late.is_err());
+        });
+        let ours = |records: &Mutex<Vec<Record>>| {
+            records
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|record| record.scope.last() == Some(&"trace_test"))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        let spans = ours(&recorder.spans);
+        let events = ours(&recorder.events);
+        let named = |records: &[Record], name: &str| {
+            records
+                .iter()
+                .filter(|record| record.name == name)
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+
+        // One span per concurrent test participant, in which the start
+        // barrier is released
+        let participants = named(&spans, "participant");
+        let mut indices = participants
+            .iter()
+            .map(|span| span.field("index").unwrap())
+            .collect::<Vec<_>>();
+        indices.sort_unstable();
+        assert_eq!(indices, ["0", "1"]);
+        let caller = participants
+            .iter()
+            .find(|span| span.field("index") == Some("1"))
+            .unwrap();
+        assert_eq!(caller.field("thread"), thread::current().name());
+        let released = named(&events, "start barrier released");
+        assert_eq!(released.len(), 2);
+        for event in released {
+            assert_eq!(event.scope, ["participant", "trace_test"]);
+        }
+
+        // Antagonist and benchmark spans, and a single stop event
+        assert_eq!(named(&spans, "antagonist").len(), 1);
+        assert_eq!(named(&spans, "benchmark").len(), 1);
+        assert_eq!(named(&events, "antagonist stop flag set").len(), 1);
+
+        // Detected races and watchdog firing
+        let races = named(&events, "RaceCell inconsistency detected");
+        assert_eq!(races.len(), 1);
+        assert_eq!(races[0].field("contents"), Some("u8"));
+        assert_eq!(named(&events, "watchdog deadline exceeded").len(), 1);
+    }
+}
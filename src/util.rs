@@ -0,0 +1,88 @@
+//! Miscellaneous utilities for concurrent tests and benchmarks
+
+use std::{
+    any::Any,
+    mem::align_of,
+    ops::{Deref, DerefMut},
+};
+
+/// Alignment of `CachePadded`, in bytes
+///
+/// This is 128 bytes on x86_64 and aarch64: although their cache lines are
+/// usually 64 bytes long, modern Intel CPUs prefetch cache lines by pairs, and
+/// some ARM CPUs like Apple's M series have 128-byte cache lines. On other
+/// architectures, 64 bytes are assumed.
+///
+pub const CACHE_LINE_SIZE: usize = if cfg!(any(target_arch = "x86_64", target_arch = "aarch64")) {
+    128
+} else {
+    64
+};
+
+/// Pads and aligns a value to the size of a cache line
+///
+/// Concurrent tests and benchmarks often need to control which data shares a
+/// cache line with which other data, either to avoid false sharing or to
+/// create it on purpose. Wrapping a value in this type guarantees that it does
+/// not share its cache line with any other value.
+///
+/// The alignment is given by `CACHE_LINE_SIZE`, and the size is rounded up to
+/// a multiple of it.
+///
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(any(target_arch = "x86_64", target_arch = "aarch64"), repr(align(128)))]
+#[cfg_attr(
+    not(any(target_arch = "x86_64", target_arch = "aarch64")),
+    repr(align(64))
+)]
+pub struct CachePadded<T> {
+    /// Inner value
+    value: T,
+}
+//
+// The alignment attribute cannot name CACHE_LINE_SIZE, so make sure that both
+// are kept in sync.
+const _: () = assert!(align_of::<CachePadded<u8>>() == CACHE_LINE_SIZE);
+//
+impl<T> CachePadded<T> {
+    /// Pad and align a value
+    pub const fn new(value: T) -> Self {
+        Self { value }
+    }
+
+    /// Extract the inner value
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+//
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+//
+impl<T> DerefMut for CachePadded<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+//
+impl<T> From<T> for CachePadded<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+/// Extract the message from a panic payload, if any
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "<non-string panic payload>"
+    }
+}
@@ -0,0 +1,216 @@
+//! CPU warmup before measurements
+//!
+//! Idle CPU cores run at a low clock frequency, and only speed up after some
+//! time under load. Benchmarks that start on idle cores are therefore slower
+//! at first, which biases the first measurements of a run, and makes
+//! contention sweeps look like performance improves as more measurements are
+//! taken. Spinning on the CPUs for a while before measuring brings their clock
+//! frequency up, which removes this bias.
+//!
+//! The `Contention` and `Sweep` builders can do this automatically, see their
+//! `cpu_warmup()` methods.
+//!
+//! ```
+//! use std::time::Duration;
+//! use testbench::warmup;
+//!
+//! warmup::spin_all_cores(Duration::from_millis(10));
+//! // ... run benchmarks ...
+//! ```
+//!
+//! Keep in mind that warming up the CPUs for too long can get them to heat up
+//! and throttle, which biases measurements in the opposite direction. A
+//! duration of the order of 100ms is usually enough for the clock frequency to
+//! ramp up.
+
+use crate::{
+    affinity::{self, AffinityError},
+    noinline, timing,
+};
+use std::{
+    sync::Barrier,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Number of spin kernel iterations between two clock reads
+const ITERATIONS_PER_CHECK: u32 = 1 << 10;
+
+/// Spin on the current thread for a certain duration
+///
+/// This is enough to warm up the CPU of a single-threaded benchmark, as long
+/// as the thread is not migrated to another CPU afterwards.
+///
+pub fn spin_current(duration: Duration) {
+    spin_until(timing::now() + duration);
+}
+
+/// Spin on all logical CPUs for a certain duration
+///
+/// One thread is spawned per logical CPU, and pinned to that CPU if thread
+/// pinning is supported (see the `affinity` module), then all threads spin
+/// until `duration` has elapsed. All threads have exited by the time this
+/// function returns.
+///
+/// Where thread pinning is not supported, the threads are left for the
+/// operating system to distribute across CPUs, which usually puts each of
+/// them on its own CPU.
+///
+pub fn spin_all_cores(duration: Duration) {
+    spin_cpus(&affinity::logical_cpus(), duration);
+}
+
+/// Spin on one logical CPU per physical core for a certain duration
+///
+/// This is a lighter variant of `spin_all_cores()`, which leaves sibling
+/// hyperthreads idle: with simultaneous multi-threading, a core's clock
+/// frequency is shared by all of its logical CPUs, so spinning on one of them
+/// is enough to warm the whole core up.
+///
+/// # Errors
+///
+/// This function fails if the CPU topology cannot be queried, including when
+/// thread pinning is not supported. In this case, it does not spin.
+///
+pub fn spin_physical_cores(duration: Duration) -> Result<(), AffinityError> {
+    let cpus = affinity::physical_cores()?
+        .into_iter()
+        .filter_map(|core| core.logical_cpus.first().copied())
+        .collect::<Vec<_>>();
+    spin_cpus(&cpus, duration);
+    Ok(())
+}
+
+/// Spin on some logical CPUs for a certain duration
+///
+/// Pinning is best-effort: threads which cannot be pinned spin anyway.
+///
+fn spin_cpus(cpus: &[usize], duration: Duration) {
+    let barrier = Barrier::new(cpus.len());
+    thread::scope(|s| {
+        for &cpu in cpus {
+            let barrier = &barrier;
+            thread::Builder::new()
+                .name("testbench-warmup".to_owned())
+                .spawn_scoped(s, move || {
+                    let _pin = affinity::pin_current_thread_scoped(cpu);
+                    barrier.wait();
+                    spin_current(duration);
+                })
+                .expect("Failed to spawn a warmup thread");
+        }
+    });
+}
+
+/// Run the spin kernel until a deadline
+fn spin_until(deadline: Instant) {
+    let mut state = 1u64;
+    let mut kernel = || {
+        state = state
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        state
+    };
+    while timing::now() < deadline {
+        for _ in 0..ITERATIONS_PER_CHECK {
+            noinline::consume(noinline::call_mut(&mut kernel));
+        }
+    }
+}
+
+/// Tests of CPU warmup
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::{Mutex, MutexGuard, PoisonError};
+
+    /// Duration of the warmups performed by the tests
+    const DURATION: Duration = Duration::from_millis(50);
+
+    /// Lock held by tests that spawn warmup threads
+    ///
+    /// Tests that check that no warmup thread is left behind would be confused
+    /// by warmup threads spawned by concurrently running tests.
+    ///
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    /// Keep other tests from spawning warmup threads
+    pub(crate) fn lock() -> MutexGuard<'static, ()> {
+        LOCK.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Spinning on the current thread should take about the requested time
+    //
+    // Miri's clock does not reflect real time, so this cannot be checked there.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn current() {
+        let start = Instant::now();
+        spin_current(DURATION);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= DURATION);
+        assert!(elapsed < 20 * DURATION, "{:?}", elapsed);
+    }
+
+    // Spinning on all cores should take about the requested time, and all
+    // warmup threads should be gone afterwards
+    //
+    // Miri's clock does not reflect real time, so this cannot be checked there.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn all_cores() {
+        let _lock = lock();
+        let start = Instant::now();
+        spin_all_cores(DURATION);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= DURATION);
+        assert!(elapsed < 20 * DURATION, "{:?}", elapsed);
+        #[cfg(all(target_os = "linux", not(miri)))]
+        assert!(warmup_threads_exit());
+    }
+
+    // Spinning on physical cores should work wherever the topology is known
+    #[test]
+    fn physical_cores() {
+        let _lock = lock();
+        match spin_physical_cores(Duration::from_millis(1)) {
+            Ok(()) | Err(AffinityError::Unsupported) => {}
+            Err(error) => panic!("Unexpected error: {}", error),
+        }
+        #[cfg(all(target_os = "linux", not(miri)))]
+        assert!(warmup_threads_exit());
+    }
+
+    /// Truth that all warmup threads of this process exit within a second
+    ///
+    /// Scoped threads can be joined slightly before the OS is done tearing
+    /// them down, so they may briefly remain visible after the scope ends.
+    ///
+    #[cfg(all(target_os = "linux", not(miri)))]
+    fn warmup_threads_exit() -> bool {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while warmup_threads() > 0 {
+            if Instant::now() > deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        true
+    }
+
+    /// Number of warmup threads in this process, according to procfs
+    ///
+    /// Thread names are truncated to 15 bytes there.
+    ///
+    #[cfg(all(target_os = "linux", not(miri)))]
+    fn warmup_threads() -> usize {
+        std::fs::read_dir("/proc/self/task")
+            .expect("Failed to list the threads of this process")
+            .filter_map(|task| {
+                let comm = task.ok()?.path().join("comm");
+                std::fs::read_to_string(comm).ok()
+            })
+            .filter(|name| name.starts_with("testbench-warmu"))
+            .count()
+    }
+}
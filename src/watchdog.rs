@@ -0,0 +1,481 @@
+//! Deadlines and liveness checks for operations which may hang
+//!
+//! Concurrency bugs often manifest as deadlocks or livelocks, which make tests
+//! hang instead of failing. Wrapping a potentially hanging operation into
+//! `run()` turns this into a test failure: a watchdog thread monitors the
+//! operation, and reports it if it exceeds its deadline.
+//!
+//! Since Rust threads cannot be interrupted, the watchdog cannot stop the
+//! operation. When the deadline passes, it panics on its own thread, which
+//! prints an error message through the panic hook. It then gives the operation
+//! a grace period to finish, after which it aborts the process. If the
+//! operation finishes during the grace period, `run()` panics on the calling
+//! thread instead, failing the test normally.
+//!
+//! Livelocks are harder to detect, as the operation keeps running and may
+//! eventually finish. `assert_progress()` detects them by monitoring a
+//! progress counter, which the operation must keep incrementing.
+
+use crate::trace;
+use std::{
+    fmt::{self, Write},
+    panic, process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Condvar, Mutex, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Run an operation on the current thread, failing if it exceeds a deadline
+///
+/// This is a shorthand for `Watchdog::new(deadline).run(operation)`.
+///
+/// # Panics
+///
+/// This function panics if the operation exceeds its deadline but finishes
+/// within the grace period, and propagates panics from the operation.
+///
+pub fn run<R>(deadline: Duration, operation: impl FnOnce() -> R) -> R {
+    Watchdog::new(deadline).run(operation)
+}
+
+/// Watchdog configuration
+#[derive(Clone, Copy, Debug)]
+pub struct Watchdog {
+    /// Duration after which the operation is reported as hanging
+    deadline: Duration,
+
+    /// Extra duration after which the process is aborted
+    grace_period: Duration,
+}
+//
+impl Watchdog {
+    /// Set up a watchdog with a certain deadline
+    ///
+    /// The grace period is initially equal to the deadline.
+    ///
+    pub fn new(deadline: Duration) -> Self {
+        Self {
+            deadline,
+            grace_period: deadline,
+        }
+    }
+
+    /// Set how long an operation which exceeded its deadline may keep running
+    /// before the process is aborted
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Run an operation on the current thread, failing if it exceeds the
+    /// deadline
+    ///
+    /// The watchdog thread is torn down as soon as the operation finishes.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the operation exceeds its deadline but
+    /// finishes within the grace period, and propagates panics from the
+    /// operation.
+    ///
+    pub fn run<R>(self, operation: impl FnOnce() -> R) -> R {
+        let start = Instant::now();
+        let finished = Finished::default();
+        let parent = trace::Parent::current();
+        let (result, late) = thread::scope(|s| {
+            let watchdog = thread::Builder::new()
+                .name("testbench-watchdog".to_owned())
+                .spawn_scoped(s, || self.watch(start, &finished, &parent))
+                .expect("Failed to spawn the watchdog thread");
+            let result = {
+                let _guard = FinishOnDrop(&finished);
+                operation()
+            };
+            let late = watchdog.join().expect("The watchdog should not panic");
+            (result, late)
+        });
+        if late {
+            panic!(
+                "Operation exceeded its {:?} deadline, and finished after {:?}",
+                self.deadline,
+                start.elapsed()
+            );
+        }
+        result
+    }
+
+    /// Monitor the operation, telling if it exceeded the deadline
+    fn watch(&self, start: Instant, finished: &Finished, parent: &trace::Parent) -> bool {
+        // A deadline too far away to be represented amounts to no deadline
+        let deadline = start.checked_add(self.deadline);
+        if finished.wait(deadline) {
+            return false;
+        }
+        trace::watchdog_fired(parent, self.deadline, start.elapsed());
+        let message = format!(
+            "Operation has been running for {:?}, exceeding its {:?} deadline",
+            start.elapsed(),
+            self.deadline
+        );
+        let _ = panic::catch_unwind(|| panic!("{}", message));
+        let abort_deadline = deadline.and_then(|deadline| deadline.checked_add(self.grace_period));
+        if !finished.wait(abort_deadline) {
+            trace::watchdog_abort(parent, start.elapsed());
+            eprintln!(
+                "Operation is still running after {:?}, aborting the process",
+                start.elapsed()
+            );
+            process::abort();
+        }
+        true
+    }
+}
+
+/// Run an operation, checking that it keeps incrementing a progress counter
+///
+/// This is a shorthand for `ProgressMonitor::new(window, min_delta).run(counter,
+/// body)`, see the documentation of `ProgressMonitor` for more details.
+///
+/// # Panics
+///
+/// This function panics if progress stalled during the operation, and
+/// propagates panics from the operation.
+///
+pub fn assert_progress(counter: &AtomicU64, window: Duration, min_delta: u64, body: impl FnOnce()) {
+    ProgressMonitor::new(window, min_delta).run(counter, body)
+}
+
+/// Liveness check for operations which increment a progress counter
+///
+/// While the operation runs, a monitor thread samples the progress counter at
+/// the end of every time window. If the counter advanced by less than a
+/// certain amount during some windows, these are reported by panicking once
+/// the operation is over. Counter wrap-around is handled.
+///
+/// Monitoring starts after a grace period, which gives the operation time to
+/// ramp up, e.g. to spawn its threads. The last window, which is cut short by
+/// the end of the operation, is not checked.
+///
+#[derive(Clone, Copy, Debug)]
+pub struct ProgressMonitor {
+    /// Duration of a monitoring window
+    window: Duration,
+
+    /// Minimal counter increment per window
+    min_delta: u64,
+
+    /// Delay before the first monitoring window
+    grace_period: Duration,
+}
+//
+impl ProgressMonitor {
+    /// Set up a monitor with a certain window and minimal counter increment
+    ///
+    /// The grace period is initially equal to one window.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `window` is zero.
+    ///
+    pub fn new(window: Duration, min_delta: u64) -> Self {
+        assert!(
+            window > Duration::ZERO,
+            "Monitoring window must not be empty"
+        );
+        Self {
+            window,
+            min_delta,
+            grace_period: window,
+        }
+    }
+
+    /// Set how long to wait before the first monitoring window
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Run an operation on the current thread, checking that it keeps
+    /// incrementing a progress counter
+    ///
+    /// # Panics
+    ///
+    /// This function panics if progress stalled during the operation, and
+    /// propagates panics from the operation.
+    ///
+    pub fn run(self, counter: &AtomicU64, body: impl FnOnce()) {
+        let finished = Finished::default();
+        let stalls = thread::scope(|s| {
+            let monitor = thread::Builder::new()
+                .name("testbench-progress".to_owned())
+                .spawn_scoped(s, || self.monitor(counter, &finished))
+                .expect("Failed to spawn the progress monitor thread");
+            {
+                let _guard = FinishOnDrop(&finished);
+                body();
+            }
+            monitor
+                .join()
+                .expect("The progress monitor should not panic")
+        });
+        if !stalls.is_empty() {
+            let mut message = format!(
+                "Progress stalled in {} window(s) of {:?}, expected at least {} increment(s) per window:",
+                stalls.len(),
+                self.window,
+                self.min_delta
+            );
+            for stall in stalls {
+                write!(message, "\n- {}", stall).expect("Writing to a String cannot fail");
+            }
+            panic!("{}", message);
+        }
+    }
+
+    /// Sample the progress counter until the operation is finished, and
+    /// report the windows where progress stalled
+    fn monitor(&self, counter: &AtomicU64, finished: &Finished) -> Vec<Stall> {
+        // Times too far away to be represented are never reached, so the
+        // operation is simply awaited
+        let start = Instant::now();
+        let mut window_start = match start.checked_add(self.grace_period) {
+            Some(window_start) => window_start,
+            None => {
+                finished.wait(None);
+                return Vec::new();
+            }
+        };
+        if finished.wait(Some(window_start)) {
+            return Vec::new();
+        }
+        let mut start_value = counter.load(Ordering::Relaxed);
+        let mut stalls = Vec::new();
+        loop {
+            let window_end = match window_start.checked_add(self.window) {
+                Some(window_end) => window_end,
+                None => {
+                    finished.wait(None);
+                    return stalls;
+                }
+            };
+            if finished.wait(Some(window_end)) {
+                return stalls;
+            }
+            let end_value = counter.load(Ordering::Relaxed);
+            if end_value.wrapping_sub(start_value) < self.min_delta {
+                stalls.push(Stall {
+                    start: window_start - start,
+                    end: window_end - start,
+                    start_value,
+                    end_value,
+                });
+            }
+            window_start = window_end;
+            start_value = end_value;
+        }
+    }
+}
+
+/// Monitoring window where progress stalled
+#[derive(Clone, Copy, Debug)]
+struct Stall {
+    /// Start of the window, relative to the start of the operation
+    start: Duration,
+
+    /// End of the window, relative to the start of the operation
+    end: Duration,
+
+    /// Value of the progress counter at the start of the window
+    start_value: u64,
+
+    /// Value of the progress counter at the end of the window
+    end_value: u64,
+}
+//
+impl fmt::Display for Stall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "from {:?} to {:?}, counter went from {} to {}",
+            self.start, self.end, self.start_value, self.end_value
+        )
+    }
+}
+
+/// Notification that the monitored operation has finished
+#[derive(Debug, Default)]
+struct Finished {
+    /// Truth that the operation has finished
+    flag: Mutex<bool>,
+
+    /// Condition variable used to notify the watchdog
+    condvar: Condvar,
+}
+//
+impl Finished {
+    /// Mark the operation as finished and wake up the watchdog
+    fn notify(&self) {
+        *self.flag.lock().unwrap_or_else(PoisonError::into_inner) = true;
+        self.condvar.notify_all();
+    }
+
+    /// Wait for the operation to finish until a certain time, or forever if
+    /// there is no such time, telling if it did finish
+    fn wait(&self, until: Option<Instant>) -> bool {
+        let mut flag = self.flag.lock().unwrap_or_else(PoisonError::into_inner);
+        while !*flag {
+            flag = match until {
+                Some(until) => {
+                    let now = Instant::now();
+                    if now >= until {
+                        return false;
+                    }
+                    self.condvar
+                        .wait_timeout(flag, until - now)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+                None => self
+                    .condvar
+                    .wait(flag)
+                    .unwrap_or_else(PoisonError::into_inner),
+            };
+        }
+        true
+    }
+}
+
+/// Marks the operation as finished when dropped, even if it panicked
+struct FinishOnDrop<'finished>(&'finished Finished);
+//
+impl Drop for FinishOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.notify();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Operations which finish in time should not be disturbed
+    #[test]
+    fn success() {
+        let start = Instant::now();
+        assert_eq!(run(Duration::from_secs(60), || 42), 42);
+        // The watchdog should not wait for the deadline before exiting
+        assert!(start.elapsed() < Duration::from_secs(30));
+    }
+
+    // Panics from the operation should be propagated without waiting
+    #[test]
+    fn operation_panic() {
+        let start = Instant::now();
+        let payload = panic::catch_unwind(|| {
+            run(Duration::from_secs(60), || panic::panic_any(42u8));
+        })
+        .unwrap_err();
+        assert_eq!(payload.downcast_ref(), Some(&42u8));
+        assert!(start.elapsed() < Duration::from_secs(30));
+    }
+
+    // Operations which exceed their deadline should fail once finished
+    #[test]
+    fn timeout() {
+        let payload = panic::catch_unwind(|| {
+            Watchdog::new(Duration::from_millis(50))
+                .grace_period(Duration::from_secs(60))
+                .run(|| thread::sleep(Duration::from_millis(500)))
+        })
+        .unwrap_err();
+        let message = payload.downcast_ref::<String>().unwrap();
+        assert!(
+            message.contains("exceeded its 50ms deadline"),
+            "{}",
+            message
+        );
+    }
+
+    // Deadlines too far away to be represented should amount to no deadline
+    #[test]
+    fn unbounded_deadline() {
+        assert_eq!(run(Duration::MAX, || 42), 42);
+        let payload = panic::catch_unwind(|| {
+            Watchdog::new(Duration::from_millis(10))
+                .grace_period(Duration::MAX)
+                .run(|| thread::sleep(Duration::from_millis(100)))
+        })
+        .unwrap_err();
+        let message = payload.downcast_ref::<String>().unwrap();
+        assert!(
+            message.contains("exceeded its 10ms deadline"),
+            "{}",
+            message
+        );
+    }
+
+    // A counter which keeps being incremented should pass the check
+    #[test]
+    fn steady_progress() {
+        // Start close to the maximum to check that wrap-around is handled
+        let counter = AtomicU64::new(u64::MAX - 10);
+        assert_progress(&counter, Duration::from_millis(100), 1, || {
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_millis(500) {
+                counter.fetch_add(1, Ordering::Relaxed);
+                thread::sleep(Duration::from_millis(1));
+            }
+        });
+    }
+
+    // Windows where the counter did not advance should be reported
+    #[test]
+    fn stalled_progress() {
+        let counter = AtomicU64::new(0);
+        let increment_for = |duration| {
+            let start = Instant::now();
+            while start.elapsed() < duration {
+                counter.fetch_add(1, Ordering::Relaxed);
+                thread::sleep(Duration::from_millis(1));
+            }
+        };
+        let payload = panic::catch_unwind(|| {
+            ProgressMonitor::new(Duration::from_millis(50), 1)
+                .grace_period(Duration::ZERO)
+                .run(&counter, || {
+                    increment_for(Duration::from_millis(100));
+                    thread::sleep(Duration::from_millis(500));
+                    increment_for(Duration::from_millis(100));
+                })
+        })
+        .unwrap_err();
+        let message = payload.downcast_ref::<String>().unwrap();
+        assert!(message.starts_with("Progress stalled in "), "{}", message);
+        assert!(message.contains("counter went from "), "{}", message);
+    }
+
+    // Nothing happening during the grace period should not be an issue
+    #[test]
+    fn ramp_up() {
+        let counter = AtomicU64::new(0);
+        ProgressMonitor::new(Duration::from_millis(100), 1)
+            .grace_period(Duration::from_secs(60))
+            .run(&counter, || thread::sleep(Duration::from_millis(200)));
+    }
+
+    // Grace periods and windows too long to be represented should amount to
+    // waiting for the operation to finish
+    #[test]
+    fn unbounded_progress_times() {
+        let counter = AtomicU64::new(0);
+        ProgressMonitor::new(Duration::from_millis(10), 1)
+            .grace_period(Duration::MAX)
+            .run(&counter, || thread::sleep(Duration::from_millis(50)));
+        ProgressMonitor::new(Duration::MAX, 1)
+            .grace_period(Duration::ZERO)
+            .run(&counter, || thread::sleep(Duration::from_millis(50)));
+    }
+}
@@ -0,0 +1,193 @@
+//! Derive macros for the testbench crate
+//!
+//! You should not use this crate directly. Instead, enable the `derive`
+//! feature of testbench, and use the macros that it re-exports.
+
+#![warn(
+    anonymous_parameters,
+    missing_copy_implementations,
+    missing_debug_implementations,
+    missing_docs,
+    nonstandard_style,
+    rust_2018_idioms,
+    single_use_lifetimes,
+    trivial_casts,
+    trivial_numeric_casts,
+    unreachable_pub,
+    unused_extern_crates,
+    unused_qualifications,
+    variant_size_differences
+)]
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, parse_quote, parse_quote_spanned, spanned::Spanned, Data, DeriveInput,
+    Error, Fields, Index,
+};
+
+/// Derive `AtomicData` for a struct whose fields all implement `AtomicData`
+///
+/// For a struct named `Foo`, this generates an atomic wrapper struct named
+/// `AtomicFoo`, with the same visibility, which holds the atomic wrapper of
+/// each field of `Foo`. Loads and stores access the fields one by one, in
+/// declaration order, so that reading a `RaceCell<Foo>` while it is being
+/// written can observe a mixture of old and new fields.
+///
+/// See the documentation of `testbench::race_cell` for more information.
+///
+#[proc_macro_derive(AtomicData)]
+pub fn derive_atomic_data(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match atomic_data_impl(input) {
+        Ok(output) => output.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+/// Generate the atomic wrapper and trait implementations for a struct
+fn atomic_data_impl(mut input: DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(data) => data.fields.clone(),
+        Data::Enum(data) => {
+            return Err(Error::new(
+                data.enum_token.span(),
+                "AtomicData can only be derived for structs, not enums",
+            ))
+        }
+        Data::Union(data) => {
+            return Err(Error::new(
+                data.union_token.span(),
+                "AtomicData can only be derived for structs, not unions",
+            ))
+        }
+    };
+
+    // Every field must be atomic data for the struct to be. Spanning these
+    // bounds with the fields makes errors point to the offending field.
+    let where_clause = input.generics.make_where_clause();
+    for field in fields.iter() {
+        let ty = &field.ty;
+        where_clause
+            .predicates
+            .push(parse_quote_spanned!(field.span()=> #ty: ::testbench::race_cell::AtomicData));
+    }
+
+    let vis = &input.vis;
+    let name = &input.ident;
+    let wrapper = format_ident!("Atomic{}", name);
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let wrapper_name = wrapper.to_string();
+    let mut debug_generics = input.generics.clone();
+    let debug_where_clause = debug_generics.make_where_clause();
+    for field in fields.iter() {
+        let ty = &field.ty;
+        debug_where_clause.predicates.push(parse_quote!(
+            <#ty as ::testbench::race_cell::AtomicData>::AtomicWrapper: ::std::fmt::Debug
+        ));
+    }
+    let wrapper_doc = format!("Atomic wrapper for `{}`, derived by `AtomicData`", name);
+
+    // Per-field code, keyed by field name or tuple index
+    let members = fields
+        .iter()
+        .enumerate()
+        .map(|(idx, field)| match &field.ident {
+            Some(ident) => quote!(#ident),
+            None => {
+                let idx = Index {
+                    index: idx as u32,
+                    span: Span::call_site(),
+                };
+                quote!(#idx)
+            }
+        })
+        .collect::<Vec<_>>();
+    let debug_fields = match &fields {
+        Fields::Named(_) => {
+            let member_names = members.iter().map(ToString::to_string);
+            quote!(f.debug_struct(#wrapper_name) #(.field(#member_names, &self.#members))*)
+        }
+        Fields::Unnamed(_) | Fields::Unit => {
+            quote!(f.debug_tuple(#wrapper_name) #(.field(&self.#members))*)
+        }
+    };
+    let wrapper_fields = fields.iter().map(|field| {
+        let ty = &field.ty;
+        let wrapper_ty = quote!(<#ty as ::testbench::race_cell::AtomicData>::AtomicWrapper);
+        match &field.ident {
+            Some(ident) => quote!(#ident: #wrapper_ty),
+            None => wrapper_ty,
+        }
+    });
+    let wrapper_def = match &fields {
+        Fields::Named(_) => {
+            quote!(#vis struct #wrapper #impl_generics #where_clause { #(#wrapper_fields,)* })
+        }
+        Fields::Unnamed(_) => {
+            quote!(#vis struct #wrapper #impl_generics (#(#wrapper_fields,)*) #where_clause;)
+        }
+        Fields::Unit => quote!(#vis struct #wrapper #impl_generics #where_clause;),
+    };
+    let identical = if members.is_empty() {
+        quote!(true)
+    } else {
+        quote!(#(::testbench::race_cell::AtomicData::identical(&self.#members, &other.#members))&&*)
+    };
+    let matches = if members.is_empty() {
+        quote!(true)
+    } else {
+        quote!(#(::testbench::race_cell::AtomicLoadStore::relaxed_matches(&self.#members, &other.#members))&&*)
+    };
+
+    Ok(quote! {
+        #[doc = #wrapper_doc]
+        #wrapper_def
+
+        impl #impl_generics ::std::fmt::Debug for #wrapper #ty_generics #debug_where_clause {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+                #debug_fields.finish()
+            }
+        }
+
+        impl #impl_generics ::testbench::race_cell::AtomicData for #name #ty_generics #where_clause {
+            type AtomicWrapper = #wrapper #ty_generics;
+
+            fn identical(&self, other: &Self) -> bool {
+                #identical
+            }
+        }
+
+        #[allow(unused_variables)]
+        impl #impl_generics ::testbench::race_cell::AtomicLoadStore for #wrapper #ty_generics #where_clause {
+            type Content = #name #ty_generics;
+
+            fn new(v: Self::Content) -> Self {
+                Self {
+                    #(#members: ::testbench::race_cell::AtomicLoadStore::new(v.#members),)*
+                }
+            }
+
+            fn relaxed_load(&self) -> Self::Content {
+                #name {
+                    #(#members: ::testbench::race_cell::AtomicLoadStore::relaxed_load(&self.#members),)*
+                }
+            }
+
+            fn relaxed_try_load(&self) -> Option<Self::Content> {
+                Some(#name {
+                    #(#members: ::testbench::race_cell::AtomicLoadStore::relaxed_try_load(&self.#members)?,)*
+                })
+            }
+
+            fn relaxed_matches(&self, other: &Self) -> bool {
+                #matches
+            }
+
+            fn relaxed_store(&self, val: Self::Content) {
+                #(::testbench::race_cell::AtomicLoadStore::relaxed_store(&self.#members, val.#members);)*
+            }
+        }
+    })
+}
@@ -0,0 +1,8 @@
+//! Compile-time error tests of the derive macros
+
+/// Misuse of the derive macros should be reported with clear errors
+#[test]
+fn ui() {
+    let tests = trybuild::TestCases::new();
+    tests.compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,9 @@
+use testbench::race_cell::AtomicData;
+
+#[derive(Clone, AtomicData)]
+enum State {
+    Idle,
+    Busy(u32),
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use testbench::race_cell::AtomicData;
+
+#[derive(Clone, AtomicData)]
+struct Entry {
+    id: u32,
+    name: String,
+}
+
+fn main() {}
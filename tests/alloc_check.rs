@@ -0,0 +1,125 @@
+//! Allocation accounting with CountingAllocator
+//!
+//! Process-wide allocation counts are affected by concurrently running tests,
+//! so all checks are carried out sequentially by a single test.
+
+use std::{
+    hint,
+    panic::{self, UnwindSafe},
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+};
+use testbench::{
+    alloc_check::{self, CountingAllocator},
+    sync::SpinBarrier,
+};
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator::new();
+
+#[test]
+fn alloc_check() {
+    counting();
+    no_leak();
+    leak();
+    no_alloc();
+    alloc();
+    thread_alloc();
+}
+
+/// Check that the operation panics with a message containing some text
+fn assert_panics_with(operation: impl FnOnce() + UnwindSafe, text: &str) {
+    let payload = panic::catch_unwind(operation).unwrap_err();
+    let message = payload.downcast_ref::<String>().unwrap();
+    assert!(message.contains(text), "{}", message);
+}
+
+// Allocations and deallocations should be counted, process-wide and per thread
+fn counting() {
+    assert!(alloc_check::is_installed());
+    let (start, thread_start) = (alloc_check::snapshot(), alloc_check::thread_snapshot());
+    let boxed = testbench::opaque::black_box(Box::new([0u8; 100]));
+    let allocated = alloc_check::thread_snapshot().since(&thread_start);
+    assert_eq!((allocated.allocations, allocated.allocated_bytes), (1, 100));
+    assert_eq!(
+        (allocated.live_allocations(), allocated.live_bytes()),
+        (1, 100)
+    );
+    drop(boxed);
+    let stats = alloc_check::thread_snapshot().since(&thread_start);
+    assert_eq!((stats.deallocations, stats.deallocated_bytes), (1, 100));
+    assert_eq!((stats.live_allocations(), stats.live_bytes()), (0, 0));
+    let stats = alloc_check::snapshot().since(&start);
+    assert!(stats.allocations >= 1 && stats.deallocations >= 1);
+}
+
+// Balanced allocations should pass leak checks, and results should be returned
+fn no_leak() {
+    let sum = alloc_check::assert_no_leaks(|| {
+        let values = testbench::opaque::black_box(vec![1u64; 1000]);
+        values.iter().sum::<u64>()
+    });
+    assert_eq!(sum, 1000);
+}
+
+// Deliberate leaks should be caught
+fn leak() {
+    let leaked = AtomicPtr::new(ptr::null_mut());
+    assert_panics_with(
+        || {
+            alloc_check::assert_no_leaks(|| {
+                let leak = Box::leak(testbench::opaque::black_box(Box::new(42u64)));
+                leaked.store(leak, Ordering::Relaxed);
+            })
+        },
+        "leaked 1 allocation(s) totaling 8 byte(s)",
+    );
+    // Free the allocation after the fact, as Miri would report it as leaked
+    drop(unsafe { Box::from_raw(leaked.load(Ordering::Relaxed)) });
+}
+
+// Operations that don't allocate should pass allocation checks
+fn no_alloc() {
+    let flag = AtomicBool::new(false);
+    alloc_check::assert_no_alloc(|| flag.store(true, Ordering::Relaxed));
+    alloc_check::assert_no_thread_alloc(|| {
+        for _ in 0..100 {
+            hint::spin_loop();
+        }
+    });
+    assert!(flag.load(Ordering::Relaxed));
+}
+
+// Deliberate allocations should be caught
+fn alloc() {
+    assert_panics_with(
+        || alloc_check::assert_no_alloc(|| drop(testbench::opaque::black_box(vec![0u8; 16]))),
+        "performed 1 allocation(s) totaling 16 byte(s) in the process",
+    );
+    assert_panics_with(
+        || {
+            alloc_check::assert_no_thread_alloc(|| {
+                drop(testbench::opaque::black_box(String::from("allocated")))
+            })
+        },
+        "performed 1 allocation(s) totaling 9 byte(s) in the current thread",
+    );
+}
+
+// Thread-scoped checks should ignore allocations from other threads
+fn thread_alloc() {
+    let barrier = SpinBarrier::new(2);
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            barrier.wait();
+            drop(testbench::opaque::black_box(vec![0u8; 16]));
+            barrier.wait();
+        });
+        let start = alloc_check::snapshot();
+        alloc_check::assert_no_thread_alloc(|| {
+            barrier.wait();
+            barrier.wait();
+        });
+        assert!(alloc_check::snapshot().since(&start).allocations >= 1);
+    });
+}
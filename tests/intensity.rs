@@ -0,0 +1,28 @@
+//! Test intensity overrides
+//!
+//! The intensity is process-wide, so all checks are carried out sequentially
+//! by a single test, in a dedicated test binary.
+
+use std::time::Duration;
+use testbench::{contention::Sweep, Intensity};
+
+#[test]
+fn intensity() {
+    Intensity::set_for_test(Intensity::Quick);
+    assert_eq!(Intensity::current(), Intensity::Quick);
+    let quick = testbench::scale_iters(1_000_000);
+
+    Intensity::set_for_test(Intensity::Soak);
+    assert_eq!(Intensity::current(), Intensity::Soak);
+    let soak = testbench::scale_iters(1_000_000);
+    assert!(soak >= 10 * quick, "{} vs {}", soak, quick);
+
+    // Soak sweeps repeat each measurement, unless told otherwise
+    let sweep_len = |sweep: Sweep| sweep.run(|_| || (), || Duration::ZERO).len();
+    assert_eq!(sweep_len(Sweep::new(&[0])), 5);
+    assert_eq!(sweep_len(Sweep::new(&[0]).repetitions(2)), 2);
+
+    Intensity::set_for_test(Intensity::Quick);
+    assert_eq!(sweep_len(Sweep::new(&[0])), 1);
+    assert_eq!(sweep_len(Sweep::new(&[0]).repetitions(3)), 3);
+}
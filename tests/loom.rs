@@ -0,0 +1,28 @@
+//! Model checking of RaceCell with loom
+//!
+//! These tests must be run with `RUSTFLAGS="--cfg loom"`.
+
+#![cfg(loom)]
+
+use loom::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use testbench::{loom::concurrent_test_2, race_cell::RaceCell};
+
+// Loom should explore an interleaving where a read overlaps with a write
+#[test]
+fn inconsistent_read() {
+    static INCONSISTENT: AtomicBool = AtomicBool::new(false);
+    loom::model(|| {
+        let cell = Arc::new(RaceCell::new(0u32));
+        let writer = cell.clone();
+        concurrent_test_2(
+            move || writer.set(1),
+            move || {
+                if cell.get().is_inconsistent() {
+                    INCONSISTENT.store(true, Ordering::Relaxed);
+                }
+            },
+        );
+    });
+    assert!(INCONSISTENT.load(Ordering::Relaxed));
+}
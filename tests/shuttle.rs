@@ -0,0 +1,50 @@
+//! Randomized schedule exploration of RaceCell with shuttle
+//!
+//! These tests must be run with `RUSTFLAGS="--cfg shuttle"`.
+
+#![cfg(shuttle)]
+
+use shuttle::{scheduler::RandomScheduler, Config, FailurePersistence, Runner};
+use std::{
+    fs,
+    panic::{self, AssertUnwindSafe},
+    process,
+};
+use testbench::{
+    concurrent_test_2,
+    race_cell::RaceCell,
+    shuttle::{explore, replay},
+};
+
+/// Racy test, which fails if a RaceCell read overlaps with a write
+fn racy_test() {
+    let cell = RaceCell::new(0u32);
+    concurrent_test_2(
+        || cell.set(1),
+        || assert!(cell.get().is_consistent(), "Observed an inconsistent read"),
+    );
+}
+
+// A failure found with a given seed should be reproducible from its schedule
+//
+// Shuttle's panic hook persists failing schedules according to the
+// configuration of the first test that it runs, so everything that needs a
+// shuttle runner must be done in this test.
+#[test]
+fn seeded_failure_replay() {
+    // Explore schedules with a fixed seed, saving the failing one to a file
+    let directory = std::env::temp_dir().join(format!("testbench-shuttle-{}", process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let mut config = Config::new();
+    config.failure_persistence = FailurePersistence::File(Some(directory.clone()));
+    let runner = Runner::new(RandomScheduler::new_from_seed(42, 1000), config);
+    assert!(panic::catch_unwind(AssertUnwindSafe(|| runner.run(racy_test))).is_err());
+
+    // Replaying the schedule should reproduce the failure
+    let schedule = fs::read_to_string(directory.join("schedule000.txt")).unwrap();
+    assert!(panic::catch_unwind(|| replay(&schedule, racy_test)).is_err());
+    fs::remove_dir_all(&directory).unwrap();
+
+    // Unseeded exploration should find the failure too
+    assert!(panic::catch_unwind(|| explore(1000, racy_test)).is_err());
+}